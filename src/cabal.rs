@@ -0,0 +1,197 @@
+//! Reading Haskell projects: those with a `cabal.project`, a `stack.yaml`, or a `.cabal` package
+//! description, whose `build-depends` fields list the Hackage packages they use.
+//!
+//! The `.cabal` files in the project directory and the directories right below it are read, which
+//! covers a single package as well as the usual layout of a multi-package project. Dependencies in
+//! conditional sections are all included, to leave nothing out.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use eyre::WrapErr;
+
+/// Files which make a directory a Haskell project even without a `.cabal` file of its own
+const PROJECT_FILES: &[&str] = &["cabal.project", "stack.yaml"];
+
+/// Directories holding build products rather than packages
+const BUILD_DIRS: &[&str] = &["dist", "dist-newstyle", ".stack-work"];
+
+/// Whether `project_dir` is a Haskell project
+pub fn is_project(project_dir: &Path) -> bool {
+    PROJECT_FILES
+        .iter()
+        .any(|file| project_dir.join(file).is_file())
+        || !cabal_files(project_dir).is_empty()
+}
+
+/// The Hackage packages the project's packages depend on
+pub async fn dependencies(project_dir: &Path) -> color_eyre::Result<BTreeSet<String>> {
+    let mut dependencies = BTreeSet::new();
+    for cabal_file in package_files(project_dir) {
+        let contents = tokio::fs::read_to_string(&cabal_file)
+            .await
+            .wrap_err_with(|| format!("Could not read `{}`", cabal_file.display()))?;
+        dependencies.extend(build_depends(&contents));
+    }
+    Ok(dependencies)
+}
+
+/// The files whose contents decide what a Haskell project needs, to watch for changes: its
+/// project files (whether or not it has them yet) and the `.cabal` files riff reads
+pub fn watched_files(project_dir: &Path) -> Vec<PathBuf> {
+    PROJECT_FILES
+        .iter()
+        .map(|file| project_dir.join(file))
+        .chain(package_files(project_dir))
+        .collect()
+}
+
+/// The `.cabal` files in the project directory and the directories right below it
+fn package_files(project_dir: &Path) -> Vec<PathBuf> {
+    let mut packages = cabal_files(project_dir);
+    if let Ok(entries) = std::fs::read_dir(project_dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir()
+                && !name.starts_with('.')
+                && !BUILD_DIRS.contains(&name.as_str())
+            {
+                packages.extend(cabal_files(&entry.path()));
+            }
+        }
+    }
+    packages
+}
+
+/// The `.cabal` files in `dir`, in order
+fn cabal_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut cabal_files = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "cabal")
+        })
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    cabal_files.sort();
+    cabal_files
+}
+
+/// The packages named in the `build-depends` fields of the `.cabal` file `contents`.
+///
+/// A field continues on the lines indented deeper than its name, and each of its comma-separated
+/// entries is a package name followed by an optional version range, eg `text >=1.2 && <2.1`.
+fn build_depends(contents: &str) -> BTreeSet<String> {
+    let mut packages = BTreeSet::new();
+    // The indentation of the `build-depends` field being read, if any
+    let mut field_indent = None;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with("--") {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        let value = match field_indent {
+            Some(field_indent) if indent > field_indent => trimmed,
+            _ => {
+                field_indent = None;
+                let Some((field, value)) = trimmed.split_once(':') else {
+                    continue;
+                };
+                if !field.trim().eq_ignore_ascii_case("build-depends") {
+                    continue;
+                }
+                field_indent = Some(indent);
+                value
+            }
+        };
+        for entry in value.split(',') {
+            let name = entry
+                .trim()
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect::<String>();
+            // What follows a line break inside a version range isn't a package
+            if name.chars().any(|c| c.is_ascii_alphabetic()) {
+                packages.insert(name);
+            }
+        }
+    }
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_depends_fields() {
+        let packages = build_depends(
+            "\
+cabal-version:      2.4
+name:               app
+build-type:         Simple
+
+library
+    exposed-modules:  App
+    -- build-depends: commented-out
+    build-depends:    base ^>=4.16, text >=1.2
+                        && <2.1
+                    , postgresql-simple
+    if os(linux)
+      Build-Depends: zlib
+    hs-source-dirs:   src
+
+executable app
+    main-is:          Main.hs
+    build-depends:
+        base,
+        app
+",
+        );
+        assert_eq!(
+            packages.into_iter().collect::<Vec<_>>(),
+            ["app", "base", "postgresql-simple", "text", "zlib"]
+        );
+    }
+
+    #[tokio::test]
+    async fn projects_and_their_packages() -> eyre::Result<()> {
+        let project = tempfile::TempDir::new()?;
+        assert!(!is_project(project.path()));
+        tokio::fs::write(project.path().join("stack.yaml"), "resolver: lts-20.0\n").await?;
+        assert!(is_project(project.path()));
+
+        let package = project.path().join("server");
+        tokio::fs::create_dir(&package).await?;
+        tokio::fs::write(
+            package.join("server.cabal"),
+            "library\n  build-depends: base, gtk\n",
+        )
+        .await?;
+        let build = project.path().join("dist-newstyle");
+        tokio::fs::create_dir(&build).await?;
+        tokio::fs::write(build.join("copy.cabal"), "library\n  build-depends: zlib\n").await?;
+
+        assert_eq!(
+            dependencies(project.path())
+                .await?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["base", "gtk"]
+        );
+        assert_eq!(
+            watched_files(project.path()),
+            [
+                project.path().join("cabal.project"),
+                project.path().join("stack.yaml"),
+                package.join("server.cabal"),
+            ]
+        );
+        Ok(())
+    }
+}
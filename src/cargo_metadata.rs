@@ -1,17 +1,763 @@
+//! The parts of `cargo metadata`'s output riff uses, cached per project so that warm runs don't
+//! need to run it again.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize};
+use target_lexicon::Triple;
+
 use crate::dependency_registry::rust::RustDependencyData;
+use crate::dependency_registry::schema::{self, Problem};
+use crate::flake_generator;
+use crate::riff_lock::{cargo_lock_sha256, sha256_hex, workspace_lock_sha256};
+use crate::{target_cfg, RIFF_XDG_PREFIX};
+
+/// Where cached `cargo metadata` output is kept, in `$XDG_CACHE_HOME/riff`
+const CARGO_METADATA_CACHE_DIR: &str = "cargo-metadata";
+
+/// Build dependencies whose purpose is finding native libraries on the system
+const NATIVE_LIBRARY_PROBES: &[&str] = &["metadeps", "pkg-config", "system-deps", "vcpkg"];
 
-#[derive(serde::Deserialize)]
+/// Build dependencies which build a bundled copy of a library, and only need a system one when
+/// they are combined with a probe
+const NATIVE_LIBRARY_BUILDERS: &[&str] = &["autotools", "cmake"];
+
+/// Returned when `--package` doesn't name a member of the workspace
+#[derive(Debug, thiserror::Error)]
+#[error("`{package}` is not a member of the workspace, which has {members}")]
+pub struct UnknownPackage {
+    package: String,
+    members: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CargoMetadata {
     pub packages: Vec<CargoMetadataPackage>,
+    /// The ids of the packages in the workspace
+    #[serde(default)]
+    pub workspace_members: Vec<String>,
+    /// The directory of the workspace's root manifest and its `Cargo.lock`
+    #[serde(default)]
+    pub workspace_root: Option<PathBuf>,
+    /// The `[workspace.metadata]` table
+    pub workspace_metadata: Option<RiffMetadata>,
+    /// The resolved dependency graph
+    #[serde(default)]
+    pub resolve: Option<CargoMetadataResolve>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CargoMetadataResolve {
+    pub nodes: Vec<CargoMetadataNode>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CargoMetadataNode {
+    pub id: String,
+    /// The ids of the packages this one depends on, of every kind
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    /// The packages this one depends on, with the kinds and targets of each dependency
+    #[serde(default)]
+    pub deps: Vec<CargoMetadataNodeDep>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CargoMetadataNodeDep {
+    pub pkg: String,
+    #[serde(default)]
+    pub dep_kinds: Vec<CargoMetadataDepKind>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CargoMetadataDepKind {
+    /// `dev`, `build`, or none for a normal dependency
+    #[serde(default)]
+    pub kind: Option<String>,
+    /// The target the dependency is declared for, eg `cfg(windows)`, or none for every target
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CargoMetadataPackage {
+    pub id: String,
     pub name: String,
+    pub version: semver::Version,
+    /// Where the package comes from, or none for a package on the local filesystem
+    #[serde(default)]
+    pub source: Option<String>,
+    pub manifest_path: PathBuf,
+    /// The `package.repository` of its manifest
+    #[serde(default)]
+    pub repository: Option<String>,
     pub metadata: Option<RiffMetadata>,
+    /// The native library the package declares it links to, with `package.links`
+    #[serde(default)]
+    pub links: Option<String>,
+    #[serde(default)]
+    pub targets: Vec<CargoMetadataTarget>,
+    #[serde(default)]
+    pub dependencies: Vec<CargoMetadataDependency>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CargoMetadataTarget {
+    /// Eg `lib`, `bin`, or `custom-build` for a build script
+    pub kind: Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CargoMetadataDependency {
+    pub name: String,
+    /// `dev`, `build`, or none for a normal dependency
+    pub kind: Option<String>,
+}
+
+/// `cargo metadata` output cached for a project, valid while its `Cargo.lock` and the manifests
+/// of its local packages are unchanged
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CachedCargoMetadata {
+    riff_version: String,
+    cargo_lock_sha256: String,
+    /// The SHA-256 of each local package's manifest, and of the project's own
+    manifests: BTreeMap<PathBuf, String>,
+    metadata: CargoMetadata,
+}
+
+impl CargoMetadata {
+    /// The cached metadata of the project in `project_dir`, if it is still valid
+    #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
+    pub async fn load_cached(project_dir: &Path) -> Option<Self> {
+        let path = cache_path(project_dir).ok()?;
+        let contents = tokio::fs::read_to_string(&path).await.ok()?;
+        let cached: CachedCargoMetadata = match serde_json::from_str(&contents) {
+            Ok(cached) => cached,
+            Err(err) => {
+                tracing::debug!(%err, "Ignoring unreadable cached `cargo metadata` output");
+                return None;
+            }
+        };
+        if cached.riff_version != env!("CARGO_PKG_VERSION")
+            || cached
+                .metadata
+                .cargo_lock_sha256(project_dir)
+                .await
+                .ok()??
+                != cached.cargo_lock_sha256
+        {
+            return None;
+        }
+        // Cached before riff kept the dependency graph, which `--package` and `--target` need
+        if cached.metadata.resolve.is_none() {
+            tracing::debug!("Cached `cargo metadata` output has no dependency graph");
+            return None;
+        }
+        for (manifest, sha256) in &cached.manifests {
+            let contents = tokio::fs::read(manifest).await.ok()?;
+            if sha256_hex(&contents) != *sha256 {
+                tracing::debug!(manifest = %manifest.display(), "Manifest changed since `cargo metadata` was cached");
+                return None;
+            }
+        }
+        tracing::debug!("Using cached `cargo metadata` output");
+        Some(cached.metadata)
+    }
+
+    /// The SHA-256 of the workspace's `Cargo.lock`, found from the metadata when it says where
+    /// the workspace's root is
+    async fn cargo_lock_sha256(&self, project_dir: &Path) -> color_eyre::Result<Option<String>> {
+        match &self.workspace_root {
+            Some(workspace_root) => workspace_lock_sha256(workspace_root).await,
+            None => cargo_lock_sha256(project_dir).await,
+        }
+    }
+
+    /// Cache this metadata of the project in `project_dir`
+    #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
+    pub async fn cache(&self, project_dir: &Path) -> color_eyre::Result<()> {
+        let Some(cargo_lock_sha256) = self.cargo_lock_sha256(project_dir).await? else {
+            return Ok(());
+        };
+        let local_manifests = self
+            .packages
+            .iter()
+            .filter(|package| package.source.is_none())
+            .map(|package| package.manifest_path.clone());
+        let mut manifests = BTreeMap::new();
+        for manifest in local_manifests.chain([project_dir.join("Cargo.toml")]) {
+            let contents = tokio::fs::read(&manifest)
+                .await
+                .wrap_err_with(|| format!("Could not read `{}`", manifest.display()))?;
+            manifests.insert(manifest, sha256_hex(&contents));
+        }
+
+        let cached = CachedCargoMetadata {
+            riff_version: env!("CARGO_PKG_VERSION").to_string(),
+            cargo_lock_sha256,
+            manifests,
+            metadata: self.clone(),
+        };
+        let path = cache_path(project_dir)?;
+        crate::execution::write(&path, serde_json::to_string(&cached)?).await
+    }
+}
+
+/// The root of the workspace whose member's manifest is in `cargo_dir`, where its `Cargo.lock`
+/// is, as `cargo metadata` finds it.
+///
+/// A directory with a `Cargo.lock`, or whose manifest declares a workspace, is its own root, so
+/// `cargo` is only asked about a member (or a package not locked yet). If it can't say, the
+/// directory is taken to be the root.
+pub(crate) async fn workspace_root(cargo_dir: &Path) -> PathBuf {
+    if cargo_dir.join("Cargo.lock").is_file()
+        || flake_generator::is_workspace_root(&cargo_dir.join("Cargo.toml"))
+    {
+        return cargo_dir.to_path_buf();
+    }
+    let mut command = tokio::process::Command::new("cargo");
+    command
+        .args([
+            "metadata",
+            "--no-deps",
+            "--offline",
+            "--format-version",
+            "1",
+        ])
+        .arg("--manifest-path")
+        .arg(cargo_dir.join("Cargo.toml"))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    let output = match crate::command_runner::run(&mut command, true, &mut |_| {}).await {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            tracing::debug!(stderr = %String::from_utf8_lossy(&output.stderr), "`cargo metadata` failed to find the workspace root");
+            return cargo_dir.to_path_buf();
+        }
+        Err(err) => {
+            tracing::debug!(%err, "Could not run `cargo metadata` to find the workspace root");
+            return cargo_dir.to_path_buf();
+        }
+    };
+
+    #[derive(Deserialize)]
+    struct WorkspaceRoot {
+        workspace_root: PathBuf,
+    }
+    match serde_json::from_slice::<WorkspaceRoot>(&output.stdout) {
+        Ok(WorkspaceRoot { workspace_root }) => workspace_root,
+        Err(err) => {
+            tracing::debug!(%err, "Could not read the workspace root from `cargo metadata`");
+            cargo_dir.to_path_buf()
+        }
+    }
+}
+
+/// The cache file for the project in `project_dir`, named for the directory's path
+pub(crate) fn cache_path(project_dir: &Path) -> color_eyre::Result<PathBuf> {
+    let file_name = format!("{}.json", flake_generator::project_cache_key(project_dir));
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.place_cache_file(Path::new(CARGO_METADATA_CACHE_DIR).join(file_name))?)
 }
 
-#[derive(serde::Deserialize)]
+impl CargoMetadata {
+    /// The ids of the workspace member called `member` and every package it depends on,
+    /// directly or not
+    pub fn dependency_closure(&self, member: &str) -> color_eyre::Result<HashSet<String>> {
+        let Some(root) = self
+            .packages
+            .iter()
+            .find(|package| package.name == member && self.workspace_members.contains(&package.id))
+        else {
+            let members = self
+                .packages
+                .iter()
+                .filter(|package| self.workspace_members.contains(&package.id))
+                .map(|package| format!("`{}`", package.name))
+                .collect::<Vec<_>>();
+            return Err(UnknownPackage {
+                package: member.to_string(),
+                members: members.join(", "),
+            }
+            .into());
+        };
+        let resolve = self.resolve.as_ref().ok_or_else(|| {
+            eyre!("`cargo metadata` didn't resolve the dependencies of `{member}`")
+        })?;
+        let dependencies: HashMap<&str, &[String]> = resolve
+            .nodes
+            .iter()
+            .map(|node| (node.id.as_str(), node.dependencies.as_slice()))
+            .collect();
+
+        let mut closure = HashSet::from([root.id.clone()]);
+        let mut pending = vec![root.id.as_str()];
+        while let Some(id) = pending.pop() {
+            for dependency in dependencies.get(id).copied().unwrap_or_default() {
+                if closure.insert(dependency.clone()) {
+                    pending.push(dependency);
+                }
+            }
+        }
+        Ok(closure)
+    }
+
+    /// The ids of the workspace members and every package they depend on when building for
+    /// `target`, leaving out those only depended on for other targets (with
+    /// `[target.'cfg(...)'.dependencies]`), if `cargo metadata` said which they are.
+    ///
+    /// Build dependencies and procedural macros, and everything they depend on, are built for the
+    /// host whatever the target, so their dependencies are those of the host.
+    pub fn target_closure(&self, target: &Triple) -> Option<HashSet<String>> {
+        let nodes = &self.resolve.as_ref()?.nodes;
+        if nodes.iter().all(|node| node.deps.is_empty()) {
+            return None;
+        }
+        let dependencies: HashMap<&str, &[CargoMetadataNodeDep]> = nodes
+            .iter()
+            .map(|node| (node.id.as_str(), node.deps.as_slice()))
+            .collect();
+        let proc_macros = self
+            .packages
+            .iter()
+            .filter(|package| {
+                package
+                    .targets
+                    .iter()
+                    .any(|target| target.kind.iter().any(|kind| kind == "proc-macro"))
+            })
+            .map(|package| package.id.as_str())
+            .collect::<HashSet<_>>();
+        let host = Triple::host();
+
+        // Each package with whether it's built for the host, since a package can be built for both
+        let mut visited = self
+            .workspace_members
+            .iter()
+            .map(|member| (member.as_str(), false))
+            .collect::<HashSet<_>>();
+        let mut pending = visited.iter().copied().collect::<Vec<_>>();
+        while let Some((id, for_host)) = pending.pop() {
+            let for_host = for_host || proc_macros.contains(id);
+            for dependency in dependencies.get(id).copied().unwrap_or_default() {
+                // A dependency declared without `dep_kinds` (by an older Cargo) is kept
+                let kinds = dependency.dep_kinds.iter().filter(|kind| {
+                    let kind_for_host = for_host || kind.kind.as_deref() == Some("build");
+                    let target = if kind_for_host { &host } else { target };
+                    kind.target
+                        .as_deref()
+                        .is_none_or(|spec| target_cfg::applies(spec, target))
+                });
+                let mut builds = kinds
+                    .map(|kind| for_host || kind.kind.as_deref() == Some("build"))
+                    .collect::<Vec<_>>();
+                if dependency.dep_kinds.is_empty() {
+                    builds.push(for_host);
+                }
+                for dependency_for_host in builds {
+                    if visited.insert((dependency.pkg.as_str(), dependency_for_host)) {
+                        pending.push((&dependency.pkg, dependency_for_host));
+                    }
+                }
+            }
+        }
+        Some(visited.into_iter().map(|(id, _)| id.to_string()).collect())
+    }
+}
+
+impl CargoMetadataPackage {
+    /// The repositories a package from git or a path may be a copy of: the one its manifest
+    /// names, and the one it was fetched from. Packages from a registry have none, since their
+    /// name is what identifies them.
+    pub fn repositories(&self) -> Vec<&str> {
+        match self.source.as_deref() {
+            None => self.repository.as_deref().into_iter().collect(),
+            Some(source) if source.starts_with("git+") => self
+                .repository
+                .as_deref()
+                .into_iter()
+                .chain(Some(source))
+                .collect(),
+            Some(_) => vec![],
+        }
+    }
+
+    /// Static signals that the package's build script looks for a native library on the system,
+    /// which are only meaningful if it has a build script that uses one of the
+    /// [`NATIVE_LIBRARY_PROBES`]. `links` or a [`NATIVE_LIBRARY_BUILDERS`] crate alone usually
+    /// means the library is bundled.
+    pub fn native_library_probes(&self) -> Vec<String> {
+        let has_build_script = self
+            .targets
+            .iter()
+            .any(|target| target.kind.iter().any(|kind| kind == "custom-build"));
+        if !has_build_script {
+            return vec![];
+        }
+
+        let build_dependencies = self
+            .dependencies
+            .iter()
+            .filter(|dependency| dependency.kind.as_deref() == Some("build"))
+            .map(|dependency| dependency.name.as_str())
+            .collect::<Vec<_>>();
+        if !build_dependencies
+            .iter()
+            .any(|name| NATIVE_LIBRARY_PROBES.contains(name))
+        {
+            return vec![];
+        }
+
+        let links = self.links.iter().map(|links| format!("links to `{links}`"));
+        let probes = build_dependencies
+            .into_iter()
+            .filter(|name| {
+                NATIVE_LIBRARY_PROBES.contains(name) || NATIVE_LIBRARY_BUILDERS.contains(name)
+            })
+            .map(|name| format!("uses `{name}`"));
+        links.chain(probes).collect()
+    }
+}
+
+/// The `riff` table of a `[package.metadata]` or `[workspace.metadata]` table
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawRiffMetadata")]
 pub struct RiffMetadata {
     pub riff: Option<RustDependencyData>,
+    /// The fields of `riff` which riff doesn't know, eg `build_inputs` for `build-inputs`
+    #[serde(
+        rename = "riff-unknown-fields",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub unknown_fields: Vec<Problem>,
+}
+
+/// A `RiffMetadata` before its unknown fields are found, which needs the `riff` table as it was
+/// written
+#[derive(Deserialize)]
+struct RawRiffMetadata {
+    riff: Option<serde_json::Value>,
+    /// The unknown fields found before the metadata was cached
+    #[serde(rename = "riff-unknown-fields", default)]
+    unknown_fields: Vec<Problem>,
+}
+
+impl TryFrom<RawRiffMetadata> for RiffMetadata {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawRiffMetadata) -> Result<Self, Self::Error> {
+        let mut unknown_fields = raw.unknown_fields;
+        let riff = match raw.riff {
+            Some(riff) => {
+                unknown_fields.extend(schema::unknown_dependency_fields(&riff));
+                serde_json::from_value(riff)?
+            }
+            None => None,
+        };
+        Ok(Self {
+            riff,
+            unknown_fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dependency_closure() -> eyre::Result<()> {
+        let package = |name: &str| {
+            serde_json::json!({
+                "id": name,
+                "name": name,
+                "version": "0.1.0",
+                "manifest_path": format!("/{name}/Cargo.toml"),
+                "metadata": null,
+            })
+        };
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [package("backend"), package("frontend"), package("openssl-sys"), package("libc")],
+            "workspace_members": ["backend", "frontend"],
+            "workspace_metadata": null,
+            "resolve": {
+                "nodes": [
+                    { "id": "backend", "dependencies": ["openssl-sys"] },
+                    { "id": "frontend", "dependencies": ["libc"] },
+                    { "id": "openssl-sys", "dependencies": ["libc"] },
+                    { "id": "libc", "dependencies": [] },
+                ],
+            },
+        }))?;
+
+        assert_eq!(
+            metadata.dependency_closure("backend")?,
+            HashSet::from(["backend", "openssl-sys", "libc"].map(String::from))
+        );
+        assert_eq!(
+            metadata.dependency_closure("frontend")?,
+            HashSet::from(["frontend", "libc"].map(String::from))
+        );
+        assert!(metadata.dependency_closure("libc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn target_closure() -> eyre::Result<()> {
+        let package = |name: &str| {
+            serde_json::json!({
+                "id": name,
+                "name": name,
+                "version": "0.1.0",
+                "manifest_path": format!("/{name}/Cargo.toml"),
+                "metadata": null,
+            })
+        };
+        let dep = |name: &str, target: Option<&str>| serde_json::json!({ "pkg": name, "dep_kinds": [{ "kind": null, "target": target }] });
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [package("app"), package("winapi"), package("libc"), package("openssl-sys")],
+            "workspace_members": ["app"],
+            "workspace_metadata": null,
+            "resolve": {
+                "nodes": [
+                    {
+                        "id": "app",
+                        "dependencies": ["winapi", "libc"],
+                        "deps": [dep("winapi", Some("cfg(windows)")), dep("libc", Some("cfg(unix)"))],
+                    },
+                    { "id": "winapi", "dependencies": ["openssl-sys"], "deps": [dep("openssl-sys", None)] },
+                    { "id": "libc", "dependencies": [], "deps": [] },
+                    { "id": "openssl-sys", "dependencies": [], "deps": [] },
+                ],
+            },
+        }))?;
+
+        let linux =
+            target_cfg::parse_target("x86_64-unknown-linux-gnu").map_err(eyre::Error::msg)?;
+        assert_eq!(
+            metadata.target_closure(&linux),
+            Some(HashSet::from(["app", "libc"].map(String::from)))
+        );
+        let windows =
+            target_cfg::parse_target("x86_64-pc-windows-msvc").map_err(eyre::Error::msg)?;
+        assert_eq!(
+            metadata.target_closure(&windows),
+            Some(HashSet::from(
+                ["app", "winapi", "openssl-sys"].map(String::from)
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn native_library_probes() -> eyre::Result<()> {
+        let package: CargoMetadataPackage = serde_json::from_str(
+            r#"{
+                "id": "libgit2-wrapper 0.1.0",
+                "name": "libgit2-wrapper",
+                "version": "0.1.0",
+                "manifest_path": "/src/Cargo.toml",
+                "metadata": null,
+                "links": "git2",
+                "targets": [{ "kind": ["lib"] }, { "kind": ["custom-build"] }],
+                "dependencies": [
+                    { "name": "pkg-config", "kind": "build" },
+                    { "name": "cc", "kind": "build" },
+                    { "name": "cmake", "kind": "dev" }
+                ]
+            }"#,
+        )?;
+        assert_eq!(
+            package.native_library_probes(),
+            vec!["links to `git2`", "uses `pkg-config`"]
+        );
+
+        let package: CargoMetadataPackage = serde_json::from_str(
+            r#"{
+                "id": "no-build-script 0.1.0",
+                "name": "no-build-script",
+                "version": "0.1.0",
+                "manifest_path": "/src/Cargo.toml",
+                "metadata": null,
+                "links": "git2",
+                "targets": [{ "kind": ["lib"] }]
+            }"#,
+        )?;
+        assert!(package.native_library_probes().is_empty());
+
+        // Builds its own copy of the library
+        let package: CargoMetadataPackage = serde_json::from_str(
+            r#"{
+                "id": "bundled-sys 0.1.0",
+                "name": "bundled-sys",
+                "version": "0.1.0",
+                "manifest_path": "/src/Cargo.toml",
+                "metadata": null,
+                "links": "bundled",
+                "targets": [{ "kind": ["lib"] }, { "kind": ["custom-build"] }],
+                "dependencies": [
+                    { "name": "cmake", "kind": "build" },
+                    { "name": "cc", "kind": "build" }
+                ]
+            }"#,
+        )?;
+        assert!(package.native_library_probes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn repositories_of_packages_not_from_a_registry() -> eyre::Result<()> {
+        let package = |source: serde_json::Value| {
+            serde_json::from_value::<CargoMetadataPackage>(serde_json::json!({
+                "id": "rocksdb-sys-fork 0.11.0",
+                "name": "rocksdb-sys-fork",
+                "version": "0.11.0",
+                "source": source,
+                "manifest_path": "/src/Cargo.toml",
+                "repository": "https://github.com/rust-rocksdb/rust-rocksdb",
+                "metadata": null,
+            }))
+        };
+
+        assert_eq!(
+            package(serde_json::Value::Null)?.repositories(),
+            ["https://github.com/rust-rocksdb/rust-rocksdb"]
+        );
+        assert_eq!(
+            package("git+https://github.com/acme/rocksdb?branch=main#4e5c2f1".into())?
+                .repositories(),
+            [
+                "https://github.com/rust-rocksdb/rust-rocksdb",
+                "git+https://github.com/acme/rocksdb?branch=main#4e5c2f1",
+            ]
+        );
+        // Crates from a registry are known by their names
+        assert!(
+            package("registry+https://github.com/rust-lang/crates.io-index".into())?
+                .repositories()
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_dependencies_are_built_for_the_host() -> eyre::Result<()> {
+        let package = |name: &str| {
+            serde_json::json!({
+                "id": name,
+                "name": name,
+                "version": "0.1.0",
+                "manifest_path": format!("/{name}/Cargo.toml"),
+                "metadata": null,
+            })
+        };
+        let dep = |name: &str, kind: Option<&str>, target: Option<&str>| serde_json::json!({ "pkg": name, "dep_kinds": [{ "kind": kind, "target": target }] });
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [package("app"), package("cc"), package("jobserver"), package("winapi")],
+            "workspace_members": ["app"],
+            "workspace_metadata": null,
+            "resolve": {
+                "nodes": [
+                    {
+                        "id": "app",
+                        "dependencies": ["cc", "winapi"],
+                        "deps": [dep("cc", Some("build"), None), dep("winapi", Some("build"), Some("cfg(windows)"))],
+                    },
+                    { "id": "cc", "dependencies": ["jobserver"], "deps": [dep("jobserver", None, Some("cfg(unix)"))] },
+                    { "id": "jobserver", "dependencies": [], "deps": [] },
+                    { "id": "winapi", "dependencies": [], "deps": [] },
+                ],
+            },
+        }))?;
+
+        // The build script runs on this (Unix) machine, whatever the target
+        let windows =
+            target_cfg::parse_target("x86_64-pc-windows-msvc").map_err(eyre::Error::msg)?;
+        assert_eq!(
+            metadata.target_closure(&windows),
+            Some(HashSet::from(["app", "cc", "jobserver"].map(String::from)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_is_invalidated_by_changes() -> eyre::Result<()> {
+        let cache_dir = tempfile::TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let project_dir = tempfile::TempDir::new()?;
+        let manifest = project_dir.path().join("Cargo.toml");
+        tokio::fs::write(&manifest, "[package]\nname = \"app\"").await?;
+        tokio::fs::write(project_dir.path().join("Cargo.lock"), "version = 3").await?;
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [{
+                "id": "app 0.1.0",
+                "name": "app",
+                "version": "0.1.0",
+                "manifest_path": manifest,
+                "metadata": null,
+            }],
+            "workspace_metadata": null,
+            "resolve": { "nodes": [{ "id": "app 0.1.0" }] },
+        }))?;
+
+        assert!(CargoMetadata::load_cached(project_dir.path())
+            .await
+            .is_none());
+        // Without the dependency graph, as cached by older versions
+        CargoMetadata {
+            resolve: None,
+            ..metadata.clone()
+        }
+        .cache(project_dir.path())
+        .await?;
+        assert!(CargoMetadata::load_cached(project_dir.path())
+            .await
+            .is_none());
+        metadata.cache(project_dir.path()).await?;
+        let cached = CargoMetadata::load_cached(project_dir.path()).await;
+        assert_eq!(cached.map(|cached| cached.packages.len()), Some(1));
+
+        tokio::fs::write(project_dir.path().join("Cargo.lock"), "version = 4").await?;
+        assert!(CargoMetadata::load_cached(project_dir.path())
+            .await
+            .is_none());
+        metadata.cache(project_dir.path()).await?;
+        tokio::fs::write(&manifest, "[package]\nname = \"renamed\"").await?;
+        assert!(CargoMetadata::load_cached(project_dir.path())
+            .await
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_root_of_a_member() -> eyre::Result<()> {
+        let workspace = tempfile::TempDir::new()?;
+        let member = workspace.path().join("app");
+        tokio::fs::create_dir(&member).await?;
+        tokio::fs::write(
+            workspace.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"app\"]",
+        )
+        .await?;
+        tokio::fs::write(member.join("Cargo.toml"), "[package]\nname = \"app\"").await?;
+        let runner =
+            std::sync::Arc::new(crate::command_runner::RecordingRunner::default().respond(
+                "cargo metadata --no-deps",
+                0,
+                &serde_json::json!({ "workspace_root": workspace.path() }).to_string(),
+                "",
+            ));
+
+        assert_eq!(workspace_root(workspace.path()).await, workspace.path());
+        let root = crate::command_runner::scope(runner.clone(), workspace_root(&member)).await;
+        assert_eq!(root, workspace.path());
+        // Only the member needed asking `cargo`
+        assert_eq!(runner.commands().len(), 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,268 @@
+//! The `ci` subcommand.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use clap::Args;
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use target_lexicon::Triple;
+
+use super::run::{first_failure, run_steps, summary_table, Step, StepOutcome};
+use crate::flake_generator::{self, GenerateOptions};
+use crate::install_nix;
+use crate::offline::OfflineMode;
+use crate::project_config::ProjectConfig;
+
+/// The steps run when none are given
+const DEFAULT_STEPS: &[&str] = &["cargo build --locked", "cargo test --locked"];
+
+/// Build and test your project in CI, with settings suited to pipelines
+///
+/// Compared to `riff run`, this reports plain progress without a spinner, never prompts (commands
+/// get no stdin, and a missing Nix is an error), fails on any warning, and reports the resource
+/// usage of every step. Without any commands, it runs `cargo build --locked` and
+/// `cargo test --locked`.
+///
+///     $ riff ci
+///
+/// Run your own steps instead, writing a JSON summary for the pipeline to keep:
+///
+///     $ RIFF_CI_OUTPUT=riff-ci.json riff ci --and 'cargo clippy --locked' --and 'cargo test --locked'
+#[derive(Debug, Args)]
+pub struct Ci {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// A shell command to run after the previous one, in the same environment (may be repeated)
+    #[clap(long = "and", value_name = "COMMAND")]
+    and: Vec<String>,
+    /// Keep running the remaining commands after one fails
+    #[clap(long)]
+    keep_going: bool,
+    /// Where to write a JSON summary of the steps and their outcomes
+    #[clap(long, env = "RIFF_CI_OUTPUT", value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// The command to run with your project's dependencies
+    command: Vec<String>,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
+}
+
+impl Ci {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let commands = self.project_commands().await;
+        let steps = self.steps(commands.as_ref().unwrap_or(&BTreeMap::new()));
+        let result = match commands {
+            Ok(_) => self.run(&steps).await,
+            Err(err) => Err(err),
+        };
+
+        if let Some(output) = &self.output {
+            let summary = match &result {
+                Ok(outcomes) => CiSummary::new(&steps, outcomes, None),
+                Err(err) => CiSummary::new(&steps, &[], Some(format!("{err:#}"))),
+            };
+            tokio::fs::write(output, serde_json::to_string_pretty(&summary)?)
+                .await
+                .wrap_err_with(|| format!("Could not write `{}`", output.display()))?;
+        }
+
+        let outcomes = result?;
+        eprintln!("\n{}", summary_table(&steps, &outcomes, true));
+        Ok(Some(first_failure(&outcomes)))
+    }
+
+    async fn run(&self, steps: &[Step]) -> color_eyre::Result<Vec<StepOutcome>> {
+        if install_nix::find_nix().is_none() {
+            return Err(eyre!(
+                "`{nix}` isn't installed, install it in an earlier step of your pipeline, for example with {url}",
+                nix = "nix".cyan(),
+                url = "https://github.com/DeterminateSystems/nix-installer-action"
+                    .blue()
+                    .underline(),
+            ));
+        }
+
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            self.project_dir.clone(),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                disable_telemetry: self.disable_telemetry,
+                deny_warnings: true,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+        let dev_env =
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh)
+                .await?;
+
+        run_steps(
+            &dev_env,
+            steps,
+            self.keep_going,
+            false,
+            &Default::default(),
+            None,
+        )
+        .await
+    }
+
+    /// The commands defined in the project's `riff.toml`
+    async fn project_commands(&self) -> color_eyre::Result<BTreeMap<String, String>> {
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir.clone(),
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        Ok(ProjectConfig::load(&project_dir).await?.commands)
+    }
+
+    /// The main command (if any) and each `--and` command, or the default steps if there are none
+    fn steps(&self, commands: &BTreeMap<String, String>) -> Vec<Step> {
+        if self.command.is_empty() && self.and.is_empty() {
+            return DEFAULT_STEPS
+                .iter()
+                .map(|script| Step::shell(script, commands))
+                .collect();
+        }
+        let main_step = (!self.command.is_empty()).then(|| Step::command(&self.command, commands));
+        main_step
+            .into_iter()
+            .chain(self.and.iter().map(|script| Step::shell(script, commands)))
+            .collect()
+    }
+}
+
+/// The JSON summary written to `--output`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CiSummary {
+    riff_version: &'static str,
+    success: bool,
+    /// Why the environment couldn't be prepared, in which case no steps ran
+    error: Option<String>,
+    steps: Vec<CiStepSummary>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CiStepSummary {
+    command: String,
+    /// `passed`, `failed`, or `skipped`
+    status: &'static str,
+    exit_code: Option<i32>,
+    wall_time_secs: Option<f64>,
+    cpu_time_secs: Option<f64>,
+    max_rss_bytes: Option<u64>,
+}
+
+impl CiSummary {
+    fn new(steps: &[Step], outcomes: &[StepOutcome], error: Option<String>) -> Self {
+        let steps = steps
+            .iter()
+            .enumerate()
+            .map(|(index, step)| {
+                let mut summary = CiStepSummary {
+                    command: step.label.clone(),
+                    status: "skipped",
+                    exit_code: None,
+                    wall_time_secs: None,
+                    cpu_time_secs: None,
+                    max_rss_bytes: None,
+                };
+                if let Some(StepOutcome::Finished { code, usage }) = outcomes.get(index) {
+                    summary.status = if *code == 0 { "passed" } else { "failed" };
+                    summary.exit_code = Some(*code);
+                    summary.wall_time_secs = Some(usage.wall_time.as_secs_f64());
+                    summary.cpu_time_secs = usage.cpu_time.map(|cpu_time| cpu_time.as_secs_f64());
+                    summary.max_rss_bytes = usage.max_rss_bytes;
+                }
+                summary
+            })
+            .collect::<Vec<_>>();
+        Self {
+            riff_version: env!("CARGO_PKG_VERSION"),
+            success: error.is_none() && steps.iter().all(|step| step.status == "passed"),
+            error,
+            steps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::nix_dev_env::ResourceUsage;
+
+    #[test]
+    fn summary_json() -> eyre::Result<()> {
+        let steps = DEFAULT_STEPS
+            .iter()
+            .map(|script| Step::shell(script, &BTreeMap::new()))
+            .collect::<Vec<_>>();
+        let outcomes = [
+            StepOutcome::Finished {
+                code: 101,
+                usage: ResourceUsage {
+                    wall_time: Duration::from_secs(2),
+                    ..Default::default()
+                },
+            },
+            StepOutcome::Skipped,
+        ];
+
+        let summary = serde_json::to_value(CiSummary::new(&steps, &outcomes, None))?;
+        assert_eq!(summary["success"], false);
+        assert_eq!(summary["steps"][0]["command"], "cargo build --locked");
+        assert_eq!(summary["steps"][0]["status"], "failed");
+        assert_eq!(summary["steps"][0]["exit-code"], 101);
+        assert_eq!(summary["steps"][0]["wall-time-secs"], 2.0);
+        assert_eq!(summary["steps"][1]["status"], "skipped");
+
+        let summary = serde_json::to_value(CiSummary::new(
+            &steps,
+            &[],
+            Some("no Cargo.toml".to_string()),
+        ))?;
+        assert_eq!(summary["error"], "no Cargo.toml");
+        assert_eq!(summary["steps"][0]["status"], "skipped");
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! The `clean` subcommand.
+
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use super::hook;
+use crate::emitters::{self, EmitKind};
+use crate::{cargo_metadata, execution, flake_generator, nix_dev_env};
+
+/// The files `riff generate` writes which carry the marker of a generated file
+const MARKED_KINDS: [EmitKind; 3] = [EmitKind::Flake, EmitKind::Envrc, EmitKind::RustAnalyzer];
+
+/// Remove what riff generated from your project
+///
+/// Removes the files `riff generate` wrote into the project which haven't been ejected (those
+/// starting with a `# Generated by riff` comment, and the `flake.lock` of such a `flake.nix`),
+/// and what riff cached about the project. Files riff didn't generate, `riff.toml`, and
+/// `riff.lock` are kept. See what would be removed first:
+///
+///     $ riff clean --dry-run
+///
+/// The environments `nix print-dev-env` evaluated are cached by their flake, which projects with
+/// the same dependencies share, so only `--all` removes them.
+#[derive(Debug, Args)]
+pub struct Clean {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Also remove the environments riff cached for every project
+    #[clap(long)]
+    all: bool,
+    #[clap(from_global)]
+    dry_run: bool,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+impl Clean {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let project_dir =
+            flake_generator::resolve_project_dir(self.project_dir, None, !self.no_walk_up)?;
+
+        let mut removable = generated_files(&project_dir).await?;
+        for cache in [
+            cargo_metadata::cache_path(&project_dir)?,
+            hook::cache_path(&project_dir)?,
+        ] {
+            if cache.exists() {
+                removable.push(cache);
+            }
+        }
+        if self.all {
+            removable.extend(nix_dev_env::cached_dev_envs()?);
+        }
+
+        if removable.is_empty() {
+            eprintln!("Nothing to clean in `{}`", project_dir.display());
+            return Ok(None);
+        }
+        for path in removable {
+            execution::remove_file(&path).await?;
+            if !self.dry_run {
+                println!("{check} Removed `{}`", path.display(), check = "✓".green());
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The files in `project_dir` riff generated
+async fn generated_files(project_dir: &Path) -> color_eyre::Result<Vec<PathBuf>> {
+    let mut generated = Vec::new();
+    for kind in MARKED_KINDS {
+        let path = project_dir.join(kind.emitter().path());
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Unable to read `{}`", path.display()))
+            }
+        };
+        if !emitters::is_generated(&contents) {
+            tracing::debug!(path = %path.display(), "Keeping a file riff didn't generate");
+            continue;
+        }
+        generated.push(path);
+
+        // The lock of a generated flake has no use without it
+        if kind == EmitKind::Flake {
+            let lock = project_dir.join(EmitKind::Lock.emitter().path());
+            if lock.exists() {
+                generated.push(lock);
+            }
+        }
+    }
+    Ok(generated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::fs::write;
+
+    #[tokio::test]
+    async fn only_generated_files_are_cleaned() -> eyre::Result<()> {
+        let project_dir = tempfile::TempDir::new()?;
+        let project_dir = project_dir.path();
+        write(
+            project_dir.join("flake.nix"),
+            "# Generated by riff from the project's dependencies\n{ }\n",
+        )
+        .await?;
+        write(project_dir.join("flake.lock"), "{}").await?;
+        write(project_dir.join(".envrc"), "use flake\n").await?;
+        write(
+            project_dir.join("rust-analyzer.toml"),
+            "# Generated by riff: run cargo inside the riff environment\n",
+        )
+        .await?;
+
+        assert_eq!(
+            generated_files(project_dir).await?,
+            [
+                project_dir.join("flake.nix"),
+                project_dir.join("flake.lock"),
+                project_dir.join("rust-analyzer.toml"),
+            ]
+        );
+
+        // An ejected flake is the project's own, and so is its lock
+        write(
+            project_dir.join("flake.nix"),
+            "# The development environment\n{ }\n",
+        )
+        .await?;
+        assert_eq!(
+            generated_files(project_dir).await?,
+            [project_dir.join("rust-analyzer.toml")]
+        );
+        Ok(())
+    }
+}
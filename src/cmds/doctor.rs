@@ -0,0 +1,158 @@
+//! The `doctor` subcommand.
+
+use clap::Args;
+use owo_colors::OwoColorize;
+use tokio::process::Command;
+
+use crate::install_nix;
+use crate::nix_config;
+use crate::offline::OfflineMode;
+
+/// The oldest Nix with the flake support riff relies on
+const MINIMUM_NIX_VERSION: (u64, u64) = (2, 4);
+
+/// Check that the tools riff needs are installed, offering to install Nix if it's missing
+///
+/// Also checks that Nix's configuration enables the experimental features riff uses (`flakes` and
+/// `nix-command`). riff enables them for its own `nix` commands either way; enable them in your
+/// `nix.conf` with:
+///
+///     $ riff doctor --fix
+#[derive(Debug, Args)]
+pub struct Doctor {
+    /// Enable the experimental features riff uses in your `nix.conf`, if they aren't
+    #[clap(long)]
+    fix: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    install_nix: bool,
+}
+
+impl Doctor {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let mut healthy = true;
+
+        if install_nix::find_nix().is_none() {
+            install_nix::offer_install(self.offline.is_offline(), self.install_nix).await?;
+        }
+        healthy &= match tool_version("nix").await {
+            Some(version) => match parse_nix_version(&version) {
+                Some(parsed) if parsed < MINIMUM_NIX_VERSION => report_problem(
+                    "nix",
+                    &format!(
+                        "{version} is too old, riff needs Nix {}.{} or newer",
+                        MINIMUM_NIX_VERSION.0, MINIMUM_NIX_VERSION.1
+                    ),
+                ),
+                _ => report_ok("nix", &version) && self.check_features().await?,
+            },
+            None => report_problem(
+                "nix",
+                "not found, install it from https://install.determinate.systems/nix",
+            ),
+        };
+
+        healthy &= match tool_version("cargo").await {
+            Some(version) => report_ok("cargo", &version),
+            None => report_problem(
+                "cargo",
+                "not found, install it from https://www.rust-lang.org/tools/install",
+            ),
+        };
+
+        Ok(Some(if healthy { 0 } else { 1 }))
+    }
+
+    /// Whether Nix's configuration enables the experimental features riff uses, enabling them
+    /// with `--fix`. Missing features are only a warning, since riff passes them to each command.
+    async fn check_features(&self) -> color_eyre::Result<bool> {
+        let Some(enabled) = nix_config::experimental_features().await else {
+            return Ok(report_problem(
+                "nix.conf",
+                "could not read Nix's configuration with `nix config show`",
+            ));
+        };
+        let missing = nix_config::missing_features(&enabled);
+        if missing.is_empty() {
+            return Ok(report_ok(
+                "nix.conf",
+                &format!("{} enabled", nix_config::REQUIRED_FEATURES.join(" and ")),
+            ));
+        }
+        if self.fix {
+            let nix_conf = nix_config::enable_features(&missing).await?;
+            return Ok(report_ok(
+                "nix.conf",
+                &format!(
+                    "enabled {} in `{}`",
+                    missing.join(" and "),
+                    nix_conf.display()
+                ),
+            ));
+        }
+        eprintln!(
+            "{} {}: {} not enabled, riff enables them for its own commands; run `{}` to enable \
+            them for `nix` too",
+            "⚠".yellow(),
+            "nix.conf".cyan(),
+            missing.join(" and "),
+            "riff doctor --fix".cyan(),
+        );
+        Ok(true)
+    }
+}
+
+/// The first line of `<tool> --version`, or `None` if it couldn't be run
+async fn tool_version(tool: &str) -> Option<String> {
+    let output = Command::new(tool).arg("--version").output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()?
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+}
+
+/// The major and minor version from `nix --version` output, eg `nix (Nix) 2.13.3`
+fn parse_nix_version(version: &str) -> Option<(u64, u64)> {
+    let number = version.split_whitespace().last()?;
+    let mut parts = number.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()?
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()?;
+    Some((major, minor))
+}
+
+fn report_ok(tool: &str, detail: &str) -> bool {
+    eprintln!("{} {}: {detail}", "✓".green(), tool.cyan());
+    true
+}
+
+fn report_problem(tool: &str, detail: &str) -> bool {
+    eprintln!("{} {}: {detail}", "✗".red(), tool.cyan());
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_nix_version;
+
+    #[test]
+    fn nix_versions() {
+        assert_eq!(parse_nix_version("nix (Nix) 2.13.3"), Some((2, 13)));
+        assert_eq!(parse_nix_version("nix (Nix) 2.3.16"), Some((2, 3)));
+        assert_eq!(
+            parse_nix_version("nix (Nix) 2.19pre20231005_abcdef"),
+            Some((2, 19))
+        );
+        assert_eq!(parse_nix_version("nix"), None);
+    }
+}
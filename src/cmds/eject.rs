@@ -0,0 +1,125 @@
+//! The `eject` subcommand.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+
+use crate::execution::{self, Effect};
+use crate::flake_generator::{self, GenerateOptions};
+use crate::offline::OfflineMode;
+
+/// Write a standalone `flake.nix` and `flake.lock` into your project
+///
+/// The generated flake no longer needs riff: every input and environment variable riff detected is
+/// written out, annotated with the dependency that needed it, so it can be maintained by hand.
+/// Afterwards, enter the environment with `nix develop`.
+#[derive(Debug, Args)]
+pub struct Eject {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Overwrite an existing `flake.nix` and `flake.lock`
+    #[clap(long)]
+    force: bool,
+    /// A flake template to render instead of the project's `riff-template.nix`
+    #[clap(long, value_name = "PATH")]
+    template: Option<PathBuf>,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    refresh: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+}
+
+impl Eject {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+
+        let written = ["flake.nix", "flake.lock"];
+        if !self.force {
+            for file in written {
+                let path = project_dir.join(file);
+                if path.exists() {
+                    return Err(eyre!(
+                        "`{}` already exists, pass `--force` to overwrite it",
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir.clone()),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                eject: true,
+                template: self.template.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        for file in written {
+            let path = project_dir.join(file);
+            if !execution::perform(Effect::Write(path.clone())) {
+                continue;
+            }
+            tokio::fs::copy(flake_dir.path().join(file), &path)
+                .await
+                .wrap_err_with(|| format!("Unable to write `{}`", path.display()))?;
+        }
+        if execution::dry_run() {
+            return Ok(None);
+        }
+
+        eprintln!(
+            "{check} Wrote `{flake_nix}` and `{flake_lock}`, enter the environment with `{nix_develop}`",
+            check = "✓".green(),
+            flake_nix = "flake.nix".cyan(),
+            flake_lock = "flake.lock".cyan(),
+            nix_develop = "nix develop".cyan(),
+        );
+
+        Ok(None)
+    }
+}
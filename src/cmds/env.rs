@@ -0,0 +1,397 @@
+//! The `env` subcommand.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Subcommand};
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+use tempfile::TempDir;
+use tokio::process::Command;
+
+use crate::flake_generator::{self, GenerateOptions, ResolvedEnvironment};
+use crate::offline::OfflineMode;
+use crate::riff_lock::RIFF_LOCK_FILE;
+
+/// Inspect your project's environment
+#[derive(Debug, Args)]
+pub struct Env {
+    #[clap(subcommand)]
+    command: EnvCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum EnvCommand {
+    Diff(Diff),
+}
+
+impl Env {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        match self.command {
+            EnvCommand::Diff(diff) => diff.cmd().await,
+        }
+    }
+}
+
+/// Show how the environments of two versions of your project differ
+///
+/// Each side is a `riff.lock` file, a project directory, or a git revision of the project. Compare
+/// the environment in `riff.lock` with the one detected now:
+///
+///     $ riff env diff
+///
+/// Review what a branch changes in the environment:
+///
+///     $ riff env diff main
+#[derive(Debug, Args)]
+pub struct Diff {
+    /// The environment to compare from (default: the project's `riff.lock`)
+    old: Option<String>,
+    /// The environment to compare to (default: the project as it is now)
+    new: Option<String>,
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Exit with 1 if the environments differ, like `git diff --exit-code`
+    #[clap(long)]
+    exit_code: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    refresh: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+}
+
+impl Diff {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir.clone(),
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        let old = match &self.old {
+            Some(old) => old.clone(),
+            None => {
+                let lock_path = project_dir.join(RIFF_LOCK_FILE);
+                if !lock_path.is_file() {
+                    return Err(eyre!(
+                        "`{}` doesn't have a `{RIFF_LOCK_FILE}`, pass the environment to compare with",
+                        project_dir.display()
+                    ));
+                }
+                lock_path.display().to_string()
+            }
+        };
+        let new = match &self.new {
+            Some(new) => new.clone(),
+            None => project_dir.display().to_string(),
+        };
+
+        let old = self.environment(&project_dir, &old).await?;
+        let new = self.environment(&project_dir, &new).await?;
+
+        let diff = EnvironmentDiff::between(&old, &new);
+        if diff.is_empty() {
+            eprintln!("The environments are the same");
+            return Ok(None);
+        }
+        print!("{diff}");
+        Ok(self.exit_code.then_some(1))
+    }
+
+    /// The environment of `side`: a `riff.lock`, a project directory, or a git revision of the
+    /// project in `project_dir`
+    async fn environment(
+        &self,
+        project_dir: &Path,
+        side: &str,
+    ) -> color_eyre::Result<ResolvedEnvironment> {
+        // `--manifest-path` names a manifest of this project, which is at the same place in the
+        // other side's
+        let options = |side_dir: &Path| GenerateOptions {
+            offline: self.offline,
+            no_walk_up: self.no_walk_up,
+            refresh: self.refresh,
+            install_nix: self.install_nix,
+            allow_empty: self.allow_empty,
+            strict: self.strict,
+            profile: self.profile.clone(),
+            package: self.package.clone(),
+            manifest_path: self.manifest_path.as_ref().and_then(|manifest_path| {
+                let manifest_path = manifest_path.canonicalize().ok()?;
+                Some(side_dir.join(manifest_path.strip_prefix(project_dir).ok()?))
+            }),
+            target: self.target.clone(),
+            ..Default::default()
+        };
+
+        let path = Path::new(side);
+        if path.file_name() == Some(OsStr::new(RIFF_LOCK_FILE)) && path.is_file() {
+            let lock_dir = path.parent().unwrap_or(project_dir);
+            return flake_generator::resolve_environment(lock_dir, &options(lock_dir), true).await;
+        }
+        if path.is_dir() {
+            let side_dir = flake_generator::resolve_project_dir(
+                Some(path.to_path_buf()),
+                None,
+                !self.no_walk_up,
+            )?;
+            return flake_generator::resolve_environment(&side_dir, &options(&side_dir), false)
+                .await;
+        }
+
+        let worktree = GitWorktree::add(project_dir, side).await?;
+        let result = flake_generator::resolve_environment(
+            &worktree.project_dir,
+            &options(&worktree.project_dir),
+            false,
+        )
+        .await;
+        worktree.remove().await?;
+        result
+    }
+}
+
+/// A temporary checkout of a git revision, next to the user's working tree
+struct GitWorktree {
+    repository: PathBuf,
+    /// Keeps the checkout's parent directory alive until the worktree is removed
+    dir: TempDir,
+    /// Where the project is in the checkout, which may be below the repository's root
+    project_dir: PathBuf,
+}
+
+impl GitWorktree {
+    async fn add(project_dir: &Path, rev: &str) -> color_eyre::Result<Self> {
+        let prefix = git(project_dir, &["rev-parse", "--show-prefix"])
+            .await
+            .wrap_err_with(|| {
+                format!("`{rev}` is neither a `{RIFF_LOCK_FILE}`, a directory, nor a git revision")
+            })?;
+        let dir = TempDir::new()?;
+        let checkout = dir.path().join("checkout");
+        git(
+            project_dir,
+            &[
+                "worktree",
+                "add",
+                "--detach",
+                &checkout.display().to_string(),
+                rev,
+            ],
+        )
+        .await
+        .wrap_err_with(|| format!("Could not check out `{rev}`"))?;
+        Ok(Self {
+            repository: project_dir.to_path_buf(),
+            project_dir: checkout.join(prefix.trim()),
+            dir,
+        })
+    }
+
+    async fn remove(self) -> color_eyre::Result<()> {
+        let checkout = self.dir.path().join("checkout");
+        git(
+            &self.repository,
+            &[
+                "worktree",
+                "remove",
+                "--force",
+                &checkout.display().to_string(),
+            ],
+        )
+        .await
+        .wrap_err("Could not remove the temporary git worktree")?;
+        Ok(())
+    }
+}
+
+/// Run `git` in `dir`, returning its output
+async fn git(dir: &Path, args: &[&str]) -> color_eyre::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .await
+        .wrap_err("Could not run `git`")?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "`git {}` failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// What changed between two environments
+#[derive(Debug, Default, PartialEq, Eq)]
+struct EnvironmentDiff {
+    /// The old and new `nixpkgs` flake reference, if it changed
+    nixpkgs: Option<(String, String)>,
+    added_overlays: BTreeSet<String>,
+    removed_overlays: BTreeSet<String>,
+    added_build_inputs: BTreeSet<String>,
+    removed_build_inputs: BTreeSet<String>,
+    added_runtime_inputs: BTreeSet<String>,
+    removed_runtime_inputs: BTreeSet<String>,
+    /// The old and new value of each environment variable which changed, `None` where it's unset
+    environment_variables: BTreeMap<String, (Option<String>, Option<String>)>,
+}
+
+impl EnvironmentDiff {
+    fn between(old: &ResolvedEnvironment, new: &ResolvedEnvironment) -> Self {
+        let (old_nixpkgs, new_nixpkgs) = (&old.nixpkgs, &new.nixpkgs);
+        let old_overlays: BTreeSet<String> = old.overlays.iter().cloned().collect();
+        let new_overlays: BTreeSet<String> = new.overlays.iter().cloned().collect();
+        let (old, new) = (&old.environment, &new.environment);
+        let names = old
+            .environment_variables
+            .keys()
+            .chain(new.environment_variables.keys());
+        let environment_variables = names
+            .filter_map(|name| {
+                let old_value = old.environment_variables.get(name);
+                let new_value = new.environment_variables.get(name);
+                (old_value != new_value)
+                    .then(|| (name.clone(), (old_value.cloned(), new_value.cloned())))
+            })
+            .collect();
+        Self {
+            nixpkgs: (old_nixpkgs != new_nixpkgs)
+                .then(|| (old_nixpkgs.clone(), new_nixpkgs.clone())),
+            added_overlays: &new_overlays - &old_overlays,
+            removed_overlays: &old_overlays - &new_overlays,
+            added_build_inputs: &new.build_inputs - &old.build_inputs,
+            removed_build_inputs: &old.build_inputs - &new.build_inputs,
+            added_runtime_inputs: &new.runtime_inputs - &old.runtime_inputs,
+            removed_runtime_inputs: &old.runtime_inputs - &new.runtime_inputs,
+            environment_variables,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for EnvironmentDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((old, new)) = &self.nixpkgs {
+            writeln!(f, "Nixpkgs:")?;
+            writeln!(f, "{}", format!("- {old}").red())?;
+            writeln!(f, "{}", format!("+ {new}").green())?;
+        }
+
+        let sections = [
+            ("Overlays", &self.added_overlays, &self.removed_overlays),
+            (
+                "Build inputs",
+                &self.added_build_inputs,
+                &self.removed_build_inputs,
+            ),
+            (
+                "Runtime inputs",
+                &self.added_runtime_inputs,
+                &self.removed_runtime_inputs,
+            ),
+        ];
+        for (title, added, removed) in sections {
+            if added.is_empty() && removed.is_empty() {
+                continue;
+            }
+            writeln!(f, "{title}:")?;
+            for input in removed {
+                writeln!(f, "{}", format!("- {input}").red())?;
+            }
+            for input in added {
+                writeln!(f, "{}", format!("+ {input}").green())?;
+            }
+        }
+
+        if !self.environment_variables.is_empty() {
+            writeln!(f, "Environment variables:")?;
+            for (name, (old_value, new_value)) in &self.environment_variables {
+                if let Some(old_value) = old_value {
+                    writeln!(f, "{}", format!("- {name}={old_value}").red())?;
+                }
+                if let Some(new_value) = new_value {
+                    writeln!(f, "{}", format!("+ {name}={new_value}").green())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riff_lock::LockedEnvironment;
+
+    #[test]
+    fn environment_diff() {
+        let old = ResolvedEnvironment {
+            environment: LockedEnvironment {
+                build_inputs: ["cargo", "openssl"].map(String::from).into(),
+                environment_variables: [("OPENSSL_STATIC", "1"), ("LANG", "C")]
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .into(),
+                ..Default::default()
+            },
+            nixpkgs: "github:NixOS/nixpkgs/nixpkgs-unstable".to_string(),
+            overlays: vec!["github:oxalica/rust-overlay".to_string()],
+        };
+        let new = ResolvedEnvironment {
+            environment: LockedEnvironment {
+                build_inputs: ["cargo", "libressl"].map(String::from).into(),
+                runtime_inputs: ["libGL"].map(String::from).into(),
+                environment_variables: [("OPENSSL_STATIC", "0"), ("LANG", "C")]
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .into(),
+                ..Default::default()
+            },
+            nixpkgs: "github:NixOS/nixpkgs/nixos-22.05".to_string(),
+            overlays: vec![],
+        };
+
+        assert!(EnvironmentDiff::between(&old, &old).is_empty());
+        let diff = EnvironmentDiff::between(&old, &new);
+        assert_eq!(
+            diff,
+            EnvironmentDiff {
+                nixpkgs: Some((old.nixpkgs.clone(), new.nixpkgs.clone())),
+                added_overlays: Default::default(),
+                removed_overlays: ["github:oxalica/rust-overlay".to_string()].into(),
+                added_build_inputs: ["libressl".to_string()].into(),
+                removed_build_inputs: ["openssl".to_string()].into(),
+                added_runtime_inputs: ["libGL".to_string()].into(),
+                removed_runtime_inputs: Default::default(),
+                environment_variables: [(
+                    "OPENSSL_STATIC".to_string(),
+                    (Some("1".to_string()), Some("0".to_string()))
+                )]
+                .into(),
+            }
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! The `generate` subcommand.
+
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Subcommand, ValueEnum};
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+
+use crate::dev_env::{FlakeOutputs, GeneratedHeader};
+use crate::emitters::{self, EmitContext, EmitKind};
+use crate::execution::{self, Effect};
+use crate::flake_generator::{self, GenerateOptions};
+use crate::offline::OfflineMode;
+use crate::{editor, nix_dev_env};
+
+/// Where VS Code keeps a project's settings
+const VSCODE_SETTINGS_PATH: &str = ".vscode/settings.json";
+
+/// Print the `flake.nix` riff generates for your project, or write integration files
+///
+/// By default the flake is locked first, which checks that Nix can resolve its inputs. With
+/// `--no-eval`, the flake is only generated, which is faster and doesn't need Nix installed:
+///
+///     $ riff generate --no-eval > flake.nix
+///
+/// Write a flake, its lock, and a devcontainer which enters it into the project:
+///
+///     $ riff generate --emit flake,lock,devcontainer --out-dir .
+///
+/// Start a flake which also builds and checks the project with `nix build` and `nix flake check`:
+///
+///     $ riff generate --with-checks --out-dir .
+///
+/// Assemble a flake for a project riff doesn't recognize by picking its packages:
+///
+///     $ riff generate --interactive --out-dir .
+///
+/// Bring a flake riff generated into the project up to date with its dependencies:
+///
+///     $ riff generate --update
+#[derive(Debug, Args)]
+pub struct Generate {
+    #[clap(subcommand)]
+    command: Option<GenerateCommand>,
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Only generate the flake, without running `nix flake lock`
+    #[clap(long)]
+    no_eval: bool,
+    /// Generate the standalone flake `riff eject` would write
+    #[clap(long)]
+    eject: bool,
+    /// If the project isn't one riff recognizes, ask which packages its environment needs
+    /// instead of failing, offering common ones and searching Nixpkgs for others
+    #[clap(long)]
+    interactive: bool,
+    /// Add a `packages.default` output building the project from the directory the flake is in
+    #[clap(long)]
+    with_package: bool,
+    /// Add `checks` outputs building the package and checking formatting (implies
+    /// `--with-package`)
+    #[clap(long)]
+    with_checks: bool,
+    /// The files to produce (default: `flake` when printing, `flake,lock` with `--out-dir`)
+    #[clap(long, value_enum, value_delimiter = ',', value_name = "KIND")]
+    emit: Vec<EmitKind>,
+    /// Write the files into this directory instead of printing one
+    #[clap(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+    /// Regenerate the `flake.nix` and `flake.lock` riff generated into `--out-dir` (default: the
+    /// project) if the environment changed, showing what changed
+    #[clap(long, conflicts_with_all = ["emit", "eject", "interactive"])]
+    update: bool,
+    /// Overwrite a `flake.nix` riff didn't generate, or which was edited since it did
+    #[clap(long)]
+    force: bool,
+    /// A flake template to render instead of the project's `riff-template.nix`
+    #[clap(long, value_name = "PATH")]
+    template: Option<PathBuf>,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    refresh: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+}
+
+#[derive(Debug, Subcommand)]
+enum GenerateCommand {
+    Vscode(Vscode),
+}
+
+impl Generate {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        if let Some(GenerateCommand::Vscode(mut vscode)) = self.command {
+            let flake_only = self.no_eval
+                || self.eject
+                || self.interactive
+                || self.with_package
+                || self.with_checks
+                || !self.emit.is_empty()
+                || self.out_dir.is_some()
+                || self.update
+                || self.force;
+            if flake_only {
+                return Err(eyre!(
+                    "Only `--project-dir` and `--template` apply to `{}`",
+                    "riff generate vscode".cyan()
+                ));
+            }
+            // `riff generate --project-dir .. vscode` configures the same project
+            vscode.project_dir = vscode.project_dir.or(self.project_dir);
+            vscode.template = vscode.template.or(self.template);
+            return vscode.cmd().await;
+        }
+        let kinds = self.kinds()?;
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        let out_dir = match self.out_dir {
+            None if self.update => Some(project_dir.clone()),
+            out_dir => out_dir,
+        };
+        let flake_nix_path = out_dir
+            .as_ref()
+            .map(|out_dir| out_dir.join(EmitKind::Flake.emitter().path()));
+        let existing = match &flake_nix_path {
+            Some(path) if kinds.contains(&EmitKind::Flake) => {
+                existing_flake(path, self.force).await?
+            }
+            _ => None,
+        };
+        if let (true, None, Some(path)) = (self.update, &existing, &flake_nix_path) {
+            return Err(eyre!(
+                "There is no `{}` to update, write one with `{}`",
+                path.display(),
+                "riff generate --out-dir".cyan()
+            ));
+        }
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir.clone()),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                disable_telemetry: self.disable_telemetry,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile,
+                overlays: self.overlay,
+                package: self.package,
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                eject: self.eject,
+                no_eval: self.no_eval,
+                interactive: self.interactive,
+                template: self.template,
+                extra_outputs: FlakeOutputs {
+                    package: self.with_package || self.with_checks,
+                    checks: self.with_checks,
+                },
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let flake_lock_path = flake_dir.path().join("flake.lock");
+        let cargo_dir = flake_generator::cargo_dir(&project_dir, self.manifest_path.as_deref());
+        let context = EmitContext {
+            project_dir: &project_dir,
+            manifest_path: self.manifest_path.as_deref(),
+            cargo_workspace_root: crate::cargo_metadata::workspace_root(&cargo_dir).await,
+            cargo_dir,
+            flake_nix: tokio::fs::read_to_string(flake_dir.path().join("flake.nix"))
+                .await
+                .wrap_err("Unable to read the generated flake.nix")?,
+            flake_lock: if flake_lock_path.exists() {
+                Some(
+                    tokio::fs::read_to_string(flake_lock_path)
+                        .await
+                        .wrap_err("Unable to read the generated flake.lock")?,
+                )
+            } else {
+                None
+            },
+        };
+
+        if let (true, Some(existing), Some(path)) = (self.update, &existing, &flake_nix_path) {
+            let changed = line_diff(without_header(existing), without_header(&context.flake_nix));
+            if changed.is_empty() {
+                eprintln!(
+                    "{check} `{}` is up to date",
+                    path.display(),
+                    check = "✓".green()
+                );
+                return Ok(None);
+            }
+            for line in changed {
+                match line {
+                    DiffLine::Removed(line) => println!("{}", format!("- {line}").red()),
+                    DiffLine::Added(line) => println!("{}", format!("+ {line}").green()),
+                }
+            }
+        }
+
+        let Some(out_dir) = out_dir else {
+            if let Some(contents) = kinds[0].emitter().emit(&context)? {
+                print!("{contents}");
+            }
+            return Ok(None);
+        };
+
+        for kind in kinds {
+            let emitter = kind.emitter();
+            let Some(contents) = emitter.emit(&context)? else {
+                tracing::debug!(?kind, "Nothing to emit");
+                continue;
+            };
+            let path = out_dir.join(emitter.path());
+            if !execution::perform(Effect::Write(path.clone())) {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .wrap_err_with(|| format!("Unable to create `{}`", parent.display()))?;
+            }
+            tokio::fs::write(&path, contents)
+                .await
+                .wrap_err_with(|| format!("Unable to write `{}`", path.display()))?;
+        }
+        if self.update && !execution::dry_run() {
+            if let Some(path) = &flake_nix_path {
+                eprintln!("{check} Updated `{}`", path.display(), check = "✓".green());
+            }
+        }
+        Ok(None)
+    }
+
+    /// The kinds of file to produce, checked against the other options
+    fn kinds(&self) -> color_eyre::Result<Vec<EmitKind>> {
+        if self.emit.is_empty() {
+            return Ok(if self.out_dir.is_some() || self.update {
+                vec![EmitKind::Flake, EmitKind::Lock]
+            } else {
+                vec![EmitKind::Flake]
+            });
+        }
+        if self.out_dir.is_none() && self.emit.len() > 1 {
+            return Err(eyre!(
+                "Only one file can be printed, pass `--out-dir` to write several"
+            ));
+        }
+        if self.no_eval {
+            if let Some(kind) = self.emit.iter().find(|kind| kind.needs_eval()) {
+                return Err(eyre!(
+                    "`--emit {}` needs the flake to be locked, which `--no-eval` skips",
+                    kind.to_possible_value()
+                        .map(|value| value.get_name().to_string())
+                        .unwrap_or_default()
+                ));
+            }
+        }
+        Ok(self.emit.clone())
+    }
+}
+
+/// The contents of the flake at `path`, if there is one, failing unless `force` or riff generated
+/// it and it wasn't edited since, so replacing it loses nothing
+async fn existing_flake(path: &Path, force: bool) -> color_eyre::Result<Option<String>> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Unable to read `{}`", path.display()))
+        }
+    };
+    if force {
+        return Ok(Some(contents));
+    }
+    if !emitters::is_generated(&contents) {
+        return Err(eyre!(
+            "`{}` wasn't generated by riff, pass `--force` to overwrite it",
+            path.display()
+        ));
+    }
+    match GeneratedHeader::split(&contents) {
+        Some((header, flake)) if header.is_edited(flake) => {
+            return Err(eyre!(
+                "`{}` was edited since riff generated it, pass `--force` to overwrite it",
+                path.display()
+            ))
+        }
+        Some((header, _)) => tracing::debug!(
+            riff_version = %header.riff_version,
+            registry_version = header.registry_version,
+            inputs_sha256 = %header.inputs_sha256,
+            "Replacing a generated `{}`",
+            path.display()
+        ),
+        // Generated before riff recorded how
+        None => tracing::debug!("Replacing a generated `{}`", path.display()),
+    }
+    Ok(Some(contents))
+}
+
+/// The flake `contents` without the header riff generated it with, which records the riff version
+/// and so differs between flakes which are otherwise the same
+fn without_header(contents: &str) -> &str {
+    GeneratedHeader::split(contents).map_or(contents, |(_, flake)| flake)
+}
+
+/// A line of [`line_diff`]
+#[derive(Debug, PartialEq, Eq)]
+enum DiffLine<'a> {
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines removed from `old` and added to make `new`, in order
+fn line_diff<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    // The length of the longest common subsequence of `old[i..]` and `new[j..]`, at `[i][j]`
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            diff.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Set up VS Code to use the riff environment
+///
+/// Merges the settings which run VS Code's integrated terminals, rust-analyzer, and ESLint in the
+/// riff environment into the project's `.vscode/settings.json`, keeping the other settings.
+/// Preview the result first:
+///
+///     $ riff generate vscode --dry-run
+#[derive(Debug, Args)]
+pub struct Vscode {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// A flake template to render instead of the project's `riff-template.nix`
+    #[clap(long, value_name = "PATH")]
+    template: Option<PathBuf>,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
+}
+
+impl Vscode {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir.clone()),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                disable_telemetry: self.disable_telemetry,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile,
+                overlays: self.overlay,
+                package: self.package,
+                manifest_path: self.manifest_path,
+                target: self.target,
+                template: self.template,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let dev_env =
+            nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh).await?;
+
+        let settings_path = project_dir.join(VSCODE_SETTINGS_PATH);
+        let existing = match tokio::fs::read_to_string(&settings_path).await {
+            Ok(existing) => existing,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Unable to read `{}`", settings_path.display()))
+            }
+        };
+        let merged = editor::merge_vscode_settings(&existing, editor::vscode_settings(&dev_env))
+            .wrap_err_with(|| format!("Unable to update `{}`", settings_path.display()))?;
+        let merged = serde_json::to_string_pretty(&merged)? + "\n";
+
+        // A dry run prints the merged settings for adding by hand
+        if !execution::perform(Effect::Write(settings_path.clone())) {
+            print!("{merged}");
+            return Ok(None);
+        }
+        if let Some(parent) = settings_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .wrap_err_with(|| format!("Unable to create `{}`", parent.display()))?;
+        }
+        tokio::fs::write(&settings_path, merged)
+            .await
+            .wrap_err_with(|| format!("Unable to write `{}`", settings_path.display()))?;
+        eprintln!(
+            "{check} Updated `{path}`, run `{command}` again when the dependencies change",
+            check = "✓".green(),
+            path = settings_path.display(),
+            command = "riff generate vscode".cyan(),
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flakes_are_diffed_by_line() {
+        let old = "buildInputs = [\n  cargo\n  openssl\n];\n";
+        let new = "buildInputs = [\n  cargo\n  libressl\n  protobuf\n];\n";
+        assert_eq!(line_diff(old, old), []);
+        assert_eq!(
+            line_diff(old, new),
+            [
+                DiffLine::Removed("  openssl"),
+                DiffLine::Added("  libressl"),
+                DiffLine::Added("  protobuf"),
+            ]
+        );
+    }
+}
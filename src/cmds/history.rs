@@ -0,0 +1,143 @@
+//! The `history` subcommand.
+
+use std::time::{Duration, SystemTime};
+
+use clap::{Args, Subcommand};
+use owo_colors::OwoColorize;
+
+use crate::flake_generator;
+use crate::history::{self, Entry};
+
+/// Inspect the local history of `riff run`s
+///
+/// Once enabled, riff records the command, a hash of the project, how long preparing the
+/// environment and the whole run took, and the exit code of each `riff run`, in
+/// `$XDG_STATE_HOME/riff/history.jsonl`. Nothing leaves your machine.
+///
+///     $ riff history enable
+///     $ riff run cargo test
+///     $ riff history stats --here
+#[derive(Debug, Args)]
+pub struct History {
+    #[clap(subcommand)]
+    command: HistoryCommand,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum HistoryCommand {
+    /// Start recording runs
+    Enable,
+    /// Stop recording runs, keeping those already recorded
+    Disable,
+    /// List the most recent runs
+    List {
+        /// How many runs to list
+        #[clap(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+        /// Only list runs of the project the current directory is in
+        #[clap(long)]
+        here: bool,
+    },
+    /// Summarize the runs of each command
+    Stats {
+        /// Only summarize runs of the project the current directory is in
+        #[clap(long)]
+        here: bool,
+    },
+    /// Forget every recorded run
+    Clear,
+}
+
+impl History {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        match self.command {
+            HistoryCommand::Enable => {
+                history::set_enabled(true).await?;
+                eprintln!(
+                    "{check} Recording runs, see them with `{list}`",
+                    check = "✓".green(),
+                    list = "riff history list".cyan(),
+                );
+            }
+            HistoryCommand::Disable => {
+                history::set_enabled(false).await?;
+                eprintln!("{check} Stopped recording runs", check = "✓".green());
+            }
+            HistoryCommand::List { limit, here } => {
+                let entries = entries(here, !self.no_walk_up).await?;
+                let now = history::timestamp(SystemTime::now());
+                for entry in &entries[entries.len().saturating_sub(limit)..] {
+                    println!(
+                        "{ago}\t{project}\t{exit_code}\t{environment}\t{duration}\t{command}",
+                        ago = ago(now.saturating_sub(entry.timestamp)),
+                        project = entry.project,
+                        exit_code = entry
+                            .exit_code
+                            .map(|code| code.to_string())
+                            .unwrap_or_else(|| "error".to_string()),
+                        environment = entry
+                            .environment_ms
+                            .map(|ms| seconds(Duration::from_millis(ms)))
+                            .unwrap_or_else(|| "-".to_string()),
+                        duration = seconds(Duration::from_millis(entry.duration_ms)),
+                        command = entry.command,
+                    );
+                }
+            }
+            HistoryCommand::Stats { here } => {
+                let entries = entries(here, !self.no_walk_up).await?;
+                for (command, stats) in history::stats(&entries) {
+                    println!(
+                        "{command}\n  {runs} runs, {failures} failed, median {duration} \
+                        ({environment} preparing the environment)",
+                        runs = stats.runs,
+                        failures = stats.failures,
+                        duration = seconds(stats.median_duration),
+                        environment = seconds(stats.median_environment),
+                    );
+                }
+            }
+            HistoryCommand::Clear => {
+                history::clear().await?;
+                if crate::execution::dry_run() {
+                    return Ok(None);
+                }
+                eprintln!("{check} Cleared the history", check = "✓".green());
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The recorded runs, of the current project only if `here` (looking for the project the current
+/// directory is in if `walk_up`)
+async fn entries(here: bool, walk_up: bool) -> color_eyre::Result<Vec<Entry>> {
+    let mut entries = history::load().await?;
+    if here {
+        let project =
+            history::project_hash(&flake_generator::resolve_project_dir(None, None, walk_up)?);
+        entries.retain(|entry| entry.project == project);
+    }
+    if entries.is_empty() && !history::enabled() {
+        eprintln!(
+            "No runs are recorded, start recording them with `{}`",
+            "riff history enable".cyan()
+        );
+    }
+    Ok(entries)
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+fn ago(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s ago"),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
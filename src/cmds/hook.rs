@@ -0,0 +1,557 @@
+//! The `hook` subcommand.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Subcommand, ValueEnum};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use target_lexicon::Triple;
+
+use crate::flake_generator::{self, GenerateOptions};
+use crate::nix_dev_env::shell_quote;
+use crate::offline::OfflineMode;
+use crate::RIFF_XDG_PREFIX;
+
+/// Where the environments activated by the hook are cached, in `$XDG_CACHE_HOME/riff`
+const HOOK_CACHE_DIR: &str = "hook-envs";
+
+/// The variable the hook keeps its state in, between prompts
+const HOOK_STATE_VAR: &str = "RIFF_HOOK_STATE";
+
+/// Variables whose values are the environment's in front of those they had before
+const PREPENDED_VARS: &[&str] = &["PATH", "XDG_DATA_DIRS"];
+
+/// Activate project environments automatically as you `cd` into them
+///
+/// Add the hook to your shell's rc file, for example in `~/.bashrc`:
+///
+///     eval "$(riff hook bash)"
+///
+/// or in `~/.zshrc`, or `~/.config/fish/config.fish`:
+///
+///     eval "$(riff hook zsh)"
+///     riff hook fish | source
+///
+/// Only projects you trusted with `riff trust` are activated, since activating a project's
+/// environment runs whatever its configuration says to.
+#[derive(Debug, Args)]
+pub struct Hook {
+    #[clap(subcommand)]
+    command: HookCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum HookCommand {
+    /// Print the hook for bash
+    Bash,
+    /// Print the hook for zsh
+    Zsh,
+    /// Print the hook for fish
+    Fish,
+    /// Print the shell code which activates the environment of the project the current
+    /// directory is in, or deactivates the one left, run by the hook at each prompt
+    #[clap(hide = true)]
+    Export(Export),
+}
+
+#[derive(Debug, Args)]
+struct Export {
+    #[clap(value_enum)]
+    shell: Shell,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Hook {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        match self.command {
+            HookCommand::Bash => print!("{}", hook(Shell::Bash)),
+            HookCommand::Zsh => print!("{}", hook(Shell::Zsh)),
+            HookCommand::Fish => print!("{}", hook(Shell::Fish)),
+            HookCommand::Export(export) => print!("{}", export.code().await),
+        }
+        Ok(None)
+    }
+}
+
+/// The hook for `shell`, running `riff hook export` before each prompt
+fn hook(shell: Shell) -> String {
+    let riff = std::env::current_exe()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "riff".to_string());
+    let riff = shell_quote(&riff);
+    match shell {
+        Shell::Bash => format!(
+            r#"_riff_hook() {{
+  local previous_exit_status=$?
+  eval "$({riff} hook export bash)"
+  return $previous_exit_status
+}}
+if [[ ";${{PROMPT_COMMAND[*]:-}};" != *";_riff_hook;"* ]]; then
+  PROMPT_COMMAND="_riff_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+fi
+"#
+        ),
+        Shell::Zsh => format!(
+            r#"_riff_hook() {{
+  eval "$({riff} hook export zsh)"
+}}
+typeset -ag precmd_functions chpwd_functions
+if (( ! ${{precmd_functions[(I)_riff_hook]}} )); then
+  precmd_functions=(_riff_hook $precmd_functions)
+fi
+if (( ! ${{chpwd_functions[(I)_riff_hook]}} )); then
+  chpwd_functions=(_riff_hook $chpwd_functions)
+fi
+"#
+        ),
+        Shell::Fish => format!(
+            r#"function __riff_hook --on-event fish_prompt --on-variable PWD
+    {riff} hook export fish | source
+end
+"#
+        ),
+    }
+}
+
+/// What the hook remembers between prompts, in `RIFF_HOOK_STATE`
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct HookState {
+    /// The project the hook last looked at
+    project_dir: Option<PathBuf>,
+    /// The fingerprint of its environment, or why it wasn't activated
+    fingerprint: String,
+    /// The values the variables the active environment sets had before it, to restore when
+    /// leaving it
+    previous: BTreeMap<String, Option<String>>,
+    /// The entries the active environment put in front of `PATH` and `XDG_DATA_DIRS`, which are
+    /// all that's removed from them when leaving it, keeping any added since
+    #[serde(default)]
+    prepended: BTreeMap<String, String>,
+}
+
+impl Export {
+    /// Shell code moving from the environment in `RIFF_HOOK_STATE` to that of the project the
+    /// current directory is in. Problems are reported on stderr, once, and leave the environment
+    /// inactive, since the hook's output is evaluated at every prompt.
+    async fn code(&self) -> String {
+        let state = std::env::var(HOOK_STATE_VAR)
+            .ok()
+            .and_then(|state| serde_json::from_str::<HookState>(&state).ok())
+            .unwrap_or_default();
+
+        // `--manifest-path` names a manifest of one project, so it doesn't apply to wherever the
+        // shell goes
+        let Ok(project_dir) = flake_generator::resolve_project_dir(None, None, !self.no_walk_up)
+        else {
+            if state == HookState::default() {
+                return String::new();
+            }
+            return transition(self.shell, &state, HookState::default(), None);
+        };
+        // Only run the detectors once for a directory they didn't recognize, rather than at each
+        // prompt
+        let was_unrecognized = state.project_dir.as_deref() == Some(project_dir.as_path())
+            && state.fingerprint == "unrecognized";
+        let recognized = if crate::dev_env::has_project_files(&project_dir) {
+            true
+        } else if was_unrecognized {
+            return String::new();
+        } else {
+            crate::dev_env::is_project(&project_dir).await
+        };
+        if !recognized {
+            let next = HookState {
+                project_dir: Some(project_dir),
+                fingerprint: "unrecognized".to_string(),
+                ..Default::default()
+            };
+            return transition(self.shell, &state, next, None);
+        }
+
+        // The hook can't ask at a prompt, so only a project trusted beforehand is activated
+        let trusted = crate::trust::decision(&project_dir)
+            .await
+            .unwrap_or(None)
+            .unwrap_or(false);
+        let fingerprint = if !trusted {
+            "untrusted".to_string()
+        } else {
+            let settings = format!(
+                "profile {:?}\noverlays {:?}\npackage {:?}\ntarget {:?}\n",
+                self.profile,
+                self.overlay,
+                self.package,
+                self.target.as_ref().map(ToString::to_string)
+            );
+            super::print_dev_env::project_fingerprint(&project_dir, &project_dir, &settings)
+                .await
+                .unwrap_or_else(|err| format!("unreadable: {err}"))
+        };
+        if state.project_dir.as_deref() == Some(project_dir.as_path())
+            && state.fingerprint == fingerprint
+        {
+            return String::new();
+        }
+
+        let next = HookState {
+            project_dir: Some(project_dir.clone()),
+            fingerprint,
+            ..Default::default()
+        };
+        if next.fingerprint == "untrusted" {
+            eprintln!(
+                "riff: `{}` isn't trusted to activate its environment, run `{}` to trust it",
+                project_dir.display(),
+                "riff trust".cyan(),
+            );
+            return transition(self.shell, &state, next, None);
+        }
+
+        match self.environment(&project_dir, &next.fingerprint).await {
+            Ok(environment) => {
+                eprintln!(
+                    "riff: activated the environment of `{}`",
+                    project_dir.display()
+                );
+                transition(self.shell, &state, next, Some(&environment))
+            }
+            Err(err) => {
+                // Remembering the fingerprint, it isn't tried again until the project changes
+                eprintln!("riff: couldn't activate the environment: {err:#}");
+                transition(self.shell, &state, next, None)
+            }
+        }
+    }
+
+    /// The environment of the project in `project_dir`, from the cache if it was made for
+    /// `fingerprint` and the store paths it refers to still exist, unless `--refresh` is given
+    async fn environment(
+        &self,
+        project_dir: &Path,
+        fingerprint: &str,
+    ) -> color_eyre::Result<CachedEnvironment> {
+        let cache_path = cache_path(project_dir)?;
+        let cached = tokio::fs::read_to_string(&cache_path)
+            .await
+            .ok()
+            .and_then(|contents| serde_json::from_str::<CachedEnvironment>(&contents).ok())
+            .filter(|_| !self.refresh)
+            .filter(|cached| cached.fingerprint == fingerprint)
+            .filter(|cached| store_paths_exist(&cached.variables));
+        if let Some(cached) = cached {
+            tracing::debug!("Using the cached environment");
+            return Ok(cached);
+        }
+
+        // The prompt waits for this, so say why
+        eprintln!(
+            "riff: building the environment of `{}`, which may take a while the first time \
+             (press Ctrl-C to skip it)",
+            project_dir.display()
+        );
+
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir.to_path_buf()),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let dev_env =
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh)
+                .await?;
+        let cached = CachedEnvironment {
+            fingerprint: fingerprint.to_string(),
+            variables: dev_env
+                .exported_variables()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            shell_hook: dev_env.shell_hook().map(str::to_string),
+        };
+        crate::execution::write(&cache_path, serde_json::to_string(&cached)?).await?;
+        Ok(cached)
+    }
+}
+
+/// Shell code leaving the environment of `state` (restoring the values from before it), and
+/// entering `next` with `environment` (if one is entered), running its `shellHook`
+fn transition(
+    shell: Shell,
+    state: &HookState,
+    mut next: HookState,
+    environment: Option<&CachedEnvironment>,
+) -> String {
+    let mut current = std::env::vars().collect::<BTreeMap<_, _>>();
+    let mut code = String::new();
+    for (name, value) in &state.previous {
+        let value = match state.prepended.get(name) {
+            Some(prepended) => current
+                .get(name)
+                .map(|current| without_entries(current, prepended))
+                .filter(|value| !value.is_empty()),
+            None => value.clone(),
+        };
+        code.push_str(&assignment(shell, name, value.as_deref()));
+        match value {
+            Some(value) => current.insert(name.clone(), value),
+            None => current.remove(name),
+        };
+    }
+
+    let Some(environment) = environment.filter(|environment| !environment.variables.is_empty())
+    else {
+        return code + &assignment(shell, HOOK_STATE_VAR, state_value(&next).as_deref());
+    };
+    for (name, value) in activated(&environment.variables, &current) {
+        next.previous
+            .insert(name.clone(), current.get(&name).cloned());
+        if let Some(prepended) = environment
+            .variables
+            .get(&name)
+            .filter(|_| PREPENDED_VARS.contains(&name.as_str()))
+        {
+            next.prepended.insert(name.clone(), prepended.clone());
+        }
+        code.push_str(&assignment(shell, &name, Some(&value)));
+    }
+    code.push_str(&assignment(
+        shell,
+        HOOK_STATE_VAR,
+        state_value(&next).as_deref(),
+    ));
+    if let Some(shell_hook) = &environment.shell_hook {
+        code.push_str(&run_shell_hook(shell, shell_hook));
+    }
+    code
+}
+
+/// The value of `RIFF_HOOK_STATE` for `state`, unset when there's nothing to remember
+fn state_value(state: &HookState) -> Option<String> {
+    if *state == HookState::default() {
+        None
+    } else {
+        serde_json::to_string(state).ok()
+    }
+}
+
+/// The values of the variables an environment setting `variables` sets, given the `current` ones
+fn activated(
+    variables: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let mut activated = BTreeMap::new();
+    for (name, value) in variables {
+        let value = match current.get(name) {
+            Some(old_value) if PREPENDED_VARS.contains(&name.as_str()) => {
+                format!("{value}:{old_value}")
+            }
+            _ => value.clone(),
+        };
+        activated.insert(name.clone(), value);
+    }
+    let in_riff = current
+        .get("IN_RIFF")
+        .and_then(|in_riff| in_riff.parse::<u32>().ok())
+        .unwrap_or(0);
+    activated.insert("IN_RIFF".to_string(), (in_riff + 1).to_string());
+    activated
+}
+
+/// `value`, a `:`-separated list, without one occurrence of each of the entries of `prepended`
+fn without_entries(value: &str, prepended: &str) -> String {
+    let mut entries = value.split(':').collect::<Vec<_>>();
+    for entry in prepended.split(':') {
+        if let Some(index) = entries.iter().position(|kept| *kept == entry) {
+            entries.remove(index);
+        }
+    }
+    entries.join(":")
+}
+
+/// Shell code running the environment's `shellHook`. It's bash, which zsh mostly runs too, and
+/// which fish can only run in a bash of its own (so the variables it sets aren't kept).
+fn run_shell_hook(shell: Shell, shell_hook: &str) -> String {
+    match shell {
+        Shell::Bash | Shell::Zsh => format!("eval {};\n", shell_quote(shell_hook)),
+        Shell::Fish => format!("bash -c {};\n", fish_quote(shell_hook)),
+    }
+}
+
+/// Shell code setting `name` to `value`, or unsetting it
+fn assignment(shell: Shell, name: &str, value: Option<&str>) -> String {
+    match (shell, value) {
+        (Shell::Bash | Shell::Zsh, Some(value)) => {
+            format!("export {name}={};\n", shell_quote(value))
+        }
+        (Shell::Bash | Shell::Zsh, None) => format!("unset {name};\n"),
+        (Shell::Fish, Some(value)) => format!("set -gx {name} {};\n", fish_quote(value)),
+        (Shell::Fish, None) => format!("set -e {name};\n"),
+    }
+}
+
+/// Quote `word` for fish, in whose single quotes backslashes escape too
+fn fish_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// A project's environment as the hook cached it
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedEnvironment {
+    fingerprint: String,
+    variables: BTreeMap<String, String>,
+    #[serde(default)]
+    shell_hook: Option<String>,
+}
+
+/// Where the environment the hook activates for the project in `project_dir` is cached
+pub(crate) fn cache_path(project_dir: &Path) -> color_eyre::Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(
+        xdg_dirs.place_cache_file(Path::new(HOOK_CACHE_DIR).join(format!(
+            "{}.json",
+            flake_generator::project_cache_key(project_dir)
+        )))?,
+    )
+}
+
+/// Whether the store paths `variables` refer to still exist, which they may not after a garbage
+/// collection
+fn store_paths_exist(variables: &BTreeMap<String, String>) -> bool {
+    crate::nix_dev_env::store_paths_exist(
+        variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activating_prepends_paths_and_counts_nesting() {
+        let variables = BTreeMap::from([
+            ("PATH".to_string(), "/nix/store/abc-cargo/bin".to_string()),
+            (
+                "OPENSSL_DIR".to_string(),
+                "/nix/store/def-openssl".to_string(),
+            ),
+        ]);
+        let current = BTreeMap::from([
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            ("OPENSSL_DIR".to_string(), "/usr".to_string()),
+        ]);
+        assert_eq!(
+            activated(&variables, &current),
+            BTreeMap::from([
+                ("IN_RIFF".to_string(), "1".to_string()),
+                (
+                    "OPENSSL_DIR".to_string(),
+                    "/nix/store/def-openssl".to_string()
+                ),
+                (
+                    "PATH".to_string(),
+                    "/nix/store/abc-cargo/bin:/usr/bin".to_string()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn leaving_restores_previous_values() {
+        let state = HookState {
+            project_dir: Some(PathBuf::from("/src/project")),
+            fingerprint: "abc".to_string(),
+            previous: BTreeMap::from([
+                ("RIFF_HOOK_TEST_SET".to_string(), Some("it's".to_string())),
+                ("RIFF_HOOK_TEST_UNSET".to_string(), None),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            transition(Shell::Bash, &state, HookState::default(), None),
+            "export RIFF_HOOK_TEST_SET='it'\\''s';\nunset RIFF_HOOK_TEST_UNSET;\nunset RIFF_HOOK_STATE;\n"
+        );
+        assert_eq!(
+            transition(Shell::Fish, &state, HookState::default(), None),
+            "set -gx RIFF_HOOK_TEST_SET 'it\\'s';\nset -e RIFF_HOOK_TEST_UNSET;\nset -e RIFF_HOOK_STATE;\n"
+        );
+    }
+
+    #[test]
+    fn leaving_removes_only_the_prepended_entries() {
+        assert_eq!(
+            without_entries(
+                "/home/me/bin:/nix/store/abc-cargo/bin:/nix/store/def-gcc/bin:/usr/bin",
+                "/nix/store/abc-cargo/bin:/nix/store/def-gcc/bin"
+            ),
+            "/home/me/bin:/usr/bin"
+        );
+        // An entry which was there before the environment stays
+        assert_eq!(
+            without_entries("/usr/bin:/bin:/usr/bin", "/usr/bin"),
+            "/bin:/usr/bin"
+        );
+    }
+
+    #[test]
+    fn entering_runs_the_shell_hook() {
+        let environment = CachedEnvironment {
+            fingerprint: "abc".to_string(),
+            variables: BTreeMap::from([("RIFF_HOOK_TEST_HOOKED".to_string(), "1".to_string())]),
+            shell_hook: Some("echo 'hi'".to_string()),
+        };
+        let next = HookState {
+            project_dir: Some(PathBuf::from("/src/project")),
+            fingerprint: "abc".to_string(),
+            ..Default::default()
+        };
+        let code = transition(Shell::Bash, &HookState::default(), next, Some(&environment));
+        assert!(code.contains("export RIFF_HOOK_TEST_HOOKED=1;\n"), "{code}");
+        assert!(code.ends_with("eval 'echo '\\''hi'\\''';\n"), "{code}");
+    }
+}
@@ -0,0 +1,79 @@
+//! The `layers` subcommand.
+
+use clap::{Args, Subcommand};
+use eyre::eyre;
+use owo_colors::OwoColorize;
+
+use crate::layers::{self, LayerSource};
+use crate::offline::OfflineMode;
+
+/// Manage layers: profiles shared from other repositories
+///
+/// Add a layer published as `riff-layer.toml` in a GitHub repository:
+///
+///     $ riff layers add github:acme/riff-layers
+///
+/// Then take its profiles in a project by adding `include = ["riff-layers"]` to its `riff.toml`.
+#[derive(Debug, Args)]
+pub struct Layers {
+    #[clap(subcommand)]
+    command: LayersCommand,
+    #[clap(from_global)]
+    offline: OfflineMode,
+}
+
+#[derive(Debug, Subcommand)]
+enum LayersCommand {
+    /// Fetch a layer and register it, so projects can include it
+    Add {
+        /// `github:owner/repo[/ref]`, or an `https://` URL of a layer file
+        source: LayerSource,
+        /// The name projects include the layer by (default: the repository's or file's name)
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Fetch layers again, all of them unless some are named
+    Update { names: Vec<String> },
+    /// List the registered layers
+    List,
+    /// Unregister a layer
+    Remove { name: String },
+}
+
+impl Layers {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let needs_network = matches!(
+            self.command,
+            LayersCommand::Add { .. } | LayersCommand::Update { .. }
+        );
+        if needs_network && self.offline.is_offline() {
+            return Err(eyre!("Fetching layers requires network access"));
+        }
+
+        match self.command {
+            LayersCommand::Add { source, name } => {
+                let name = layers::add(name, source).await?;
+                eprintln!(
+                    "{check} Added the layer `{name}`, include it with `{include}` in `riff.toml`",
+                    check = "✓".green(),
+                    include = format!("include = [\"{name}\"]").cyan(),
+                );
+            }
+            LayersCommand::Update { names } => {
+                for name in layers::update(&names).await? {
+                    eprintln!("{check} Updated `{name}`", check = "✓".green());
+                }
+            }
+            LayersCommand::List => {
+                for (name, source) in layers::list().await? {
+                    println!("{name}\t{source}");
+                }
+            }
+            LayersCommand::Remove { name } => {
+                layers::remove(&name).await?;
+                eprintln!("{check} Removed `{name}`", check = "✓".green());
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,94 @@
+//! The `lock` subcommand.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use eyre::eyre;
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+
+use crate::flake_generator::{self, GenerateOptions};
+use crate::offline::OfflineMode;
+use crate::riff_lock::RIFF_LOCK_FILE;
+
+/// Snapshot your project's environment into `riff.lock`
+///
+/// While a project has a `riff.lock`, the other commands use the environment recorded in it
+/// (inputs, environment variables, and the `nixpkgs` revision) instead of detecting one, so it
+/// stays the same as riff's registry changes. Commit it to share the environment with your team.
+#[derive(Debug, Args)]
+pub struct Lock {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Detect the environment again and replace the existing `riff.lock`
+    #[clap(long)]
+    update: bool,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    refresh: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    deny_warnings: bool,
+}
+
+impl Lock {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+
+        if !self.update && project_dir.join(RIFF_LOCK_FILE).exists() {
+            return Err(eyre!(
+                "`{}` already has a `{RIFF_LOCK_FILE}`, run `{riff_lock_update}` to refresh it",
+                project_dir.display(),
+                riff_lock_update = "riff lock --update".cyan(),
+            ));
+        }
+
+        flake_generator::generate_flake_from_project_dir(
+            Some(project_dir.clone()),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                disable_telemetry: self.disable_telemetry,
+                deny_warnings: self.deny_warnings,
+                relock: true,
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                ..Default::default()
+            },
+        )
+        .await?;
+        if crate::execution::dry_run() {
+            return Ok(None);
+        }
+
+        eprintln!(
+            "{check} Wrote `{lock}`",
+            check = "✓".green(),
+            lock = project_dir.join(RIFF_LOCK_FILE).display(),
+        );
+        Ok(None)
+    }
+}
@@ -1,6 +1,24 @@
+mod ci;
+mod clean;
+mod doctor;
+mod eject;
+mod env;
+mod generate;
+mod history;
+mod hook;
+mod layers;
+mod lock;
+mod prepare;
 mod print_dev_env;
+mod push_cache;
+mod registry;
 mod run;
+mod self_test;
+mod setup;
 mod shell;
+mod shim;
+mod trust;
+mod which;
 
 use clap::Subcommand;
 
@@ -9,4 +27,24 @@ pub enum Commands {
     Shell(shell::Shell),
     Run(run::Run),
     PrintDevEnv(print_dev_env::PrintDevEnv),
+    Eject(eject::Eject),
+    Generate(generate::Generate),
+    Doctor(doctor::Doctor),
+    Lock(lock::Lock),
+    Prepare(prepare::Prepare),
+    Layers(layers::Layers),
+    Ci(ci::Ci),
+    Setup(setup::Setup),
+    Env(env::Env),
+    Registry(registry::Registry),
+    PushCache(push_cache::PushCache),
+    History(history::History),
+    Clean(clean::Clean),
+    Hook(hook::Hook),
+    Trust(trust::Trust),
+    Untrust(trust::Untrust),
+    Shim(shim::Shim),
+    Which(which::Which),
+    #[clap(hide = true)]
+    SelfTest(self_test::SelfTest),
 }
@@ -0,0 +1,172 @@
+//! The `prepare` subcommand.
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+use clap::Args;
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+
+use crate::flake_generator::{self, GenerateOptions};
+use crate::offline::OfflineMode;
+
+/// Prepare your project's environment without entering it
+///
+/// This does everything `riff shell` and `riff run` do before starting a command: detecting the
+/// project's dependencies, generating and locking the flake, and building or downloading
+/// everything the environment needs into the Nix store. Run it in a CI step whose Nix store is
+/// cached, and later `riff run` calls only need to check the store:
+///
+///     $ riff prepare
+///     $ riff run cargo test
+#[derive(Debug, Args)]
+pub struct Prepare {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Check the environment works once it's prepared, with quick probes of the tools it provides
+    /// (such as `cc --version` or `pkg-config --exists openssl`)
+    #[clap(long)]
+    verify: bool,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
+}
+
+impl Prepare {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let started = Instant::now();
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            self.project_dir,
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile,
+                overlays: self.overlay,
+                package: self.package,
+                manifest_path: self.manifest_path,
+                target: self.target,
+                disable_telemetry: self.disable_telemetry,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // `nix print-dev-env` realizes every input of the environment before printing it
+        let dev_env =
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh)
+                .await?;
+        if self.verify {
+            crate::verify::verify(&dev_env, &Default::default()).await?;
+        }
+
+        eprintln!(
+            "{check} Prepared the environment in {:.1}s",
+            started.elapsed().as_secs_f64(),
+            check = "✓".green(),
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::command_runner::{self, RecordingRunner};
+
+    #[tokio::test]
+    async fn prepare_realizes_the_environment() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let project_dir = TempDir::new()?;
+        tokio::fs::write(project_dir.path().join("Cargo.toml"), "").await?;
+        let metadata = serde_json::json!({
+            "packages": [{
+                "id": "riff-test 0.1.0 (path+file:///riff-test)",
+                "name": "riff-test",
+                "version": "0.1.0",
+                "manifest_path": "/riff-test/Cargo.toml",
+                "metadata": { "riff": { "build-inputs": ["hello"] } },
+            }],
+            "workspace_members": ["riff-test 0.1.0 (path+file:///riff-test)"],
+            "workspace_metadata": null,
+        });
+        let runner = Arc::new(
+            RecordingRunner::default()
+                .respond("cargo metadata", 0, &metadata.to_string(), "")
+                .respond(
+                    "nix --extra-experimental-features 'nix-command flakes' flake lock",
+                    0,
+                    "",
+                    "",
+                )
+                .respond(
+                    "nix --extra-experimental-features 'nix-command flakes' print-dev-env",
+                    0,
+                    r#"{"variables":{}}"#,
+                    "",
+                ),
+        );
+
+        let prepare = Prepare {
+            project_dir: Some(project_dir.path().to_owned()),
+            verify: false,
+            disable_telemetry: true,
+            offline: OfflineMode::Online,
+            no_walk_up: false,
+            install_nix: false,
+            allow_empty: false,
+            strict: false,
+            deny_warnings: false,
+            profile: None,
+            overlay: vec![],
+            package: None,
+            manifest_path: None,
+            target: None,
+            refresh: true,
+        };
+        let exit = command_runner::scope(runner.clone(), prepare.cmd()).await;
+        assert!(matches!(exit, Ok(None)), "{exit:?}");
+
+        let commands = runner.commands();
+        assert!(commands
+            .iter()
+            .any(|command| command.contains("flake lock")));
+        assert!(commands
+            .last()
+            .is_some_and(|command| command.contains("print-dev-env")));
+        Ok(())
+    }
+}
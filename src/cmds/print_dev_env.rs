@@ -1,87 +1,575 @@
-//! The `run` subcommand.
+//! The `print-dev-env` subcommand.
 
-use std::{path::PathBuf, process::Stdio};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
 
-use clap::Args;
+use clap::{Args, ValueEnum};
 use eyre::WrapErr;
-use owo_colors::OwoColorize;
-use tokio::process::Command;
+use target_lexicon::Triple;
 
-use crate::flake_generator;
+use crate::execution::Effect;
+use crate::flake_generator::{self, GenerateOptions};
+use crate::offline::OfflineMode;
+use crate::project_config::{PROJECT_CONFIG_FILE, PROJECT_TEMPLATE_FILE};
+use crate::riff_lock::{sha256_hex, RIFF_LOCK_FILE};
+use crate::{editor, nix_dev_env};
+
+/// The project files whose contents decide the environment, for `--hash-check`
+const FINGERPRINTED_FILES: &[&str] = &[
+    "Cargo.toml",
+    "Cargo.lock",
+    PROJECT_CONFIG_FILE,
+    PROJECT_TEMPLATE_FILE,
+    RIFF_LOCK_FILE,
+];
 
 /// print shell code that can be sourced by bash to reproduce the riff environment
 ///
 /// For example, run `cargo build` inside riff:
 ///
 ///     $ eval $(riff print-dev-env)
+///
+/// Keep the environment in a file, only regenerating it when the project's dependencies change:
+///
+///     $ riff print-dev-env --out .riff-env --hash-check && source .riff-env
+///
+/// Print the settings which make rust-analyzer use the environment, to add to VS Code's
+/// `settings.json`:
+///
+///     $ riff print-dev-env --rust-analyzer
 #[derive(Debug, Args)]
 pub struct PrintDevEnv {
-    /// The root directory of the project
+    /// The root directory of the project, or the path to its `Cargo.toml`
     #[clap(long, value_parser)]
     project_dir: Option<PathBuf>,
     #[clap(from_global)]
     disable_telemetry: bool,
     #[clap(from_global)]
-    offline: bool,
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
     #[clap(long)]
     json: bool,
+    /// Print the rust-analyzer settings (`CARGO`, `RUSTC`, `PATH`, and the other variables) which
+    /// make it use the environment, as VS Code `settings.json` entries
+    #[clap(long, conflicts_with = "json")]
+    rust_analyzer: bool,
+    /// How to print the environment: `bash` code to source, or `dotenv` `NAME=value` lines of
+    /// the exported variables, for docker-compose's `env_file`, systemd's `EnvironmentFile`, and
+    /// other tools reading `.env` files
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Format::Bash,
+        conflicts_with_all = ["json", "rust_analyzer"]
+    )]
+    format: Format,
+    /// Write the environment to this file instead of printing it, replacing the file atomically
+    #[clap(long, value_name = "PATH")]
+    out: Option<PathBuf>,
+    /// Leave the `--out` file alone if it was written for the same `Cargo.toml`, `Cargo.lock`,
+    /// `riff.toml`, and `riff.lock`
+    #[clap(long, requires = "out")]
+    hash_check: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Bash,
+    Dotenv,
 }
 
 impl PrintDevEnv {
     pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
-        let flake_dir = flake_generator::generate_flake_from_project_dir(
+        let project_dir = flake_generator::resolve_project_dir(
             self.project_dir.clone(),
-            self.offline,
-            self.disable_telemetry,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+
+        let fingerprint = match &self.out {
+            Some(out) => {
+                let fingerprint = self.fingerprint(&project_dir).await?;
+                if self.hash_check && !self.refresh && out.exists() {
+                    let written = tokio::fs::read_to_string(fingerprint_path(out)).await.ok();
+                    if written.as_deref() == Some(fingerprint.as_str()) {
+                        tracing::debug!(out = %out.display(), "The environment is up to date");
+                        return Ok(Some(0));
+                    }
+                }
+                Some(fingerprint)
+            }
+            None => None,
+        };
+
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                ..Default::default()
+            },
         )
         .await?;
 
-        let mut nix_print_dev_env_command = Command::new("nix");
+        if self.rust_analyzer || self.format == Format::Dotenv {
+            return self.print_from_dev_env(flake_dir.path(), fingerprint).await;
+        }
+
+        let mut nix_print_dev_env_command = crate::nix_config::command(["print-dev-env"]);
         nix_print_dev_env_command
-            .arg("print-dev-env")
-            .args(["--extra-experimental-features", "flakes nix-command"])
             .arg("-L")
             .arg(format!("path://{}", flake_dir.path().to_str().unwrap()))
             .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
+            .stdout(if self.out.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
             .stderr(Stdio::inherit());
 
         if self.json {
             nix_print_dev_env_command.arg("--json");
         }
+        crate::process_limits::apply_to_nix(&mut nix_print_dev_env_command);
+
+        // Only with `--offline=strict-offline`, since the packages of a lock generated online may
+        // not be in the store yet
+        if self.offline.is_strict() {
+            nix_print_dev_env_command.arg("--offline");
+        }
+        if !crate::execution::perform(Effect::run(nix_print_dev_env_command.as_std())) {
+            return Ok(None);
+        }
+
+        let nix_print_dev_env_exit = crate::command_runner::run(
+            &mut nix_print_dev_env_command,
+            self.offline.is_offline(),
+            &mut |_| {},
+        )
+        .await
+        .map_err(|err| flake_generator::missing_nix("nix print-dev-env").with_source(err))?;
+
+        if let (Some(out), Some(fingerprint)) = (&self.out, fingerprint) {
+            if nix_print_dev_env_exit.status.success() {
+                write_atomically(out, &nix_print_dev_env_exit.stdout).await?;
+                write_atomically(&fingerprint_path(out), fingerprint.as_bytes()).await?;
+            }
+        }
+
+        Ok(nix_print_dev_env_exit.status.code())
+    }
+
+    /// Print (or write to `--out`) the rust-analyzer settings or the dotenv file for the
+    /// environment of the flake in `flake_dir`, which riff renders itself rather than Nix
+    async fn print_from_dev_env(
+        &self,
+        flake_dir: &Path,
+        fingerprint: Option<String>,
+    ) -> color_eyre::Result<Option<i32>> {
+        let dev_env = nix_dev_env::get_nix_dev_env(flake_dir, self.offline, self.refresh).await?;
+        let rendered = if self.rust_analyzer {
+            serde_json::to_string_pretty(&editor::rust_analyzer_settings(&dev_env))? + "\n"
+        } else {
+            render_dotenv(&dev_env.exported_variables())
+        };
+        match (&self.out, fingerprint) {
+            (Some(out), Some(fingerprint)) => {
+                write_atomically(out, rendered.as_bytes()).await?;
+                write_atomically(&fingerprint_path(out), fingerprint.as_bytes()).await?;
+            }
+            _ => print!("{rendered}"),
+        }
+        Ok(Some(0))
+    }
+
+    /// A hash of everything which decides the printed environment
+    async fn fingerprint(&self, project_dir: &Path) -> color_eyre::Result<String> {
+        let settings = format!(
+            "profile {:?}\noverlays {:?}\npackage {:?}\nmanifest-path {:?}\ntarget {:?}\njson {}\nrust-analyzer {}\nformat {:?}\n",
+            self.profile,
+            self.overlay,
+            self.package,
+            self.manifest_path,
+            self.target.as_ref().map(ToString::to_string),
+            self.json,
+            self.rust_analyzer,
+            self.format
+        );
+        let cargo_dir = flake_generator::cargo_dir(project_dir, self.manifest_path.as_deref());
+        project_fingerprint(project_dir, &cargo_dir, &settings).await
+    }
+}
 
-        // TODO(@hoverbear): Try to enable this somehow. Right now since we don't keep the lock
-        // in a consistent place, we can't reliably pick up a lock generated in online mode.
-        //
-        // If we stored the generated flake/lock in a consistent place this could be enabled.
-        //
-        // if self.offline {
-        //     nix_develop_command.arg("--offline");
-        // }
-
-        tracing::trace!(command = ?nix_print_dev_env_command.as_std(), "Running");
-        let nix_print_dev_env_exit = match nix_print_dev_env_command
-            .spawn()
-            .wrap_err("Failed to spawn `nix print-dev-env`")?
-            .wait_with_output()
-            .await
-        {
-            Ok(nix_print_dev_env_exit) => nix_print_dev_env_exit,
+/// A hash of this riff's version, `settings`, and the files of the project in `project_dir` which
+/// decide its environment, with its Cargo manifest in `cargo_dir` (see
+/// [`flake_generator::cargo_dir`])
+pub(super) async fn project_fingerprint(
+    project_dir: &Path,
+    cargo_dir: &Path,
+    settings: &str,
+) -> color_eyre::Result<String> {
+    let mut fingerprinted = format!("riff {}\n{settings}", env!("CARGO_PKG_VERSION")).into_bytes();
+    let workspace_root = crate::cargo_metadata::workspace_root(cargo_dir).await;
+    for file in FINGERPRINTED_FILES {
+        let path = match *file {
+            "Cargo.toml" => cargo_dir.join(file),
+            "Cargo.lock" => workspace_root.join(file),
+            _ => project_dir.join(file),
+        };
+        let hash = match tokio::fs::read(&path).await {
+            Ok(contents) => sha256_hex(&contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => "missing".to_string(),
             Err(err) => {
-                let err_msg = format!(
-                    "\
-                    Could not execute `{nix_print_dev_env}`. Is `{nix}` installed?\n\n\
-                    Get instructions for installing Nix: {nix_install_url}\
-                    ",
-                    nix_print_dev_env = "nix print-dev-env".cyan(),
-                    nix = "nix".cyan(),
-                    nix_install_url = "https://nixos.org/download.html".blue().underline(),
-                );
-                eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-                std::process::exit(1);
+                return Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display()))
             }
         };
+        fingerprinted.extend(format!("{file} {hash}\n").as_bytes());
+    }
+    for path in flake_generator::language_files(project_dir) {
+        let hash = match tokio::fs::read(&path).await {
+            Ok(contents) => sha256_hex(&contents),
+            Err(_) => "missing".to_string(),
+        };
+        let file = path.strip_prefix(project_dir).unwrap_or(&path);
+        fingerprinted.extend(format!("{} {hash}\n", file.display()).as_bytes());
+    }
+    // The workspace's root manifest and every member's decide the environment of any member
+    let root_manifest = (workspace_root != cargo_dir).then(|| workspace_root.join("Cargo.toml"));
+    for manifest in root_manifest
+        .into_iter()
+        .chain(member_manifests(&workspace_root))
+    {
+        let hash = sha256_hex(&tokio::fs::read(&manifest).await.unwrap_or_default());
+        let member = manifest.strip_prefix(&workspace_root).unwrap_or(&manifest);
+        fingerprinted.extend(format!("{} {hash}\n", member.display()).as_bytes());
+    }
+    for layer in included_layers(project_dir).await {
+        let hash = match tokio::fs::read(crate::layers::cache_path(&layer)?).await {
+            Ok(contents) => sha256_hex(&contents),
+            Err(_) => "missing".to_string(),
+        };
+        fingerprinted.extend(format!("layer {layer} {hash}\n").as_bytes());
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    fingerprinted.extend(detectors_fingerprint(&path).as_bytes());
+    Ok(sha256_hex(&fingerprinted))
+}
 
-        Ok(nix_print_dev_env_exit.status.code())
+/// The detectors in the directories of `path` (see [`crate::detectors::find`]), with when each
+/// was last modified, since installing, removing, or updating one can change the environment
+fn detectors_fingerprint(path: &OsStr) -> String {
+    crate::detectors::find(path)
+        .into_iter()
+        .map(|(name, detector)| {
+            let modified = std::fs::metadata(&detector)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            format!("detector {name} {} {modified:?}\n", detector.display())
+        })
+        .collect()
+}
+
+/// The manifests of the members of the workspace whose root is in `cargo_dir`, from its
+/// `workspace.members`, which may use `*` wildcards
+fn member_manifests(cargo_dir: &Path) -> Vec<PathBuf> {
+    let Some(manifest) = std::fs::read_to_string(cargo_dir.join("Cargo.toml"))
+        .ok()
+        .and_then(|contents| toml::from_str::<toml::Value>(&contents).ok())
+    else {
+        return vec![];
+    };
+    let patterns = |key: &str| -> Vec<String> {
+        manifest
+            .get("workspace")
+            .and_then(|workspace| workspace.get(key))
+            .and_then(toml::Value::as_array)
+            .map(|patterns| {
+                patterns
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .map(|pattern| pattern.trim_end_matches('/').to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let excluded = patterns("exclude")
+        .into_iter()
+        .map(|exclude| cargo_dir.join(exclude))
+        .collect::<Vec<_>>();
+
+    let mut manifests = patterns("members")
+        .iter()
+        .flat_map(|pattern| {
+            pattern
+                .split('/')
+                .fold(vec![cargo_dir.to_owned()], |dirs, segment| {
+                    if !segment.contains('*') {
+                        return dirs.into_iter().map(|dir| dir.join(segment)).collect();
+                    }
+                    dirs.into_iter()
+                        .filter_map(|dir| std::fs::read_dir(dir).ok())
+                        .flatten()
+                        .filter_map(Result::ok)
+                        .filter(|entry| {
+                            crate::project_config::branch_matches(
+                                segment,
+                                &entry.file_name().to_string_lossy(),
+                            )
+                        })
+                        .map(|entry| entry.path())
+                        .collect()
+                })
+        })
+        .filter(|dir| !excluded.contains(dir))
+        .map(|dir| dir.join("Cargo.toml"))
+        .filter(|manifest| manifest.is_file())
+        .collect::<Vec<_>>();
+    manifests.sort();
+    manifests.dedup();
+    manifests
+}
+
+/// The names of the layers the `riff.toml` in `project_dir` includes
+async fn included_layers(project_dir: &Path) -> Vec<String> {
+    let Ok(contents) = tokio::fs::read_to_string(project_dir.join(PROJECT_CONFIG_FILE)).await
+    else {
+        return vec![];
+    };
+    toml::from_str::<toml::Value>(&contents)
+        .ok()
+        .and_then(|config| config.get("include").cloned())
+        .and_then(|include| include.try_into().ok())
+        .unwrap_or_default()
+}
+
+/// `NAME=value` lines for `variables`, quoting values with anything but plain characters
+///
+/// Values are single quoted unless they contain a single quote: docker-compose, systemd, and other
+/// `.env` readers take single quoted values literally (newlines included), so a `$` isn't
+/// interpolated. Values with a single quote are double quoted instead, escaping backslashes,
+/// double quotes, dollar signs, and newlines with a backslash.
+fn render_dotenv(variables: &BTreeMap<&str, &str>) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-./:,+@%".contains(c);
+    variables
+        .iter()
+        .map(|(name, value)| {
+            if value.chars().all(is_plain) {
+                format!("{name}={value}\n")
+            } else if !value.contains('\'') {
+                format!("{name}='{value}'\n")
+            } else {
+                let escaped = value
+                    .replace('\\', r"\\")
+                    .replace('"', r#"\""#)
+                    .replace('$', r"\$")
+                    .replace('\n', r"\n");
+                format!("{name}=\"{escaped}\"\n")
+            }
+        })
+        .collect()
+}
+
+/// Where the fingerprint of the project the `--out` file was written for is kept
+fn fingerprint_path(out: &Path) -> PathBuf {
+    let mut path = out.as_os_str().to_owned();
+    path.push(".fingerprint");
+    PathBuf::from(path)
+}
+
+/// Replace `path` with `contents`, so readers never see a partly written file
+async fn write_atomically(path: &Path, contents: &[u8]) -> color_eyre::Result<()> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(format!(".new{}", std::process::id()));
+    let temporary_path = PathBuf::from(temporary_path);
+
+    tokio::fs::write(&temporary_path, contents)
+        .await
+        .wrap_err_with(|| format!("Could not write `{}`", temporary_path.display()))?;
+    tokio::fs::rename(&temporary_path, path)
+        .await
+        .wrap_err_with(|| format!("Could not replace `{}`", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fingerprint_follows_project_files() -> eyre::Result<()> {
+        let project_dir = tempfile::TempDir::new()?;
+        let print_dev_env = PrintDevEnv {
+            project_dir: None,
+            disable_telemetry: true,
+            offline: OfflineMode::Cached,
+            no_walk_up: false,
+            install_nix: false,
+            allow_empty: false,
+            strict: false,
+            deny_warnings: false,
+            profile: None,
+            overlay: vec![],
+            package: None,
+            manifest_path: None,
+            target: None,
+            refresh: false,
+            json: false,
+            rust_analyzer: false,
+            format: Format::Bash,
+            out: None,
+            hash_check: false,
+        };
+
+        let empty = print_dev_env.fingerprint(project_dir.path()).await?;
+        tokio::fs::write(project_dir.path().join("Cargo.lock"), "version = 3").await?;
+        let locked = print_dev_env.fingerprint(project_dir.path()).await?;
+        assert_ne!(empty, locked);
+        assert_eq!(locked, print_dev_env.fingerprint(project_dir.path()).await?);
+
+        let gpu = PrintDevEnv {
+            profile: Some("gpu".to_string()),
+            ..print_dev_env
+        };
+        assert_ne!(locked, gpu.fingerprint(project_dir.path()).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fingerprint_follows_members_and_layers() -> eyre::Result<()> {
+        let cache_dir = tempfile::TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let project_dir = tempfile::TempDir::new()?;
+        let fingerprint = || project_fingerprint(project_dir.path(), project_dir.path(), "");
+        tokio::fs::write(
+            project_dir.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"crates/*\", \"tool\"]\nexclude = [\"crates/old\"]\n",
+        )
+        .await?;
+        for member in ["crates/a", "crates/old", "tool"] {
+            tokio::fs::create_dir_all(project_dir.path().join(member)).await?;
+            tokio::fs::write(project_dir.path().join(member).join("Cargo.toml"), "").await?;
+        }
+        assert_eq!(
+            member_manifests(project_dir.path()),
+            vec![
+                project_dir.path().join("crates/a/Cargo.toml"),
+                project_dir.path().join("tool/Cargo.toml"),
+            ]
+        );
+
+        let before = fingerprint().await?;
+        tokio::fs::write(
+            project_dir.path().join("crates/a/Cargo.toml"),
+            "[dependencies]\nopenssl-sys = \"0.9\"\n",
+        )
+        .await?;
+        let member_changed = fingerprint().await?;
+        assert_ne!(before, member_changed);
+        tokio::fs::write(
+            project_dir.path().join("crates/old/Cargo.toml"),
+            "[dependencies]\nopenssl-sys = \"0.9\"\n",
+        )
+        .await?;
+        assert_eq!(member_changed, fingerprint().await?);
+
+        tokio::fs::write(
+            project_dir.path().join(PROJECT_CONFIG_FILE),
+            "include = [\"gpu\"]\n",
+        )
+        .await?;
+        let included = fingerprint().await?;
+        tokio::fs::write(
+            crate::layers::cache_path("gpu")?,
+            "version = 1\n[profile.gpu]\nruntime-inputs = [\"cudatoolkit\"]\n",
+        )
+        .await?;
+        assert_ne!(included, fingerprint().await?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fingerprint_follows_detectors() -> eyre::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin = tempfile::TempDir::new()?;
+        let before = detectors_fingerprint(bin.path().as_os_str());
+        let detector = bin.path().join("riff-detector-elixir");
+        std::fs::write(&detector, "#!/bin/sh\n")?;
+        assert_eq!(before, detectors_fingerprint(bin.path().as_os_str()));
+        std::fs::set_permissions(&detector, std::fs::Permissions::from_mode(0o755))?;
+        assert_ne!(before, detectors_fingerprint(bin.path().as_os_str()));
+        Ok(())
+    }
+
+    #[test]
+    fn dotenv_values_are_escaped() {
+        let variables = BTreeMap::from([
+            ("CARGO", "/nix/store/abc-cargo/bin/cargo"),
+            ("EMPTY", ""),
+            ("GREETING", "say \"hi\" to $USER\nand C:\\"),
+            ("PS1", "\\u@\\h $ "),
+            ("PRICE", "it's $5\nor \"$6\""),
+        ]);
+        assert_eq!(
+            render_dotenv(&variables),
+            r#"CARGO=/nix/store/abc-cargo/bin/cargo
+EMPTY=
+GREETING='say "hi" to $USER
+and C:\'
+PRICE="it's \$5\nor \"\$6\""
+PS1='\u@\h $ '
+"#
+        );
+    }
+
+    #[tokio::test]
+    async fn out_files_are_replaced() -> eyre::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let out = dir.path().join(".riff-env");
+        write_atomically(&out, b"old").await?;
+        write_atomically(&out, b"new").await?;
+        assert_eq!(tokio::fs::read_to_string(&out).await?, "new");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        assert_eq!(
+            fingerprint_path(&out),
+            dir.path().join(".riff-env.fingerprint")
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,195 @@
+//! The `push-cache` subcommand.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+use tokio::process::Command;
+
+use crate::errors::{ErrorCode, RiffError};
+use crate::execution::{self, Effect};
+use crate::flake_generator::{self, GenerateOptions};
+use crate::nix_config;
+use crate::nix_progress::NixOutput;
+use crate::offline::OfflineMode;
+use crate::project_config::{ProjectConfig, PROJECT_CONFIG_FILE};
+use crate::spinner::{self, Phase};
+
+/// Push your project's environment to a binary cache
+///
+/// Builds or downloads everything the environment needs, then copies it to a Nix binary cache, so
+/// teammates fetch it from there instead of building it again. The cache is any store URL `nix
+/// copy` accepts, from `push-cache` in `riff.toml` or `--to`:
+///
+///     $ riff push-cache --to 's3://acme-nix-cache?region=eu-west-1'
+///
+/// Teammates fetch from the cache once it's in the `substituters` of `riff.toml`.
+#[derive(Debug, Args)]
+pub struct PushCache {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// The store URL of the cache, instead of `push-cache` from `riff.toml`
+    #[clap(long, value_name = "URL")]
+    to: Option<String>,
+    /// Sign the environment's store paths with this secret key before pushing them, for caches
+    /// which don't sign paths themselves
+    #[clap(long, value_name = "KEY_FILE")]
+    sign_with: Option<PathBuf>,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    refresh: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+}
+
+impl PushCache {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        if self.offline.is_offline() {
+            return Err(eyre!(
+                "Pushing to a cache needs the network, drop `--offline`"
+            ));
+        }
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        let project_config = ProjectConfig::load(&project_dir).await?;
+        let cache = self
+            .to
+            .or_else(|| project_config.push_cache.clone())
+            .ok_or_else(|| {
+                eyre!("Pass the cache's store URL with `--to`, or set `push-cache` in `{PROJECT_CONFIG_FILE}`")
+            })?;
+
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir),
+            &GenerateOptions {
+                allow_empty: self.allow_empty,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile,
+                overlays: self.overlay,
+                package: self.package,
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // The shell's input derivation depends on everything the environment has, so its closure
+        // is the whole environment
+        let output = run(
+            nix_config::command(["build"])
+                .arg("--no-link")
+                .arg("--print-out-paths")
+                .arg(format!(
+                    "path://{}#devShells.{}.default.inputDerivation",
+                    flake_dir.path().display(),
+                    crate::target_cfg::nix_system(&target_lexicon::HOST)
+                )),
+            Phase::EvaluatingEnvironment,
+            "nix build",
+        )
+        .await?;
+        let closure = String::from_utf8(output.stdout)
+            .wrap_err("Output produced by `nix build` was not valid UTF8")?
+            .trim()
+            .to_string();
+
+        if let Some(key_file) = &self.sign_with {
+            push(
+                nix_config::command(["store", "sign"])
+                    .args(["--recursive", "--key-file"])
+                    .arg(key_file)
+                    .arg(&closure),
+                Phase::PushingCache,
+                "nix store sign",
+            )
+            .await?;
+        }
+
+        push(
+            nix_config::command(["copy"])
+                .args(["--to", &cache])
+                .arg(&closure),
+            Phase::PushingCache,
+            "nix copy",
+        )
+        .await?;
+        if execution::dry_run() {
+            return Ok(None);
+        }
+
+        eprintln!(
+            "{check} Pushed the environment to `{cache}`",
+            check = "✓".green(),
+        );
+        if !project_config.substituters.contains(&cache) {
+            eprintln!(
+                "Add the cache's URL to `substituters` (and its public key to `trusted-public-keys`) \
+                in `{PROJECT_CONFIG_FILE}` for riff to fetch from it"
+            );
+        }
+        Ok(None)
+    }
+}
+
+/// Run the `nix` command `name`, which changes a store, unless this is a dry run
+async fn push(command: &mut Command, phase: Phase, name: &str) -> color_eyre::Result<()> {
+    if execution::perform(Effect::run(command.as_std())) {
+        run(command, phase, name).await?;
+    }
+    Ok(())
+}
+
+/// Run the `nix` command `name`, failing if it does
+async fn run(command: &mut Command, phase: Phase, name: &str) -> color_eyre::Result<NixOutput> {
+    command.kill_on_drop(true);
+    crate::process_limits::apply_to_nix(command);
+
+    let spinner = spinner::start(phase, &format!("Running `{}`", name.cyan()))
+        .wrap_err("Failed to construct progress spinner")?;
+    let output = crate::nix_progress::run_nix(command, false, &spinner)
+        .await
+        .map_err(|err| flake_generator::missing_nix(name).with_source(err))?;
+    spinner.finish_and_clear();
+
+    if !output.status.success() {
+        return Err(RiffError::new(
+            ErrorCode::NixFailed,
+            format!("`{name}` failed:\n{}", output.progress.errors()),
+        )
+        .into());
+    }
+    Ok(output)
+}
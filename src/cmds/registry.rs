@@ -0,0 +1,390 @@
+//! The `registry` subcommand.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Subcommand};
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use serde_json::{json, Value};
+
+use crate::dependency_registry::schema;
+use crate::dev_env;
+use crate::offline::OfflineMode;
+use crate::project_config::DEFAULT_NIXPKGS;
+use crate::spinner::Phase;
+
+/// Where registry suggestions are filed
+const REGISTRY_ISSUES_URL: &str = "https://github.com/DeterminateSystems/riff/issues/new";
+
+/// The registry version `riff registry suggest --write` writes
+const SUGGESTED_REGISTRY_VERSION: usize = 2;
+
+/// Work with the registry of crates' external dependencies
+#[derive(Debug, Args)]
+pub struct Registry {
+    #[clap(subcommand)]
+    command: RegistryCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum RegistryCommand {
+    Suggest(Suggest),
+    Validate(Validate),
+    /// Print the JSON Schema of registry files, for editors
+    Schema,
+}
+
+impl Registry {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        match self.command {
+            RegistryCommand::Suggest(suggest) => suggest.cmd().await,
+            RegistryCommand::Validate(validate) => validate.cmd().await,
+            RegistryCommand::Schema => {
+                print!("{}", schema::JSON_SCHEMA);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Check a registry file before proposing it, or before adding it to `registry.d`
+///
+/// Reports unknown fields (such as `build_inputs` instead of `build-inputs`), unsupported
+/// versions, and names which aren't Nixpkgs attribute paths, crate names, or environment variable
+/// names:
+///
+///     $ riff registry validate registry/registry.json
+#[derive(Debug, Args)]
+pub struct Validate {
+    /// The registry file to check
+    #[clap(value_parser)]
+    file: PathBuf,
+}
+
+impl Validate {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let contents = tokio::fs::read_to_string(&self.file)
+            .await
+            .wrap_err_with(|| format!("Could not read `{}`", self.file.display()))?;
+        let problems = schema::validate(&contents);
+        if problems.is_empty() {
+            eprintln!(
+                "{check} `{file}` is a valid registry file",
+                check = "✓".green(),
+                file = self.file.display(),
+            );
+            return Ok(None);
+        }
+        for problem in &problems {
+            eprintln!("{} {problem}", "✗".red());
+        }
+        Err(eyre!(
+            "`{}` has {} problem{}",
+            self.file.display(),
+            problems.len(),
+            if problems.len() == 1 { "" } else { "s" }
+        ))
+    }
+}
+
+/// Suggest what a crate needs, for the registry
+///
+/// Checks that the inputs exist in Nixpkgs, prints the registry entry, and prints a link to a
+/// prefilled GitHub issue suggesting it:
+///
+///     $ riff registry suggest openssl-sys --build-inputs openssl,pkg-config
+///
+/// To use the entry before it's in the registry, write it into a local registry file instead:
+///
+///     $ riff registry suggest openssl-sys --build-inputs openssl --write openssl-sys.json
+#[derive(Debug, Args)]
+pub struct Suggest {
+    /// The crate's name, optionally constrained to some of its versions, eg `openssl-sys@<0.9.60`
+    name: String,
+    /// The Nixpkgs packages the crate needs to build
+    #[clap(long, value_delimiter = ',', value_name = "ATTR")]
+    build_inputs: Vec<String>,
+    /// The Nixpkgs packages whose libraries the crate loads at run time
+    #[clap(long, value_delimiter = ',', value_name = "ATTR")]
+    runtime_inputs: Vec<String>,
+    /// Environment variables the crate needs
+    #[clap(long, value_name = "NAME=VALUE", value_parser = dev_env::parse_environment_variable)]
+    environment_variables: Vec<(String, String)>,
+    /// Only suggest these settings for one target, given with `--target` (eg
+    /// `aarch64-apple-darwin`)
+    #[clap(from_global)]
+    target: Option<target_lexicon::Triple>,
+    /// Don't check that the inputs exist in Nixpkgs
+    #[clap(long)]
+    no_validate: bool,
+    /// Open the prefilled GitHub issue in a browser
+    #[clap(long, conflicts_with = "write")]
+    open: bool,
+    /// Add the entry to this local registry file instead of suggesting it on GitHub
+    #[clap(long, value_name = "PATH")]
+    write: Option<PathBuf>,
+    #[clap(from_global)]
+    offline: OfflineMode,
+}
+
+impl Suggest {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let entry = self.entry()?;
+
+        if !self.no_validate {
+            let attrs = self
+                .build_inputs
+                .iter()
+                .chain(&self.runtime_inputs)
+                .filter(|input| !input.contains('#'))
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            let missing = missing_attrs(&attrs, self.offline.is_offline()).await?;
+            if !missing.is_empty() {
+                return Err(eyre!(
+                    "Nixpkgs has no {}, search for the right name at https://search.nixos.org/packages",
+                    missing
+                        .iter()
+                        .map(|attr| format!("`{attr}`"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+
+        let snippet = serde_json::to_string_pretty(&entry)?;
+        println!("{snippet}");
+
+        if let Some(path) = &self.write {
+            write_entry(path, &self.name, &entry[&self.name]).await?;
+            eprintln!(
+                "{check} Added `{name}` to `{path}`",
+                check = "✓".green(),
+                name = self.name,
+                path = path.display(),
+            );
+            return Ok(None);
+        }
+
+        let url = issue_url(&self.name, &snippet)?;
+        eprintln!(
+            "Suggest this entry for the registry at:\n{}",
+            url.as_str().blue().underline()
+        );
+        if self.open {
+            open_in_browser(url.as_str())?;
+        }
+        Ok(None)
+    }
+
+    /// The registry entry, as an object with the crate's name as its only key
+    fn entry(&self) -> color_eyre::Result<Value> {
+        if self.build_inputs.is_empty()
+            && self.runtime_inputs.is_empty()
+            && self.environment_variables.is_empty()
+        {
+            return Err(eyre!(
+                "Pass the crate's `--build-inputs`, `--runtime-inputs`, or `--environment-variables`"
+            ));
+        }
+
+        // Sorted, so the same suggestion always reads the same
+        let mut settings = serde_json::Map::new();
+        if !self.build_inputs.is_empty() {
+            let build_inputs = self.build_inputs.iter().collect::<BTreeSet<_>>();
+            settings.insert("build-inputs".to_string(), json!(build_inputs));
+        }
+        if !self.runtime_inputs.is_empty() {
+            let runtime_inputs = self.runtime_inputs.iter().collect::<BTreeSet<_>>();
+            settings.insert("runtime-inputs".to_string(), json!(runtime_inputs));
+        }
+        if !self.environment_variables.is_empty() {
+            let environment_variables = self
+                .environment_variables
+                .iter()
+                .cloned()
+                .collect::<BTreeMap<_, _>>();
+            settings.insert(
+                "environment-variables".to_string(),
+                json!(environment_variables),
+            );
+        }
+
+        let settings = match &self.target {
+            Some(target) => {
+                let target = target.to_string();
+                json!({ "targets": { target: settings } })
+            }
+            None => Value::Object(settings),
+        };
+        Ok(json!({ &self.name: settings }))
+    }
+}
+
+/// The attributes of `attrs` which Nixpkgs doesn't have
+async fn missing_attrs(attrs: &BTreeSet<String>, offline: bool) -> color_eyre::Result<Vec<String>> {
+    if attrs.is_empty() {
+        return Ok(vec![]);
+    }
+    // The names are spliced into a Nix expression, so only allow what attribute paths are made of
+    if let Some(attr) = attrs.iter().find(|attr| {
+        !attr
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.'+".contains(c))
+    }) {
+        return Err(eyre!("`{attr}` isn't a Nixpkgs attribute"));
+    }
+
+    let attr_list = attrs
+        .iter()
+        .map(|attr| format!("\"{attr}\""))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut command = crate::nix_config::command(["eval"]);
+    command
+        .arg("--json")
+        .arg(format!(
+            "{DEFAULT_NIXPKGS}#legacyPackages.{}",
+            crate::target_cfg::nix_system(&target_lexicon::HOST)
+        ))
+        .arg("--apply")
+        .arg(format!(
+            "pkgs: builtins.filter (attr: !pkgs.lib.hasAttrByPath (pkgs.lib.splitString \".\" attr) pkgs) [ {attr_list} ]"
+        ))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    if offline {
+        command.arg("--offline");
+    }
+    crate::process_limits::apply_to_nix(&mut command);
+
+    let spinner = crate::spinner::start(Phase::QueryingNixpkgs, "Checking the inputs")
+        .wrap_err("Failed to construct progress spinner")?;
+    let output = crate::command_runner::run(&mut command, offline, &mut |_| {})
+        .await
+        .wrap_err("Could not run `nix eval` to check the inputs")?;
+    spinner.finish_and_clear();
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "Could not check the inputs in Nixpkgs (pass `--no-validate` to skip the check):\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Add the settings for `name` to the local registry file at `path`, creating it if needed
+async fn write_entry(path: &Path, name: &str, settings: &Value) -> color_eyre::Result<()> {
+    let mut registry = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("`{}` isn't a registry file", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            json!({ "version": SUGGESTED_REGISTRY_VERSION, "language": { "rust": {} } })
+        }
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display()))
+        }
+    };
+    let dependencies = registry
+        .pointer_mut("/language/rust")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| eyre!("`{}` isn't a registry file", path.display()))?
+        .entry("dependencies")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| eyre!("`{}` isn't a registry file", path.display()))?;
+    dependencies.insert(name.to_string(), settings.clone());
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, serde_json::to_string_pretty(&registry)? + "\n")
+        .await
+        .wrap_err_with(|| format!("Could not write `{}`", path.display()))?;
+    Ok(())
+}
+
+/// A link to a new GitHub issue suggesting `snippet` as the registry entry for `name`
+fn issue_url(name: &str, snippet: &str) -> color_eyre::Result<reqwest::Url> {
+    let title = format!("Registry: add `{name}`");
+    let body = format!(
+        "Riff doesn't know what `{name}` needs, this entry made it build for me on `{host}`:\n\n\
+        ```json\n{snippet}\n```\n",
+        host = target_lexicon::HOST,
+    );
+    Ok(reqwest::Url::parse_with_params(
+        REGISTRY_ISSUES_URL,
+        [("title", title.as_str()), ("body", body.as_str())],
+    )?)
+}
+
+fn open_in_browser(url: &str) -> color_eyre::Result<()> {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    let status = std::process::Command::new(opener)
+        .arg(url)
+        .status()
+        .wrap_err_with(|| format!("Could not run `{opener}` to open the link"))?;
+    if !status.success() {
+        return Err(eyre!("`{opener}` could not open the link"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependency_registry::rust::RustDependencyData;
+
+    #[test]
+    fn suggested_entry_is_registry_data() -> eyre::Result<()> {
+        let suggest = Suggest {
+            name: "openssl-sys".to_string(),
+            build_inputs: vec!["pkg-config".to_string(), "openssl".to_string()],
+            runtime_inputs: vec![],
+            environment_variables: vec![("OPENSSL_STATIC".to_string(), "1".to_string())],
+            target: Some(
+                crate::target_cfg::parse_target("x86_64-unknown-linux-gnu")
+                    .map_err(eyre::Error::msg)?,
+            ),
+            no_validate: true,
+            open: false,
+            write: None,
+            offline: OfflineMode::Online,
+        };
+        let entry = suggest.entry()?;
+        assert_eq!(
+            entry,
+            json!({
+                "openssl-sys": {
+                    "targets": {
+                        "x86_64-unknown-linux-gnu": {
+                            "build-inputs": ["openssl", "pkg-config"],
+                            "environment-variables": { "OPENSSL_STATIC": "1" },
+                        }
+                    }
+                }
+            })
+        );
+        let data: RustDependencyData = serde_json::from_value(entry["openssl-sys"].clone())?;
+        assert_eq!(
+            data.targets["x86_64-unknown-linux-gnu"].build_inputs,
+            ["openssl", "pkg-config"].map(String::from).into()
+        );
+
+        let url = issue_url("openssl-sys", "{}")?;
+        assert!(url
+            .as_str()
+            .starts_with("https://github.com/DeterminateSystems/riff/issues/new?title=Registry"));
+        Ok(())
+    }
+}
@@ -1,12 +1,23 @@
 //! The `run` subcommand.
 
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Instant, SystemTime};
 
 use clap::Args;
-use eyre::WrapErr;
+use eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
+use target_lexicon::Triple;
 
-use crate::flake_generator;
+use crate::dev_env::{EnvironmentAdditions, NIXGL_WRAPPERS};
+use crate::execution::Effect;
+use crate::flake_generator::{self, GenerateOptions};
+use crate::history;
+use crate::nix_dev_env::{shell_quote, HostEnvironment, NixDevEnv, ResourceUsage};
+use crate::offline::OfflineMode;
+use crate::project_config::ProjectConfig;
+use crate::remote::RemoteTarget;
 
 /// Run a command with your project's dependencies
 ///
@@ -17,65 +28,639 @@ use crate::flake_generator;
 /// Run cargo check and cargo build at the same time:
 ///
 ///     $ riff run -- sh -c 'cargo check && cargo build'
+///
+/// Run several CI steps in one environment, printing a summary at the end:
+///
+///     $ riff run --and 'cargo fmt --check' --and 'cargo clippy' --and 'cargo test'
+///
+/// Report how long cargo build took, and how much CPU time and memory it used:
+///
+///     $ riff run --stats cargo build
+///
+/// Run the `test` command defined in the `[commands]` table of `riff.toml`, passing it `--workspace`:
+///
+///     $ riff run test --workspace
+///
+/// Run the tests of one package of a monorepo, from its directory:
+///
+///     $ riff run --chdir crates/parser cargo test
+///
+/// Run a game using OpenGL or Vulkan, on Linux other than NixOS:
+///
+///     $ riff run --nixgl cargo run
+///
+/// Run cargo build on a build server, in `/srv/project`:
+///
+///     $ riff run --on ssh://me@builder/srv/project -- cargo build
+///
+/// Replace riff with the program once the environment is ready, as a wrapper script or shebang
+/// line would (on Unix):
+///
+///     $ riff run --exec -- cargo run
 #[derive(Debug, Args)]
 pub struct Run {
-    /// The root directory of the project
+    /// The root directory of the project, or the path to its `Cargo.toml`
     #[clap(long, value_parser)]
     project_dir: Option<PathBuf>,
-    /// The command to run with your project's dependencies
-    #[clap(required = true)]
+    /// Run the command on another machine instead, given as `ssh://[user@]host[:port][/directory]`
+    ///
+    /// The environment is detected for this machine's platform, and the project must already be
+    /// present in the directory on the remote machine.
+    #[clap(long, value_name = "URL", conflicts_with = "and")]
+    on: Option<RemoteTarget>,
+    /// A shell command to run after the previous one, in the same environment (may be repeated)
+    #[clap(long = "and", value_name = "COMMAND")]
+    pub(crate) and: Vec<String>,
+    /// Keep running the remaining `--and` commands after one fails
+    #[clap(long, requires = "and")]
+    keep_going: bool,
+    /// Report the wall time, CPU time, and peak memory use of each command once it exits
+    #[clap(long, conflicts_with = "on")]
+    stats: bool,
+    /// Run the commands in this directory instead of the current one
+    #[clap(long, value_name = "DIR", conflicts_with = "on")]
+    chdir: Option<PathBuf>,
+    /// Run the commands through nixGL, so programs using OpenGL or Vulkan load the host's
+    /// drivers (on Linux other than NixOS)
+    #[clap(long, conflicts_with = "on")]
+    nixgl: bool,
+    /// Check the environment works, with quick probes of the tools it provides (such as `cc
+    /// --version` or `pkg-config --exists openssl`), before running anything in it
+    #[clap(long, conflicts_with = "on")]
+    verify: bool,
+    /// Replace riff with the command once the environment is ready, instead of waiting for it, so
+    /// it receives signals directly and riff doesn't linger as its parent (Unix only; the run
+    /// isn't recorded in `riff history`)
+    #[clap(long, conflicts_with_all = ["on", "and", "stats"])]
+    exec: bool,
+    #[clap(flatten)]
+    host_environment: HostEnvironment,
+    #[clap(flatten)]
+    additions: EnvironmentAdditions,
+    /// The command to run with your project's dependencies, or the name of one defined in
+    /// `riff.toml`
+    #[clap(required_unless_present = "and")]
     pub(crate) command: Vec<String>,
     #[clap(from_global)]
     disable_telemetry: bool,
     #[clap(from_global)]
-    offline: bool,
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
     // TODO(@cole-h): support additional nix develop args?
 }
 
 impl Run {
     pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let started = (SystemTime::now(), Instant::now());
+        let mut environment_ready = None;
+        let result = self.run(&mut environment_ready).await;
+
+        if history::enabled() {
+            if let Err(err) = self.record(started, environment_ready, &result).await {
+                tracing::warn!(%err, "Could not record the run in riff's history");
+            }
+        }
+        result
+    }
+
+    /// Run the commands, noting when the environment is ready in `environment_ready`
+    async fn run(
+        &self,
+        environment_ready: &mut Option<Instant>,
+    ) -> color_eyre::Result<Option<i32>> {
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir.clone(),
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        let commands = ProjectConfig::load(&project_dir).await?.commands;
+        // Before any Nix work, which a typo would waste
+        if let Some(dir) = &self.chdir {
+            if !dir.is_dir() {
+                return Err(eyre!("`{}` isn't a directory", dir.display()));
+            }
+        }
+
         let flake_dir = flake_generator::generate_flake_from_project_dir(
+            Some(project_dir),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                additions: self.additions.clone(),
+                nixgl: self.nixgl,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let mut steps = self.steps(&commands);
+        if let Some(remote) = &self.on {
+            let status = remote.run(flake_dir.path(), &steps[0].argv).await?;
+            return Ok(crate::nix_dev_env::exit_code(status));
+        }
+
+        let dev_env =
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh)
+                .await?;
+        *environment_ready = Some(Instant::now());
+        if self.verify {
+            crate::verify::verify(&dev_env, &self.host_environment).await?;
+        }
+        if self.nixgl {
+            wrap_with_nixgl(&dev_env, &mut steps);
+        }
+        if self.exec {
+            // Nothing runs riff's destructors after `exec`, so clean up the temporary flake first
+            drop(flake_dir);
+            return exec_step(
+                &dev_env,
+                &steps[0].argv,
+                &self.host_environment,
+                self.chdir.as_deref(),
+            );
+        }
+
+        if self.and.is_empty() {
+            let (code, usage) = run_step(
+                &dev_env,
+                &steps[0].argv,
+                true,
+                &self.host_environment,
+                self.chdir.as_deref(),
+            )
+            .await?;
+            if self.stats {
+                eprintln!("\n{} {usage}", "⏱".dimmed());
+            }
+            if code.unwrap_or(0) != 0 && !self.nixgl {
+                suggest_nixgl(&dev_env);
+            }
+            return Ok(code);
+        }
+
+        let outcomes = run_steps(
+            &dev_env,
+            &steps,
+            self.keep_going,
+            true,
+            &self.host_environment,
+            self.chdir.as_deref(),
+        )
+        .await?;
+
+        eprintln!("\n{}", summary_table(&steps, &outcomes, self.stats));
+        Ok(Some(first_failure(&outcomes)))
+    }
+
+    /// Add the run to riff's history, see `riff history`
+    async fn record(
+        &self,
+        (started_at, started): (SystemTime, Instant),
+        environment_ready: Option<Instant>,
+        result: &color_eyre::Result<Option<i32>>,
+    ) -> eyre::Result<()> {
+        let project_dir = flake_generator::resolve_project_dir(
             self.project_dir.clone(),
-            self.offline,
-            self.disable_telemetry,
+            self.manifest_path.as_deref(),
+            !self.no_walk_up,
+        )?;
+        let command = (!self.command.is_empty())
+            .then(|| self.command.join(" "))
+            .into_iter()
+            .chain(self.and.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" && ");
+        history::record(&history::Entry {
+            timestamp: history::timestamp(started_at),
+            project: history::project_hash(&project_dir),
+            command,
+            environment_ms: environment_ready
+                .map(|ready| ready.duration_since(started).as_millis() as u64),
+            duration_ms: started.elapsed().as_millis() as u64,
+            // A command killed by a signal exited with `128 + signal`, see `exit_code`
+            exit_code: result.as_ref().ok().copied().flatten(),
+        })
+        .await
+    }
+
+    /// The main command (if any), followed by each `--and` command, with the project's
+    /// `commands` resolved
+    fn steps(&self, commands: &BTreeMap<String, String>) -> Vec<Step> {
+        let main_step = (!self.command.is_empty()).then(|| Step::command(&self.command, commands));
+        main_step
+            .into_iter()
+            .chain(self.and.iter().map(|script| Step::shell(script, commands)))
+            .collect()
+    }
+}
+
+/// Run `steps` through the environment's nixGL wrappers, see `--nixgl`
+fn wrap_with_nixgl(dev_env: &NixDevEnv, steps: &mut [Step]) {
+    let wrappers = NIXGL_WRAPPERS
+        .iter()
+        .filter_map(|wrapper| dev_env.find_program(wrapper))
+        .map(|wrapper| wrapper.display().to_string())
+        .collect::<Vec<_>>();
+    if wrappers.is_empty() {
+        if crate::platform::needs_nixgl() {
+            eprintln!(
+                "{} `--nixgl` has nothing to wrap, the environment has no OpenGL or Vulkan libraries",
+                "⚠".yellow()
+            );
+        }
+        return;
+    }
+    for step in steps {
+        step.argv.splice(0..0, wrappers.iter().cloned());
+    }
+}
+
+/// Suggest `--nixgl` after a command failed, if the environment's graphics libraries may not have
+/// found the host's drivers
+fn suggest_nixgl(dev_env: &NixDevEnv) {
+    let library_path = dev_env
+        .exported_variables()
+        .get("LD_LIBRARY_PATH")
+        .copied()
+        .unwrap_or_default();
+    let loads_drivers = ["-libglvnd-", "-mesa-", "-vulkan-loader-"]
+        .iter()
+        .any(|library| library_path.contains(library));
+    if loads_drivers && crate::platform::needs_nixgl() {
+        eprintln!(
+            "If the command couldn't load OpenGL or Vulkan (eg `cannot open libGL`), try `{}`",
+            "riff run --nixgl".cyan(),
+        );
+    }
+}
+
+/// Run each of `steps` in turn, skipping the rest after one fails unless `keep_going`
+pub(super) async fn run_steps(
+    dev_env: &NixDevEnv,
+    steps: &[Step],
+    keep_going: bool,
+    interactive: bool,
+    host_environment: &HostEnvironment,
+    working_dir: Option<&Path>,
+) -> color_eyre::Result<Vec<StepOutcome>> {
+    let mut outcomes = Vec::with_capacity(steps.len());
+    let mut failed = false;
+    for step in steps {
+        if failed && !keep_going {
+            outcomes.push(StepOutcome::Skipped);
+            continue;
+        }
+        let (code, usage) = run_step(
+            dev_env,
+            &step.argv,
+            interactive,
+            host_environment,
+            working_dir,
         )
         .await?;
+        let code = code.unwrap_or(0);
+        failed |= code != 0;
+        outcomes.push(StepOutcome::Finished { code, usage });
+    }
+    Ok(outcomes)
+}
 
-        let dev_env = crate::nix_dev_env::get_nix_dev_env(flake_dir.path()).await?;
+/// The exit code of the first step which failed, or 0 if none did
+pub(super) fn first_failure(outcomes: &[StepOutcome]) -> i32 {
+    outcomes
+        .iter()
+        .find_map(|outcome| match outcome {
+            StepOutcome::Finished { code, .. } if *code != 0 => Some(*code),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
 
-        let command_name = &self.command[0];
+/// Run `argv` in the environment, in `working_dir` (or the current directory), with stdin closed
+/// unless `interactive`
+pub(super) async fn run_step(
+    dev_env: &NixDevEnv,
+    argv: &[String],
+    interactive: bool,
+    host_environment: &HostEnvironment,
+    working_dir: Option<&Path>,
+) -> color_eyre::Result<(Option<i32>, ResourceUsage)> {
+    let command_name = &argv[0];
 
-        let mut command = crate::nix_dev_env::run_in_dev_env(&dev_env, command_name).await?;
+    let mut command =
+        crate::nix_dev_env::run_in_dev_env(dev_env, command_name, host_environment).await?;
 
-        command.args(&self.command[1..]);
+    command.args(&argv[1..]);
+    if let Some(working_dir) = working_dir {
+        command.current_dir(working_dir);
+    }
+    if !interactive {
+        command.stdin(Stdio::null());
+    }
+    if !crate::execution::perform(Effect::run(command.as_std())) {
+        return Ok((Some(0), ResourceUsage::default()));
+    }
 
-        Ok(command
-            .spawn()
-            .map_err(|err| {
-                if err.kind() == std::io::ErrorKind::NotFound {
-                    eprintln!(
-                        "The command you attempted to run was not found.
+    let result = crate::nix_dev_env::spawn_and_wait_with_usage(&mut command).await;
+    // Not found by riff, or by whatever ran it (eg a nixGL wrapper), which exits with 127
+    let not_found = match &result {
+        Ok((status, _)) => crate::nix_dev_env::exit_code(*status) == Some(127),
+        Err(err) => err.kind() == std::io::ErrorKind::NotFound,
+    };
+    if not_found {
+        eprintln!(
+            "The command you attempted to run was not found.
 Try running it in a shell; for example:
 \t{riff_run_example}\n",
-                        riff_run_example =
-                            format!("riff run -- sh -c '{}'", self.command.join(" ")).cyan(),
-                    );
-                };
-                err
-            })
-            .wrap_err(format!("Cannot run the command `{command_name}`"))?
-            .wait_with_output()
-            .await?
-            .status
-            .code())
+            riff_run_example = format!("riff run -- sh -c '{}'", argv.join(" ")).cyan(),
+        );
+    }
+    let (status, usage) = result.wrap_err(format!("Cannot run the command `{command_name}`"))?;
+
+    Ok((crate::nix_dev_env::exit_code(status), usage))
+}
+
+/// Replace riff with `argv` run in the environment, in `working_dir` (or the current directory),
+/// returning only if that fails
+#[cfg(unix)]
+fn exec_step(
+    dev_env: &NixDevEnv,
+    argv: &[String],
+    host_environment: &HostEnvironment,
+    working_dir: Option<&Path>,
+) -> color_eyre::Result<Option<i32>> {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = crate::nix_dev_env::dev_env_command(dev_env, &argv[0], host_environment);
+    command.args(&argv[1..]);
+    if let Some(working_dir) = working_dir {
+        command.current_dir(working_dir);
+    }
+    if !crate::execution::perform(Effect::run(&command)) {
+        return Ok(Some(0));
+    }
+    tracing::trace!(?command, "Replacing riff with the command");
+    let err = command.exec();
+    Err(err).wrap_err(format!("Cannot run the command `{}`", argv[0]))
+}
+
+#[cfg(not(unix))]
+fn exec_step(
+    _dev_env: &NixDevEnv,
+    _argv: &[String],
+    _host_environment: &HostEnvironment,
+    _working_dir: Option<&Path>,
+) -> color_eyre::Result<Option<i32>> {
+    Err(eyre!("`--exec` is only supported on Unix"))
+}
+
+/// One command in a `--and` pipeline
+pub(super) struct Step {
+    /// How the command is shown in the summary
+    pub(super) label: String,
+    pub(super) argv: Vec<String>,
+}
+
+impl Step {
+    /// Run `argv` directly, unless its first word names one of the project's `commands`, in which
+    /// case that command runs with the rest of `argv` appended
+    pub(super) fn command(argv: &[String], commands: &BTreeMap<String, String>) -> Self {
+        let label = argv
+            .iter()
+            .map(String::as_str)
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let argv = match commands.get(&argv[0]) {
+            Some(script) => {
+                tracing::debug!(name = %argv[0], %script, "Running a command from `riff.toml`");
+                // `sh -c SCRIPT NAME ARGS...` sets `$0` to `NAME` and `$@` to `ARGS`
+                ["sh", "-c", &format!("{script} \"$@\""), &argv[0]]
+                    .into_iter()
+                    .map(ToString::to_string)
+                    .chain(argv[1..].iter().cloned())
+                    .collect()
+            }
+            None => argv.to_vec(),
+        };
+        Self { label, argv }
+    }
+
+    /// Run `script` with `sh -c`, unless it is just the name of one of the project's `commands`
+    pub(super) fn shell(script: &str, commands: &BTreeMap<String, String>) -> Self {
+        let command = commands.get(script.trim()).map(String::as_str);
+        Self {
+            label: script.to_string(),
+            argv: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                command.unwrap_or(script).to_string(),
+            ],
+        }
     }
 }
 
+pub(super) enum StepOutcome {
+    Finished {
+        code: i32,
+        usage: ResourceUsage,
+    },
+    /// Not run, because an earlier step failed
+    Skipped,
+}
+
+/// One line per step, with its full resource usage if `stats`, or just how long it took
+pub(super) fn summary_table(steps: &[Step], outcomes: &[StepOutcome], stats: bool) -> String {
+    let width = steps
+        .iter()
+        .map(|step| step.label.chars().count())
+        .max()
+        .unwrap_or(0);
+    steps
+        .iter()
+        .zip(outcomes)
+        .map(|(step, outcome)| {
+            let label = format!("{:width$}", step.label);
+            let stats = |usage: &ResourceUsage| {
+                if stats {
+                    format!(" ({usage})")
+                } else {
+                    String::new()
+                }
+            };
+            match outcome {
+                StepOutcome::Finished { code: 0, usage } => format!(
+                    "{} {label}  passed in {:.1}s{}",
+                    "✓".green(),
+                    usage.wall_time.as_secs_f64(),
+                    stats(usage),
+                ),
+                StepOutcome::Finished { code, usage } => format!(
+                    "{} {label}  failed with exit code {code} after {:.1}s{}",
+                    "✗".red(),
+                    usage.wall_time.as_secs_f64(),
+                    stats(usage),
+                ),
+                StepOutcome::Skipped => format!("{} {label}  skipped", "-".dimmed()),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use tempfile::TempDir;
 
-    use super::Run;
+    use std::collections::BTreeMap;
+
+    use super::{summary_table, ResourceUsage, Run, Step, StepOutcome};
+    use crate::offline::OfflineMode;
+
+    #[test]
+    fn summary_table_aligns_steps() {
+        let steps = ["cargo fmt --check", "cargo clippy", "cargo test"]
+            .into_iter()
+            .map(|label| Step {
+                label: label.to_string(),
+                argv: vec![],
+            })
+            .collect::<Vec<_>>();
+        let outcomes = [
+            StepOutcome::Finished {
+                code: 0,
+                usage: ResourceUsage {
+                    wall_time: Duration::from_millis(1200),
+                    ..Default::default()
+                },
+            },
+            StepOutcome::Finished {
+                code: 101,
+                usage: ResourceUsage {
+                    wall_time: Duration::from_secs(12),
+                    cpu_time: Some(Duration::from_secs(30)),
+                    max_rss_bytes: Some(512 * 1024 * 1024),
+                },
+            },
+            StepOutcome::Skipped,
+        ];
+
+        let table = summary_table(&steps, &outcomes, false);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("cargo fmt --check  passed in 1.2s"));
+        assert!(lines[1].ends_with("cargo clippy       failed with exit code 101 after 12.0s"));
+        assert!(lines[2].ends_with("cargo test         skipped"));
+
+        let table = summary_table(&steps, &outcomes, true);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with("passed in 1.2s (1.2s wall)"));
+        assert!(lines[1].ends_with(
+            "failed with exit code 101 after 12.0s (12.0s wall, 30.0s CPU, 512.0 MiB max RSS)"
+        ));
+    }
+
+    #[test]
+    fn project_commands_are_resolved() {
+        let commands = BTreeMap::from([("test".to_string(), "cargo nextest run".to_string())]);
+        let argv = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        let step = Step::command(&argv(&["test", "--workspace"]), &commands);
+        assert_eq!(step.label, "test --workspace");
+        assert_eq!(
+            step.argv,
+            argv(&[
+                "sh",
+                "-c",
+                "cargo nextest run \"$@\"",
+                "test",
+                "--workspace"
+            ])
+        );
+
+        let step = Step::command(&argv(&["cargo", "test"]), &commands);
+        assert_eq!(step.argv, argv(&["cargo", "test"]));
+
+        let step = Step::shell("test", &commands);
+        assert_eq!(step.label, "test");
+        assert_eq!(step.argv, argv(&["sh", "-c", "cargo nextest run"]));
+        let step = Step::shell("test -f Cargo.toml", &commands);
+        assert_eq!(step.argv, argv(&["sh", "-c", "test -f Cargo.toml"]));
+    }
+
+    #[tokio::test]
+    async fn missing_chdir_fails_before_generating() -> eyre::Result<()> {
+        // Generating the flake of a directory without a project would fail differently
+        let temp_dir = TempDir::new()?;
+        let run = Run {
+            project_dir: Some(temp_dir.path().to_owned()),
+            on: None,
+            and: vec![],
+            keep_going: false,
+            stats: false,
+            chdir: Some(temp_dir.path().join("missing")),
+            nixgl: false,
+            verify: false,
+            exec: false,
+            host_environment: Default::default(),
+            additions: Default::default(),
+            command: vec!["true".to_string()],
+            offline: OfflineMode::Cached,
+            no_walk_up: false,
+            install_nix: false,
+            allow_empty: false,
+            strict: false,
+            deny_warnings: false,
+            profile: None,
+            overlay: vec![],
+            package: None,
+            manifest_path: None,
+            target: None,
+            refresh: false,
+            disable_telemetry: true,
+        };
+
+        let err = run
+            .run(&mut None)
+            .await
+            .expect_err("`--chdir` names a missing directory");
+        assert!(err.to_string().contains("isn't a directory"));
+        Ok(())
+    }
 
     // We can't run this test by default because it calls Nix. Calling Nix inside Nix doesn't appear
     // to work very well (at least, for this use case).
@@ -105,11 +690,32 @@ path = "lib.rs"
 
         let run = Run {
             project_dir: Some(temp_dir.path().to_owned()),
+            on: None,
+            and: vec![],
+            keep_going: false,
+            stats: false,
+            chdir: None,
+            nixgl: false,
+            verify: false,
+            exec: false,
+            host_environment: Default::default(),
+            additions: Default::default(),
             command: ["sh", "-c", "exit 6"]
                 .into_iter()
                 .map(String::from)
                 .collect(),
-            offline: true,
+            offline: OfflineMode::Cached,
+            no_walk_up: false,
+            install_nix: false,
+            allow_empty: false,
+            strict: false,
+            deny_warnings: false,
+            profile: None,
+            overlay: vec![],
+            package: None,
+            manifest_path: None,
+            target: None,
+            refresh: false,
             disable_telemetry: true,
         };
 
@@ -0,0 +1,230 @@
+//! The `self-test` subcommand.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Output;
+use std::sync::Arc;
+
+use clap::Args;
+use itertools::Itertools;
+use owo_colors::OwoColorize;
+use tempfile::TempDir;
+use tokio::process::Command;
+
+use crate::command_runner::{self, BoxFuture, CommandRunner, SystemRunner};
+use crate::flake_generator::{self, GenerateOptions};
+use crate::install_nix;
+use crate::offline::OfflineMode;
+
+/// An example project, with what `cargo metadata` says about it
+struct Example {
+    name: &'static str,
+    manifest: &'static str,
+    cargo_metadata: &'static str,
+    /// Inputs its environment should have
+    build_inputs: &'static [&'static str],
+}
+
+const EXAMPLES: &[Example] = &[
+    Example {
+        name: "rust-openssl",
+        manifest: "[package]\nname = \"fetcher\"\nversion = \"0.1.0\"\n\n[dependencies]\nopenssl-sys = \"0.9\"\n",
+        cargo_metadata: include_str!("self_test/rust-openssl.json"),
+        build_inputs: &["cargo", "rustc", "openssl"],
+    },
+    Example {
+        name: "rust-workspace",
+        manifest: "[workspace]\nmembers = [\"cli\", \"store\"]\n",
+        cargo_metadata: include_str!("self_test/rust-workspace.json"),
+        build_inputs: &["cargo", "libgit2", "sqlite", "hello"],
+    },
+];
+
+/// The XDG base directory variables riff finds its configuration, caches, and state by
+const XDG_VARIABLES: &[&str] = &[
+    "XDG_CONFIG_HOME",
+    "XDG_CONFIG_DIRS",
+    "XDG_CACHE_HOME",
+    "XDG_STATE_HOME",
+    "XDG_DATA_HOME",
+    "XDG_DATA_DIRS",
+];
+
+/// Check that this build of riff generates the environments it should
+///
+/// Generates the environment of each of a few built-in example projects, and checks it has the
+/// inputs it should. The examples' `cargo metadata` output is built in, so this doesn't need
+/// `cargo`. If `nix` is installed, each environment is also built and checked, unless `--no-nix`
+/// is given. For packagers trying a build of riff on a new platform.
+///
+/// Your own registry additions, layers, trust decisions, and riff's caches are left out: riff
+/// runs with empty XDG directories, though `nix` still uses its usual configuration and caches.
+#[derive(Debug, Args)]
+pub struct SelfTest {
+    /// Don't build the environments, even if `nix` is installed
+    #[clap(long)]
+    no_nix: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+}
+
+impl SelfTest {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let xdg_root = TempDir::new()?;
+        let host_xdg = isolate_xdg(xdg_root.path());
+        let build = !self.no_nix && install_nix::find_nix().is_some();
+        if !build && !self.no_nix {
+            eprintln!("`nix` isn't installed, so the environments won't be built");
+        }
+
+        let mut passed = true;
+        for example in EXAMPLES {
+            passed &= match self.missing_inputs(example, build, &host_xdg).await {
+                Ok(missing) if missing.is_empty() => {
+                    eprintln!("{} {}", "✓".green(), example.name.cyan());
+                    true
+                }
+                Ok(missing) => {
+                    eprintln!(
+                        "{} {}: missing {}",
+                        "✗".red(),
+                        example.name.cyan(),
+                        missing.iter().map(|input| format!("`{input}`")).join(", ")
+                    );
+                    false
+                }
+                Err(err) => {
+                    eprintln!("{} {}: {err:#}", "✗".red(), example.name.cyan());
+                    false
+                }
+            };
+        }
+        Ok(Some(if passed { 0 } else { 1 }))
+    }
+
+    /// The inputs `example`'s environment should have but doesn't, once generated and, if
+    /// `build` is set, once built. The commands riff runs get `host_xdg`, see [`isolate_xdg`].
+    async fn missing_inputs(
+        &self,
+        example: &Example,
+        build: bool,
+        host_xdg: &[(&'static str, Option<OsString>)],
+    ) -> color_eyre::Result<Vec<&'static str>> {
+        let project_dir = TempDir::new()?;
+        tokio::fs::write(project_dir.path().join("Cargo.toml"), example.manifest).await?;
+        let runner = Arc::new(ExampleRunner {
+            cargo_metadata: example.cargo_metadata,
+            system: SystemRunner {
+                offline: self.offline.is_offline(),
+            },
+            host_xdg: host_xdg.to_vec(),
+        });
+        let options = GenerateOptions {
+            offline: self.offline,
+            disable_telemetry: true,
+            ..Default::default()
+        };
+
+        let environment = command_runner::scope(
+            runner.clone(),
+            flake_generator::resolve_environment(project_dir.path(), &options, false),
+        )
+        .await?;
+        let mut missing = example
+            .build_inputs
+            .iter()
+            .copied()
+            .filter(|input| !environment.environment.build_inputs.contains(*input))
+            .collect::<Vec<_>>();
+        if !build || !missing.is_empty() {
+            return Ok(missing);
+        }
+
+        let built = command_runner::scope(runner, async {
+            let flake_dir = flake_generator::generate_flake_from_project_dir(
+                Some(project_dir.path().to_owned()),
+                &options,
+            )
+            .await?;
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, true).await
+        })
+        .await?
+        .input_names();
+        missing.extend(
+            example
+                .build_inputs
+                .iter()
+                .copied()
+                .filter(|input| !built.contains(*input)),
+        );
+        Ok(missing)
+    }
+}
+
+/// Point riff's XDG directories at empty ones in `xdg_root`, returning the values they had
+fn isolate_xdg(xdg_root: &Path) -> Vec<(&'static str, Option<OsString>)> {
+    XDG_VARIABLES
+        .iter()
+        .map(|variable| {
+            let host = std::env::var_os(variable);
+            std::env::set_var(variable, xdg_root.join(variable.to_lowercase()));
+            (*variable, host)
+        })
+        .collect()
+}
+
+/// Answers `cargo metadata` for an example project, and runs every other command
+struct ExampleRunner {
+    cargo_metadata: &'static str,
+    system: SystemRunner,
+    /// The XDG variables riff was started with, which the commands it runs (such as `nix`) get
+    host_xdg: Vec<(&'static str, Option<OsString>)>,
+}
+
+impl CommandRunner for ExampleRunner {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut Command,
+        on_stderr_line: &'a mut (dyn FnMut(&str) + Send),
+    ) -> BoxFuture<'a, std::io::Result<Output>> {
+        if command.as_std().get_program() != "cargo" {
+            for (variable, value) in &self.host_xdg {
+                match value {
+                    Some(value) => command.env(variable, value),
+                    None => command.env_remove(variable),
+                };
+            }
+            return self.system.run(command, on_stderr_line);
+        }
+        let output = Output {
+            status: command_runner::exit_status(0),
+            stdout: self.cargo_metadata.as_bytes().to_vec(),
+            stderr: vec![],
+        };
+        Box::pin(async move { Ok(output) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn examples_have_their_inputs() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let self_test = SelfTest {
+            no_nix: true,
+            offline: OfflineMode::Cached,
+        };
+        for example in EXAMPLES {
+            assert_eq!(
+                self_test.missing_inputs(example, false, &[]).await?,
+                Vec::<&str>::new(),
+                "{}",
+                example.name
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! The `setup` subcommand.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use atty::Stream;
+use clap::Args;
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+
+use crate::flake_generator;
+use crate::offline::OfflineMode;
+use crate::project_config::PROJECT_CONFIG_FILE;
+use crate::{install_nix, nix_config, telemetry};
+
+/// Where the `riff.toml` documentation lives, for the file `riff setup` starts
+const PROJECT_CONFIG_DOCS_URL: &str =
+    "https://github.com/DeterminateSystems/riff#project-configuration";
+
+/// The `riff.toml` `riff setup` starts a project with, which changes nothing until edited
+const PROJECT_CONFIG_TEMPLATE: &str = "\
+# Settings for riff, see https://github.com/DeterminateSystems/riff#project-configuration
+
+# The Nixpkgs revision packages are taken from
+# nixpkgs = \"github:NixOS/nixpkgs/nixos-unstable\"
+
+# Commands for `riff run <name>`
+# [commands]
+# check = \"cargo clippy --workspace --all-targets\"
+";
+
+/// Get this machine ready for riff
+///
+/// Installs Nix if it's missing, enables the Nix features riff needs, asks whether to send
+/// telemetry, and starts a `riff.toml` for the project in the current directory, asking before
+/// each step. To accept every default without asking, eg when provisioning a machine:
+///
+///     $ riff setup --yes
+#[derive(Debug, Args)]
+pub struct Setup {
+    /// Accept the default answer to every question instead of asking
+    #[clap(long, short)]
+    yes: bool,
+    /// The project to start a `riff.toml` for (default: the project the current directory is in)
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+impl Setup {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        if !self.yes && (!atty::is(Stream::Stdin) || !atty::is(Stream::Stderr)) {
+            return Err(eyre!(
+                "`riff setup` asks questions, pass `--yes` to run it without a terminal"
+            ));
+        }
+
+        self.set_up_nix().await?;
+        self.set_up_telemetry().await?;
+        self.set_up_project().await?;
+
+        eprintln!(
+            "{check} Riff is set up, try `{riff_shell}` in a project",
+            check = "✓".green(),
+            riff_shell = "riff shell".cyan(),
+        );
+        Ok(None)
+    }
+
+    async fn set_up_nix(&self) -> color_eyre::Result<()> {
+        if install_nix::find_nix().is_none() {
+            if self.offline.is_offline() {
+                return Err(eyre!(
+                    "Nix isn't installed, and installing it needs the network, which `--offline` \
+                    disables"
+                ));
+            }
+            if !self.confirm(
+                "Nix isn't installed. Install it with the Determinate Nix Installer?",
+                true,
+            )? {
+                eprintln!("Skipping the rest of the Nix setup, riff needs Nix to work");
+                return Ok(());
+            }
+            install_nix::install(self.yes).await?;
+        }
+
+        let Some(enabled) = nix_config::experimental_features().await else {
+            eprintln!(
+                "{} Could not read Nix's configuration, check it with `{}`",
+                "⚠".yellow(),
+                "riff doctor".cyan(),
+            );
+            return Ok(());
+        };
+        let missing = nix_config::missing_features(&enabled);
+        if missing.is_empty() {
+            eprintln!(
+                "{check} Nix has {features} enabled",
+                check = "✓".green(),
+                features = nix_config::REQUIRED_FEATURES.join(" and "),
+            );
+            return Ok(());
+        }
+        if self.confirm(
+            &format!(
+                "Riff needs the Nix experimental features {}. Enable them in your `nix.conf`?",
+                missing.join(" and ")
+            ),
+            true,
+        )? {
+            let nix_conf = nix_config::enable_features(&missing).await?;
+            eprintln!(
+                "{check} Enabled {features} in `{nix_conf}`",
+                check = "✓".green(),
+                features = missing.join(" and "),
+                nix_conf = nix_conf.display(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn set_up_telemetry(&self) -> color_eyre::Result<()> {
+        let enabled = !(self.disable_telemetry || telemetry::disabled_by_preference());
+        let enabled = self.confirm(
+            "Send anonymous usage telemetry to Determinate Systems? See \
+            https://github.com/DeterminateSystems/riff#telemetry for what is sent.",
+            enabled,
+        )?;
+        telemetry::set_preference(enabled)
+            .await
+            .wrap_err("Could not save your telemetry preference")?;
+        Ok(())
+    }
+
+    async fn set_up_project(&self) -> color_eyre::Result<()> {
+        let manifest_path = self.manifest_path.as_deref();
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir.clone(),
+            manifest_path,
+            !self.no_walk_up,
+        )?;
+        let config_path = project_dir.join(PROJECT_CONFIG_FILE);
+        let cargo_dir = flake_generator::cargo_dir(&project_dir, manifest_path);
+        if !cargo_dir.join("Cargo.toml").exists() || config_path.exists() {
+            return Ok(());
+        }
+        if self.confirm(
+            &format!(
+                "Start a `{PROJECT_CONFIG_FILE}` for the project in `{}`?",
+                project_dir.display()
+            ),
+            true,
+        )? {
+            tokio::fs::write(&config_path, PROJECT_CONFIG_TEMPLATE)
+                .await
+                .wrap_err_with(|| format!("Could not write `{}`", config_path.display()))?;
+            eprintln!(
+                "{check} Wrote `{path}`, see {url} for what it can configure",
+                check = "✓".green(),
+                path = config_path.display(),
+                url = PROJECT_CONFIG_DOCS_URL.blue().underline(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Ask `question`, with `default` as the answer to an empty line or to `--yes`
+    fn confirm(&self, question: &str, default: bool) -> color_eyre::Result<bool> {
+        if self.yes {
+            eprintln!("{question} {}", if default { "yes" } else { "no" });
+            return Ok(default);
+        }
+        crate::spinner::suspend(|| -> color_eyre::Result<bool> {
+            loop {
+                eprint!("{question} [{}] ", if default { "Y/n" } else { "y/N" });
+                std::io::stderr().flush()?;
+                let mut answer = String::new();
+                if std::io::stdin().read_line(&mut answer)? == 0 {
+                    return Ok(default);
+                }
+                match answer.trim().to_lowercase().as_str() {
+                    "" => return Ok(default),
+                    "y" | "yes" => return Ok(true),
+                    "n" | "no" => return Ok(false),
+                    _ => eprintln!("Please answer yes or no."),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project_config::ProjectConfig;
+
+    #[test]
+    fn project_config_template_is_valid() -> eyre::Result<()> {
+        toml::from_str::<ProjectConfig>(PROJECT_CONFIG_TEMPLATE)?;
+        Ok(())
+    }
+}
@@ -3,42 +3,129 @@ use std::path::PathBuf;
 
 use clap::Args;
 use eyre::WrapErr;
+use target_lexicon::Triple;
 
-use crate::flake_generator;
+use crate::dev_env::{DetectedLanguage, EnvironmentAdditions};
+use crate::execution::Effect;
+use crate::flake_generator::{self, GenerateOptions};
+use crate::nix_dev_env::HostEnvironment;
+use crate::offline::OfflineMode;
 
 /// Start a development shell
+///
+/// Commands following `--` are run non-interactively by the shell instead, for example:
+///
+///     $ riff shell -- cargo test
+///
+/// Check the project builds without anything from your own environment:
+///
+///     $ riff shell --pure -- cargo build
 #[derive(Debug, Args, Clone)]
 pub struct Shell {
-    /// The root directory of the project
+    /// The root directory of the project, or the path to its `Cargo.toml`
     #[clap(long, value_parser)]
     project_dir: Option<PathBuf>,
+    /// The shell to start, instead of `$SHELL` or your login shell
+    #[clap(long, env = "RIFF_SHELL")]
+    shell: Option<String>,
+    /// Check the environment works, with quick probes of the tools it provides (such as `cc
+    /// --version` or `pkg-config --exists openssl`), before running anything in it
+    #[clap(long)]
+    verify: bool,
+    /// In a project in several languages, start the shell of just one of them, without what only
+    /// the others need
+    #[clap(long, value_enum, value_name = "LANGUAGE")]
+    only: Option<DetectedLanguage>,
+    #[clap(flatten)]
+    host_environment: HostEnvironment,
+    #[clap(flatten)]
+    additions: EnvironmentAdditions,
+    /// A command for the shell to run, instead of starting interactively
+    #[clap(last = true)]
+    command: Vec<String>,
     #[clap(from_global)]
     disable_telemetry: bool,
     #[clap(from_global)]
-    offline: bool,
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    deny_warnings: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
 }
 
 impl Shell {
     pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
         let flake_dir = flake_generator::generate_flake_from_project_dir(
             self.project_dir,
-            self.offline,
-            self.disable_telemetry,
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                deny_warnings: self.deny_warnings,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                additions: self.additions.clone(),
+                only: self.only,
+                ..Default::default()
+            },
         )
         .await?;
 
-        let dev_env = crate::nix_dev_env::get_nix_dev_env(flake_dir.path()).await?;
+        let dev_env =
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh)
+                .await?;
+
+        if self.verify {
+            crate::verify::verify(&dev_env, &self.host_environment).await?;
+        }
+
+        let shell = crate::nix_dev_env::get_shell(self.shell).await?;
 
-        let shell = crate::nix_dev_env::get_shell().await?;
+        let mut command =
+            crate::nix_dev_env::run_in_dev_env(&dev_env, &shell, &self.host_environment).await?;
+        if !self.command.is_empty() {
+            let script = self
+                .command
+                .iter()
+                .map(String::as_str)
+                .map(crate::nix_dev_env::shell_quote)
+                .collect::<Vec<_>>()
+                .join(" ");
+            command.arg("-c").arg(script);
+        }
+        if !crate::execution::perform(Effect::run(command.as_std())) {
+            return Ok(None);
+        }
+        let status = crate::nix_dev_env::spawn_and_wait(&mut command)
+            .await
+            .wrap_err(format!("Cannot run the shell `{shell}`"))?;
 
-        Ok(crate::nix_dev_env::run_in_dev_env(&dev_env, &shell)
-            .await?
-            .spawn()
-            .wrap_err(format!("Cannot run the shell `{shell}`"))?
-            .wait_with_output()
-            .await?
-            .status
-            .code())
+        Ok(crate::nix_dev_env::exit_code(status))
     }
 }
 
@@ -82,7 +169,24 @@ shellHook = "exit 6"
 
         let shell = Shell {
             project_dir: Some(temp_dir.path().to_owned()),
-            offline: true,
+            shell: None,
+            verify: false,
+            only: None,
+            host_environment: Default::default(),
+            additions: Default::default(),
+            command: vec![],
+            offline: OfflineMode::Cached,
+            no_walk_up: false,
+            install_nix: false,
+            allow_empty: false,
+            strict: false,
+            deny_warnings: false,
+            profile: None,
+            overlay: vec![],
+            package: None,
+            manifest_path: None,
+            target: None,
+            refresh: false,
             disable_telemetry: true,
         };
 
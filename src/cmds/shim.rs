@@ -0,0 +1,233 @@
+//! The `shim` subcommand.
+
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Subcommand};
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+
+use crate::flake_generator;
+
+/// Where shims are installed, in the project directory
+const SHIM_DIR: &str = ".riff/bin";
+
+/// The line which marks a file in [`SHIM_DIR`] as a shim riff may replace or remove
+const SHIM_MARKER: &str = "# Installed by `riff shim install`";
+
+/// Wrapper scripts which run tools in your project's environment
+///
+/// A shim for `cargo` in `.riff/bin` runs `riff run --exec -- cargo`, so IDEs and scripts which
+/// run `cargo` with `.riff/bin` at the front of their `PATH` get the environment without riff:
+///
+///     $ riff shim install cargo make npm
+///     $ PATH="$PWD/.riff/bin:$PATH" make
+#[derive(Debug, Args)]
+pub struct Shim {
+    #[clap(subcommand)]
+    command: ShimCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum ShimCommand {
+    Install(Install),
+    Remove(Remove),
+}
+
+impl Shim {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        match self.command {
+            ShimCommand::Install(install) => install.cmd().await,
+            ShimCommand::Remove(remove) => remove.cmd().await,
+        }
+    }
+}
+
+/// Install shims for tools into the project's `.riff/bin`
+#[derive(Debug, Args)]
+pub struct Install {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// The tools to install shims for, such as `cargo`
+    #[clap(required = true, value_parser = parse_tool)]
+    tools: Vec<String>,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+impl Install {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        // Before writing anything, which would leave shims that can't run
+        check_platform()?;
+        let project_dir =
+            flake_generator::resolve_project_dir(self.project_dir, None, !self.no_walk_up)?;
+        let shim_dir = project_dir.join(SHIM_DIR);
+        if crate::execution::perform(crate::execution::Effect::Write(shim_dir.clone())) {
+            tokio::fs::create_dir_all(&shim_dir)
+                .await
+                .wrap_err_with(|| format!("Unable to create `{}`", shim_dir.display()))?;
+        }
+        // Shims hold nothing worth committing
+        crate::execution::write(&project_dir.join(".riff/.gitignore"), "*\n").await?;
+
+        for tool in &self.tools {
+            let path = shim_dir.join(tool);
+            ensure_shim(&path).await?;
+            crate::execution::write(&path, shim_script(tool)).await?;
+            make_executable(&path).await?;
+            eprintln!(
+                "{check} Installed `{}`",
+                path.display(),
+                check = "✓".green()
+            );
+        }
+        eprintln!(
+            "Put `{}` at the front of the `PATH` of your IDE or scripts to use them",
+            shim_dir.display().to_string().cyan()
+        );
+        Ok(None)
+    }
+}
+
+/// Remove shims from the project's `.riff/bin`
+#[derive(Debug, Args)]
+pub struct Remove {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// The tools to remove the shims of
+    #[clap(required = true, value_parser = parse_tool)]
+    tools: Vec<String>,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+impl Remove {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let project_dir =
+            flake_generator::resolve_project_dir(self.project_dir, None, !self.no_walk_up)?;
+        for tool in &self.tools {
+            let path = project_dir.join(SHIM_DIR).join(tool);
+            if !path.exists() {
+                eprintln!("There is no shim for `{tool}`");
+                continue;
+            }
+            ensure_shim(&path).await?;
+            crate::execution::remove_file(&path).await?;
+            eprintln!("{check} Removed `{}`", path.display(), check = "✓".green());
+        }
+        Ok(None)
+    }
+}
+
+/// Parse the name of a tool to shim, which becomes a file name
+fn parse_tool(tool: &str) -> Result<String, String> {
+    if tool.is_empty() || tool == "." || tool == ".." || tool.contains(['/', '\\']) {
+        return Err(format!("`{tool}` isn't the name of a program"));
+    }
+    Ok(tool.to_string())
+}
+
+/// Fail unless `path` is missing or a shim, so riff never replaces the user's own scripts
+async fn ensure_shim(path: &Path) -> color_eyre::Result<()> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) if contents.lines().any(|line| line == SHIM_MARKER) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        _ => Err(eyre!(
+            "`{}` wasn't installed by `riff shim install`, leaving it alone",
+            path.display()
+        )),
+    }
+}
+
+/// Shims are `/bin/sh` scripts made executable, which only Unix runs
+fn check_platform() -> color_eyre::Result<()> {
+    if cfg!(unix) {
+        Ok(())
+    } else {
+        Err(eyre!("Shims are only supported on Unix"))
+    }
+}
+
+#[cfg(unix)]
+async fn make_executable(path: &Path) -> color_eyre::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if crate::execution::dry_run() {
+        return Ok(());
+    }
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .await
+        .wrap_err_with(|| format!("Unable to make `{}` executable", path.display()))
+}
+
+#[cfg(not(unix))]
+async fn make_executable(_path: &Path) -> color_eyre::Result<()> {
+    check_platform()
+}
+
+/// A script running `tool` in the environment of the project two directories above it.
+///
+/// The shim's own directory is taken out of `PATH` first, so a tool the environment doesn't
+/// provide is found on the rest of `PATH`, not as the shim again. Inside a riff environment
+/// (`IN_RIFF`), the tool runs directly.
+fn shim_script(tool: &str) -> String {
+    let tool = crate::nix_dev_env::shell_quote(tool);
+    format!(
+        r#"#!/bin/sh
+{SHIM_MARKER}
+shim_dir=$(CDPATH= cd -- "$(dirname -- "$0")" && pwd)
+path=
+old_ifs=$IFS
+IFS=:
+set -f
+for dir in $PATH; do
+  [ "$dir" = "$shim_dir" ] || path=${{path:+$path:}}$dir
+done
+set +f
+IFS=$old_ifs
+PATH=$path
+export PATH
+if [ -n "$IN_RIFF" ]; then
+  exec {tool} "$@"
+fi
+exec "${{RIFF:-riff}}" run --exec --project-dir "$shim_dir/../.." -- {tool} "$@"
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_names() {
+        assert_eq!(parse_tool("cargo"), Ok("cargo".to_string()));
+        assert!(parse_tool("../cargo").is_err());
+        assert!(parse_tool("..").is_err());
+        assert!(parse_tool("").is_err());
+    }
+
+    #[tokio::test]
+    async fn only_shims_are_replaced() -> eyre::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let shim = dir.path().join("cargo");
+        ensure_shim(&shim).await?;
+
+        tokio::fs::write(&shim, shim_script("cargo")).await?;
+        ensure_shim(&shim).await?;
+
+        let own = dir.path().join("make");
+        tokio::fs::write(&own, "#!/bin/sh\nexec /usr/bin/make \"$@\"\n").await?;
+        assert!(ensure_shim(&own).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shims_run_the_tool_in_the_environment() {
+        let script = shim_script("npm");
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains(r#"run --exec --project-dir "$shim_dir/../.." -- npm "$@""#));
+        assert!(script.contains("path=${path:+$path:}$dir"));
+    }
+}
@@ -0,0 +1,140 @@
+//! The `trust` and `untrust` subcommands.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use owo_colors::OwoColorize;
+
+use crate::{flake_generator, trust};
+
+/// Apply the environment variables and shell hooks your project's configuration sets
+///
+/// A project's `workspace.metadata.riff` and its members' `package.metadata.riff` can set
+/// environment variables and shell hooks, which run as you in `riff shell`. The first time riff
+/// sees a project whose configuration does, or once that changes, it asks whether to trust the
+/// project; this answers yes ahead of time (for what the next riff command finds it sets), or
+/// changes an earlier no. It also lets the shell hook activate the project's environment.
+#[derive(Debug, Args)]
+pub struct Trust {
+    /// The root directory of the project, or the path to its `Cargo.toml` (default: the project
+    /// the current directory is in)
+    project_dir: Option<PathBuf>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+impl Trust {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let manifest_path = self.manifest_path.as_deref();
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            manifest_path,
+            !self.no_walk_up,
+        )?;
+        trust::decide(&project_dir, true).await?;
+        eprintln!(
+            "{check} Trusting `{}`",
+            project_dir.display(),
+            check = "✓".green(),
+        );
+        Ok(None)
+    }
+}
+
+/// Leave out the environment variables and shell hooks your project's configuration sets
+///
+/// Its inputs still apply, but the shell hook no longer activates its environment. Run `riff
+/// trust` to apply the rest again.
+#[derive(Debug, Args)]
+pub struct Untrust {
+    /// The root directory of the project, or the path to its `Cargo.toml` (default: the project
+    /// the current directory is in)
+    project_dir: Option<PathBuf>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    no_walk_up: bool,
+}
+
+impl Untrust {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let manifest_path = self.manifest_path.as_deref();
+        let project_dir = flake_generator::resolve_project_dir(
+            self.project_dir,
+            manifest_path,
+            !self.no_walk_up,
+        )?;
+        trust::decide(&project_dir, false).await?;
+        eprintln!(
+            "{check} No longer trusting `{}`",
+            project_dir.display(),
+            check = "✓".green(),
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flake_generator::{resolve_environment, GenerateOptions};
+    use crate::offline::OfflineMode;
+    use crate::project_config::DEFAULT_NIXPKGS;
+    use crate::riff_lock::RIFF_LOCK_FILE;
+    use tempfile::TempDir;
+    use tokio::fs::write;
+
+    #[tokio::test]
+    async fn trusting_with_manifest_path_applies_to_generating() -> eyre::Result<()> {
+        let xdg_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", xdg_dir.path().join("cache"));
+        std::env::set_var("XDG_STATE_HOME", xdg_dir.path().join("state"));
+        let project_dir = TempDir::new()?;
+        let manifest_path = project_dir.path().join("sub").join("Cargo.toml");
+        tokio::fs::create_dir_all(project_dir.path().join("sub")).await?;
+        write(&manifest_path, "[package]\nname = \"sub\"\n").await?;
+        let riff_lock = serde_json::json!({
+            "version": 1,
+            "riff-version": env!("CARGO_PKG_VERSION"),
+            "nixpkgs": DEFAULT_NIXPKGS,
+            "nixpkgs-rev": null,
+            "cargo-lock-sha256": null,
+            "environment": {
+                "environment-variables": { "GREETING": "hello" },
+            },
+            "registry": {},
+            "flake-lock": {},
+        });
+        write(
+            project_dir.path().join(RIFF_LOCK_FILE),
+            riff_lock.to_string(),
+        )
+        .await?;
+
+        Trust {
+            project_dir: Some(project_dir.path().to_owned()),
+            manifest_path: Some(manifest_path.clone()),
+            no_walk_up: false,
+        }
+        .cmd()
+        .await?;
+
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            manifest_path: Some(manifest_path),
+            ..Default::default()
+        };
+        let resolved = resolve_environment(project_dir.path(), &options, true).await?;
+        assert_eq!(
+            resolved
+                .environment
+                .environment_variables
+                .get("GREETING")
+                .map(String::as_str),
+            Some("hello")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! The `which` subcommand.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use clap::Args;
+use owo_colors::OwoColorize;
+use target_lexicon::Triple;
+
+use crate::dependency_registry::rust::RustDependencyRegistryData;
+use crate::dependency_registry::DependencyRegistry;
+use crate::flake_generator::{self, GenerateOptions};
+use crate::offline::OfflineMode;
+
+/// Show which program a command runs in your project's environment
+///
+/// Prints the store path `riff run` would run the program from, following symlinks, and which
+/// program of your own `PATH` that shadows, if any:
+///
+///     $ riff which cc
+///
+/// Exits with 1 if the environment doesn't provide the program, suggesting packages which may.
+#[derive(Debug, Args)]
+pub struct Which {
+    /// The root directory of the project, or the path to its `Cargo.toml`
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// The name of the program, as you would run it
+    program: String,
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    offline: OfflineMode,
+    #[clap(from_global)]
+    no_walk_up: bool,
+    #[clap(from_global)]
+    install_nix: bool,
+    #[clap(from_global)]
+    allow_empty: bool,
+    #[clap(from_global)]
+    strict: bool,
+    #[clap(from_global)]
+    profile: Option<String>,
+    #[clap(from_global)]
+    overlay: Vec<String>,
+    #[clap(from_global)]
+    package: Option<String>,
+    #[clap(from_global)]
+    manifest_path: Option<PathBuf>,
+    #[clap(from_global)]
+    target: Option<Triple>,
+    #[clap(from_global)]
+    refresh: bool,
+}
+
+impl Which {
+    pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
+        let flake_dir = flake_generator::generate_flake_from_project_dir(
+            self.project_dir.clone(),
+            &GenerateOptions {
+                offline: self.offline,
+                no_walk_up: self.no_walk_up,
+                refresh: self.refresh,
+                install_nix: self.install_nix,
+                allow_empty: self.allow_empty,
+                strict: self.strict,
+                profile: self.profile.clone(),
+                overlays: self.overlay.clone(),
+                package: self.package.clone(),
+                manifest_path: self.manifest_path.clone(),
+                target: self.target.clone(),
+                disable_telemetry: self.disable_telemetry,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let dev_env =
+            crate::nix_dev_env::get_nix_dev_env(flake_dir.path(), self.offline, self.refresh)
+                .await?;
+
+        let host_path = std::env::var_os("PATH").unwrap_or_default();
+        let on_host = find_in_path(&host_path, &self.program);
+
+        if let Some(found) = dev_env.find_program(&self.program) {
+            let resolved = found.canonicalize().unwrap_or_else(|_| found.clone());
+            println!("{}", resolved.display());
+            if resolved != found {
+                eprintln!("Found as `{}` in the environment", found.display());
+            }
+            // Inside a riff environment, its `PATH` is already part of this one
+            if let Some(on_host) = on_host.filter(|on_host| *on_host != found) {
+                eprintln!(
+                    "This shadows `{}` from your own `PATH`",
+                    on_host.display().to_string().cyan()
+                );
+            }
+            return Ok(None);
+        }
+
+        eprintln!(
+            "{} `{}` is not provided by the environment",
+            "✗".red(),
+            self.program.cyan()
+        );
+        if let Some(on_host) = on_host {
+            eprintln!(
+                "`riff run` would run `{}` from your own `PATH` instead",
+                on_host.display()
+            );
+        }
+
+        let registry = DependencyRegistry::new(self.offline.is_offline(), false).await?;
+        let suggestions = suggestions(&registry.language().await.rust, &self.program);
+        for (input, crates) in &suggestions {
+            eprintln!(
+                "The registry adds `{}`, which may provide it, for {}",
+                input.cyan(),
+                crates
+                    .iter()
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        let input = suggestions
+            .keys()
+            .next()
+            .map_or(self.program.as_str(), String::as_str);
+        eprintln!(
+            "Try adding the package which provides it, for example: {}",
+            format!("riff run --input {input} -- {}", self.program).cyan()
+        );
+        Ok(Some(1))
+    }
+}
+
+/// The first executable `program` in the directories of `path`, a `PATH`-like list, as a shell
+/// would find it
+fn find_in_path(path: &OsStr, program: &str) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| crate::detectors::is_executable(candidate))
+}
+
+/// The registry's inputs which may provide `program`, since their package is named after it, with
+/// the crates (or `default`) adding each
+fn suggestions(
+    registry: &RustDependencyRegistryData,
+    program: &str,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let provides = |input: &String| {
+        input
+            .rsplit('.')
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case(program))
+    };
+    let mut suggestions = BTreeMap::<String, BTreeSet<String>>::new();
+    let entries = std::iter::once(("default", &registry.default)).chain(
+        registry.dependencies.iter().flat_map(|(key, data)| {
+            std::iter::once(&data.default)
+                .chain(data.targets.values())
+                .map(move |target_config| (key.as_str(), target_config))
+        }),
+    );
+    for (key, target_config) in entries {
+        for input in target_config
+            .build_inputs
+            .iter()
+            .chain(&target_config.runtime_inputs)
+            .filter(|input| provides(input))
+        {
+            suggestions
+                .entry(input.clone())
+                .or_default()
+                .insert(key.to_string());
+        }
+    }
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_inputs_named_after_the_program() -> eyre::Result<()> {
+        let registry: RustDependencyRegistryData = serde_json::from_value(serde_json::json!({
+            "default": { "build-inputs": ["cargo"] },
+            "dependencies": {
+                "prost-build": { "build-inputs": ["protobuf"] },
+                "cmake": { "build-inputs": ["cmake"] },
+                "sdl2-sys": { "build-inputs": ["SDL2", "cmake"] },
+                "clang-sys": {
+                    "targets": {
+                        "x86_64-unknown-linux-gnu": {
+                            "build-inputs": ["llvmPackages.clang"]
+                        }
+                    }
+                }
+            }
+        }))?;
+
+        assert_eq!(
+            suggestions(&registry, "cmake"),
+            BTreeMap::from([(
+                "cmake".to_string(),
+                BTreeSet::from(["cmake".to_string(), "sdl2-sys".to_string()])
+            )])
+        );
+        assert_eq!(
+            suggestions(&registry, "clang")
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec!["llvmPackages.clang"]
+        );
+        assert!(suggestions(&registry, "cargo")["cargo"].contains("default"));
+        assert!(suggestions(&registry, "protoc").is_empty());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_the_first_program_in_path() -> eyre::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let first = tempfile::TempDir::new()?;
+        let second = tempfile::TempDir::new()?;
+        let write_program = |path: PathBuf, mode: u32| -> std::io::Result<()> {
+            std::fs::write(&path, "")?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+        };
+        write_program(second.path().join("tool"), 0o755)?;
+        let path = std::env::join_paths([first.path(), second.path()])?;
+
+        assert_eq!(
+            find_in_path(&path, "tool"),
+            Some(second.path().join("tool"))
+        );
+        // A file which isn't executable isn't run, so it doesn't shadow the second
+        write_program(first.path().join("tool"), 0o644)?;
+        assert_eq!(
+            find_in_path(&path, "tool"),
+            Some(second.path().join("tool"))
+        );
+        write_program(first.path().join("tool"), 0o755)?;
+        assert_eq!(find_in_path(&path, "tool"), Some(first.path().join("tool")));
+        assert!(find_in_path(&path, "missing").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Running the `cargo` and `nix` commands riff reads the output of.
+//!
+//! They go through a [`CommandRunner`], so that tests can answer them with recorded output
+//! instead of needing `cargo` and `nix` installed, and check which commands riff ran.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::{ExitStatus, Output};
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Runs a command to completion
+pub trait CommandRunner: Send + Sync {
+    /// Run `command`, calling `on_stderr_line` with each line it writes to a piped stderr as it
+    /// does. Only the stdout and stderr the command pipes are captured in the output.
+    fn run<'a>(
+        &'a self,
+        command: &'a mut Command,
+        on_stderr_line: &'a mut (dyn FnMut(&str) + Send),
+    ) -> BoxFuture<'a, io::Result<Output>>;
+}
+
+/// Runs commands as child processes of riff
+pub struct SystemRunner {
+    /// Whether riff is offline, in which case it doesn't offer to install a missing `nix`
+    pub offline: bool,
+}
+
+impl CommandRunner for SystemRunner {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut Command,
+        on_stderr_line: &'a mut (dyn FnMut(&str) + Send),
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        Box::pin(async move {
+            let mut child = if command.as_std().get_program() == "nix" {
+                crate::install_nix::spawn_nix(command, self.offline).await?
+            } else {
+                command.spawn()?
+            };
+            let stdout = child.stdout.take();
+            let stderr = child.stderr.take();
+
+            let read_stdout = async {
+                let mut output = Vec::new();
+                if let Some(mut stdout) = stdout {
+                    stdout.read_to_end(&mut output).await?;
+                }
+                Ok::<_, io::Error>(output)
+            };
+            let read_stderr = async {
+                let mut output = Vec::new();
+                if let Some(stderr) = stderr {
+                    let mut lines = BufReader::new(stderr).lines();
+                    while let Some(line) = lines.next_line().await? {
+                        on_stderr_line(&line);
+                        output.extend_from_slice(line.as_bytes());
+                        output.push(b'\n');
+                    }
+                }
+                Ok::<_, io::Error>(output)
+            };
+            let (stdout, stderr) = tokio::try_join!(read_stdout, read_stderr)?;
+            let status = child.wait().await?;
+            Ok(Output {
+                status,
+                stdout,
+                stderr,
+            })
+        })
+    }
+}
+
+tokio::task_local! {
+    static RUNNER: Arc<dyn CommandRunner>;
+}
+
+/// Run `command` with the [`CommandRunner`] of the current task, see [`CommandRunner::run`]
+///
+/// That's a [`SystemRunner`], unless the task is running in a [`scope`].
+pub async fn run(
+    command: &mut Command,
+    offline: bool,
+    on_stderr_line: &mut (dyn FnMut(&str) + Send),
+) -> io::Result<Output> {
+    crate::execution::inspect(command.as_std());
+    match RUNNER.try_with(Arc::clone) {
+        Ok(runner) => runner.run(command, on_stderr_line).await,
+        Err(_) => SystemRunner { offline }.run(command, on_stderr_line).await,
+    }
+}
+
+/// Run `future` with `runner` running its commands. Tasks it spawns use a [`SystemRunner`].
+pub async fn scope<F: Future>(runner: Arc<dyn CommandRunner>, future: F) -> F::Output {
+    RUNNER.scope(runner, future).await
+}
+
+/// The status of a process which exited with `code`, for commands which aren't really run
+#[cfg(unix)]
+pub fn exit_status(code: i32) -> ExitStatus {
+    std::os::unix::process::ExitStatusExt::from_raw(code << 8)
+}
+
+/// The status of a process which exited with `code`, for commands which aren't really run
+#[cfg(windows)]
+pub fn exit_status(code: i32) -> ExitStatus {
+    std::os::windows::process::ExitStatusExt::from_raw(code as u32)
+}
+
+#[cfg(test)]
+pub(crate) use recording::RecordingRunner;
+
+#[cfg(test)]
+mod recording {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Answers each command with the output given for it, recording the command lines it was
+    /// asked to run
+    #[derive(Default)]
+    pub(crate) struct RecordingRunner {
+        /// Outputs for the commands whose command line starts with the first of the pair, each
+        /// used once, in order
+        responses: Mutex<VecDeque<(String, Output)>>,
+        commands: Mutex<Vec<String>>,
+    }
+
+    impl RecordingRunner {
+        /// Answer the next command starting with `prefix` (eg `cargo metadata`) by exiting with
+        /// `code` after writing `stdout` and `stderr`
+        pub(crate) fn respond(self, prefix: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+            self.responses.lock().unwrap().push_back((
+                prefix.to_string(),
+                Output {
+                    status: exit_status(code),
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: stderr.as_bytes().to_vec(),
+                },
+            ));
+            self
+        }
+
+        /// The command lines run so far
+        pub(crate) fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for RecordingRunner {
+        fn run<'a>(
+            &'a self,
+            command: &'a mut Command,
+            on_stderr_line: &'a mut (dyn FnMut(&str) + Send),
+        ) -> BoxFuture<'a, io::Result<Output>> {
+            let command_line = crate::execution::command_line(command.as_std());
+            self.commands.lock().unwrap().push(command_line.clone());
+            let output = {
+                let mut responses = self.responses.lock().unwrap();
+                responses
+                    .iter()
+                    .position(|(prefix, _)| command_line.starts_with(prefix.as_str()))
+                    .and_then(|index| responses.remove(index))
+                    .map(|(_, output)| output)
+            };
+            Box::pin(async move {
+                let output = output.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No output recorded for `{command_line}`"),
+                    )
+                })?;
+                for line in String::from_utf8_lossy(&output.stderr).lines() {
+                    on_stderr_line(line);
+                }
+                Ok(output)
+            })
+        }
+    }
+}
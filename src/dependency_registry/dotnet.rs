@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::rust::{RustDependencyData, RustDependencyTargetData};
+
+/// The registry's settings for .NET projects, which have the same shape as the Rust ones
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct DotnetDependencyRegistryData {
+    /// Settings which every .NET project needs (eg `dotnet-sdk`)
+    #[serde(default)]
+    pub(crate) default: RustDependencyTargetData,
+    /// A mapping of dependencies (by NuGet package ID) to configuration
+    #[serde(default)]
+    pub(crate) dependencies: HashMap<String, RustDependencyData>,
+}
+
+impl DotnetDependencyRegistryData {
+    /// Merge a registry overlay over this registry: the overlay's defaults add to these, and its
+    /// entries replace the entries with the same key
+    pub(crate) fn apply_overlay(&mut self, overlay: &DotnetDependencyRegistryData) {
+        self.default = overlay.default.clone().inherit(&self.default);
+        for (key, data) in &overlay.dependencies {
+            self.dependencies.insert(key.clone(), data.clone());
+        }
+    }
+
+    /// The entry for the NuGet package `id`, along with its key. Package IDs are
+    /// case-insensitive, so `skiasharp` finds the entry for `SkiaSharp`.
+    pub(crate) fn dependency(&self, id: &str) -> Option<(&str, &RustDependencyData)> {
+        self.dependencies
+            .get_key_value(id)
+            .or_else(|| {
+                self.dependencies
+                    .iter()
+                    .filter(|(key, _)| key.eq_ignore_ascii_case(id))
+                    .min_by_key(|(key, _)| key.as_str())
+            })
+            .map(|(key, data)| (key.as_str(), data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn package_ids_are_case_insensitive() -> eyre::Result<()> {
+        let registry: DotnetDependencyRegistryData = serde_json::from_value(serde_json::json!({
+            "dependencies": {
+                "SkiaSharp": { "runtime-inputs": ["fontconfig"] },
+                "Microsoft.Data.Sqlite": { "runtime-inputs": ["sqlite"] }
+            }
+        }))?;
+        assert_eq!(
+            registry.dependency("SkiaSharp").map(|(key, _)| key),
+            Some("SkiaSharp")
+        );
+        assert_eq!(
+            registry
+                .dependency("microsoft.data.sqlite")
+                .map(|(key, _)| key),
+            Some("Microsoft.Data.Sqlite")
+        );
+        assert!(registry.dependency("Newtonsoft.Json").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::rust::{RustDependencyData, RustDependencyTargetData};
+
+/// The registry's settings for Haskell projects, which have the same shape as the Rust ones
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct HaskellDependencyRegistryData {
+    /// Settings which every Haskell project needs (eg `ghc`)
+    #[serde(default)]
+    pub(crate) default: RustDependencyTargetData,
+    /// A mapping of dependencies (by Hackage package name) to configuration
+    #[serde(default)]
+    pub(crate) dependencies: HashMap<String, RustDependencyData>,
+}
+
+impl HaskellDependencyRegistryData {
+    /// Merge a registry overlay over this registry: the overlay's defaults add to these, and its
+    /// entries replace the entries with the same key
+    pub(crate) fn apply_overlay(&mut self, overlay: &HaskellDependencyRegistryData) {
+        self.default = overlay.default.clone().inherit(&self.default);
+        for (key, data) in &overlay.dependencies {
+            self.dependencies.insert(key.clone(), data.clone());
+        }
+    }
+}
@@ -1,5 +1,10 @@
-use crate::RIFF_XDG_PREFIX;
-use serde::Deserialize;
+use crate::warnings::{Warning, WarningCode};
+use crate::{file_lock::FileLock, RIFF_XDG_PREFIX};
+use reqwest::{
+    header::{HeaderMap, HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    RequestBuilder, StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use std::{
     path::{Path, PathBuf},
     sync::Arc,
@@ -12,14 +17,37 @@ use tokio::{
 };
 use xdg::{BaseDirectories, BaseDirectoriesError};
 
-use self::rust::RustDependencyRegistryData;
+use self::schema::SUPPORTED_REGISTRY_VERSIONS;
+pub use self::schema::{DependencyRegistryData, DependencyRegistryLanguageData};
 
+pub(crate) mod dotnet;
+pub(crate) mod haskell;
 pub(crate) mod rust;
+pub(crate) mod schema;
 
 const DEPENDENCY_REGISTRY_REMOTE_URL: &str =
     "https://registry.riff.determinate.systems/riff-registry.json";
+/// The minisign signature of the registry served from `DEPENDENCY_REGISTRY_REMOTE_URL`
+const DEPENDENCY_REGISTRY_SIGNATURE_URL: &str =
+    "https://registry.riff.determinate.systems/riff-registry.json.minisig";
+/// The keys the registry may be signed with, a fetched registry replaces the cached one only if
+/// it has a valid signature from one of them
+///
+/// `registry/riff-registry.pub` holds the public halves of the minisign keys the registry is
+/// published with, see `releasing.md` for who signs it and how the key is rotated. The registry
+/// isn't served with a signature yet, and until it is, riff keeps using its cached or bundled
+/// registry.
+const DEPENDENCY_REGISTRY_PUBLIC_KEY: &str = include_str!("../../registry/riff-registry.pub");
 const DEPENDENCY_REGISTRY_CACHE_PATH: &str = "registry.json";
+/// The `ETag` and `Last-Modified` the cached registry was served with
+const DEPENDENCY_REGISTRY_VALIDATORS_PATH: &str = "registry.json.validators";
+/// Held while reading or replacing the cached registry and its validators, so concurrent riff
+/// processes see them change together
+const DEPENDENCY_REGISTRY_LOCK_PATH: &str = "registry.json.lock";
 const DEPENDENCY_REGISTRY_FALLBACK: &str = include_str!("../../registry/registry.json");
+/// Registry files of the user's (or their company's) own, merged over the registry, under the
+/// XDG config directories
+const DEPENDENCY_REGISTRY_OVERLAYS_DIR: &str = "registry.d";
 
 #[derive(Debug, thiserror::Error)]
 pub enum DependencyRegistryError {
@@ -35,24 +63,46 @@ pub enum DependencyRegistryError {
     Json(#[from] serde_json::Error),
     #[error("Request error")]
     Reqwest(#[from] reqwest::Error),
-    #[error("Wrong registry data version: 1 (expected) != {0} (got)")]
+    #[error(
+        "Wrong registry data version: {} (expected) != {0} (got)",
+        SUPPORTED_REGISTRY_VERSIONS.iter().map(ToString::to_string).collect::<Vec<_>>().join(" or ")
+    )]
     WrongVersion(usize),
+    #[error("The registry entry `{0}` has a version constraint, which needs registry version 2 or later")]
+    ConstraintNeedsVersion2(String),
+    #[error("Registry signature verification failed")]
+    Signature(#[from] minisign_verify::Error),
+    #[error("Reading the registry overlay `{}`", .0.display())]
+    Overlay(PathBuf, #[source] Box<DependencyRegistryError>),
 }
 
 #[derive(Debug)]
 pub struct DependencyRegistry {
     data: Arc<RwLock<DependencyRegistryData>>,
+    /// The format version of the registry as loaded, see [`SUPPORTED_REGISTRY_VERSIONS`]
+    version: usize,
+    /// About the overlays, eg fields riff doesn't know
+    warnings: Vec<Warning>,
+    /// About the registry in use, which a refresh replaces along with it
+    registry_warnings: Arc<std::sync::Mutex<Vec<Warning>>>,
     offline: bool,
     refresh_handle: Option<JoinHandle<()>>,
 }
 
 impl DependencyRegistry {
-    #[tracing::instrument(skip_all, fields(%offline))]
-    pub async fn new(offline: bool) -> Result<Self, DependencyRegistryError> {
+    /// Load the cached registry and fetch it again in the background, unless `offline`
+    ///
+    /// With `refresh`, the registry is fetched again even if the cached one looks current, and
+    /// before this returns rather than in the background.
+    #[tracing::instrument(skip_all, fields(%offline, %refresh))]
+    pub async fn new(offline: bool, refresh: bool) -> Result<Self, DependencyRegistryError> {
+        let _timer = crate::timings::start("registry load");
         let xdg_dirs = BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
         // Create the directory if needed
         let cached_registry_pathbuf =
             xdg_dirs.place_cache_file(Path::new(DEPENDENCY_REGISTRY_CACHE_PATH))?;
+        let lock_pathbuf = xdg_dirs.place_cache_file(DEPENDENCY_REGISTRY_LOCK_PATH)?;
+        let lock = FileLock::exclusive(&lock_pathbuf).await?;
         // Create the file if needed.
         let mut cached_registry_file = OpenOptions::new()
             .read(true)
@@ -68,25 +118,47 @@ impl DependencyRegistry {
             .map_err(DependencyRegistryError::ReadCachedRegistry)?;
         drop(cached_registry_file);
 
+        // Only ask the server whether the cached registry is still current if there is one, and
+        // `--refresh` wasn't given
+        let validators = if cached_registry_content.is_empty() || refresh {
+            CacheValidators::default()
+        } else {
+            CacheValidators::load(&xdg_dirs).await
+        };
+        drop(lock);
+
         cached_registry_content = if cached_registry_content.is_empty() {
             DEPENDENCY_REGISTRY_FALLBACK.to_string()
         } else {
             cached_registry_content
         };
 
-        let data: DependencyRegistryData = serde_json::from_str(&cached_registry_content)?;
-        if data.version != 1 {
-            return Err(DependencyRegistryError::WrongVersion(data.version));
-        }
+        let mut data: DependencyRegistryData = serde_json::from_str(&cached_registry_content)?;
+        check_version(&data)?;
+        let registry_warnings = Arc::new(std::sync::Mutex::new(unknown_field_warnings(
+            &cached_registry_content,
+        )));
+        let (overlays, warnings) = load_overlays(&xdg_dirs).await?;
+        data.apply_overlays(&overlays);
 
         let data = Arc::new(RwLock::new(data));
         // We detach the join handle as we don't actually care when/if this finishes
         let data_clone = Arc::clone(&data);
-        let refresh_handle = if !offline {
+        let registry_warnings_clone = Arc::clone(&registry_warnings);
+        let refresh_handle = if !offline
+            && crate::execution::perform(crate::execution::Effect::Fetch(
+                DEPENDENCY_REGISTRY_REMOTE_URL.to_string(),
+            )) {
             let handle = tokio::spawn(async move {
                 // Refresh the cache
-                let http_client = reqwest::Client::new();
-                let req = http_client.get(DEPENDENCY_REGISTRY_REMOTE_URL);
+                let http_client = match crate::http::client() {
+                    Ok(http_client) => http_client,
+                    Err(err) => {
+                        tracing::error!(err = %eyre::eyre!(err), "Could not create an HTTP client to fetch new registry data");
+                        return;
+                    }
+                };
+                let req = validators.apply(http_client.get(DEPENDENCY_REGISTRY_REMOTE_URL));
                 tracing::trace!("Fetching new registry data from {DEPENDENCY_REGISTRY_REMOTE_URL}");
                 let res = match req.send().await {
                     Ok(res) => res,
@@ -95,6 +167,13 @@ impl DependencyRegistry {
                         return;
                     }
                 };
+                if res.status() == StatusCode::NOT_MODIFIED {
+                    tracing::debug!(
+                        "Cached registry is current, not modified since it was fetched"
+                    );
+                    return;
+                }
+                let fresh_validators = CacheValidators::from_headers(res.headers());
                 let content = match res.text().await {
                     Ok(content) => content,
                     Err(err) => {
@@ -102,14 +181,53 @@ impl DependencyRegistry {
                         return;
                     }
                 };
-                let fresh_data: DependencyRegistryData = match serde_json::from_str(&content) {
+                let signature = match http_client
+                    .get(DEPENDENCY_REGISTRY_SIGNATURE_URL)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                {
+                    Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => {
+                        // Expected until the registry is published with its signature
+                        tracing::debug!("The new registry data from {DEPENDENCY_REGISTRY_REMOTE_URL} isn't signed, {DEPENDENCY_REGISTRY_SIGNATURE_URL} is missing. Keeping the cached registry.");
+                        return;
+                    }
+                    Ok(res) => match res.text().await {
+                        Ok(signature) => signature,
+                        Err(err) => {
+                            tracing::warn!(err = %eyre::eyre!(err), "Could not fetch the new registry data's signature, keeping the cached registry");
+                            return;
+                        }
+                    },
+                    Err(err) => {
+                        tracing::warn!(err = %eyre::eyre!(err), "Could not fetch the new registry data's signature, keeping the cached registry");
+                        return;
+                    }
+                };
+                if let Err(err) =
+                    verify_registry(DEPENDENCY_REGISTRY_PUBLIC_KEY, &content, &signature)
+                {
+                    tracing::warn!(err = %eyre::eyre!(err), "New registry data from {DEPENDENCY_REGISTRY_REMOTE_URL} is not correctly signed, keeping the cached registry");
+                    return;
+                }
+                let mut fresh_data: DependencyRegistryData = match serde_json::from_str(&content) {
                     Ok(data) => data,
                     Err(err) => {
                         tracing::error!(err = %eyre::eyre!(err), "Could not parse new registry data from {DEPENDENCY_REGISTRY_REMOTE_URL}");
                         return;
                     }
                 };
+                if let Err(err) = check_version(&fresh_data) {
+                    tracing::warn!(err = %eyre::eyre!(err), "New registry data from {DEPENDENCY_REGISTRY_REMOTE_URL} can't be used by this riff, keeping the cached registry");
+                    return;
+                }
+                // The overlays stay on top of whichever registry is current
+                fresh_data.apply_overlays(&overlays);
                 *data_clone.write().await = fresh_data;
+                *registry_warnings_clone
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                    unknown_field_warnings(&content);
                 // Write out the update
                 let new_registry_pathbuf = match xdg_dirs.place_cache_file(PathBuf::from(
                     DEPENDENCY_REGISTRY_CACHE_PATH.to_string()
@@ -144,22 +262,47 @@ impl DependencyRegistry {
                         return;
                     }
                 };
+                let _lock = match FileLock::exclusive(&lock_pathbuf).await {
+                    Ok(lock) => lock,
+                    Err(err) => {
+                        tracing::error!(err = %eyre::eyre!(err), "Could not lock the cached registry to replace it");
+                        let _ = tokio::fs::remove_file(&new_registry_pathbuf).await;
+                        return;
+                    }
+                };
                 match tokio::fs::rename(&new_registry_pathbuf, &cached_registry_pathbuf).await {
                     Ok(_) => {
                         tracing::debug!(new = %new_registry_pathbuf.display(), current = %cached_registry_pathbuf.display(), "Renamed new registry to replace cached registry")
                     }
                     Err(err) => {
                         tracing::error!(new = %new_registry_pathbuf.display(), current = %cached_registry_pathbuf.display(), err = %eyre::eyre!(err), "Could not persist the registry update");
+                        return;
                     }
                 }
+                if let Err(err) = fresh_validators.write(&xdg_dirs).await {
+                    tracing::error!(err = %eyre::eyre!(err), "Could not record the registry's cache validators");
+                }
             });
             Some(handle)
         } else {
             None
         };
+        let refresh_handle = match refresh_handle {
+            Some(mut handle) if refresh => {
+                if let Err(err) = (&mut handle).await {
+                    tracing::error!(err = %eyre::eyre!(err), "Could not refresh the registry");
+                }
+                Some(handle)
+            }
+            handle => handle,
+        };
+        let version = data.read().await.version;
 
         Ok(Self {
             data,
+            version,
+            warnings,
+            registry_warnings,
             offline,
             refresh_handle,
         })
@@ -178,11 +321,28 @@ impl DependencyRegistry {
         self.offline
     }
 
-    pub async fn language(&self) -> RwLockReadGuard<DependencyRegistryLanguageData> {
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Warnings about the registry in use and the overlays, eg fields riff doesn't know
+    pub fn warnings(&self) -> Vec<Warning> {
+        let registry_warnings = self
+            .registry_warnings
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        registry_warnings
+            .iter()
+            .chain(&self.warnings)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn language(&self) -> RwLockReadGuard<'_, DependencyRegistryLanguageData> {
         RwLockReadGuard::map(self.data.read().await, |v| &v.language)
     }
 
-    pub async fn latest_riff_version(&self) -> RwLockReadGuard<Option<String>> {
+    pub async fn latest_riff_version(&self) -> RwLockReadGuard<'_, Option<String>> {
         RwLockReadGuard::map(self.data.read().await, |v| &v.latest_riff_version)
     }
 }
@@ -191,6 +351,9 @@ impl Drop for DependencyRegistry {
     fn drop(&mut self) {
         let Self {
             data: _,
+            version: _,
+            warnings: _,
+            registry_warnings: _,
             offline: _,
             refresh_handle,
         } = self;
@@ -204,21 +367,300 @@ impl Clone for DependencyRegistry {
     fn clone(&self) -> Self {
         Self {
             data: self.data.clone(),
+            version: self.version,
+            warnings: self.warnings.clone(),
+            registry_warnings: self.registry_warnings.clone(),
             offline: self.offline,
             refresh_handle: None,
         }
     }
 }
 
-/// A registry of known mappings from language specific dependencies to riff settings
-#[derive(Deserialize, Clone, Debug)]
-pub struct DependencyRegistryData {
-    pub(crate) latest_riff_version: Option<String>,
-    pub(crate) version: usize, // Checked for ABI compat
-    pub(crate) language: DependencyRegistryLanguageData,
+/// Warnings about the fields of the registry `content` which riff doesn't know, which `--strict`
+/// fails on like those in overlays
+fn unknown_field_warnings(content: &str) -> Vec<Warning> {
+    let Ok(registry) = serde_json::from_str::<serde_json::Value>(content) else {
+        return vec![];
+    };
+    schema::unknown_fields(&registry)
+        .into_iter()
+        .map(|problem| {
+            Warning::new(
+                WarningCode::UnknownField,
+                format!("In the dependency registry: {problem}"),
+            )
+        })
+        .collect()
+}
+
+/// The registry overlays in the `registry.d` config directories, in the order they apply, and
+/// warnings about their fields riff doesn't know
+///
+/// Overlays apply in the order of their file names, so a later file's entry for a crate replaces
+/// an earlier one's.
+async fn load_overlays(
+    xdg_dirs: &BaseDirectories,
+) -> Result<(Vec<DependencyRegistryData>, Vec<Warning>), DependencyRegistryError> {
+    let mut paths = xdg_dirs
+        .list_config_files(DEPENDENCY_REGISTRY_OVERLAYS_DIR)
+        .into_iter()
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect::<Vec<_>>();
+    paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    let mut overlays = Vec::with_capacity(paths.len());
+    let mut warnings = Vec::new();
+    for path in paths {
+        let overlay = async {
+            let content = tokio::fs::read_to_string(&path).await?;
+            let overlay: serde_json::Value = serde_json::from_str(&content)?;
+            for problem in schema::unknown_fields(&overlay) {
+                warnings.push(Warning::new(
+                    WarningCode::UnknownField,
+                    format!("In the registry overlay `{}`: {problem}", path.display()),
+                ));
+            }
+            let overlay: DependencyRegistryData = serde_json::from_value(overlay)?;
+            check_version(&overlay)?;
+            Ok(overlay)
+        }
+        .await
+        .map_err(|err| DependencyRegistryError::Overlay(path.clone(), Box::new(err)))?;
+        tracing::debug!(path = %path.display(), "Loaded registry overlay");
+        overlays.push(overlay);
+    }
+    Ok((overlays, warnings))
 }
 
-#[derive(Deserialize, Default, Clone, Debug)]
-pub struct DependencyRegistryLanguageData {
-    pub(crate) rust: RustDependencyRegistryData,
+/// Check that riff understands `data`'s version, and that it only uses what that version allows:
+/// version constraints in dependency keys, eg `openssl-sys@<0.9.60`, need version 2
+fn check_version(data: &DependencyRegistryData) -> Result<(), DependencyRegistryError> {
+    if !SUPPORTED_REGISTRY_VERSIONS.contains(&data.version) {
+        return Err(DependencyRegistryError::WrongVersion(data.version));
+    }
+    if data.version < 2 {
+        if let Some(key) = data
+            .language
+            .rust
+            .dependencies
+            .keys()
+            .find(|key| key.contains('@'))
+        {
+            return Err(DependencyRegistryError::ConstraintNeedsVersion2(
+                key.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check that `signature` is a valid minisign signature of `content` by one of `public_keys`,
+/// both in the format written by `minisign`, with any number of keys one after the other
+fn verify_registry(
+    public_keys: &str,
+    content: &str,
+    signature: &str,
+) -> Result<(), DependencyRegistryError> {
+    let signature = minisign_verify::Signature::decode(signature)?;
+    // Skip the key file's untrusted comments
+    let mut result = Err(minisign_verify::Error::UnexpectedKeyId);
+    for public_key in public_keys
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+    {
+        result = minisign_verify::PublicKey::from_base64(public_key)
+            .and_then(|public_key| public_key.verify(content.as_bytes(), &signature, false));
+        if result.is_ok() {
+            break;
+        }
+    }
+    Ok(result?)
+}
+
+/// What the server said identifies the version of the registry we cached, sent back with the next
+/// request so it can reply `304 Not Modified` instead of sending the same registry again
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct CacheValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    /// Make `req` conditional on the registry having changed since it was cached
+    fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        req
+    }
+
+    /// The validators of the cached registry, or none if they're missing or unreadable
+    async fn load(xdg_dirs: &BaseDirectories) -> Self {
+        let Some(path) = xdg_dirs.find_cache_file(DEPENDENCY_REGISTRY_VALIDATORS_PATH) else {
+            return Self::default();
+        };
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(err) => {
+                tracing::debug!(err = %eyre::eyre!(err), "Could not read the registry's cache validators");
+                Self::default()
+            }
+        }
+    }
+
+    async fn write(&self, xdg_dirs: &BaseDirectories) -> Result<(), DependencyRegistryError> {
+        let path = xdg_dirs.place_cache_file(DEPENDENCY_REGISTRY_VALIDATORS_PATH)?;
+        tokio::fs::write(path, serde_json::to_string(self)?).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    const TEST_PUBLIC_KEY: &str = "untrusted comment: minisign public key 06C5D1A0C8B0B628
+RWQotrDIoNHFBoB6NOGDOEqVBIUWGfADp9UkrkgS+ScUzFTwMSYvMTpS
+";
+    const TEST_REGISTRY: &str = r#"{"version":2,"language":{"rust":{}}}"#;
+    const TEST_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQotrDIoNHFBmQTOIW2EqJoXQNqTobxaaGoADAmiDtUszW1z2NlmSk9g5bSHKPRNWc/89phGkBpiQCrFmHuAL3p3HdofTmaTQw=
+trusted comment: timestamp:1700000000\tfile:riff-registry.json
+0ShQFiNCLMpcralJ1mOTZSOAbT7HOaQCWZzZvbZZzoXctaGMoAja0Bx051bMLPpwYg3NzEDpbZpC3sYrdb4rAQ==
+";
+
+    #[test]
+    fn registry_signatures() {
+        assert!(verify_registry(TEST_PUBLIC_KEY, TEST_REGISTRY, TEST_SIGNATURE).is_ok());
+        let tampered = TEST_REGISTRY.replace("\"rust\":{}", "\"rust\":{\"x\":1}");
+        assert!(verify_registry(TEST_PUBLIC_KEY, &tampered, TEST_SIGNATURE).is_err());
+        // Signed by a different key
+        assert!(verify_registry(
+            DEPENDENCY_REGISTRY_PUBLIC_KEY,
+            TEST_REGISTRY,
+            TEST_SIGNATURE
+        )
+        .is_err());
+        assert!(verify_registry(TEST_PUBLIC_KEY, TEST_REGISTRY, "not a signature").is_err());
+        // Signed by either key while the key is being rotated
+        let rotating = format!("{DEPENDENCY_REGISTRY_PUBLIC_KEY}{TEST_PUBLIC_KEY}");
+        assert!(verify_registry(&rotating, TEST_REGISTRY, TEST_SIGNATURE).is_ok());
+        assert!(verify_registry("", TEST_REGISTRY, TEST_SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn versions_are_checked() -> Result<(), serde_json::Error> {
+        let check = |registry: &str| -> Result<(), DependencyRegistryError> {
+            check_version(&serde_json::from_str(registry)?)
+        };
+        let constrained =
+            r#"{"language":{"rust":{"dependencies":{"openssl-sys@<0.9.60":{}}}},"version":"#;
+        assert!(check(&format!("{constrained}2}}")).is_ok());
+        assert!(matches!(
+            check(&format!("{constrained}1}}")),
+            Err(DependencyRegistryError::ConstraintNeedsVersion2(key)) if key == "openssl-sys@<0.9.60"
+        ));
+        assert!(
+            check(r#"{"version":1,"language":{"rust":{"dependencies":{"openssl-sys":{}}}}}"#)
+                .is_ok()
+        );
+        let wrong = check(r#"{"version":3,"language":{}}"#).unwrap_err();
+        assert_eq!(
+            wrong.to_string(),
+            "Wrong registry data version: 1 or 2 (expected) != 3 (got)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn registry_unknown_fields_are_warned_about() {
+        assert_eq!(unknown_field_warnings(DEPENDENCY_REGISTRY_FALLBACK), vec![]);
+        let warnings = unknown_field_warnings(
+            r#"{"version":1,"language":{"rust":{"dependencies":{"openssl-sys":{"build_inputs":[]}}}}}"#,
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, WarningCode::UnknownField);
+        assert!(warnings[0]
+            .message
+            .starts_with("In the dependency registry: "));
+    }
+
+    #[test]
+    fn overlays_apply_in_order() -> Result<(), serde_json::Error> {
+        let mut data: DependencyRegistryData = serde_json::from_str(
+            r#"{"version":2,"language":{"rust":{
+                "default":{"build-inputs":["cargo"]},
+                "dependencies":{"openssl-sys":{"build-inputs":["openssl"]}}
+            }}}"#,
+        )?;
+        let overlays: Vec<DependencyRegistryData> = [
+            r#"{"version":2,"language":{"rust":{"dependencies":{
+                "openssl-sys":{"build-inputs":["libressl"]},
+                "acme-sys":{"build-inputs":["acme"]}
+            }}}}"#,
+            r#"{"version":2,"language":{"rust":{"default":{"build-inputs":["protobuf"]}}}}"#,
+            r#"{"version":2,"language":{}}"#,
+        ]
+        .into_iter()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+        data.apply_overlays(&overlays);
+
+        let rust = &data.language.rust;
+        assert_eq!(
+            rust.default.build_inputs,
+            ["cargo", "protobuf"].map(String::from).into()
+        );
+        assert_eq!(
+            rust.dependencies["openssl-sys"].default.build_inputs,
+            ["libressl"].map(String::from).into()
+        );
+        assert!(rust.index().knows("acme-sys"));
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_requests() -> Result<(), reqwest::Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"abc123\""));
+        let validators = CacheValidators::from_headers(&headers);
+        assert_eq!(validators.etag.as_deref(), Some("\"abc123\""));
+        assert_eq!(validators.last_modified, None);
+
+        let client = reqwest::Client::new();
+        let req = validators
+            .apply(client.get(DEPENDENCY_REGISTRY_REMOTE_URL))
+            .build()?;
+        assert_eq!(req.headers()[IF_NONE_MATCH], "\"abc123\"");
+        assert!(!req.headers().contains_key(IF_MODIFIED_SINCE));
+
+        let req = CacheValidators::default()
+            .apply(client.get(DEPENDENCY_REGISTRY_REMOTE_URL))
+            .build()?;
+        assert!(!req.headers().contains_key(IF_NONE_MATCH));
+        Ok(())
+    }
 }
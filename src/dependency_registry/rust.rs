@@ -1,35 +1,173 @@
 use std::collections::{HashMap, HashSet};
 
-use serde::Deserialize;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use target_lexicon::Triple;
 
 use crate::dev_env::{DevEnvironment, DevEnvironmentAppliable};
+use crate::project_config::Exclusions;
 
 /// A language specific registry of dependencies to riff settings
-#[derive(Deserialize, Default, Clone, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub struct RustDependencyRegistryData {
     /// Settings which are needed for every instance of this language (Eg `cargo` for Rust)
+    #[serde(default)]
     pub(crate) default: RustDependencyTargetData,
     /// A mapping of dependencies (by crate name) to configuration
-    // TODO(@hoverbear): How do we handle crates with conflicting names? eg a `rocksdb-sys` crate from one repo and another from another having different requirements?
+    ///
+    /// Since registry version 2, a key may also constrain the crate's version, eg
+    /// `openssl-sys@<0.9.60`, to give those versions different settings. Crates from git or a
+    /// path under another name match an entry by its `repositories` instead.
+    #[serde(default)]
     pub(crate) dependencies: HashMap<String, RustDependencyData>,
 }
 
-#[derive(Deserialize, Default, Clone, Debug)]
+impl RustDependencyRegistryData {
+    /// The entries by crate name and by repository, for looking up every crate of a project
+    pub(crate) fn index(&self) -> RustRegistryIndex<'_> {
+        let mut index = RustRegistryIndex::default();
+        for (key, data) in &self.dependencies {
+            let key = key.as_str();
+            let (name, constraint) = match key.split_once('@') {
+                Some((name, constraint)) => match semver::VersionReq::parse(constraint) {
+                    Ok(constraint) => (name, Some(constraint)),
+                    Err(err) => {
+                        tracing::debug!(%key, %err, "Ignoring registry entry with an invalid version constraint");
+                        continue;
+                    }
+                },
+                None => (key, None),
+            };
+            index
+                .by_name
+                .entry(name)
+                .or_default()
+                .push((key, constraint, data));
+            for repository in &data.repositories {
+                let entry = index
+                    .by_repository
+                    .entry(repository_key(repository))
+                    .or_insert((key, data));
+                if key < entry.0 {
+                    *entry = (key, data);
+                }
+            }
+        }
+        for entries in index.by_name.values_mut() {
+            // Constrained entries by key, then the unconstrained one
+            entries.sort_by_key(|(key, constraint, _)| (constraint.is_none(), *key));
+        }
+        index
+    }
+
+    /// Merge a registry overlay over this registry: the overlay's defaults add to these, and its
+    /// entries replace the entries with the same key
+    pub(crate) fn apply_overlay(&mut self, overlay: &RustDependencyRegistryData) {
+        self.default = overlay.default.clone().inherit(&self.default);
+        for (key, data) in &overlay.dependencies {
+            self.dependencies.insert(key.clone(), data.clone());
+        }
+    }
+}
+
+/// The entries of a [`RustDependencyRegistryData`], arranged to look crates up quickly
+#[derive(Default)]
+pub(crate) struct RustRegistryIndex<'a> {
+    /// The entries for each crate with their version constraints, in the order they take
+    /// precedence
+    by_name: HashMap<&'a str, Vec<RegistryEntry<'a>>>,
+    /// The first entry by key listing each repository, by [`repository_key`]
+    by_repository: HashMap<String, (&'a str, &'a RustDependencyData)>,
+}
+
+type RegistryEntry<'a> = (&'a str, Option<semver::VersionReq>, &'a RustDependencyData);
+
+impl<'a> RustRegistryIndex<'a> {
+    /// The entry for `version` of the crate `name`, along with its key.
+    ///
+    /// Entries whose version constraint matches take precedence over the unconstrained entry. If
+    /// several constrained entries match, the first by key wins.
+    pub(crate) fn dependency(
+        &self,
+        name: &str,
+        version: &semver::Version,
+    ) -> Option<(&'a str, &'a RustDependencyData)> {
+        self.by_name
+            .get(name)?
+            .iter()
+            .find(|(_, constraint, _)| {
+                constraint
+                    .as_ref()
+                    .is_none_or(|constraint| constraint.matches(version))
+            })
+            .map(|(key, _, data)| (*key, *data))
+    }
+
+    /// The entry listing one of `repositories` (eg the `repository` of a crate's manifest, or the
+    /// git URL it comes from), along with its key. If several do, the first by key wins.
+    pub(crate) fn dependency_by_repository<'r>(
+        &self,
+        repositories: impl IntoIterator<Item = &'r str>,
+    ) -> Option<(&'a str, &'a RustDependencyData)> {
+        repositories
+            .into_iter()
+            .filter_map(|repository| self.by_repository.get(&repository_key(repository)))
+            .min_by_key(|(key, _)| *key)
+            .copied()
+    }
+
+    /// Whether there is any entry for the crate `name`, whatever its version
+    pub(crate) fn knows(&self, name: &str) -> bool {
+        self.by_name.contains_key(name)
+    }
+}
+
+/// `repository` without what varies between spellings of the same repository: its scheme, a
+/// `git+` prefix, a query or fragment (as in the `source` of a git dependency), a `.git` suffix,
+/// and case
+fn repository_key(repository: &str) -> String {
+    let repository = repository.trim().trim_start_matches("git+");
+    let repository = repository
+        .split_once("://")
+        .map_or(repository, |(_, rest)| rest);
+    let repository = repository
+        .split_once('@')
+        .filter(|(user, _)| !user.contains('/'))
+        .map_or(repository, |(_, rest)| rest);
+    let repository = repository.split(['?', '#']).next().unwrap_or_default();
+    let repository = repository.trim_end_matches('/');
+    repository
+        .strip_suffix(".git")
+        .unwrap_or(repository)
+        .to_lowercase()
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub struct RustDependencyData {
     #[serde(flatten)]
     pub(crate) default: RustDependencyTargetData,
     // Keep the key a `String` since users can make custom targets.
     #[serde(default)]
     pub(crate) targets: HashMap<String, RustDependencyTargetData>,
+    /// What to filter out of the environment, which only workspace members may declare
+    #[serde(flatten)]
+    pub(crate) exclusions: Exclusions,
+    /// The repositories the crate is developed in, which match forks and renamed copies of it
+    /// from git or a path. Only the registry's are used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) repositories: Vec<String>,
 }
 
 impl RustDependencyData {
+    /// The settings specific to `target`, if there are any
+    fn target_config(&self, target: &Triple) -> Option<&RustDependencyTargetData> {
+        self.targets.get(&target.to_string())
+    }
     #[tracing::instrument(skip_all)]
-    pub(crate) fn build_inputs(&self) -> HashSet<String> {
-        let target = format!("{}", target_lexicon::HOST);
+    pub(crate) fn build_inputs(&self, target: &Triple) -> HashSet<String> {
         let mut build_inputs = self.default.build_inputs.clone();
         // Importantly: These come after, they are more specific.
-        if let Some(target_config) = self.targets.get(&target) {
+        if let Some(target_config) = self.target_config(target) {
             build_inputs = build_inputs
                 .union(&target_config.build_inputs)
                 .cloned()
@@ -38,11 +176,10 @@ impl RustDependencyData {
         build_inputs
     }
     #[tracing::instrument(skip_all)]
-    pub(crate) fn environment_variables(&self) -> HashMap<String, String> {
-        let target = format!("{}", target_lexicon::HOST);
+    pub(crate) fn environment_variables(&self, target: &Triple) -> HashMap<String, String> {
         let mut environment_variables = self.default.environment_variables.clone();
         // Importantly: These come after, they are more specific.
-        if let Some(target_config) = self.targets.get(&target) {
+        if let Some(target_config) = self.target_config(target) {
             for (k, v) in &target_config.environment_variables {
                 environment_variables.insert(k.clone(), v.clone());
             }
@@ -50,11 +187,10 @@ impl RustDependencyData {
         environment_variables
     }
     #[tracing::instrument(skip_all)]
-    pub(crate) fn runtime_inputs(&self) -> HashSet<String> {
-        let target = format!("{}", target_lexicon::HOST);
+    pub(crate) fn runtime_inputs(&self, target: &Triple) -> HashSet<String> {
         let mut runtime_inputs = self.default.runtime_inputs.clone();
         // Importantly: These come after, they are more specific.
-        if let Some(target_config) = self.targets.get(&target) {
+        if let Some(target_config) = self.target_config(target) {
             runtime_inputs = runtime_inputs
                 .union(&target_config.runtime_inputs)
                 .cloned()
@@ -62,22 +198,85 @@ impl RustDependencyData {
         }
         runtime_inputs
     }
+    /// The shell hooks for `target`, the default one first
+    pub(crate) fn shell_hooks(&self, target: &Triple) -> Vec<String> {
+        self.default
+            .shell_hook
+            .iter()
+            .chain(
+                self.target_config(target)
+                    .and_then(|target_config| target_config.shell_hook.as_ref()),
+            )
+            .cloned()
+            .collect()
+    }
+    /// The environment variables and shell hooks this sets for `target`, described for deciding
+    /// whether to trust a project with them
+    pub(crate) fn overrides(&self, target: &Triple) -> Vec<String> {
+        let mut environment_variables = self
+            .environment_variables(target)
+            .into_iter()
+            .map(|(name, value)| format!("`{name}={value}`"))
+            .collect::<Vec<_>>();
+        environment_variables.sort();
+        environment_variables
+            .into_iter()
+            .chain(
+                self.shell_hooks(target)
+                    .iter()
+                    .map(|shell_hook| format!("a shell hook running `{}`", shell_hook.trim())),
+            )
+            .collect()
+    }
+    /// This without the environment variables and shell hooks of an untrusted project
+    pub(crate) fn without_overrides(mut self) -> Self {
+        for target_config in std::iter::once(&mut self.default).chain(self.targets.values_mut()) {
+            target_config.environment_variables.clear();
+            target_config.shell_hook = None;
+        }
+        self
+    }
+    /// The inputs and environment variables this provides for `target`
+    pub(crate) fn provides(&self, target: &Triple) -> Vec<String> {
+        self.build_inputs(target)
+            .into_iter()
+            .chain(self.runtime_inputs(target))
+            .chain(self.environment_variables(target).into_keys())
+            .collect()
+    }
+
+    /// Merge a workspace member's `package.metadata.riff` over the `workspace.metadata.riff` it
+    /// inherits, section by section (the defaults with the defaults, and each target with the
+    /// same target)
+    pub(crate) fn inherit(mut self, workspace: &RustDependencyData) -> Self {
+        self.default = self.default.inherit(&workspace.default);
+        for (target, workspace_target_config) in &workspace.targets {
+            let target_config = self
+                .targets
+                .remove(target)
+                .unwrap_or_default()
+                .inherit(workspace_target_config);
+            self.targets.insert(target.clone(), target_config);
+        }
+        self.exclusions.extend(&workspace.exclusions);
+        self
+    }
 }
 
 impl DevEnvironmentAppliable for RustDependencyData {
     #[tracing::instrument(skip_all)]
-    fn apply(&self, dev_env: &mut DevEnvironment) {
-        self.default.apply(dev_env);
-        let target = format!("{}", target_lexicon::HOST);
+    fn apply(&self, dev_env: &mut DevEnvironment, source: &str) {
+        self.default.apply(dev_env, source);
         // Importantly: These come after, they are more specific.
-        if let Some(target_config) = self.targets.get(&target) {
-            target_config.apply(dev_env);
+        if let Some(target_config) = self.target_config(&dev_env.target) {
+            target_config.apply(dev_env, source);
         }
     }
 }
 
 /// Dependency specific information needed for riff
-#[derive(Deserialize, Default, Clone, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+#[serde(from = "TargetDataFields")]
 pub struct RustDependencyTargetData {
     /// The Nix `buildInputs` needed
     #[serde(default, rename = "build-inputs")]
@@ -85,37 +284,91 @@ pub struct RustDependencyTargetData {
     /// Any packaging specific environment variables that need to be set
     #[serde(default, rename = "environment-variables")]
     pub(crate) environment_variables: HashMap<String, String>,
-    /// The Nix packages which should have the result of `lib.getLib` run on them placed on the `LD_LIBRARY_PATH`
+    /// The Nix packages which should have the result of `lib.getLib` run on them placed on the `LD_LIBRARY_PATH` (`DYLD_FALLBACK_LIBRARY_PATH` on macOS)
     #[serde(default, rename = "runtime-inputs")]
     pub(crate) runtime_inputs: HashSet<String>,
+    /// A shell script fragment to run when entering the environment (eg to create a cache directory)
+    #[serde(default, rename = "shell-hook")]
+    pub(crate) shell_hook: Option<String>,
+}
+
+/// [`RustDependencyTargetData`] as written, where tools such as build hooks may be listed as
+/// `native-build-inputs`, which a dev shell treats like its other build inputs
+#[derive(Deserialize)]
+struct TargetDataFields {
+    #[serde(default, rename = "build-inputs")]
+    build_inputs: HashSet<String>,
+    #[serde(default, rename = "native-build-inputs")]
+    native_build_inputs: HashSet<String>,
+    #[serde(default, rename = "environment-variables")]
+    environment_variables: HashMap<String, String>,
+    #[serde(default, rename = "runtime-inputs")]
+    runtime_inputs: HashSet<String>,
+    #[serde(default, rename = "shell-hook")]
+    shell_hook: Option<String>,
+}
+
+impl From<TargetDataFields> for RustDependencyTargetData {
+    fn from(mut fields: TargetDataFields) -> Self {
+        fields.build_inputs.extend(fields.native_build_inputs);
+        Self {
+            build_inputs: fields.build_inputs,
+            environment_variables: fields.environment_variables,
+            runtime_inputs: fields.runtime_inputs,
+            shell_hook: fields.shell_hook,
+        }
+    }
+}
+
+impl RustDependencyTargetData {
+    /// The inputs and environment variables this provides
+    pub(crate) fn provides(&self) -> Vec<String> {
+        self.build_inputs
+            .iter()
+            .chain(&self.runtime_inputs)
+            .chain(self.environment_variables.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// Merge these settings over inherited ones: inputs are unioned, environment variables set
+    /// here win, and a `shell-hook` set here replaces the inherited one
+    pub(crate) fn inherit(mut self, inherited: &RustDependencyTargetData) -> Self {
+        self.build_inputs
+            .extend(inherited.build_inputs.iter().cloned());
+        self.runtime_inputs
+            .extend(inherited.runtime_inputs.iter().cloned());
+        for (key, value) in &inherited.environment_variables {
+            self.environment_variables
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        if self.shell_hook.is_none() {
+            self.shell_hook = inherited.shell_hook.clone();
+        }
+        self
+    }
 }
 
 impl DevEnvironmentAppliable for RustDependencyTargetData {
     #[tracing::instrument(skip_all)]
-    fn apply(&self, dev_env: &mut DevEnvironment) {
+    fn apply(&self, dev_env: &mut DevEnvironment, source: &str) {
         dev_env.build_inputs = dev_env
             .build_inputs
             .union(&self.build_inputs)
             .cloned()
             .collect();
-        for (ref env_key, ref env_val) in &self.environment_variables {
-            if let Some(existing_value) = dev_env
-                .environment_variables
-                .insert(env_key.to_string(), env_val.to_string())
-            {
-                tracing::debug!(
-                    key = env_key,
-                    existing_value,
-                    new_value = env_val,
-                    "Overriding previously declared environment variable"
-                )
-            }
+        for (env_key, env_val) in self.environment_variables.iter().sorted() {
+            dev_env.set_environment_variable(env_key, env_val, source);
         }
         dev_env.runtime_inputs = dev_env
             .runtime_inputs
             .union(&self.runtime_inputs)
             .cloned()
             .collect();
+        if let Some(shell_hook) = &self.shell_hook {
+            dev_env.add_shell_hook(shell_hook);
+        }
     }
 }
 
@@ -129,7 +382,7 @@ mod test {
     async fn try_apply() -> eyre::Result<()> {
         let cache_dir = TempDir::new()?;
         std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
-        let registry = DependencyRegistry::new(true).await?;
+        let registry = DependencyRegistry::new(true, false).await?;
         let mut dev_env = DevEnvironment::new(&registry);
 
         let target = format!("{}", target_lexicon::HOST);
@@ -143,6 +396,7 @@ mod test {
                 .into_iter()
                 .collect(),
                 runtime_inputs: vec!["default".into()].into_iter().collect(),
+                shell_hook: Some("mkdir -p .cache".into()),
             },
             targets: {
                 let mut map = HashMap::default();
@@ -157,13 +411,16 @@ mod test {
                         .into_iter()
                         .collect(),
                         runtime_inputs: vec!["target_specific".into()].into_iter().collect(),
+                        shell_hook: Some("export TARGET_HOOK=1".into()),
                     },
                 );
                 map
             },
+            exclusions: Default::default(),
+            repositories: Default::default(),
         };
 
-        data.apply(&mut dev_env);
+        data.apply(&mut dev_env, "`test` (riff registry)");
 
         assert_eq!(
             dev_env.build_inputs,
@@ -187,10 +444,182 @@ mod test {
                 .into_iter()
                 .collect()
         );
+        assert_eq!(
+            dev_env.shell_hooks,
+            vec!["mkdir -p .cache", "export TARGET_HOOK=1"]
+        );
+        // Target-specific settings override the same entry's defaults without conflicting.
+        assert!(dev_env.warnings.is_empty());
+
+        assert_eq!(
+            data.overrides(&Triple::host()),
+            vec![
+                "`CONFLICT=target_specific`",
+                "`DEFAULT_VAR=default`",
+                "`TARGET_VAR=target_specific`",
+                "a shell hook running `mkdir -p .cache`",
+                "a shell hook running `export TARGET_HOOK=1`",
+            ]
+        );
+        // An untrusted project's configuration still provides its inputs
+        let untrusted = data.clone().without_overrides();
+        assert!(untrusted.overrides(&Triple::host()).is_empty());
+        assert_eq!(
+            untrusted.build_inputs(&Triple::host()),
+            data.build_inputs(&Triple::host())
+        );
+        assert_eq!(
+            untrusted.runtime_inputs(&Triple::host()),
+            data.runtime_inputs(&Triple::host())
+        );
 
         Ok(())
     }
 
+    #[test]
+    fn inherit_from_workspace() -> eyre::Result<()> {
+        let target = format!("{}", target_lexicon::HOST);
+        let workspace = RustDependencyData {
+            default: RustDependencyTargetData {
+                build_inputs: vec!["workspace".into()].into_iter().collect(),
+                environment_variables: vec![
+                    ("WORKSPACE_VAR".into(), "workspace".into()),
+                    ("CONFLICT".into(), "workspace".into()),
+                ]
+                .into_iter()
+                .collect(),
+                shell_hook: Some("echo workspace".into()),
+                ..Default::default()
+            },
+            targets: {
+                let mut map = HashMap::default();
+                map.insert(
+                    target.clone(),
+                    RustDependencyTargetData {
+                        runtime_inputs: vec!["workspace_target".into()].into_iter().collect(),
+                        ..Default::default()
+                    },
+                );
+                map
+            },
+            exclusions: Default::default(),
+            repositories: Default::default(),
+        };
+        let member = RustDependencyData {
+            default: RustDependencyTargetData {
+                build_inputs: vec!["member".into()].into_iter().collect(),
+                environment_variables: vec![("CONFLICT".into(), "member".into())]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+            targets: Default::default(),
+            exclusions: Default::default(),
+            repositories: Default::default(),
+        };
+
+        let merged = member.inherit(&workspace);
+
+        assert_eq!(
+            merged.build_inputs(&Triple::host()),
+            vec!["member".into(), "workspace".into()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            merged.environment_variables(&Triple::host()),
+            vec![
+                ("WORKSPACE_VAR".into(), "workspace".into()),
+                ("CONFLICT".into(), "member".into()),
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(
+            merged.runtime_inputs(&Triple::host()),
+            vec!["workspace_target".into()].into_iter().collect()
+        );
+        assert_eq!(merged.default.shell_hook.as_deref(), Some("echo workspace"));
+        Ok(())
+    }
+
+    #[test]
+    fn versioned_registry_entries() -> eyre::Result<()> {
+        let entry = |input: &str| RustDependencyData {
+            default: RustDependencyTargetData {
+                build_inputs: vec![input.into()].into_iter().collect(),
+                ..Default::default()
+            },
+            targets: Default::default(),
+            exclusions: Default::default(),
+            repositories: Default::default(),
+        };
+        let registry = RustDependencyRegistryData {
+            default: Default::default(),
+            dependencies: [
+                ("openssl-sys", entry("openssl_3")),
+                ("openssl-sys@<0.9.60", entry("openssl_1_1")),
+                ("libz-sys@>=1.1", entry("zlib")),
+            ]
+            .into_iter()
+            .map(|(key, data)| (key.to_string(), data))
+            .collect(),
+        };
+        let key_for = |name, version| {
+            registry
+                .index()
+                .dependency(name, &semver::Version::parse(version).unwrap())
+                .map(|(key, _)| key)
+        };
+
+        assert_eq!(
+            key_for("openssl-sys", "0.9.50"),
+            Some("openssl-sys@<0.9.60")
+        );
+        assert_eq!(key_for("openssl-sys", "0.9.80"), Some("openssl-sys"));
+        assert_eq!(key_for("libz-sys", "1.1.8"), Some("libz-sys@>=1.1"));
+        assert_eq!(key_for("libz-sys", "1.0.0"), None);
+        assert!(registry.index().knows("libz-sys"));
+        assert!(!registry.index().knows("openssl"));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_entries_by_repository() {
+        let registry = RustDependencyRegistryData {
+            default: Default::default(),
+            dependencies: [(
+                "librocksdb-sys".to_string(),
+                RustDependencyData {
+                    repositories: vec!["https://github.com/rust-rocksdb/rust-rocksdb".into()],
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let key_for = |repositories: &[&str]| {
+            registry
+                .index()
+                .dependency_by_repository(repositories.iter().copied())
+                .map(|(key, _)| key)
+        };
+
+        assert_eq!(
+            key_for(&["git+https://github.com/Rust-RocksDB/rust-rocksdb.git?branch=main#4e5c2f1"]),
+            Some("librocksdb-sys")
+        );
+        assert_eq!(
+            key_for(&[
+                "https://github.com/acme/rocksdb-fork",
+                "ssh://git@github.com/rust-rocksdb/rust-rocksdb/",
+            ]),
+            Some("librocksdb-sys")
+        );
+        assert_eq!(key_for(&["https://github.com/acme/rocksdb-fork"]), None);
+        assert_eq!(key_for(&[]), None);
+    }
+
     #[test]
     fn build_input_merge() -> eyre::Result<()> {
         let target = format!("{}", target_lexicon::HOST);
@@ -210,8 +639,10 @@ mod test {
                 );
                 map
             },
+            exclusions: Default::default(),
+            repositories: Default::default(),
         };
-        let merged = data.build_inputs();
+        let merged = data.build_inputs(&Triple::host());
         assert_eq!(
             merged,
             vec!["default".into(), "target_specific".into()]
@@ -250,8 +681,10 @@ mod test {
                 );
                 map
             },
+            exclusions: Default::default(),
+            repositories: Default::default(),
         };
-        let merged = data.environment_variables();
+        let merged = data.environment_variables(&Triple::host());
         assert_eq!(
             merged,
             vec![
@@ -284,8 +717,10 @@ mod test {
                 );
                 map
             },
+            exclusions: Default::default(),
+            repositories: Default::default(),
         };
-        let merged = data.runtime_inputs();
+        let merged = data.runtime_inputs(&Triple::host());
         assert_eq!(
             merged,
             vec!["default".into(), "target_specific".into()]
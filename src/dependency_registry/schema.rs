@@ -0,0 +1,595 @@
+//! The format of registry files: the registry riff downloads, and the overlays in `registry.d`.
+//!
+//! The format is published as a JSON Schema ([`JSON_SCHEMA`], printed by `riff registry schema`)
+//! for editors, and [`validate`] (behind `riff registry validate`) checks a candidate file more
+//! closely than loading it does, so registry contributors can test their changes locally.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::dotnet::DotnetDependencyRegistryData;
+use super::haskell::HaskellDependencyRegistryData;
+use super::rust::RustDependencyRegistryData;
+
+/// Version 2 added version constraints to dependency keys, eg `openssl-sys@<0.9.60`
+pub(crate) const SUPPORTED_REGISTRY_VERSIONS: &[usize] = &[1, 2];
+
+/// The JSON Schema of registry files
+pub const JSON_SCHEMA: &str = include_str!("../../registry/riff-registry.schema.json");
+
+/// A registry of known mappings from language specific dependencies to riff settings
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DependencyRegistryData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) latest_riff_version: Option<String>,
+    pub(crate) version: usize, // Checked for ABI compat
+    pub(crate) language: DependencyRegistryLanguageData,
+}
+
+impl DependencyRegistryData {
+    /// Merge `overlays` over this registry, in order
+    pub(super) fn apply_overlays(&mut self, overlays: &[DependencyRegistryData]) {
+        for overlay in overlays {
+            self.language.rust.apply_overlay(&overlay.language.rust);
+            self.language
+                .haskell
+                .apply_overlay(&overlay.language.haskell);
+            self.language.dotnet.apply_overlay(&overlay.language.dotnet);
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct DependencyRegistryLanguageData {
+    /// Optional, so registry overlays which don't touch Rust can leave it out
+    #[serde(default)]
+    pub(crate) rust: RustDependencyRegistryData,
+    /// Optional, since registries made before riff detected Haskell projects don't have it
+    #[serde(default)]
+    pub(crate) haskell: HaskellDependencyRegistryData,
+    /// Optional, since registries made before riff detected .NET projects don't have it
+    #[serde(default)]
+    pub(crate) dotnet: DotnetDependencyRegistryData,
+}
+
+/// The settings a dependency (or its `default`, or one of its `targets`) can have
+const TARGET_FIELDS: &[&str] = &[
+    "build-inputs",
+    "native-build-inputs",
+    "environment-variables",
+    "runtime-inputs",
+    "shell-hook",
+];
+
+/// What only a workspace member's `package.metadata.riff` can declare
+const EXCLUSION_FIELDS: &[&str] = &[
+    "exclude-build-inputs",
+    "exclude-runtime-inputs",
+    "unset-environment-variables",
+];
+
+/// Something wrong with a registry file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Problem {
+    /// Where the problem is, as a JSON pointer, eg `/language/rust/dependencies/openssl-sys`
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.path.as_str() {
+            "" => write!(f, "{}", self.message),
+            path => write!(f, "`{path}`: {}", self.message),
+        }
+    }
+}
+
+/// Everything wrong with the registry file `contents`, or nothing if riff can use it as it is
+pub fn validate(contents: &str) -> Vec<Problem> {
+    let value: Value = match serde_json::from_str(contents) {
+        Ok(value) => value,
+        Err(err) => {
+            return vec![Problem {
+                path: String::new(),
+                message: format!("Not valid JSON: {err}"),
+            }]
+        }
+    };
+
+    let mut validator = Validator::default();
+    validator.registry(&value);
+    if validator.problems.is_empty() {
+        // Anything the checks above don't cover, such as a value of the wrong type
+        if let Err(err) = serde_json::from_value::<DependencyRegistryData>(value) {
+            validator.problem("", err.to_string());
+        }
+    }
+    validator.problems
+}
+
+/// The fields of the registry file `registry` which riff doesn't know, and ignores
+pub fn unknown_fields(registry: &Value) -> Vec<Problem> {
+    let mut validator = Validator {
+        fields_only: true,
+        ..Default::default()
+    };
+    validator.registry(registry);
+    validator.problems
+}
+
+/// The fields of a dependency's settings (as in the registry, or in `package.metadata.riff`)
+/// which riff doesn't know, and ignores
+pub fn unknown_dependency_fields(dependency: &Value) -> Vec<Problem> {
+    let mut validator = Validator {
+        fields_only: true,
+        ..Default::default()
+    };
+    validator.dependency("", dependency);
+    validator.problems
+}
+
+#[derive(Default)]
+struct Validator {
+    problems: Vec<Problem>,
+    version: Option<u64>,
+    /// Only report unknown fields, leaving the rest to deserializing
+    fields_only: bool,
+}
+
+impl Validator {
+    /// Report a problem other than an unknown field, unless only unknown fields are wanted
+    fn problem(&mut self, path: &str, message: impl Into<String>) {
+        if !self.fields_only {
+            self.report(path, message);
+        }
+    }
+
+    /// Report a field riff doesn't know
+    fn unknown_field(&mut self, path: &str, message: impl Into<String>) {
+        self.report(path, message);
+    }
+
+    fn report(&mut self, path: &str, message: impl Into<String>) {
+        self.problems.push(Problem {
+            path: path.to_string(),
+            message: message.into(),
+        });
+    }
+
+    /// `value` as an object with only the `known` fields, reporting any others
+    fn object<'a>(
+        &mut self,
+        path: &str,
+        value: &'a Value,
+        known: &[&str],
+    ) -> Option<&'a Map<String, Value>> {
+        let Some(object) = value.as_object() else {
+            self.problem(path, "Expected an object");
+            return None;
+        };
+        for key in object.keys() {
+            if known.contains(&key.as_str()) {
+                continue;
+            }
+            let suggestion = known
+                .iter()
+                .find(|known| known.replace('-', "_") == key.replace('-', "_"))
+                .map(|known| format!(", did you mean `{known}`?"))
+                .unwrap_or_else(|| format!(" (expected one of {})", quoted(known)));
+            self.unknown_field(
+                &pointer(path, key),
+                format!("Unknown field `{key}`{suggestion}"),
+            );
+        }
+        Some(object)
+    }
+
+    fn registry(&mut self, value: &Value) {
+        let Some(registry) =
+            self.object("", value, &["version", "latest_riff_version", "language"])
+        else {
+            return;
+        };
+
+        match registry.get("version") {
+            None => self.problem("", "Missing the `version` field"),
+            Some(version) => match version.as_u64() {
+                Some(number)
+                    if SUPPORTED_REGISTRY_VERSIONS
+                        .iter()
+                        .any(|supported| *supported as u64 == number) =>
+                {
+                    self.version = Some(number)
+                }
+                _ => self.problem(
+                    "/version",
+                    format!(
+                        "Unsupported version {version}, expected one of {}",
+                        SUPPORTED_REGISTRY_VERSIONS
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ),
+            },
+        }
+
+        match registry.get("language") {
+            None => self.problem("", "Missing the `language` field"),
+            Some(language) => {
+                if let Some(language) =
+                    self.object("/language", language, &["rust", "haskell", "dotnet"])
+                {
+                    if let Some(rust) = language.get("rust") {
+                        self.packages("/language/rust", rust, Self::dependency_key);
+                    }
+                    if let Some(haskell) = language.get("haskell") {
+                        self.packages("/language/haskell", haskell, Self::haskell_package);
+                    }
+                    if let Some(dotnet) = language.get("dotnet") {
+                        self.packages("/language/dotnet", dotnet, Self::nuget_package);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A language's section, whose dependency keys are checked with `check_key`
+    fn packages(&mut self, path: &str, value: &Value, check_key: fn(&mut Self, &str, &str)) {
+        let Some(language) = self.object(path, value, &["default", "dependencies"]) else {
+            return;
+        };
+        if let Some(default) = language.get("default") {
+            self.target(&pointer(path, "default"), default, TARGET_FIELDS);
+        }
+        let Some(dependencies) = language.get("dependencies") else {
+            return;
+        };
+        let path = pointer(path, "dependencies");
+        // Every key is a package, so none is unknown
+        let Some(dependencies) = dependencies.as_object() else {
+            self.problem(&path, "Expected an object");
+            return;
+        };
+        for (key, dependency) in dependencies {
+            let path = pointer(&path, key);
+            check_key(self, &path, key);
+            self.dependency(&path, dependency);
+        }
+    }
+
+    /// A NuGet package ID: letters, digits, `.`, `-`, and `_`, eg `Microsoft.Data.Sqlite`
+    fn nuget_package(&mut self, path: &str, key: &str) {
+        let is_package = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".-_".contains(c));
+        if !is_package {
+            self.problem(path, format!("`{key}` isn't a NuGet package ID"));
+        }
+    }
+
+    /// A Hackage package name: words of letters and digits, each with a letter, joined by `-`
+    fn haskell_package(&mut self, path: &str, key: &str) {
+        let is_package = key.split('-').all(|word| {
+            word.chars().all(|c| c.is_ascii_alphanumeric())
+                && word.chars().any(|c| c.is_ascii_alphabetic())
+        });
+        if !is_package {
+            self.problem(path, format!("`{key}` isn't a Haskell package name"));
+        }
+    }
+
+    fn dependency_key(&mut self, path: &str, key: &str) {
+        if self.fields_only {
+            return;
+        }
+        let (name, constraint) = match key.split_once('@') {
+            Some((name, constraint)) => (name, Some(constraint)),
+            None => (key, None),
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.problem(path, format!("`{name}` isn't a crate name"));
+        }
+        if let Some(constraint) = constraint {
+            if self.version.is_some_and(|version| version < 2) {
+                self.problem(
+                    path,
+                    "Version constraints in keys need `\"version\": 2` or later",
+                );
+            }
+            if let Err(err) = semver::VersionReq::parse(constraint) {
+                self.problem(
+                    path,
+                    format!("`{constraint}` isn't a version constraint: {err}"),
+                );
+            }
+        }
+    }
+
+    fn dependency(&mut self, path: &str, value: &Value) {
+        let known = [
+            TARGET_FIELDS,
+            EXCLUSION_FIELDS,
+            &["targets", "repositories"],
+        ]
+        .concat();
+        let Some(dependency) = self.target(path, value, &known) else {
+            return;
+        };
+        for field in EXCLUSION_FIELDS {
+            if dependency.contains_key(*field) && !self.fields_only {
+                self.problem(
+                    &pointer(path, field),
+                    "Only a workspace member's `package.metadata.riff` can exclude, the registry's \
+                    exclusions are ignored",
+                );
+            }
+        }
+        if let Some(repositories) = dependency.get("repositories") {
+            let path = pointer(path, "repositories");
+            match repositories.as_array() {
+                Some(repositories) => {
+                    for (index, repository) in repositories.iter().enumerate() {
+                        if !repository.as_str().is_some_and(|url| url.contains("://")) {
+                            self.problem(
+                                &pointer(&path, &index.to_string()),
+                                "Expected a repository URL, eg `https://github.com/owner/repo`",
+                            );
+                        }
+                    }
+                }
+                None => self.problem(&path, "Expected an array of repository URLs"),
+            }
+        }
+        if let Some(targets) = dependency.get("targets") {
+            let path = pointer(path, "targets");
+            match targets.as_object() {
+                // Targets are free-form, users can make custom ones
+                Some(targets) => {
+                    for (target, settings) in targets {
+                        self.target(&pointer(&path, target), settings, TARGET_FIELDS);
+                    }
+                }
+                None => self.problem(&path, "Expected an object"),
+            }
+        }
+    }
+
+    /// Check the settings of a target in `value`, which may also have the `known` fields
+    fn target<'a>(
+        &mut self,
+        path: &str,
+        value: &'a Value,
+        known: &[&str],
+    ) -> Option<&'a Map<String, Value>> {
+        let settings = self.object(path, value, known)?;
+        if self.fields_only {
+            return Some(settings);
+        }
+        for field in ["build-inputs", "runtime-inputs"] {
+            let Some(inputs) = settings.get(field) else {
+                continue;
+            };
+            let path = pointer(path, field);
+            let Some(inputs) = inputs.as_array() else {
+                self.problem(&path, "Expected an array of attribute names");
+                continue;
+            };
+            for (index, input) in inputs.iter().enumerate() {
+                match input.as_str() {
+                    Some(input) if is_input(input) => {}
+                    Some(input) => self.problem(
+                        &pointer(&path, &index.to_string()),
+                        format!(
+                            "`{input}` isn't a Nixpkgs attribute path (eg `openssl` or \
+                            `darwin.apple_sdk.frameworks.Security`) or a flake reference (eg \
+                            `github:owner/repo#package`)"
+                        ),
+                    ),
+                    None => self.problem(
+                        &pointer(&path, &index.to_string()),
+                        "Expected an attribute name",
+                    ),
+                }
+            }
+        }
+        if let Some(variables) = settings.get("environment-variables") {
+            let path = pointer(path, "environment-variables");
+            match variables.as_object() {
+                Some(variables) => {
+                    for name in variables.keys() {
+                        if !is_variable_name(name) {
+                            self.problem(
+                                &pointer(&path, name),
+                                format!("`{name}` isn't an environment variable name"),
+                            );
+                        }
+                    }
+                }
+                None => self.problem(&path, "Expected an object"),
+            }
+        }
+        Some(settings)
+    }
+}
+
+/// Whether `input` is a Nixpkgs attribute path, or a flake reference to a package
+fn is_input(input: &str) -> bool {
+    if let Some((flake, attr)) = input.split_once('#') {
+        return !flake.is_empty() && !attr.is_empty();
+    }
+    input.split('.').all(|segment| {
+        segment.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-'".contains(c))
+    })
+}
+
+fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The JSON pointer to `key` in the value at `path`
+fn pointer(path: &str, key: &str) -> String {
+    format!("{path}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn quoted(names: &[&str]) -> String {
+    names
+        .iter()
+        .map(|name| format!("`{name}`"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_registries_pass() -> Result<(), serde_json::Error> {
+        let problems = validate(
+            r#"{
+                "version": 2,
+                "language": {
+                    "rust": {
+                        "default": { "build-inputs": ["rustc", "cargo"] },
+                        "dependencies": {
+                            "openssl-sys@<0.9.60": {
+                                "build-inputs": ["openssl_1_1", "pkg-config"],
+                                "environment-variables": { "OPENSSL_NO_VENDOR": "1" }
+                            },
+                            "security-framework-sys": {
+                                "targets": {
+                                    "aarch64-apple-darwin": {
+                                        "build-inputs": ["darwin.apple_sdk.frameworks.Security"]
+                                    }
+                                }
+                            },
+                            "acme-sys": { "runtime-inputs": ["github:acme/nix#libacme"] },
+                            "librocksdb-sys": {
+                                "build-inputs": ["rocksdb"],
+                                "repositories": ["https://github.com/rust-rocksdb/rust-rocksdb"]
+                            }
+                        }
+                    },
+                    "haskell": {
+                        "default": { "build-inputs": ["ghc", "cabal-install"] },
+                        "dependencies": {
+                            "postgresql-libpq": { "build-inputs": ["postgresql", "pkg-config"] },
+                            "HsOpenSSL": { "build-inputs": ["openssl"] }
+                        }
+                    },
+                    "dotnet": {
+                        "default": { "build-inputs": ["dotnet-sdk"] },
+                        "dependencies": {
+                            "Microsoft.Data.Sqlite": { "runtime-inputs": ["sqlite"] }
+                        }
+                    }
+                }
+            }"#,
+        );
+        assert_eq!(problems, []);
+        // The schema is published as it is
+        let _: Value = serde_json::from_str(JSON_SCHEMA)?;
+        Ok(())
+    }
+
+    #[test]
+    fn problems_are_reported() {
+        let problems = validate(
+            r#"{
+                "version": 1,
+                "language": {
+                    "rust": {
+                        "dependencies": {
+                            "openssl-sys@<0.9.60": {
+                                "build_inputs": ["openssl"],
+                                "targets": {
+                                    "x86_64-unknown-linux-gnu": {
+                                        "runtime-inputs": ["lib openssl"]
+                                    }
+                                }
+                            },
+                            "libz-sys": {
+                                "environment-variables": { "LIBZ SYS STATIC": "1" },
+                                "exclude-build-inputs": ["zlib"],
+                                "repositories": ["rust-lang/libz-sys"]
+                            }
+                        }
+                    },
+                    "haskell": {
+                        "dependencies": {
+                            "zlib-0.6": { "build-inputs": ["zlib"] }
+                        }
+                    },
+                    "dotnet": {
+                        "dependencies": {
+                            "SkiaSharp@2.88": { "runtime-inputs": ["fontconfig"] }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "`/language/rust/dependencies/openssl-sys@<0.9.60`: Version constraints in keys \
+                need `\"version\": 2` or later",
+                "`/language/rust/dependencies/openssl-sys@<0.9.60/build_inputs`: Unknown field \
+                `build_inputs`, did you mean `build-inputs`?",
+                "`/language/rust/dependencies/openssl-sys@<0.9.60/targets/x86_64-unknown-linux-gnu/runtime-inputs/0`: \
+                `lib openssl` isn't a Nixpkgs attribute path (eg `openssl` or \
+                `darwin.apple_sdk.frameworks.Security`) or a flake reference (eg \
+                `github:owner/repo#package`)",
+                "`/language/rust/dependencies/libz-sys/environment-variables/LIBZ SYS STATIC`: \
+                `LIBZ SYS STATIC` isn't an environment variable name",
+                "`/language/rust/dependencies/libz-sys/exclude-build-inputs`: Only a workspace \
+                member's `package.metadata.riff` can exclude, the registry's exclusions are ignored",
+                "`/language/rust/dependencies/libz-sys/repositories/0`: Expected a repository URL, \
+                eg `https://github.com/owner/repo`",
+                "`/language/haskell/dependencies/zlib-0.6`: `zlib-0.6` isn't a Haskell package \
+                name",
+                "`/language/dotnet/dependencies/SkiaSharp@2.88`: `SkiaSharp@2.88` isn't a NuGet \
+                package ID",
+            ]
+        );
+
+        assert_eq!(
+            validate(r#"{"version": 3, "language": {}}"#)[0].to_string(),
+            "`/version`: Unsupported version 3, expected one of 1, 2"
+        );
+    }
+
+    #[test]
+    fn only_unknown_fields_of_metadata_are_reported() {
+        let problems = unknown_dependency_fields(&serde_json::json!({
+            "build_inputs": ["openssl"],
+            "exclude-build-inputs": ["perl"],
+            "targets": {
+                "not-a-triple": { "runtime-inputs": ["lib openssl"], "shellHook": "true" }
+            }
+        }));
+        assert_eq!(
+            problems
+                .iter()
+                .map(|problem| problem.path.as_str())
+                .collect::<Vec<_>>(),
+            ["/build_inputs", "/targets/not-a-triple/shellHook"]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! External detectors: programs named `riff-detector-<name>` on `PATH`, which add to the
+//! environment of projects riff doesn't know how to detect itself.
+//!
+//! riff runs each detector with the project directory as its only argument. A detector which
+//! recognizes the project prints a JSON object on stdout and exits successfully:
+//!
+//! ```json
+//! {
+//!   "language": "elixir",
+//!   "build-inputs": ["elixir", "erlang"],
+//!   "runtime-inputs": ["openssl"],
+//!   "environment-variables": { "MIX_HOME": ".mix" }
+//! }
+//! ```
+//!
+//! Every field is optional, and `shell-hook` is accepted as in `package.metadata.riff`. A detector
+//! which doesn't recognize the project prints nothing (or `null`). One which fails is reported as
+//! a warning, and the environment is detected without it.
+//!
+//! The `environment-variables` and `shell-hook` a detector prints only apply if the project is
+//! trusted (see [`crate::trust`]), since they may come from the project.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::dependency_registry::rust::RustDependencyTargetData;
+use crate::spinner::{self, Phase};
+
+/// The prefix of the names of detector programs
+pub const DETECTOR_PREFIX: &str = "riff-detector-";
+
+/// What a detector found in a project
+#[derive(Debug, Default, Deserialize)]
+pub struct Detected {
+    /// The language the project is in, eg `elixir`
+    #[serde(default)]
+    pub language: Option<String>,
+    /// The inputs and environment variables the project needs
+    #[serde(flatten)]
+    pub settings: RustDependencyTargetData,
+}
+
+/// The detectors in the directories of `path`, a `PATH`-like list, by name. If several
+/// directories have a detector of the same name, the first one's is used, as a shell would.
+pub fn find(path: &OsStr) -> BTreeMap<String, PathBuf> {
+    let mut detectors = BTreeMap::new();
+    for dir in std::env::split_paths(path) {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.len() > DETECTOR_PREFIX.len()
+                && name.starts_with(DETECTOR_PREFIX)
+                && is_executable(&entry.path())
+            {
+                detectors.entry(name).or_insert_with(|| entry.path());
+            }
+        }
+    }
+    detectors
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Run the detector at `detector` on the project in `project_dir`, returning what it found, if
+/// it recognized the project
+pub async fn run(detector: &Path, project_dir: &Path) -> color_eyre::Result<Option<Detected>> {
+    let name = detector
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let _timer = crate::timings::start(&name);
+    let mut command = Command::new(detector);
+    command
+        .arg(project_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    crate::process_limits::apply(&mut command);
+    crate::execution::inspect(command.as_std());
+
+    let spinner = spinner::start(Phase::Detecting, &format!("Running `{}`", name.cyan()))
+        .wrap_err("Failed to construct progress spinner")?;
+    let output = command
+        .output()
+        .await
+        .wrap_err_with(|| format!("Could not run `{}`", detector.display()))?;
+    spinner.finish_and_clear();
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "It exited with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    parse(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse what a detector printed
+fn parse(stdout: &str) -> color_eyre::Result<Option<Detected>> {
+    if stdout.trim().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(stdout).wrap_err("It printed something other than a JSON object")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detector_output() -> eyre::Result<()> {
+        let detected = parse(
+            r#"{
+                "language": "elixir",
+                "build-inputs": ["elixir", "erlang"],
+                "environment-variables": { "MIX_HOME": ".mix" }
+            }"#,
+        )?
+        .ok_or_else(|| eyre!("Nothing was detected"))?;
+        assert_eq!(detected.language.as_deref(), Some("elixir"));
+        assert!(detected.settings.build_inputs.contains("erlang"));
+        assert_eq!(
+            detected.settings.environment_variables.get("MIX_HOME"),
+            Some(&".mix".to_string())
+        );
+
+        assert!(parse("\n")?.is_none());
+        assert!(parse("null")?.is_none());
+        assert!(parse("Detected Elixir!").is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn detectors_on_path() -> eyre::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let first = tempfile::TempDir::new()?;
+        let second = tempfile::TempDir::new()?;
+        let write_detector = |dir: &Path, name: &str, script: &str| -> std::io::Result<()> {
+            let path = dir.join(name);
+            std::fs::write(&path, script)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+        };
+        write_detector(
+            first.path(),
+            "riff-detector-elixir",
+            "#!/bin/sh\n[ -f \"$1/mix.exs\" ] && echo '{\"build-inputs\": [\"elixir\"]}'\nexit 0\n",
+        )?;
+        write_detector(second.path(), "riff-detector-elixir", "#!/bin/sh\nexit 1\n")?;
+        write_detector(second.path(), "riff-detector-broken", "#!/bin/sh\nexit 3\n")?;
+        // Not executable, so not a detector
+        std::fs::write(second.path().join("riff-detector-notes"), "")?;
+        let path = std::env::join_paths([first.path(), second.path()])?;
+
+        let detectors = find(&path);
+        assert_eq!(
+            detectors.keys().collect::<Vec<_>>(),
+            ["riff-detector-broken", "riff-detector-elixir"]
+        );
+        assert_eq!(
+            detectors["riff-detector-elixir"],
+            first.path().join("riff-detector-elixir")
+        );
+
+        let project = tempfile::TempDir::new()?;
+        let elixir = &detectors["riff-detector-elixir"];
+        assert!(run(elixir, project.path()).await?.is_none());
+        std::fs::write(project.path().join("mix.exs"), "")?;
+        let detected = run(elixir, project.path())
+            .await?
+            .ok_or_else(|| eyre!("Nothing was detected"))?;
+        assert!(detected.settings.build_inputs.contains("elixir"));
+
+        assert!(run(&detectors["riff-detector-broken"], project.path())
+            .await
+            .is_err());
+        Ok(())
+    }
+}
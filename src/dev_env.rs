@@ -1,20 +1,179 @@
 //! The developer environment setup.
 
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
-use eyre::{eyre, WrapErr};
+use clap::Args;
+use eyre::WrapErr;
 use itertools::Itertools;
 use owo_colors::OwoColorize;
+use target_lexicon::Triple;
 use tokio::process::Command;
 
 use crate::cargo_metadata::CargoMetadata;
+use crate::dependency_registry::rust::RustDependencyData;
+use crate::dependency_registry::schema::Problem;
 use crate::dependency_registry::DependencyRegistry;
-use crate::spinner::SimpleSpinner;
+use crate::dotnet::PinnedSdk;
+use crate::errors::{ErrorCode, RiffError};
+use crate::offline::OfflineMode;
+use crate::project_config::{ConflictStrategy, Exclusions, Profile, DEFAULT_NIXPKGS};
+use crate::riff_lock::{sha256_hex, LockedEnvironment};
+use crate::spinner::{self, Phase};
+use crate::warnings::{Warning, WarningCode};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+/// Inputs and environment variables added to the detected environment on the command line, to try
+/// them out without editing any configuration
+#[derive(Debug, Clone, Default, Args)]
+pub struct EnvironmentAdditions {
+    /// A Nixpkgs package to add to the environment's build inputs (can be repeated)
+    #[clap(long = "input", value_name = "PKG")]
+    pub inputs: Vec<String>,
+    /// An environment variable to set in the environment (can be repeated)
+    #[clap(long = "env", value_name = "NAME=VALUE", value_parser = parse_environment_variable)]
+    pub environment_variables: Vec<(String, String)>,
+}
+
+/// Parse a `NAME=VALUE` argument
+pub(crate) fn parse_environment_variable(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("`{arg}` should be `NAME=VALUE`")),
+    }
+}
+
+/// The inputs of the environment of a directory riff doesn't recognize, with `--allow-empty`
+const BASE_ENVIRONMENT_INPUTS: [&str; 3] = ["git", "gnumake", "pkg-config"];
+
+/// Where `--nixgl` takes its OpenGL and Vulkan wrappers from
+const NIXGL_FLAKE: &str = "github:nix-community/nixGL";
+
+/// Runtime inputs which load the host's OpenGL drivers
+const OPENGL_RUNTIME_INPUTS: &[&str] = &["libGL", "libglvnd", "mesa"];
+
+/// Runtime inputs which load the host's Vulkan drivers
+const VULKAN_RUNTIME_INPUTS: &[&str] = &["vulkan-loader"];
+
+/// nixGL's wrappers for OpenGL and Vulkan (using Mesa's drivers), which `riff run --nixgl` runs
+/// commands through
+pub(crate) const NIXGL_WRAPPERS: [&str; 2] = ["nixGLIntel", "nixVulkanIntel"];
+
+/// The first line of a generated (not ejected) flake, marking it as riff's so `riff clean` and
+/// `riff generate` know they may replace it (see [`crate::emitters::GENERATED_MARKER`])
+const GENERATED_FLAKE_MARKER: &str =
+    "# Generated by riff from the project's dependencies, regenerate it rather than editing it";
+
+/// What the header of a generated flake records about how it was generated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedHeader {
+    pub riff_version: String,
+    /// The format version of the registry the environment was detected with
+    pub registry_version: usize,
+    /// A hash of what the flake provides, see [`DevEnvironment::inputs_sha256`]
+    pub inputs_sha256: String,
+    /// A hash of the rest of the flake, to tell whether it was edited since
+    pub flake_sha256: String,
+}
+
+impl GeneratedHeader {
+    fn render(&self) -> String {
+        format!(
+            "{GENERATED_FLAKE_MARKER}\n\
+            # riff-version: {}\n\
+            # registry-version: {}\n\
+            # inputs-sha256: {}\n\
+            # flake-sha256: {}\n",
+            self.riff_version, self.registry_version, self.inputs_sha256, self.flake_sha256
+        )
+    }
+
+    /// The header of the flake `contents`, if riff generated it, and the rest of the flake
+    pub fn split(contents: &str) -> Option<(Self, &str)> {
+        let mut lines = contents.split_inclusive('\n');
+        if lines.next()?.trim_end() != GENERATED_FLAKE_MARKER {
+            return None;
+        }
+        let mut header_len = GENERATED_FLAKE_MARKER.len() + 1;
+        let mut fields = HashMap::new();
+        for line in lines {
+            let Some((key, value)) = line
+                .strip_prefix("# ")
+                .and_then(|field| field.trim_end().split_once(": "))
+            else {
+                break;
+            };
+            fields.insert(key, value);
+            header_len += line.len();
+        }
+        let header = Self {
+            riff_version: fields.get("riff-version")?.to_string(),
+            registry_version: fields.get("registry-version")?.parse().ok()?,
+            inputs_sha256: fields.get("inputs-sha256")?.to_string(),
+            flake_sha256: fields.get("flake-sha256")?.to_string(),
+        };
+        Some((header, contents.get(header_len..)?))
+    }
+
+    /// Whether `flake`, the rest of the flake this is the header of, was edited since riff
+    /// generated it
+    pub fn is_edited(&self, flake: &str) -> bool {
+        sha256_hex(flake.as_bytes()) != self.flake_sha256
+    }
+}
+
+const EJECTED_FLAKE_HEADER: &str = "\
+# The development environment for this project, originally generated by inspecting its
+# dependencies. Inputs and environment variables are annotated with the dependencies needing them.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, clap::ValueEnum)]
 pub enum DetectedLanguage {
     Rust,
+    Haskell,
+    Dotnet,
+    /// A language detected by a `riff-detector-*` program, which is left unnamed here so
+    /// telemetry doesn't report the names of people's own detectors
+    #[value(skip)]
+    Plugin,
+}
+
+impl DetectedLanguage {
+    /// The name of the language's own dev shell, `devShells.<system>.<name>`, if it gets one
+    pub fn shell_name(self) -> Option<&'static str> {
+        match self {
+            DetectedLanguage::Rust => Some("rust"),
+            DetectedLanguage::Haskell => Some("haskell"),
+            DetectedLanguage::Dotnet => Some("dotnet"),
+            DetectedLanguage::Plugin => None,
+        }
+    }
+}
+
+/// What detecting one language added to the environment, to give it a dev shell of its own
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LanguageContribution {
+    /// The inputs and environment variables the language's dependencies provide
+    names: HashSet<String>,
+    shell_hooks: HashSet<String>,
+}
+
+/// Where the `packages.default` of [`FlakeOutputs`] builds the project from, relative to the flake
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageSource {
+    /// The root of the Cargo workspace, which has its `Cargo.lock`
+    pub workspace_root: PathBuf,
+    /// The member to build, relative to the workspace root, unless that's the root's own package
+    pub member: Option<PathBuf>,
+}
+
+/// Flake outputs generated alongside the dev shell, see `riff generate --with-package`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlakeOutputs {
+    /// A `packages.default` building the project
+    pub package: bool,
+    /// `checks` building the package and checking formatting
+    pub checks: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -22,8 +181,57 @@ pub struct DevEnvironment<'a> {
     pub(crate) registry: &'a DependencyRegistry,
     pub(crate) build_inputs: HashSet<String>,
     pub(crate) environment_variables: HashMap<String, String>,
+    /// The environment variables whose values are taken literally rather than as the contents of
+    /// a Nix string, which those from the registry and the project's configuration are (eg
+    /// `${rustPlatform.rustLibSrc}`): those set with `--env` or by a `riff.toml` profile
+    literal_variables: HashSet<String>,
     pub(crate) runtime_inputs: HashSet<String>,
     pub(crate) detected_languages: HashSet<DetectedLanguage>,
+    /// What each detected language added, see [`DevEnvironment::only`]
+    pub(crate) language_contributions: HashMap<DetectedLanguage, LanguageContribution>,
+    /// The language being detected, which anything added is attributed to
+    detecting: Option<DetectedLanguage>,
+    /// The sources (eg a crate's registry entry) of each input and environment variable
+    pub(crate) provenance: HashMap<String, BTreeSet<String>>,
+    /// Shell script fragments run on entering the environment, in the order they were added
+    pub(crate) shell_hooks: Vec<String>,
+    /// The flake reference to take Nix packages from, see `riff.toml`
+    pub(crate) nixpkgs: Option<String>,
+    /// Flakes whose overlays apply to Nixpkgs, as `<flakeref>[#<overlay>]`, see `riff.toml`
+    pub(crate) overlays: Vec<String>,
+    /// Advisory warnings found while detecting the environment
+    pub(crate) warnings: Vec<Warning>,
+    /// What each riff registry entry used contributed, for `riff.lock`
+    pub(crate) registry_entries: BTreeMap<String, LockedEnvironment>,
+    /// How to resolve environment variables set differently by several sources, see `riff.toml`
+    pub(crate) conflict_strategies: HashMap<String, ConflictStrategy>,
+    /// The source which set each environment variable's current value
+    environment_variable_sources: HashMap<String, String>,
+    /// The name and version of the project's package, for `extra_outputs`
+    pub(crate) project_name: Option<String>,
+    pub(crate) project_version: Option<String>,
+    /// The outputs to generate besides the dev shell
+    pub(crate) extra_outputs: FlakeOutputs,
+    /// What to filter out once every dependency has been applied, from `riff.toml` and the
+    /// workspace members' `package.metadata.riff`
+    pub(crate) exclusions: Exclusions,
+    /// The workspace member to scope the environment to, see `--package`
+    pub(crate) package: Option<String>,
+    /// The Cargo manifest to read instead of the project's own, see `--manifest-path`
+    pub(crate) manifest_path: Option<PathBuf>,
+    /// The target whose target-specific dependencies and registry settings apply, see `--target`
+    pub(crate) target: Triple,
+    /// How much the network may be used, see `--offline`
+    pub(crate) offline: OfflineMode,
+    /// Run `cargo metadata` again instead of using its cached output, see `--refresh`
+    pub(crate) refresh: bool,
+    /// Where the `extra_outputs` build the project from
+    pub(crate) package_source: PackageSource,
+    /// A flake template to render instead of riff's own, see `riff-template.nix`
+    pub(crate) template: Option<String>,
+    /// Whether the environment variables and shell hooks of the project's own configuration
+    /// apply, see `riff trust`
+    pub(crate) trusted: bool,
 }
 
 // TODO(@cole-h): should this become a trait that the various languages we may support have to implement?
@@ -33,192 +241,1312 @@ impl<'a> DevEnvironment<'a> {
             registry,
             build_inputs: Default::default(),
             environment_variables: Default::default(),
+            literal_variables: Default::default(),
             runtime_inputs: Default::default(),
             detected_languages: Default::default(),
+            language_contributions: Default::default(),
+            detecting: None,
+            provenance: Default::default(),
+            shell_hooks: Default::default(),
+            nixpkgs: None,
+            overlays: Default::default(),
+            warnings: registry.warnings(),
+            registry_entries: Default::default(),
+            conflict_strategies: Default::default(),
+            environment_variable_sources: Default::default(),
+            project_name: None,
+            project_version: None,
+            extra_outputs: Default::default(),
+            exclusions: Default::default(),
+            package: None,
+            manifest_path: None,
+            target: Triple::host(),
+            offline: Default::default(),
+            refresh: false,
+            package_source: Default::default(),
+            template: None,
+            trusted: true,
         }
     }
     pub fn to_flake(&self) -> String {
-        // TODO: use rnix for generating Nix?
+        let flake = self.render_flake(false);
+        let header = GeneratedHeader {
+            riff_version: env!("CARGO_PKG_VERSION").to_string(),
+            registry_version: self.registry.version(),
+            inputs_sha256: self.inputs_sha256(),
+            flake_sha256: sha256_hex(flake.as_bytes()),
+        };
+        header.render() + &flake
+    }
+
+    /// A hash of what the environment provides and where its packages come from, which changes
+    /// when the flake generated for it would
+    pub fn inputs_sha256(&self) -> String {
+        let inputs = serde_json::json!({
+            "environment": LockedEnvironment::from_dev_env(self),
+            "nixpkgs": self.nixpkgs,
+            "overlays": self.overlays,
+        });
+        sha256_hex(inputs.to_string().as_bytes())
+    }
+
+    /// Render a standalone flake, without riff-specific markers, for maintaining by hand
+    pub fn to_ejected_flake(&self) -> String {
+        self.render_flake(true)
+    }
+
+    fn render_flake(&self, ejected: bool) -> String {
+        let header = if ejected { EJECTED_FLAKE_HEADER } else { "" };
+        let placeholders = self.placeholders(ejected);
+        if let Some(template) = &self.template {
+            return header.to_string() + &render_template(template, &placeholders.entries());
+        }
         format!(
             include_str!("flake-template.inc"),
-            build_inputs = self.build_inputs.iter().join(" "),
-            environment_variables = self
-                .environment_variables
+            header = header,
+            nixpkgs = placeholders.nixpkgs,
+            pinned_inputs = placeholders.pinned_inputs,
+            overlays = placeholders.overlays,
+            default_shell = render_shell(&placeholders),
+            language_shells = self.render_language_shells(ejected),
+            outputs = placeholders.outputs,
+        )
+    }
+
+    /// Render a `devShells.<system>.<name>` for each detected language, narrowed to what it needs
+    /// (see [`DevEnvironment::only`]), if the project has several
+    fn render_language_shells(&self, ejected: bool) -> String {
+        let language_shells = self.language_shells();
+        if language_shells.len() < 2 {
+            return "".to_string();
+        }
+        language_shells
+            .into_iter()
+            .filter_map(|(name, language)| {
+                let mut dev_env = self.clone();
+                dev_env.only(language).ok()?;
+                let mut placeholders = dev_env.placeholders(ejected);
+                placeholders.shell_name = format!("{}-{name}", placeholders.shell_name);
+                Some(format!(
+                    "\n        {name} = {};",
+                    render_shell(&placeholders)
+                ))
+            })
+            .join("")
+    }
+
+    /// What goes into the placeholders of the flake template
+    fn placeholders(&self, ejected: bool) -> FlakePlaceholders {
+        // TODO: use rnix for generating Nix?
+        let sources = |name: &str| match self.provenance.get(name) {
+            Some(sources) if ejected => Some(sources.iter().join(", ")),
+            _ => None,
+        };
+        let pinned_flakes = self.pinned_flakes();
+        FlakePlaceholders {
+            nixpkgs: self
+                .nixpkgs
+                .as_deref()
+                .unwrap_or(DEFAULT_NIXPKGS)
+                .to_string(),
+            pinned_inputs: pinned_flakes
                 .iter()
-                .map(|(name, value)| format!("\"{name}\" = \"{value}\";"))
-                .join("\n"),
-            ld_library_path = if !self.runtime_inputs.is_empty() {
+                .map(|(flake, name)| (name.clone(), flake.to_string()))
+                .chain(self.overlays.iter().enumerate().map(|(index, overlay)| {
+                    (
+                        format!("overlay{index}"),
+                        split_overlay(overlay).0.to_string(),
+                    )
+                }))
+                .map(|(name, flake)| {
+                    format!("\n  inputs.{name}.url = \"{}\";", escape_nix_string(&flake))
+                })
+                .join(""),
+            overlays: if self.overlays.is_empty() {
+                "".to_string()
+            } else {
                 format!(
-                    "\"LD_LIBRARY_PATH\" = \"{}\";",
-                    self.runtime_inputs
+                    " overlays = [ {} ];",
+                    self.overlays
                         .iter()
-                        .map(|v| format!("${{lib.getLib {v}}}/lib"))
-                        .join(":")
+                        .enumerate()
+                        .map(|(index, overlay)| format!(
+                            "inputs.overlay{index}.overlays.\"{}\"",
+                            escape_nix_string(split_overlay(overlay).1)
+                        ))
+                        .join(" ")
                 )
+            },
+            shell_name: if ejected { "dev-shell" } else { "riff-shell" }.to_string(),
+            build_inputs: if ejected {
+                self.build_inputs
+                    .iter()
+                    .sorted()
+                    .map(|input| {
+                        let expression = package_expression(input, &pinned_flakes);
+                        match sources(input) {
+                            Some(sources) => format!("{expression} # {sources}"),
+                            None => expression,
+                        }
+                    })
+                    .join("\n              ")
             } else {
+                self.build_inputs
+                    .iter()
+                    .sorted()
+                    .map(|input| package_expression(input, &pinned_flakes))
+                    .join(" ")
+            },
+            environment_variables: self
+                .environment_variables
+                .iter()
+                .sorted()
+                .map(|(name, value)| {
+                    let value = if self.literal_variables.contains(name) {
+                        escape_nix_string(value)
+                    } else {
+                        value.clone()
+                    };
+                    let comment = match sources(name) {
+                        Some(sources) => format!("# {sources}\n            "),
+                        None => "".to_string(),
+                    };
+                    format!("{comment}\"{}\" = \"{value}\";", escape_nix_string(name))
+                })
+                .join("\n            "),
+            ld_library_path: self.library_path("LD_LIBRARY_PATH", ejected),
+            // macOS ignores `LD_LIBRARY_PATH`. The fallback path is only searched after the
+            // system's own locations, so it can't shadow system libraries.
+            dyld_fallback_library_path: self.library_path("DYLD_FALLBACK_LIBRARY_PATH", false),
+            shell_hook: if self.shell_hooks.is_empty() {
                 "".to_string()
+            } else {
+                format!(
+                    "shellHook = \"\n{}\n\";",
+                    escape_nix_string(&self.shell_hooks.join("\n"))
+                )
+            },
+            outputs: self.render_extra_outputs(),
+        }
+    }
+
+    /// Render the `extra_outputs`, which build the project from the directory the flake is in
+    fn render_extra_outputs(&self) -> String {
+        let FlakeOutputs { package, checks } = self.extra_outputs;
+        let mut outputs = String::new();
+        if package {
+            outputs += &format!(
+                r#"
+      packages = forAllSystems ({{ system, pkgs, ... }}: {{
+        default = pkgs.rustPlatform.buildRustPackage {{
+          pname = "{name}";
+          version = "{version}";
+          src = {src};
+          cargoLock.lockFile = {lock_file};{member}
+          inherit (self.devShells.${{system}}.default) buildInputs nativeBuildInputs;
+        }};
+      }});
+"#,
+                name = escape_nix_string(self.project_name.as_deref().unwrap_or("project")),
+                version = escape_nix_string(self.project_version.as_deref().unwrap_or("0.0.0")),
+                src = flake_path(&self.package_source.workspace_root),
+                lock_file = flake_path(&self.package_source.workspace_root.join("Cargo.lock")),
+                member = match &self.package_source.member {
+                    Some(member) => format!(
+                        "\n          buildAndTestSubdir = \"{}\";",
+                        escape_nix_string(&member.to_string_lossy())
+                    ),
+                    None => "".to_string(),
+                },
+            );
+        }
+        if checks {
+            outputs += r#"
+      checks = forAllSystems ({ system, pkgs, ... }: {
+        package = self.packages.${system}.default;
+        fmt = pkgs.runCommand "cargo-fmt-check" { nativeBuildInputs = [ pkgs.cargo pkgs.rustfmt ]; } ''
+          cd ${./.}
+          cargo fmt --check
+          touch $out
+        '';
+      });
+"#;
+        }
+        outputs
+    }
+
+    /// Set an environment variable on behalf of `source`, resolving conflicts with a value set by
+    /// another source according to `conflict_strategies`.
+    ///
+    /// Without a configured strategy, path-like variables (eg `PKG_CONFIG_PATH`) are appended to,
+    /// and any other variable takes the last value set with a warning.
+    pub(crate) fn set_environment_variable(&mut self, name: &str, value: &str, source: &str) {
+        let existing_source = self.environment_variable_sources.get(name).cloned();
+        let existing_value = self.environment_variables.get(name).cloned();
+        let (existing_source, existing_value) = match (existing_source, existing_value) {
+            // The same source may override itself, eg with a target-specific value.
+            (Some(existing_source), Some(existing_value))
+                if existing_source != source && existing_value != value =>
+            {
+                (existing_source, existing_value)
+            }
+            _ => {
+                self.environment_variables
+                    .insert(name.to_string(), value.to_string());
+                self.environment_variable_sources
+                    .insert(name.to_string(), source.to_string());
+                return;
             }
+        };
+
+        let configured = self.conflict_strategies.get(name).copied();
+        let strategy = configured.unwrap_or_else(|| ConflictStrategy::default_for(name));
+        tracing::debug!(
+            key = name,
+            existing_value,
+            new_value = value,
+            ?strategy,
+            "Resolving conflicting environment variable"
+        );
+        let resolved = match strategy {
+            ConflictStrategy::First => return,
+            ConflictStrategy::Last => value.to_string(),
+            ConflictStrategy::Append => {
+                let mut entries = existing_value.split(':').collect::<Vec<_>>();
+                for entry in value.split(':') {
+                    if !entries.contains(&entry) {
+                        entries.push(entry);
+                    }
+                }
+                entries.join(":")
+            }
+        };
+        if configured.is_none() && strategy == ConflictStrategy::Last {
+            self.warnings.push(Warning::new(
+                WarningCode::EnvironmentVariableConflict,
+                format!(
+                    "`{name}` is set to `{existing_value}` by {existing_source} and to `{value}` \
+                    by {source}, so `{value}` is used. Choose a strategy for it under \
+                    `[conflicts]` in `riff.toml` to silence this."
+                ),
+            ));
+        }
+        self.environment_variables
+            .insert(name.to_string(), resolved);
+        self.environment_variable_sources
+            .insert(name.to_string(), source.to_string());
+    }
+
+    /// Add a shell hook fragment, unless an identical one was already added
+    pub(crate) fn add_shell_hook(&mut self, shell_hook: &str) {
+        let shell_hook = shell_hook.trim_end();
+        if shell_hook.is_empty() {
+            return;
+        }
+        if let Some(language) = self.detecting {
+            self.language_contributions
+                .entry(language)
+                .or_default()
+                .shell_hooks
+                .insert(shell_hook.to_string());
+        }
+        if !self.shell_hooks.iter().any(|hook| hook == shell_hook) {
+            self.shell_hooks.push(shell_hook.to_string());
+        }
+    }
+
+    /// Render the `runtime_inputs` as a library search path assigned to `variable`
+    fn library_path(&self, variable: &str, with_sources: bool) -> String {
+        if self.runtime_inputs.is_empty() {
+            return "".to_string();
+        }
+        let runtime_inputs = self.runtime_inputs.iter().sorted().collect::<Vec<_>>();
+        let pinned_flakes = self.pinned_flakes();
+        format!(
+            "{comments}\"{variable}\" = \"{}\";",
+            runtime_inputs
+                .iter()
+                .map(|v| format!(
+                    "${{lib.getLib {}}}/lib",
+                    package_expression(v, &pinned_flakes)
+                ))
+                .join(":"),
+            comments = runtime_inputs
+                .iter()
+                .filter(|_| with_sources)
+                .filter_map(|v| {
+                    self.provenance.get(v.as_str()).map(|sources| {
+                        format!("# {v}: {}\n            ", sources.iter().join(", "))
+                    })
+                })
+                .join(""),
         )
     }
 
+    /// The flakes which pinned inputs (see `split_pinned_input`) come from, with the names of the
+    /// flake inputs they are bound to
+    ///
+    /// Each name only depends on its flake, so the entries of a `flake.lock` (or a `riff.lock`)
+    /// stay with their flakes as pins are added or removed, eg by a profile or `--input`.
+    fn pinned_flakes(&self) -> BTreeMap<&str, String> {
+        self.build_inputs
+            .iter()
+            .chain(&self.runtime_inputs)
+            .filter_map(|input| split_pinned_input(input).map(|(flake, _)| flake))
+            .map(|flake| (flake, pinned_input_name(flake)))
+            .collect()
+    }
+
+    /// Whether any input is pinned to a flake, which the generated flake then takes as an input
+    pub(crate) fn has_pinned_inputs(&self) -> bool {
+        !self.pinned_flakes().is_empty()
+    }
+
+    /// The flakes inputs are pinned to
+    pub(crate) fn pinned_inputs(&self) -> BTreeSet<String> {
+        self.pinned_flakes().into_keys().map(String::from).collect()
+    }
+
+    /// Adjust the detected environment with a profile from `riff.toml`, removing before adding
+    pub(crate) fn apply_profile(&mut self, name: &str, profile: &Profile) {
+        tracing::debug!(profile = name, "Applying profile");
+        self.build_inputs
+            .retain(|input| !profile.remove_build_inputs.contains(input));
+        self.runtime_inputs
+            .retain(|input| !profile.remove_runtime_inputs.contains(input));
+        self.environment_variables
+            .retain(|name, _| !profile.remove_environment_variables.contains(name));
+
+        self.build_inputs
+            .extend(profile.build_inputs.iter().cloned());
+        self.runtime_inputs
+            .extend(profile.runtime_inputs.iter().cloned());
+        self.environment_variables.extend(
+            profile
+                .environment_variables
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        self.literal_variables
+            .extend(profile.environment_variables.keys().cloned());
+        self.record_provenance(
+            &format!("`{name}` profile (`riff.toml`)"),
+            profile
+                .build_inputs
+                .iter()
+                .chain(&profile.runtime_inputs)
+                .chain(profile.environment_variables.keys())
+                .cloned(),
+        );
+    }
+
+    /// Add the `--input`s and `--env`s given on the command line, over everything else
+    pub(crate) fn apply_additions(&mut self, additions: &EnvironmentAdditions) {
+        self.build_inputs.extend(additions.inputs.iter().cloned());
+        self.record_provenance("`--input`", additions.inputs.iter().cloned());
+        for (name, value) in &additions.environment_variables {
+            self.environment_variables
+                .insert(name.clone(), value.clone());
+            self.literal_variables.insert(name.clone());
+            self.record_provenance("`--env`", [name.clone()]);
+        }
+    }
+
+    /// Give a directory without a recognized project the base environment of `--allow-empty`
+    pub(crate) fn add_base_environment(&mut self) {
+        let build_inputs = BASE_ENVIRONMENT_INPUTS.map(String::from);
+        self.record_provenance("`--allow-empty`", build_inputs.iter().cloned());
+        self.build_inputs.extend(build_inputs);
+    }
+
+    /// Add nixGL's wrappers for the OpenGL and Vulkan libraries among the runtime inputs, see
+    /// `riff run --nixgl`
+    pub(crate) fn add_nixgl(&mut self) {
+        let uses = |libraries: &[&str]| {
+            self.runtime_inputs
+                .iter()
+                .any(|input| libraries.contains(&input.as_str()))
+        };
+        let [opengl_wrapper, vulkan_wrapper] = NIXGL_WRAPPERS;
+        let wrappers = [
+            uses(OPENGL_RUNTIME_INPUTS).then_some(opengl_wrapper),
+            uses(VULKAN_RUNTIME_INPUTS).then_some(vulkan_wrapper),
+        ];
+        let build_inputs = wrappers
+            .into_iter()
+            .flatten()
+            .map(|wrapper| format!("{NIXGL_FLAKE}#{wrapper}"))
+            .collect::<Vec<_>>();
+        if build_inputs.is_empty() {
+            tracing::debug!("No OpenGL or Vulkan libraries for nixGL to wrap");
+        }
+        self.record_provenance("`--nixgl`", build_inputs.iter().cloned());
+        self.build_inputs.extend(build_inputs);
+    }
+
+    /// Add the packages picked with `riff generate --interactive`
+    pub(crate) fn add_picked(&mut self, build_inputs: Vec<String>) {
+        self.record_provenance("picked with `--interactive`", build_inputs.iter().cloned());
+        self.build_inputs.extend(build_inputs);
+    }
+
+    /// Filter the `exclusions` out of the environment
+    pub(crate) fn apply_exclusions(&mut self) {
+        let Exclusions {
+            exclude_build_inputs,
+            exclude_runtime_inputs,
+            unset_environment_variables,
+        } = &self.exclusions;
+        tracing::debug!(exclusions = ?self.exclusions, "Applying exclusions");
+        self.build_inputs
+            .retain(|input| !exclude_build_inputs.contains(input));
+        self.runtime_inputs
+            .retain(|input| !exclude_runtime_inputs.contains(input));
+        self.environment_variables
+            .retain(|name, _| !unset_environment_variables.contains(name));
+    }
+
+    /// Warn about `targets` keys in `section` (declared by `owner`) which aren't target triples
+    fn check_targets(&mut self, owner: &str, section: &str, config: &RustDependencyData) {
+        for target in config.targets.keys() {
+            if target.parse::<target_lexicon::Triple>().is_err() {
+                self.warnings.push(Warning::new(
+                    WarningCode::UnknownTarget,
+                    format!(
+                        "{owner} declares `{section}.targets.{target}`, but `{target}` is not a \
+                        target triple, so it will never be used."
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Warn about the `unknown_fields` of the `section` table (of the package `owner`)
+    fn check_fields(&mut self, section: &str, owner: Option<&str>, unknown_fields: &[Problem]) {
+        for problem in unknown_fields {
+            // The table the field is in, eg `.targets.x86_64-unknown-linux-gnu`
+            let table = problem
+                .path
+                .rsplit_once('/')
+                .map_or("", |(table, _)| table)
+                .replace('/', ".");
+            let owner = owner
+                .map(|owner| format!(" of `{owner}`"))
+                .unwrap_or_default();
+            self.warnings.push(Warning::new(
+                WarningCode::UnknownField,
+                format!("In `{section}{table}`{owner}: {}", problem.message),
+            ));
+        }
+    }
+
+    fn record_provenance(&mut self, source: &str, names: impl IntoIterator<Item = String>) {
+        for name in names {
+            if let Some(language) = self.detecting {
+                self.language_contributions
+                    .entry(language)
+                    .or_default()
+                    .names
+                    .insert(name.clone());
+            }
+            self.provenance
+                .entry(name)
+                .or_default()
+                .insert(source.to_string());
+        }
+    }
+
+    /// Attribute what's added from now on to `language`
+    fn start_detecting(&mut self, language: DetectedLanguage) {
+        self.detected_languages.insert(language);
+        self.detecting = Some(language);
+    }
+
+    /// The detected languages which get dev shells of their own, by name
+    fn language_shells(&self) -> BTreeMap<&'static str, DetectedLanguage> {
+        self.detected_languages
+            .iter()
+            .filter_map(|language| Some((language.shell_name()?, *language)))
+            .collect()
+    }
+
+    /// Narrow the environment to what `language` needs, leaving out what only the project's other
+    /// languages added. What no language added in particular (eg `riff.toml` profiles or
+    /// `riff-detector-*` programs) is kept.
+    pub(crate) fn only(&mut self, language: DetectedLanguage) -> color_eyre::Result<()> {
+        if !self.detected_languages.contains(&language) {
+            let detected = self.language_shells().into_keys().join(", ");
+            return Err(eyre::eyre!(
+                "`--only {}` doesn't match a language of the project (it has: {})",
+                language.shell_name().unwrap_or("plugin"),
+                if detected.is_empty() {
+                    "none"
+                } else {
+                    &detected
+                },
+            ));
+        }
+        let own = self
+            .language_contributions
+            .get(&language)
+            .cloned()
+            .unwrap_or_default();
+        let others = self
+            .language_contributions
+            .iter()
+            .filter(|(other, _)| **other != language)
+            .map(|(_, contribution)| contribution.clone())
+            .collect::<Vec<_>>();
+        let belongs = |name: &String| {
+            own.names.contains(name) || !others.iter().any(|other| other.names.contains(name))
+        };
+        self.build_inputs.retain(belongs);
+        self.runtime_inputs.retain(belongs);
+        self.environment_variables.retain(|name, _| belongs(name));
+        self.shell_hooks.retain(|hook| {
+            own.shell_hooks.contains(hook)
+                || !others.iter().any(|other| other.shell_hooks.contains(hook))
+        });
+        self.detected_languages
+            .retain(|detected| *detected == language);
+        Ok(())
+    }
+
     pub async fn detect(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
-        if project_dir.join("Cargo.toml").exists() {
-            self.detected_languages.insert(DetectedLanguage::Rust);
-            self.add_deps_from_cargo(project_dir).await?;
-            Ok(())
-        } else {
-            Err(eyre!(
-                "'{}' does not contain a project recognized by Riff.",
-                project_dir.display()
-            ))
+        let cargo_dir =
+            crate::flake_generator::cargo_dir(project_dir, self.manifest_path.as_deref());
+        if cargo_dir.join("Cargo.toml").exists() {
+            self.start_detecting(DetectedLanguage::Rust);
+            self.add_deps_from_cargo(project_dir, &cargo_dir).await?;
+        }
+        // `--manifest-path` only names a Cargo manifest, so other languages are detected in the
+        // project directory itself
+        if crate::cabal::is_project(project_dir) {
+            self.start_detecting(DetectedLanguage::Haskell);
+            self.add_deps_from_cabal(project_dir).await?;
+        }
+        if crate::dotnet::is_project(project_dir) {
+            self.start_detecting(DetectedLanguage::Dotnet);
+            self.add_deps_from_dotnet(project_dir).await?;
         }
+        // What detectors add is shared by every language's shell
+        self.detecting = None;
+        self.add_deps_from_detectors(project_dir).await?;
+        if self.detected_languages.is_empty() {
+            return Err(RiffError::new(
+                ErrorCode::NoProject,
+                format!(
+                    "`{}` doesn't contain a project recognized by Riff. Try running riff in a \
+                    Rust, Haskell, or .NET project directory, or pass `--allow-empty` for a base \
+                    environment.",
+                    project_dir.display()
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Add what the registry maps the Hackage packages of a Haskell project to
+    #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
+    async fn add_deps_from_cabal(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
+        tracing::debug!("Adding Haskell dependencies...");
+        let dependencies = crate::cabal::dependencies(project_dir).await?;
+        let haskell = self.registry.language().await.haskell.clone();
+        haskell
+            .default
+            .apply(self, "Haskell defaults (riff registry)");
+        self.record_provenance(
+            "Haskell defaults (riff registry)",
+            haskell.default.provides(),
+        );
+
+        for name in &dependencies {
+            let Some(dep_config) = haskell.dependencies.get(name) else {
+                continue;
+            };
+            tracing::debug!(package = %name, "Detected known Haskell package information");
+            let source = format!("`{name}` (riff registry, Haskell)");
+            dep_config.apply(self, &source);
+            self.record_provenance(&source, dep_config.provides(&self.target));
+            self.registry_entries.insert(
+                format!("haskell/{name}"),
+                LockedEnvironment::from_dependency(dep_config, &self.target),
+            );
+        }
+        Ok(())
+    }
+
+    /// Add the .NET SDK the project's `global.json` pins, and what the registry maps the NuGet
+    /// packages of its project files to
+    #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
+    async fn add_deps_from_dotnet(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
+        tracing::debug!("Adding .NET dependencies...");
+        let references = crate::dotnet::package_references(project_dir).await?;
+        let dotnet = self.registry.language().await.dotnet.clone();
+        let mut defaults = dotnet.default.clone();
+        // The SDK `global.json` pins replaces the registry's default one
+        match crate::dotnet::pinned_sdk(project_dir).await? {
+            Some(PinnedSdk {
+                attribute: Some(sdk),
+                ..
+            }) if defaults.build_inputs.contains("dotnet-sdk") => {
+                defaults.build_inputs.remove("dotnet-sdk");
+                tracing::debug!(%sdk, "Using the .NET SDK pinned by `global.json`");
+                defaults.build_inputs.insert(sdk.clone());
+                self.record_provenance("`global.json`", [sdk]);
+            }
+            Some(PinnedSdk {
+                version,
+                attribute: None,
+            }) => self.warnings.push(Warning::new(
+                WarningCode::UnavailableSdk,
+                format!(
+                    "`global.json` pins the .NET SDK {version}, which Nixpkgs no longer has, so \
+                    the default SDK is used instead"
+                ),
+            )),
+            _ => {}
+        }
+        defaults.apply(self, ".NET defaults (riff registry)");
+        self.record_provenance(".NET defaults (riff registry)", defaults.provides());
+
+        for id in &references {
+            let Some((key, dep_config)) = dotnet.dependency(id) else {
+                continue;
+            };
+            tracing::debug!(package = %id, "Detected known NuGet package information");
+            let source = format!("`{key}` (riff registry, .NET)");
+            dep_config.apply(self, &source);
+            self.record_provenance(&source, dep_config.provides(&self.target));
+            self.registry_entries.insert(
+                format!("dotnet/{key}"),
+                LockedEnvironment::from_dependency(dep_config, &self.target),
+            );
+        }
+        Ok(())
     }
 
+    /// Add what the `riff-detector-*` programs on `PATH` find in the project, see
+    /// [`crate::detectors`]
     #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
-    async fn add_deps_from_cargo(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
+    async fn add_deps_from_detectors(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        for (name, detector) in crate::detectors::find(&path) {
+            let detected = match crate::detectors::run(&detector, project_dir).await {
+                Ok(Some(detected)) => detected,
+                Ok(None) => {
+                    tracing::debug!(%name, "The detector didn't recognize the project");
+                    continue;
+                }
+                Err(err) => {
+                    self.warnings.push(Warning::new(
+                        WarningCode::DetectorFailed,
+                        format!("`{}` failed, leaving it out: {err:#}", detector.display()),
+                    ));
+                    continue;
+                }
+            };
+            tracing::debug!(%name, language = ?detected.language, "The detector recognized the project");
+            self.detected_languages.insert(DetectedLanguage::Plugin);
+            let source = match &detected.language {
+                Some(language) => format!("`{name}` ({language})"),
+                None => format!("`{name}`"),
+            };
+            // What a detector prints may come from the project, so its environment variables
+            // and shell hook only apply if the project is trusted, like its own configuration's
+            let mut settings = RustDependencyData {
+                default: detected.settings,
+                ..Default::default()
+            };
+            let overrides = settings.overrides(&self.target);
+            if !crate::trust::check(project_dir, &overrides).await? {
+                self.warnings
+                    .push(crate::trust::untrusted_warning(&source, &overrides));
+                settings = settings.without_overrides();
+            }
+            self.record_provenance(&source, settings.provides(&self.target));
+            settings.apply(self, &source);
+        }
+        Ok(())
+    }
+
+    /// Add what the registry and the project's own configuration contribute for the Cargo
+    /// manifest in `cargo_dir`, whose trust is decided for the project in `project_dir`
+    #[tracing::instrument(skip_all, fields(cargo_dir = %cargo_dir.display()))]
+    async fn add_deps_from_cargo(
+        &mut self,
+        project_dir: &Path,
+        cargo_dir: &Path,
+    ) -> color_eyre::Result<()> {
         tracing::debug!("Adding Cargo dependencies...");
 
+        let cached = if self.refresh {
+            None
+        } else {
+            CargoMetadata::load_cached(cargo_dir).await
+        };
+        let metadata = match cached {
+            Some(metadata) => metadata,
+            None => {
+                let metadata = self.cargo_metadata(cargo_dir).await?;
+                if let Err(err) = metadata.cache(cargo_dir).await {
+                    tracing::debug!(%err, "Could not cache `cargo metadata` output");
+                }
+                metadata
+            }
+        };
+        let overrides = project_overrides(&metadata, &self.target);
+        self.trusted = crate::trust::check(project_dir, &overrides).await?;
+        if !self.trusted {
+            self.warnings.push(crate::trust::untrusted_warning(
+                "The project's configuration",
+                &overrides,
+            ));
+        }
+        self.add_deps_from_cargo_metadata(metadata).await
+    }
+
+    /// Run `cargo metadata` on the project in `project_dir`
+    async fn cargo_metadata(&self, project_dir: &Path) -> color_eyre::Result<CargoMetadata> {
+        let _timer = crate::timings::start("cargo metadata");
         let mut cargo_metadata_command = Command::new("cargo");
         cargo_metadata_command.args(["metadata", "--format-version", "1"]);
         cargo_metadata_command.arg("--manifest-path");
         cargo_metadata_command.arg(project_dir.join("Cargo.toml"));
+        cargo_metadata_command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        crate::process_limits::apply(&mut cargo_metadata_command);
 
         // Infer offline-ness from our stored registry
         if self.registry.offline() {
             cargo_metadata_command.arg("--offline");
         }
 
-        tracing::trace!(command = ?cargo_metadata_command.as_std(), "Running");
-        let spinner = SimpleSpinner::new_with_message(Some(&format!(
-            "Running `{cargo_metadata}`",
-            cargo_metadata = "cargo metadata".cyan()
-        )))
+        let spinner = spinner::start(
+            Phase::Detecting,
+            &format!(
+                "Running `{cargo_metadata}`",
+                cargo_metadata = "cargo metadata".cyan()
+            ),
+        )
         .context("Failed to construct progress spinner")?;
 
-        let cargo_metadata_output = match cargo_metadata_command.output().await {
-            Ok(output) => output,
-            Err(err) => {
-                let err_msg = format!(
-                    "\
-                    Could not execute `{cargo_metadata}`. Is `{cargo}` installed?\n\n\
-                    Get instructions for installing Cargo: {rust_install_url}\
-                    ",
-                    cargo_metadata = "cargo metadata".cyan(),
-                    cargo = "cargo".cyan(),
-                    rust_install_url = "https://www.rust-lang.org/tools/install".blue().underline()
-                );
-                eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-                std::process::exit(1);
-            }
-        };
+        let cargo_metadata_output = crate::command_runner::run(
+            &mut cargo_metadata_command,
+            self.registry.offline(),
+            &mut |_| {},
+        )
+        .await
+        .map_err(|err| {
+            RiffError::new(
+                ErrorCode::MissingTool,
+                "Could not execute `cargo metadata`. Is `cargo` installed?\n\n\
+                Get instructions for installing Cargo: https://www.rust-lang.org/tools/install",
+            )
+            .with_source(err)
+        })?;
 
         spinner.finish_and_clear();
 
         if !cargo_metadata_output.status.success() {
-            return Err(eyre!(
-                "`cargo metadata` exited with code {}:\n{}",
-                cargo_metadata_output
-                    .status
-                    .code()
-                    .map(|x| x.to_string())
-                    .unwrap_or_else(|| "unknown".to_string()),
-                std::str::from_utf8(&cargo_metadata_output.stderr)?,
-            ));
+            if self.offline.is_strict() {
+                return Err(crate::offline::not_cached(
+                    "cargo metadata",
+                    "a crate the project depends on",
+                    &String::from_utf8_lossy(&cargo_metadata_output.stderr),
+                )
+                .into());
+            }
+            return Err(RiffError::new(
+                ErrorCode::CargoFailed,
+                format!(
+                    "`cargo metadata` exited with code {}:\n{}",
+                    cargo_metadata_output
+                        .status
+                        .code()
+                        .map(|x| x.to_string())
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    String::from_utf8_lossy(&cargo_metadata_output.stderr),
+                ),
+            )
+            .into());
         }
 
         let cargo_metadata_output = std::str::from_utf8(&cargo_metadata_output.stdout)
             .wrap_err("Output produced by `cargo metadata` was not valid UTF8")?;
-        let metadata: CargoMetadata = serde_json::from_str(cargo_metadata_output).wrap_err(
+        serde_json::from_str(cargo_metadata_output).wrap_err(
             "Unable to parse output produced by `cargo metadata` into our desired structure",
-        )?;
+        )
+    }
 
+    async fn add_deps_from_cargo_metadata(
+        &mut self,
+        mut metadata: CargoMetadata,
+    ) -> color_eyre::Result<()> {
+        let _timer = crate::timings::start("registry resolution");
         tracing::debug!(fresh = %self.registry.fresh(), "Cache freshness");
         let language_registry = self.registry.language().await.clone();
-        language_registry.rust.default.apply(self);
+        language_registry
+            .rust
+            .default
+            .apply(self, "Rust defaults (riff registry)");
+        self.record_provenance(
+            "Rust defaults (riff registry)",
+            language_registry.rust.default.provides(),
+        );
+
+        // Only the chosen member and its dependencies contribute to the environment
+        if let Some(package) = &self.package {
+            let closure = metadata.dependency_closure(package)?;
+            metadata
+                .packages
+                .retain(|package| closure.contains(&package.id));
+            metadata
+                .workspace_members
+                .retain(|member| closure.contains(member));
+        }
+
+        // Dependencies only declared for other targets aren't built, so need nothing
+        let target = &self.target;
+        if let Some(closure) = metadata.target_closure(target) {
+            metadata.packages.retain(|package| {
+                let applies = closure.contains(&package.id);
+                if !applies {
+                    tracing::debug!(package = %package.name, %target, "Skipping a dependency for other targets");
+                }
+                applies
+            });
+        }
+
+        if let [member] = metadata.workspace_members.as_slice() {
+            if let Some(package) = metadata
+                .packages
+                .iter()
+                .find(|package| &package.id == member)
+            {
+                self.project_name = Some(package.name.clone());
+                self.project_version = Some(package.version.to_string());
+            }
+        }
+
+        let workspace_config = metadata.workspace_metadata.and_then(|metadata| {
+            self.check_fields("workspace.metadata.riff", None, &metadata.unknown_fields);
+            metadata.riff
+        });
+        let workspace_config = workspace_config.map(|config| {
+            if self.trusted {
+                config
+            } else {
+                config.without_overrides()
+            }
+        });
+        if let Some(workspace_config) = &workspace_config {
+            self.check_targets("The workspace", "workspace.metadata.riff", workspace_config);
+            self.record_provenance(
+                "`workspace.metadata.riff`",
+                workspace_config.provides(&self.target),
+            );
+        }
+
+        // Look each crate up once, however many packages share its name and version, and apply
+        // each matching entry once, however many packages match it
+        let index = language_registry.rust.index();
+        let mut lookups = HashMap::new();
+        let mut matched_entries = BTreeMap::new();
+        let known = metadata
+            .packages
+            .iter()
+            .map(|package| {
+                let entry = *lookups
+                    .entry((package.name.as_str(), &package.version))
+                    .or_insert_with(|| index.dependency(&package.name, &package.version));
+                match entry.or_else(|| index.dependency_by_repository(package.repositories())) {
+                    Some((key, dep_config)) => {
+                        matched_entries.insert(key, dep_config);
+                        true
+                    }
+                    None => index.knows(&package.name),
+                }
+            })
+            .collect::<Vec<_>>();
+        for (key, dep_config) in matched_entries {
+            tracing::debug!(
+                registry_key = key,
+                "build-inputs" = %dep_config.build_inputs(&self.target).iter().join(", "),
+                "environment-variables" = %dep_config.environment_variables(&self.target).iter().map(|(k, v)| format!("{k}={v}")).join(", "),
+                "runtime-inputs" = %dep_config.runtime_inputs(&self.target).iter().join(", "),
+                "Detected known crate information"
+            );
+            dep_config.apply(self, &format!("`{key}` (riff registry)"));
+            self.record_provenance(
+                &format!("`{key}` (riff registry)"),
+                dep_config.provides(&self.target),
+            );
+            self.registry_entries.insert(
+                key.to_string(),
+                LockedEnvironment::from_dependency(dep_config, &self.target),
+            );
+        }
 
-        for package in metadata.packages {
+        let workspace_members = metadata
+            .workspace_members
+            .iter()
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+        // Crates without any configuration whose build scripts look for native libraries, checked
+        // once every input is known
+        let mut unmapped_probes = vec![];
+        for (package, known) in metadata.packages.into_iter().zip(known) {
+            let native_library_probes = package.native_library_probes();
+            let links = package.links;
             let name = package.name;
 
-            if let Some(dep_config) = language_registry.rust.dependencies.get(name.as_str()) {
-                tracing::debug!(
-                    package_name = %name,
-                    "build-inputs" = %dep_config.build_inputs().iter().join(", "),
-                    "environment-variables" = %dep_config.environment_variables().iter().map(|(k, v)| format!("{k}={v}")).join(", "),
-                    "runtime-inputs" = %dep_config.runtime_inputs().iter().join(", "),
-                    "Detected known crate information"
+            // Workspace members inherit `workspace.metadata.riff`, other packages don't.
+            let is_member = workspace_members.contains(package.id.as_str());
+            let inherited_config = workspace_config.as_ref().filter(|_| is_member);
+            let package_config = package.metadata.and_then(|metadata| {
+                // Only the project's own manifests are the user's to fix
+                if is_member {
+                    self.check_fields(
+                        "package.metadata.riff",
+                        Some(&name),
+                        &metadata.unknown_fields,
+                    );
+                }
+                metadata.riff
+            });
+            let package_config = package_config.map(|config| {
+                if is_member && !self.trusted {
+                    config.without_overrides()
+                } else {
+                    config
+                }
+            });
+            if let Some(package_config) = &package_config {
+                self.check_targets(
+                    &format!("`{name}`"),
+                    "package.metadata.riff",
+                    package_config,
+                );
+                self.record_provenance(
+                    &format!("`{name}` (`package.metadata.riff`)"),
+                    package_config.provides(&self.target),
                 );
-                dep_config.clone().apply(self);
             }
 
-            let metadata_object = match package.metadata {
-                Some(metadata_object) => metadata_object,
-                None => continue,
-            };
-
-            let dep_config = match metadata_object.riff {
-                Some(riff_object) => riff_object,
-                None => continue,
+            let dep_config = match (package_config, inherited_config) {
+                (Some(package_config), Some(inherited_config)) => {
+                    package_config.inherit(inherited_config)
+                }
+                (Some(package_config), None) => package_config,
+                (None, Some(inherited_config)) => inherited_config.clone(),
+                (None, None) => {
+                    if known {
+                        continue;
+                    }
+                    if name.ends_with("-sys") {
+                        self.warnings.push(Warning::new(
+                            WarningCode::UnmappedSysCrate,
+                            format!(
+                                "`{name}` probably links to a native library, but riff doesn't \
+                                know which Nix packages it needs. Declare them in its \
+                                `package.metadata.riff`, or suggest them for riff's registry."
+                            ),
+                        ));
+                    } else if !native_library_probes.is_empty() {
+                        unmapped_probes.push((name, links, native_library_probes));
+                    }
+                    continue;
+                }
             };
 
             tracing::debug!(
                 package = %name,
-                "build-inputs" = %dep_config.build_inputs().iter().join(", "),
-                "environment-variables" = %dep_config.environment_variables().iter().map(|(k, v)| format!("{k}={v}")).join(", "),
-                "runtime-inputs" = %dep_config.runtime_inputs().iter().join(", "),
+                "build-inputs" = %dep_config.build_inputs(&self.target).iter().join(", "),
+                "environment-variables" = %dep_config.environment_variables(&self.target).iter().map(|(k, v)| format!("{k}={v}")).join(", "),
+                "runtime-inputs" = %dep_config.runtime_inputs(&self.target).iter().join(", "),
                 "Detected `package.metadata.riff` in `Crate.toml`"
             );
-            dep_config.apply(self);
-        }
-
-        eprintln!(
-            "{check} {lang}: {colored_inputs}{maybe_colored_envs}",
-            check = "✓".green(),
-            lang = "🦀 rust".bold().red(),
-            colored_inputs = {
-                let mut sorted_build_inputs = self
-                    .build_inputs
-                    .union(&self.runtime_inputs)
-                    .collect::<Vec<_>>();
-                sorted_build_inputs.sort();
-                sorted_build_inputs.iter().map(|v| v.cyan()).join(", ")
-            },
-            maybe_colored_envs = {
-                if !self.environment_variables.is_empty() {
-                    let mut sorted_environment_variables =
-                        self.environment_variables.keys().collect::<Vec<_>>();
-                    sorted_environment_variables.sort();
+            dep_config.apply(self, &format!("`{name}` (`package.metadata.riff`)"));
+            if is_member {
+                self.exclusions.extend(&dep_config.exclusions);
+            }
+        }
+        self.apply_exclusions();
+
+        for (name, links, probes) in unmapped_probes {
+            // A library named after an input we already provide is probably found
+            let provided = links.is_some_and(|links| {
+                self.build_inputs
+                    .iter()
+                    .chain(&self.runtime_inputs)
+                    .any(|input| *input == links || *input == format!("lib{links}"))
+            });
+            if !provided {
+                self.warnings.push(Warning::new(
+                    WarningCode::UnmappedNativeLibrary,
                     format!(
-                        " ({})",
-                        sorted_environment_variables
-                            .iter()
-                            .map(|v| v.green())
-                            .join(", ")
-                    )
-                } else {
-                    "".to_string()
-                }
+                        "`{name}` has a build script which probably looks for a native library \
+                        (it {}), but riff doesn't know which Nix packages it needs. Declare them \
+                        in its `package.metadata.riff`, or suggest them for riff's registry.",
+                        probes.join(" and ")
+                    ),
+                ));
             }
-        );
+        }
 
+        crate::spinner::suspend(|| {
+            eprintln!(
+                "{check} {lang}: {colored_inputs}{maybe_colored_envs}",
+                check = "✓".green(),
+                lang = "🦀 rust".bold().red(),
+                colored_inputs = {
+                    let mut sorted_build_inputs = self
+                        .build_inputs
+                        .union(&self.runtime_inputs)
+                        .collect::<Vec<_>>();
+                    sorted_build_inputs.sort();
+                    sorted_build_inputs.iter().map(|v| v.cyan()).join(", ")
+                },
+                maybe_colored_envs = {
+                    if !self.environment_variables.is_empty() {
+                        let mut sorted_environment_variables =
+                            self.environment_variables.keys().collect::<Vec<_>>();
+                        sorted_environment_variables.sort();
+                        format!(
+                            " ({})",
+                            sorted_environment_variables
+                                .iter()
+                                .map(|v| v.green())
+                                .join(", ")
+                        )
+                    } else {
+                        "".to_string()
+                    }
+                }
+            )
+        });
         Ok(())
     }
 }
 
+/// Whether `project_dir` has the files of a project riff detects itself, or a `riff.toml`, so it
+/// is a project without running any detectors
+pub fn has_project_files(project_dir: &Path) -> bool {
+    project_dir.join("Cargo.toml").is_file()
+        || crate::cabal::is_project(project_dir)
+        || crate::dotnet::is_project(project_dir)
+        || project_dir
+            .join(crate::project_config::PROJECT_CONFIG_FILE)
+            .is_file()
+}
+
+/// Whether `project_dir` is a project riff recognizes, like [`DevEnvironment::detect`] would:
+/// one with [its own files](has_project_files), or one a detector on `PATH` recognizes
+pub async fn is_project(project_dir: &Path) -> bool {
+    if has_project_files(project_dir) {
+        return true;
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    for detector in crate::detectors::find(&path).values() {
+        if let Ok(Some(_)) = crate::detectors::run(detector, project_dir).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// The environment variables and shell hooks the project's own `workspace.metadata.riff` and its
+/// members' `package.metadata.riff` set for `target`, which only apply if the project is trusted
+fn project_overrides(metadata: &CargoMetadata, target: &Triple) -> Vec<String> {
+    let workspace_config = metadata
+        .workspace_metadata
+        .as_ref()
+        .and_then(|metadata| metadata.riff.as_ref());
+    let member_configs = metadata
+        .packages
+        .iter()
+        .filter(|package| metadata.workspace_members.contains(&package.id))
+        .filter_map(|package| package.metadata.as_ref()?.riff.as_ref());
+    let mut overrides = vec![];
+    for config in workspace_config.into_iter().chain(member_configs) {
+        for item in config.overrides(target) {
+            if !overrides.contains(&item) {
+                overrides.push(item);
+            }
+        }
+    }
+    overrides
+}
+
+/// Split an input given as a flake reference and attribute, eg
+/// `github:NixOS/nixpkgs/<rev>#openssl`, rather than as a Nixpkgs attribute
+fn split_pinned_input(input: &str) -> Option<(&str, &str)> {
+    input
+        .split_once('#')
+        .filter(|(flake, attribute)| !flake.is_empty() && !attribute.is_empty())
+}
+
+/// Split an overlay given as `<flakeref>[#<overlay>]` into the flake reference and the name of
+/// the overlay in the flake's `overlays` output
+fn split_overlay(overlay: &str) -> (&str, &str) {
+    match overlay.split_once('#') {
+        Some((flake, name)) if !name.is_empty() => (flake, name),
+        _ => (overlay.trim_end_matches('#'), "default"),
+    }
+}
+
+/// The name of the flake input of the generated flake which `flake` is bound to
+fn pinned_input_name(flake: &str) -> String {
+    format!("pinned-{}", &sha256_hex(flake.as_bytes())[..12])
+}
+
+/// The Nix expression for `input` in the generated flake, where `pinned_flakes` names the flake
+/// inputs of pinned inputs
+fn package_expression(input: &str, pinned_flakes: &BTreeMap<&str, String>) -> String {
+    match split_pinned_input(input) {
+        Some((flake, attribute)) => {
+            let name = &pinned_flakes[flake];
+            format!(
+                "(inputs.{name}.packages.${{system}}.{attribute} or inputs.{name}.legacyPackages.${{system}}.{attribute})"
+            )
+        }
+        None => input.to_string(),
+    }
+}
+
+/// A Nix path to `path`, relative to the directory of the flake
+fn flake_path(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        return "./.".to_string();
+    }
+    format!("./. + \"/{}\"", escape_nix_string(&path.to_string_lossy()))
+}
+
+/// Escape `contents` for use inside a double-quoted Nix string, so that it is taken literally
+/// rather than interpolated
+fn escape_nix_string(contents: &str) -> String {
+    contents
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+}
+
+/// What riff fills into a flake template, each written `@name@` in a `riff-template.nix`
+struct FlakePlaceholders {
+    /// The flake reference of Nixpkgs
+    nixpkgs: String,
+    /// The `inputs.<name>.url = ...;` of pinned inputs and overlays
+    pinned_inputs: String,
+    /// ` overlays = [ ... ];` for importing Nixpkgs, if there are overlays
+    overlays: String,
+    shell_name: String,
+    build_inputs: String,
+    /// `"NAME" = "value";` for each environment variable
+    environment_variables: String,
+    ld_library_path: String,
+    dyld_fallback_library_path: String,
+    shell_hook: String,
+    /// The outputs besides the dev shell, see [`FlakeOutputs`]
+    outputs: String,
+}
+
+impl FlakePlaceholders {
+    fn entries(&self) -> [(&'static str, &str); 10] {
+        [
+            ("nixpkgs", &self.nixpkgs),
+            ("pinned_inputs", &self.pinned_inputs),
+            ("overlays", &self.overlays),
+            ("shell_name", &self.shell_name),
+            ("build_inputs", &self.build_inputs),
+            ("environment_variables", &self.environment_variables),
+            ("ld_library_path", &self.ld_library_path),
+            (
+                "dyld_fallback_library_path",
+                &self.dyld_fallback_library_path,
+            ),
+            ("shell_hook", &self.shell_hook),
+            ("outputs", &self.outputs),
+        ]
+    }
+}
+
+/// Render the `pkgs.stdenv.mkDerivation` of a dev shell
+fn render_shell(placeholders: &FlakePlaceholders) -> String {
+    format!(
+        include_str!("shell-template.inc"),
+        shell_name = placeholders.shell_name,
+        build_inputs = placeholders.build_inputs,
+        environment_variables = placeholders.environment_variables,
+        ld_library_path = placeholders.ld_library_path,
+        dyld_fallback_library_path = placeholders.dyld_fallback_library_path,
+        shell_hook = placeholders.shell_hook,
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// Fill the `@name@` placeholders of `template` with `placeholders`, leaving any other `@` as it
+/// is (eg that of `{ self, ... }@inputs`)
+fn render_template(template: &str, placeholders: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('@') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let placeholder = placeholders.iter().find(|(name, _)| {
+            after
+                .strip_prefix(name)
+                .is_some_and(|after| after.starts_with('@'))
+        });
+        match placeholder {
+            Some((name, value)) => {
+                rendered.push_str(value);
+                rest = &after[name.len() + 1..];
+            }
+            None => {
+                rendered.push('@');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
 pub(crate) trait DevEnvironmentAppliable {
-    fn apply(&self, dev_env: &mut DevEnvironment);
+    /// Apply these settings to `dev_env`, on behalf of `source` (eg "`openssl-sys` (riff registry)")
+    fn apply(&self, dev_env: &mut DevEnvironment, source: &str);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::command_runner::{self, RecordingRunner};
+    use eyre::eyre;
+    use std::sync::Arc;
     use tempfile::TempDir;
     use tokio::fs::write;
 
+    #[tokio::test]
+    async fn projects_are_recognized_by_their_files() -> eyre::Result<()> {
+        for file in ["Cargo.toml", "cabal.project", "app.csproj", "riff.toml"] {
+            let project_dir = TempDir::new()?;
+            assert!(!has_project_files(project_dir.path()));
+            write(project_dir.path().join(file), "").await?;
+            assert!(has_project_files(project_dir.path()), "{file}");
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn dev_env_to_flake() -> eyre::Result<()> {
         let cache_dir = TempDir::new()?;
         std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
-        let registry = DependencyRegistry::new(true).await?;
+        let registry = DependencyRegistry::new(true, false).await?;
         let dev_env = DevEnvironment {
             build_inputs: ["cargo", "hello"]
                 .into_iter()
@@ -228,19 +1556,53 @@ mod tests {
                 .into_iter()
                 .map(|(k, v)| (k.to_string(), v.to_string()))
                 .collect(),
+            literal_variables: Default::default(),
             runtime_inputs: ["nix", "libGL"]
                 .into_iter()
                 .map(ToString::to_string)
                 .collect(),
             detected_languages: vec![DetectedLanguage::Rust].into_iter().collect(),
+            language_contributions: Default::default(),
+            detecting: None,
+            provenance: Default::default(),
+            shell_hooks: vec![
+                r#"mkdir -p "${XDG_CACHE_HOME}/example""#.to_string(),
+                r#"echo \n"#.to_string(),
+            ],
+            nixpkgs: None,
+            overlays: Default::default(),
+            warnings: Default::default(),
+            registry_entries: Default::default(),
+            conflict_strategies: Default::default(),
+            environment_variable_sources: Default::default(),
+            project_name: None,
+            project_version: None,
+            extra_outputs: Default::default(),
+            exclusions: Default::default(),
+            package: None,
+            manifest_path: None,
+            target: Triple::host(),
+            offline: Default::default(),
+            refresh: false,
+            package_source: Default::default(),
+            template: None,
+            trusted: true,
             registry: &registry,
         };
 
         let flake = dev_env.to_flake();
         eprintln!("{}", &flake);
+        assert!(crate::emitters::is_generated(&flake));
+        let (header, rest) = GeneratedHeader::split(&flake).expect("a generated header");
+        assert_eq!(header.riff_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(header.inputs_sha256, dev_env.inputs_sha256());
+        assert!(!header.is_edited(rest));
+        assert!(header.is_edited(&rest.replace("hello", "goodbye")));
+        assert!(!crate::emitters::is_generated(&dev_env.to_ejected_flake()));
         assert!(
             flake.contains("buildInputs = [") && flake.contains("cargo") && flake.contains("hello")
         );
+        assert!(flake.contains(r#"inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";"#));
         assert!(flake.contains(r#""GOODBYE" = "WORLD""#));
         assert!(flake.contains(r#""HELLO" = "WORLD""#));
         assert!(
@@ -248,6 +1610,344 @@ mod tests {
                 && flake.contains("${lib.getLib nix}/lib")
                 && flake.contains("${lib.getLib libGL}/lib")
         );
+        assert!(flake.contains(
+            r#""DYLD_FALLBACK_LIBRARY_PATH" = "${lib.getLib libGL}/lib:${lib.getLib nix}/lib";"#
+        ));
+        assert!(flake.contains(
+            r#"shellHook = "
+mkdir -p \"\${XDG_CACHE_HOME}/example\"
+echo \\n
+";"#
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_extra_outputs() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        assert!(!dev_env.to_flake().contains("packages ="));
+
+        dev_env.project_name = Some("riff-test".to_string());
+        dev_env.extra_outputs.package = true;
+        let flake = dev_env.to_flake();
+        assert!(flake.contains(r#"pname = "riff-test";"#));
+        assert!(flake.contains(r#"version = "0.0.0";"#));
+        assert!(!flake.contains("checks ="));
+
+        dev_env.extra_outputs.checks = true;
+        assert!(dev_env
+            .to_flake()
+            .contains("package = self.packages.${system}.default;"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_custom_template() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.build_inputs = ["cargo", "openssl"].map(String::from).into();
+        dev_env.template = Some(
+            r#"{
+  inputs.nixpkgs.url = "@nixpkgs@";
+  outputs = { self, nixpkgs, ... }@inputs: {
+    devShells.x86_64-linux.default = with nixpkgs.legacyPackages.x86_64-linux;
+      mkShell { name = "@shell_name@"; packages = [ @build_inputs@ ]; };
+    # @unknown@ placeholders are left alone
+  };
+}
+"#
+            .to_string(),
+        );
+
+        let flake = dev_env.to_flake();
+        assert!(crate::emitters::is_generated(&flake));
+        let (_, flake) = GeneratedHeader::split(&flake).expect("a generated header");
+        assert_eq!(
+            flake,
+            format!(
+                r#"{{
+  inputs.nixpkgs.url = "{DEFAULT_NIXPKGS}";
+  outputs = {{ self, nixpkgs, ... }}@inputs: {{
+    devShells.x86_64-linux.default = with nixpkgs.legacyPackages.x86_64-linux;
+      mkShell {{ name = "riff-shell"; packages = [ cargo openssl ]; }};
+    # @unknown@ placeholders are left alone
+  }};
+}}
+"#
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_pinned_inputs() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.build_inputs.extend([
+            "cargo".to_string(),
+            "github:NixOS/nixpkgs/nixos-22.11#openssl".to_string(),
+        ]);
+        dev_env
+            .runtime_inputs
+            .insert("github:NixOS/nixpkgs/nixos-22.11#libGL".to_string());
+        assert!(dev_env.has_pinned_inputs());
+
+        let flake = dev_env.to_flake();
+        let pinned = pinned_input_name("github:NixOS/nixpkgs/nixos-22.11");
+        assert_eq!(flake.matches(&format!("inputs.{pinned}.url")).count(), 1);
+        assert!(flake.contains(&format!(
+            r#"inputs.{pinned}.url = "github:NixOS/nixpkgs/nixos-22.11";"#
+        )));
+        assert!(flake.contains(&format!("cargo (inputs.{pinned}.packages.${{system}}.openssl or inputs.{pinned}.legacyPackages.${{system}}.openssl)")));
+        assert!(flake.contains(&format!(
+            "${{lib.getLib (inputs.{pinned}.packages.${{system}}.libGL or"
+        )));
+        assert_eq!(
+            flake.matches("inputs.pinned-").count(),
+            flake.matches(&format!("inputs.{pinned}")).count()
+        );
+
+        // Another pin, sorting first, leaves the name of the first one alone
+        dev_env
+            .build_inputs
+            .insert("github:acme/aardvark#tool".to_string());
+        let flake = dev_env.to_flake();
+        assert!(flake.contains(&format!(
+            r#"inputs.{pinned}.url = "github:NixOS/nixpkgs/nixos-22.11";"#
+        )));
+        assert!(flake.contains(&format!(
+            r#"inputs.{}.url = "github:acme/aardvark";"#,
+            pinned_input_name("github:acme/aardvark")
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_overlays() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        assert!(dev_env
+            .to_flake()
+            .contains("pkgs = import nixpkgs { inherit system; };"));
+
+        dev_env.overlays = vec![
+            "github:oxalica/rust-overlay".to_string(),
+            "github:acme/overlays#rust".to_string(),
+            "github:acme/overlays#x; builtins.abort \"${y}\"".to_string(),
+        ];
+        let flake = dev_env.to_flake();
+        assert!(flake.contains(r#"inputs.overlay0.url = "github:oxalica/rust-overlay";"#));
+        assert!(flake.contains(r#"inputs.overlay1.url = "github:acme/overlays";"#));
+        assert!(flake.contains(
+            r#"overlays = [ inputs.overlay0.overlays."default" inputs.overlay1.overlays."rust" inputs.overlay2.overlays."x; builtins.abort \"\${y}\"" ];"#
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_environment_variable_conflicts() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .conflict_strategies
+            .insert("KEEP_FIRST".to_string(), ConflictStrategy::First);
+
+        for (source, value) in [("`a`", "/a"), ("`b`", "/b"), ("`c`", "/a")] {
+            dev_env.set_environment_variable("PKG_CONFIG_PATH", value, source);
+            dev_env.set_environment_variable("KEEP_FIRST", value, source);
+        }
+        dev_env.set_environment_variable("CC", "gcc", "`a`");
+        dev_env.set_environment_variable("CC", "gcc", "`b`");
+        dev_env.set_environment_variable("CC", "clang", "`c`");
+
+        assert_eq!(dev_env.environment_variables["PKG_CONFIG_PATH"], "/a:/b");
+        assert_eq!(dev_env.environment_variables["KEEP_FIRST"], "/a");
+        assert_eq!(dev_env.environment_variables["CC"], "clang");
+        assert_eq!(dev_env.warnings.len(), 1);
+        assert_eq!(
+            dev_env.warnings[0].code,
+            WarningCode::EnvironmentVariableConflict
+        );
+        assert!(dev_env.warnings[0]
+            .message
+            .contains("by `b` and to `clang` by `c`"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_apply_profile() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.build_inputs.insert("openssl".to_string());
+        dev_env
+            .environment_variables
+            .insert("RUST_LOG".to_string(), "info".to_string());
+        let profile = Profile {
+            build_inputs: ["openssl_3".to_string()].into_iter().collect(),
+            remove_build_inputs: ["openssl".to_string()].into_iter().collect(),
+            remove_environment_variables: ["RUST_LOG".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+
+        dev_env.apply_profile("ci", &profile);
+
+        assert_eq!(
+            dev_env.build_inputs,
+            ["openssl_3".to_string()].into_iter().collect()
+        );
+        assert!(dev_env.environment_variables.is_empty());
+        assert!(dev_env.provenance["openssl_3"].contains("`ci` profile (`riff.toml`)"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_apply_additions() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .environment_variables
+            .insert("FOO".to_string(), "detected".to_string());
+        let additions = EnvironmentAdditions {
+            inputs: vec!["protobuf".to_string()],
+            environment_variables: vec![
+                parse_environment_variable("FOO=bar=baz").map_err(|err| eyre!(err))?
+            ],
+        };
+
+        dev_env.apply_additions(&additions);
+
+        assert!(dev_env.build_inputs.contains("protobuf"));
+        assert_eq!(dev_env.environment_variables["FOO"], "bar=baz");
+        assert!(dev_env.provenance["FOO"].contains("`--env`"));
+        assert!(parse_environment_variable("=bar").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn literal_environment_variables_are_escaped() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.environment_variables.insert(
+            "RUST_SRC_PATH".to_string(),
+            "${rustPlatform.rustLibSrc}".to_string(),
+        );
+        let additions = EnvironmentAdditions {
+            inputs: vec![],
+            environment_variables: vec![
+                parse_environment_variable(r#"FOO=a"b ${builtins.readFile /etc/shadow}"#)
+                    .map_err(|err| eyre!(err))?,
+                parse_environment_variable(r#"BAR"${x}=1"#).map_err(|err| eyre!(err))?,
+            ],
+        };
+
+        dev_env.apply_additions(&additions);
+
+        let flake = dev_env.to_flake();
+        assert!(flake.contains(r#""FOO" = "a\"b \${builtins.readFile /etc/shadow}";"#));
+        assert!(flake.contains(r#""BAR\"\${x}" = "1";"#));
+        // The registry's values are Nix string contents
+        assert!(flake.contains(r#""RUST_SRC_PATH" = "${rustPlatform.rustLibSrc}";"#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_add_nixgl() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+
+        dev_env.add_nixgl();
+        assert!(dev_env.build_inputs.is_empty());
+
+        dev_env.runtime_inputs.insert("vulkan-loader".to_string());
+        dev_env.add_nixgl();
+        assert_eq!(
+            dev_env.build_inputs,
+            HashSet::from(["github:nix-community/nixGL#nixVulkanIntel".to_string()])
+        );
+        let flake = dev_env.to_flake();
+        assert!(flake.contains(&format!(
+            "inputs.{}.packages.${{system}}.nixVulkanIntel",
+            pinned_input_name("github:nix-community/nixGL")
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_exclusions() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        // As if from `riff.toml`
+        dev_env
+            .exclusions
+            .unset_environment_variables
+            .insert("HI".to_string());
+
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [{
+                "id": "riff-test 0.1.0 (path+file:///riff-test)",
+                "name": "riff-test",
+                "version": "0.1.0",
+                "manifest_path": "/riff-test/Cargo.toml",
+                "metadata": {
+                    "riff": {
+                        "build-inputs": ["hello"],
+                        "environment-variables": { "HI": "BYE" },
+                        "exclude-build-inputs": ["rustfmt"],
+                    },
+                },
+            }],
+            "workspace_members": ["riff-test 0.1.0 (path+file:///riff-test)"],
+            "workspace_metadata": null,
+        }))?;
+        dev_env.add_deps_from_cargo_metadata(metadata).await?;
+
+        assert!(dev_env.build_inputs.contains("hello"));
+        assert!(dev_env.build_inputs.contains("cargo"));
+        assert!(!dev_env.build_inputs.contains("rustfmt"));
+        assert!(!dev_env.environment_variables.contains_key("HI"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_to_ejected_flake() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.build_inputs.insert("openssl".to_string());
+        dev_env.runtime_inputs.insert("libGL".to_string());
+        dev_env.record_provenance(
+            "`openssl-sys` (riff registry)",
+            ["openssl".to_string(), "libGL".to_string()],
+        );
+
+        let flake = dev_env.to_ejected_flake();
+        eprintln!("{}", &flake);
+        assert!(flake.contains("openssl # `openssl-sys` (riff registry)"));
+        assert!(flake.contains("# libGL: `openssl-sys` (riff registry)"));
+        assert!(!flake.contains("riff-shell"));
+        assert!(!dev_env.to_flake().contains("openssl-sys"));
         Ok(())
     }
 
@@ -284,17 +1984,135 @@ HI = "BYE"
         )
         .await?;
 
-        let registry = DependencyRegistry::new(true).await?;
+        let registry = DependencyRegistry::new(true, false).await?;
         let mut dev_env = DevEnvironment::new(&registry);
         let detect = dev_env.detect(temp_dir.path()).await;
         assert!(detect.is_ok(), "{detect:?}");
 
-        assert!(dev_env.build_inputs.get("hello").is_some());
+        assert!(dev_env.build_inputs.contains("hello"));
         assert_eq!(
             dev_env.environment_variables.get("HI"),
             Some(&String::from("BYE"))
         );
-        assert!(dev_env.runtime_inputs.get("libGL").is_some());
+        assert!(dev_env.runtime_inputs.contains("libGL"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_from_cargo_metadata() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("Cargo.toml"), "").await?;
+        let metadata = serde_json::json!({
+            "packages": [
+                {
+                    "id": "riff-test 0.1.0 (path+file:///riff-test)",
+                    "name": "riff-test",
+                    "version": "0.1.0",
+                    "manifest_path": "/riff-test/Cargo.toml",
+                    "metadata": { "riff": { "build-inputs": ["hello"] } },
+                },
+                {
+                    "id": "openssl-sys 0.9.80 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "name": "openssl-sys",
+                    "version": "0.9.80",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "manifest_path": "/cargo/openssl-sys-0.9.80/Cargo.toml",
+                    "metadata": null,
+                },
+            ],
+            "workspace_members": ["riff-test 0.1.0 (path+file:///riff-test)"],
+            "workspace_root": temp_dir.path(),
+            "workspace_metadata": null,
+        });
+        let runner = Arc::new(RecordingRunner::default().respond(
+            "cargo metadata",
+            0,
+            &metadata.to_string(),
+            "",
+        ));
+
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        command_runner::scope(runner.clone(), dev_env.detect(temp_dir.path())).await?;
+
+        assert!(dev_env.build_inputs.contains("hello"));
+        assert!(dev_env.build_inputs.contains("openssl"));
+        assert_eq!(dev_env.project_name.as_deref(), Some("riff-test"));
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].starts_with("cargo metadata --format-version 1"));
+        Ok(())
+    }
+
+    // A benchmark rather than a test, run it with
+    // `cargo test --release registry_resolution_of_large_workspaces -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn registry_resolution_of_large_workspaces() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let rust = registry.language().await.rust.clone();
+
+        // Every crate the registry knows, and as many it doesn't, in several versions from
+        // several sources, like the lock of a large monorepo
+        let names = rust
+            .dependencies
+            .keys()
+            .map(|key| key.split('@').next().unwrap_or(key).to_string())
+            .chain((0..rust.dependencies.len()).map(|n| format!("unknown-crate-{n}")))
+            .collect::<Vec<_>>();
+        let packages = (0..20_000)
+            .map(|n| {
+                let name = &names[n % names.len()];
+                let version = format!("0.{}.0", n % 7);
+                serde_json::json!({
+                    "id": format!("{name} {version} (source {n})"),
+                    "name": name,
+                    "version": version,
+                    "manifest_path": format!("/cargo/{name}-{version}/Cargo.toml"),
+                    "metadata": null,
+                })
+            })
+            .collect::<Vec<_>>();
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "packages": packages,
+            "workspace_members": [],
+            "workspace_metadata": null,
+        }))?;
+
+        let started = std::time::Instant::now();
+        let index = rust.index();
+        for package in &metadata.packages {
+            std::hint::black_box(index.dependency(&package.name, &package.version));
+        }
+        let indexed = started.elapsed();
+
+        // Scanning every entry for each package, as riff did before it indexed the registry
+        let started = std::time::Instant::now();
+        for package in &metadata.packages {
+            std::hint::black_box(rust.dependencies.iter().find(|(key, _)| {
+                let (name, constraint) = key.split_once('@').unwrap_or((key.as_str(), "*"));
+                name == package.name
+                    && semver::VersionReq::parse(constraint)
+                        .is_ok_and(|constraint| constraint.matches(&package.version))
+            }));
+        }
+        let scanned = started.elapsed();
+
+        let started = std::time::Instant::now();
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.add_deps_from_cargo_metadata(metadata).await?;
+        let resolved = started.elapsed();
+
+        println!(
+            "{} packages: {indexed:?} to look up with an index, {scanned:?} by scanning, \
+            {resolved:?} to resolve the environment",
+            packages.len()
+        );
+        assert!(indexed < scanned);
         Ok(())
     }
 
@@ -303,10 +2121,113 @@ HI = "BYE"
         let cache_dir = TempDir::new()?;
         std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
         let temp_dir = TempDir::new()?;
-        let registry = DependencyRegistry::new(true).await?;
+        let registry = DependencyRegistry::new(true, false).await?;
         let mut dev_env = DevEnvironment::new(&registry);
         let detect = dev_env.detect(temp_dir.path()).await;
-        assert!(detect.is_err());
+        assert_eq!(
+            detect.as_ref().map_err(ErrorCode::of).err(),
+            Some(ErrorCode::NoProject)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_haskell_project() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        tokio::fs::write(
+            temp_dir.path().join("app.cabal"),
+            "executable app\n  build-depends: base, postgresql-simple\n",
+        )
+        .await?;
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.detect(temp_dir.path()).await?;
+
+        assert!(dev_env
+            .detected_languages
+            .contains(&DetectedLanguage::Haskell));
+        assert!(dev_env.build_inputs.contains("ghc"));
+        assert!(dev_env.build_inputs.contains("postgresql"));
+        assert!(dev_env
+            .registry_entries
+            .contains_key("haskell/postgresql-simple"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_language_shells() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        tokio::fs::write(
+            temp_dir.path().join("app.cabal"),
+            "executable app\n  build-depends: base, postgresql-simple\n",
+        )
+        .await?;
+        tokio::fs::write(
+            temp_dir.path().join("App.csproj"),
+            r#"<Project><ItemGroup><PackageReference Include="microsoft.data.sqlite" /></ItemGroup></Project>"#,
+        )
+        .await?;
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.detect(temp_dir.path()).await?;
+
+        let flake = dev_env.to_flake();
+        assert!(flake.contains("default = with pkgs;"));
+        assert!(flake.contains("dotnet = with pkgs;"));
+        assert!(flake.contains("haskell = with pkgs;"));
+        assert!(flake.contains(r#"name = "riff-shell-haskell";"#));
+        assert!(!flake.contains("rust = with pkgs;"));
+
+        let mut haskell = dev_env.clone();
+        haskell.only(DetectedLanguage::Haskell)?;
+        assert!(haskell.build_inputs.contains("ghc"));
+        assert!(haskell.build_inputs.contains("postgresql"));
+        assert!(!haskell.build_inputs.contains("dotnet-sdk"));
+        assert!(!haskell.runtime_inputs.contains("sqlite"));
+        assert!(!haskell.to_flake().contains("haskell = with pkgs;"));
+
+        let mut dotnet = dev_env.clone();
+        dotnet.only(DetectedLanguage::Dotnet)?;
+        assert!(dotnet.build_inputs.contains("dotnet-sdk"));
+        assert!(dotnet.runtime_inputs.contains("sqlite"));
+        assert!(!dotnet.build_inputs.contains("ghc"));
+
+        assert!(dev_env.clone().only(DetectedLanguage::Rust).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_dotnet_project() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        tokio::fs::write(
+            temp_dir.path().join("App.csproj"),
+            r#"<Project><ItemGroup><PackageReference Include="microsoft.data.sqlite" /></ItemGroup></Project>"#,
+        )
+        .await?;
+        tokio::fs::write(
+            temp_dir.path().join("global.json"),
+            r#"{ "sdk": { "version": "8.0.100" } }"#,
+        )
+        .await?;
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.detect(temp_dir.path()).await?;
+
+        assert!(dev_env
+            .detected_languages
+            .contains(&DetectedLanguage::Dotnet));
+        assert!(dev_env.build_inputs.contains("dotnetCorePackages.sdk_8_0"));
+        assert!(!dev_env.build_inputs.contains("dotnet-sdk"));
+        assert!(dev_env.runtime_inputs.contains("sqlite"));
+        assert!(dev_env
+            .registry_entries
+            .contains_key("dotnet/Microsoft.Data.Sqlite"));
         Ok(())
     }
 }
@@ -0,0 +1,285 @@
+//! Reading .NET projects: those with a `global.json`, or a C# (`.csproj`) or F# (`.fsproj`)
+//! project file, whose `PackageReference` items list the NuGet packages they use.
+//!
+//! Project files are looked for in the project directory and two levels of directories below it,
+//! which covers a project next to its solution as well as the usual `src/<Project>/` layout.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use eyre::WrapErr;
+use serde::Deserialize;
+
+/// The extensions of the project files riff reads
+const PROJECT_EXTENSIONS: &[&str] = &["csproj", "fsproj"];
+
+/// Directories holding build products or other tools' files rather than projects
+const BUILD_DIRS: &[&str] = &["bin", "obj", "node_modules"];
+
+/// How many levels of directories below the project directory are searched for project files
+const SEARCH_DEPTH: usize = 2;
+
+/// The oldest SDK release Nixpkgs still has: it dropped those before .NET 6, such as 3.1 and 2.1
+const OLDEST_SDK: (u32, u32) = (6, 0);
+
+/// The .NET SDK a project's `global.json` pins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinnedSdk {
+    /// The version it pins, eg `8.0.100`
+    pub version: String,
+    /// The Nixpkgs attribute of that release's SDK, or `None` if Nixpkgs no longer has it
+    pub attribute: Option<String>,
+}
+
+/// Whether `project_dir` is a .NET project
+pub fn is_project(project_dir: &Path) -> bool {
+    project_dir.join("global.json").is_file() || !project_files(project_dir).is_empty()
+}
+
+/// The .NET SDK pinned by the project's `global.json`, if it pins one
+pub async fn pinned_sdk(project_dir: &Path) -> color_eyre::Result<Option<PinnedSdk>> {
+    #[derive(Deserialize)]
+    struct GlobalJson {
+        sdk: Option<Sdk>,
+    }
+    #[derive(Deserialize)]
+    struct Sdk {
+        version: Option<String>,
+    }
+
+    let path = project_dir.join("global.json");
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display()))
+        }
+    };
+    // `dotnet` allows comments in `global.json`, which riff can't read, so it uses the default
+    // SDK rather than failing
+    let global_json: GlobalJson = match serde_json::from_str(&contents) {
+        Ok(global_json) => global_json,
+        Err(err) => {
+            tracing::debug!(%err, "Could not parse `global.json`, using the default .NET SDK");
+            return Ok(None);
+        }
+    };
+    let Some(version) = global_json.sdk.and_then(|sdk| sdk.version) else {
+        return Ok(None);
+    };
+    let Some(release) = sdk_release(&version) else {
+        tracing::debug!(%version, "Could not read the .NET SDK version, using the default one");
+        return Ok(None);
+    };
+    Ok(Some(PinnedSdk {
+        attribute: sdk_attribute(release),
+        version: version.trim().to_string(),
+    }))
+}
+
+/// The release (major and minor version) of the SDK `version`, eg `(8, 0)` for `8.0.100`
+fn sdk_release(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse::<u32>().ok()?;
+    let minor = parts.next()?.parse::<u32>().ok()?;
+    Some((major, minor))
+}
+
+/// The Nixpkgs attribute of the SDK of `release`, unless Nixpkgs dropped it
+fn sdk_attribute((major, minor): (u32, u32)) -> Option<String> {
+    ((major, minor) >= OLDEST_SDK).then(|| format!("dotnetCorePackages.sdk_{major}_{minor}"))
+}
+
+/// The NuGet packages the project's project files reference
+pub async fn package_references(project_dir: &Path) -> color_eyre::Result<BTreeSet<String>> {
+    let mut references = BTreeSet::new();
+    for project_file in project_files(project_dir) {
+        let contents = tokio::fs::read_to_string(&project_file)
+            .await
+            .wrap_err_with(|| format!("Could not read `{}`", project_file.display()))?;
+        references.extend(package_reference_ids(&contents));
+    }
+    Ok(references)
+}
+
+/// The files whose contents decide what a .NET project needs, to watch for changes: its
+/// `global.json` (whether or not it has one yet) and the project files riff reads
+pub fn watched_files(project_dir: &Path) -> Vec<PathBuf> {
+    std::iter::once(project_dir.join("global.json"))
+        .chain(project_files(project_dir))
+        .collect()
+}
+
+/// The project files in `dir` and the directories below it, down to [`SEARCH_DEPTH`], in order
+fn project_files(dir: &Path) -> Vec<PathBuf> {
+    let mut project_files = vec![];
+    let mut dirs = vec![(dir.to_path_buf(), 0)];
+    while let Some((dir, depth)) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if depth < SEARCH_DEPTH
+                    && !name.starts_with('.')
+                    && !BUILD_DIRS.contains(&name.as_str())
+                {
+                    dirs.push((path, depth + 1));
+                }
+            } else if path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| PROJECT_EXTENSIONS.contains(&extension))
+            {
+                project_files.push(path);
+            }
+        }
+    }
+    project_files.sort();
+    project_files
+}
+
+/// The IDs in the `Include` attributes of the `PackageReference` items of the project file
+/// `contents`, eg `SkiaSharp` for `<PackageReference Include="SkiaSharp" Version="2.88.6" />`
+fn package_reference_ids(contents: &str) -> BTreeSet<String> {
+    let mut ids = BTreeSet::new();
+    let mut rest = contents;
+    while let Some(start) = rest.find("<PackageReference") {
+        rest = &rest[start + "<PackageReference".len()..];
+        let element = &rest[..rest.find('>').unwrap_or(rest.len())];
+        if let Some(id) = attribute(element, "Include") {
+            // One item may include several packages, separated by `;`
+            ids.extend(
+                id.split(';')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .map(ToString::to_string),
+            );
+        }
+    }
+    ids
+}
+
+/// The value of the attribute `name` in the attributes of an XML element
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    while let Some(start) = rest.find(name) {
+        let preceded_by_space = rest[..start]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_whitespace);
+        rest = &rest[start + name.len()..];
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let value = &value[1..];
+        let end = value.find(quote)?;
+        if preceded_by_space {
+            return Some(&value[..end]);
+        }
+        rest = &value[end + 1..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_references_in_project_files() {
+        let ids = package_reference_ids(
+            r#"<Project Sdk="Microsoft.NET.Sdk">
+  <ItemGroup>
+    <PackageReference Include="SkiaSharp" Version="2.88.6" />
+    <PackageReference
+        Version="8.0.0"
+        Include='Microsoft.Data.Sqlite' />
+    <PackageReference Include="xunit;xunit.runner.visualstudio">
+      <Version>2.5.0</Version>
+    </PackageReference>
+    <PackageReference Update="Newtonsoft.Json" Version="13.0.3" />
+    <ProjectReference Include="../Library/Library.csproj" />
+  </ItemGroup>
+</Project>"#,
+        );
+        assert_eq!(
+            ids.into_iter().collect::<Vec<_>>(),
+            [
+                "Microsoft.Data.Sqlite",
+                "SkiaSharp",
+                "xunit",
+                "xunit.runner.visualstudio"
+            ]
+        );
+    }
+
+    #[test]
+    fn sdk_versions() {
+        assert_eq!(sdk_release("8.0.100"), Some((8, 0)));
+        assert!(sdk_release("latest").is_none());
+        assert_eq!(
+            sdk_attribute((8, 0)).as_deref(),
+            Some("dotnetCorePackages.sdk_8_0")
+        );
+        assert_eq!(
+            sdk_attribute((10, 0)).as_deref(),
+            Some("dotnetCorePackages.sdk_10_0")
+        );
+        // Nixpkgs dropped these
+        assert!(sdk_attribute((3, 1)).is_none());
+        assert!(sdk_attribute((2, 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn projects_and_their_packages() -> eyre::Result<()> {
+        let project = tempfile::TempDir::new()?;
+        assert!(!is_project(project.path()));
+        assert!(pinned_sdk(project.path()).await?.is_none());
+
+        let app = project.path().join("src").join("App");
+        tokio::fs::create_dir_all(app.join("obj")).await?;
+        tokio::fs::write(
+            app.join("App.fsproj"),
+            r#"<Project><ItemGroup><PackageReference Include="SkiaSharp" /></ItemGroup></Project>"#,
+        )
+        .await?;
+        tokio::fs::write(
+            app.join("obj").join("Generated.csproj"),
+            r#"<Project><ItemGroup><PackageReference Include="Npgsql" /></ItemGroup></Project>"#,
+        )
+        .await?;
+        assert!(is_project(project.path()));
+        assert_eq!(
+            package_references(project.path())
+                .await?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["SkiaSharp"]
+        );
+        assert_eq!(
+            watched_files(project.path()),
+            [project.path().join("global.json"), app.join("App.fsproj")]
+        );
+
+        tokio::fs::write(
+            project.path().join("global.json"),
+            r#"{ "sdk": { "version": "7.0.404", "rollForward": "latestFeature" } }"#,
+        )
+        .await?;
+        assert_eq!(
+            pinned_sdk(project.path()).await?,
+            Some(PinnedSdk {
+                version: "7.0.404".to_string(),
+                attribute: Some("dotnetCorePackages.sdk_7_0".to_string()),
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! Editor settings which point language servers at the riff environment, so editors needn't
+//! source the whole environment into their own.
+
+use std::collections::BTreeMap;
+
+use eyre::eyre;
+use serde_json::{json, Map, Value};
+
+use crate::nix_dev_env::NixDevEnv;
+
+/// The rust-analyzer settings whose environment variables run cargo and rustc in the riff
+/// environment, keyed as in VS Code's `settings.json`
+pub fn rust_analyzer_settings(dev_env: &NixDevEnv) -> Map<String, Value> {
+    let env = json!(rust_analyzer_env(dev_env));
+    ["server", "cargo", "runnables"]
+        .into_iter()
+        .map(|section| (format!("rust-analyzer.{section}.extraEnv"), env.clone()))
+        .collect()
+}
+
+/// The VS Code settings which run its integrated terminals and language servers (rust-analyzer,
+/// and ESLint's Node.js) in the riff environment
+pub fn vscode_settings(dev_env: &NixDevEnv) -> Map<String, Value> {
+    let mut settings = rust_analyzer_settings(dev_env);
+    let os = if cfg!(target_os = "macos") {
+        "osx"
+    } else {
+        "linux"
+    };
+    settings.insert(
+        format!("terminal.integrated.env.{os}"),
+        json!(terminal_env(dev_env)),
+    );
+    if let Some(node) = dev_env.find_program("node") {
+        settings.insert(
+            "eslint.runtime".to_string(),
+            json!(node.display().to_string()),
+        );
+    }
+    settings
+}
+
+/// `existing` VS Code settings with `settings` merged over them, keeping the settings riff doesn't
+/// set
+pub fn merge_vscode_settings(
+    existing: &str,
+    settings: Map<String, Value>,
+) -> color_eyre::Result<Value> {
+    let mut merged = if existing.trim().is_empty() {
+        Map::new()
+    } else {
+        // VS Code allows comments, which riff couldn't keep
+        match serde_json::from_str(existing) {
+            Ok(Value::Object(existing)) => existing,
+            _ => {
+                return Err(eyre!(
+                    "The existing settings aren't plain JSON (comments can't be merged), preview \
+                    the settings with `--dry-run` and add them by hand"
+                ))
+            }
+        }
+    };
+    merged.extend(settings);
+    Ok(Value::Object(merged))
+}
+
+/// The environment's variables, with `CARGO` and `RUSTC` pointing at its toolchain and its `PATH`
+/// in front of the editor's
+fn rust_analyzer_env(dev_env: &NixDevEnv) -> BTreeMap<String, String> {
+    let mut env = terminal_env(dev_env);
+    for (variable, program) in [("CARGO", "cargo"), ("RUSTC", "rustc")] {
+        if let Some(program) = dev_env.find_program(program) {
+            env.insert(variable.to_string(), program.display().to_string());
+        }
+    }
+    env
+}
+
+/// The environment's variables, with its `PATH` in front of the editor's
+fn terminal_env(dev_env: &NixDevEnv) -> BTreeMap<String, String> {
+    let mut env = dev_env
+        .exported_variables()
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<BTreeMap<_, _>>();
+    if let Some(path) = env.get_mut("PATH") {
+        // Substituted by the editor, so tools the environment lacks are still found
+        path.push_str(":${env:PATH}");
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rust_analyzer_settings_use_the_environment() -> eyre::Result<()> {
+        let bin = tempfile::TempDir::new()?;
+        std::fs::write(bin.path().join("cargo"), "")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(
+                bin.path().join("cargo"),
+                std::fs::Permissions::from_mode(0o755),
+            )?;
+        }
+        let dev_env: NixDevEnv = serde_json::from_value(json!({
+            "variables": {
+                "PATH": { "type": "exported", "value": bin.path() },
+                "OPENSSL_DIR": { "type": "exported", "value": "/nix/store/openssl" },
+                "TMPDIR": { "type": "exported", "value": "/build" },
+                "shellHook": { "type": "var", "value": "echo hi" },
+            }
+        }))?;
+
+        let settings = rust_analyzer_settings(&dev_env);
+        let env = &settings["rust-analyzer.server.extraEnv"];
+        assert_eq!(env["OPENSSL_DIR"], "/nix/store/openssl");
+        assert_eq!(
+            env["PATH"],
+            format!("{}:${{env:PATH}}", bin.path().display())
+        );
+        assert_eq!(env["CARGO"], bin.path().join("cargo").display().to_string());
+        assert!(env.get("RUSTC").is_none());
+        assert!(env.get("TMPDIR").is_none());
+        assert!(env.get("shellHook").is_none());
+        assert_eq!(settings["rust-analyzer.runnables.extraEnv"], *env);
+        Ok(())
+    }
+
+    #[test]
+    fn vscode_settings_are_merged() -> eyre::Result<()> {
+        let settings = Map::from_iter([
+            ("editor.tabSize".to_string(), json!(4)),
+            (
+                "rust-analyzer.cargo.extraEnv".to_string(),
+                json!({"A": "1"}),
+            ),
+        ]);
+        let merged = merge_vscode_settings(
+            r#"{"editor.tabSize": 2, "files.trimTrailingWhitespace": true}"#,
+            settings.clone(),
+        )?;
+        assert_eq!(
+            merged,
+            json!({
+                "editor.tabSize": 4,
+                "files.trimTrailingWhitespace": true,
+                "rust-analyzer.cargo.extraEnv": {"A": "1"},
+            })
+        );
+        assert_eq!(
+            merge_vscode_settings("", settings.clone())?,
+            Value::Object(settings.clone())
+        );
+        // The user's settings stay in the order they wrote them
+        let merged = merge_vscode_settings(r#"{"z.last": 1, "a.first": 2}"#, settings.clone())?;
+        let keys = merged.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert_eq!(keys[..2], ["z.last", "a.first"]);
+        assert!(merge_vscode_settings("// tabs\n{}", settings).is_err());
+        Ok(())
+    }
+}
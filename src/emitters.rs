@@ -0,0 +1,259 @@
+//! The files `riff generate` can produce for a project, each made by an [`Emitter`].
+//!
+//! Adding an integration means adding an [`EmitKind`] and an emitter which turns the generated
+//! flake into the integration's file.
+
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use eyre::eyre;
+use serde_json::json;
+
+/// The first line of the files riff generates and may remove again, with `riff clean`
+pub const GENERATED_MARKER: &str = "# Generated by riff";
+
+/// Whether `contents` are of a file riff generated (and wasn't ejected)
+pub fn is_generated(contents: &str) -> bool {
+    contents.starts_with(GENERATED_MARKER)
+}
+
+/// Everything emitters make their files from
+#[derive(Debug)]
+pub struct EmitContext<'a> {
+    pub project_dir: &'a Path,
+    /// The `--manifest-path` riff was given, if any
+    pub manifest_path: Option<&'a Path>,
+    /// The directory of the project's `Cargo.toml`
+    pub cargo_dir: PathBuf,
+    /// The root of the Cargo workspace, which holds `Cargo.lock`
+    pub cargo_workspace_root: PathBuf,
+    /// The generated `flake.nix`
+    pub flake_nix: String,
+    /// The generated `flake.lock`, unless locking was skipped with `--no-eval`
+    pub flake_lock: Option<String>,
+}
+
+/// Produces one file from the generated flake
+pub trait Emitter {
+    /// Where the file goes, relative to the output directory
+    fn path(&self) -> &'static str;
+    /// The file's contents, or `None` if there is nothing to write
+    fn emit(&self, context: &EmitContext) -> color_eyre::Result<Option<String>>;
+}
+
+/// The kinds of file `--emit` selects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EmitKind {
+    /// `flake.nix`
+    Flake,
+    /// `flake.lock`
+    Lock,
+    /// A direnv `.envrc` which loads the riff environment
+    Envrc,
+    /// A `.devcontainer/devcontainer.json` which enters the generated flake
+    Devcontainer,
+    /// A `rust-analyzer.toml` which runs cargo inside riff
+    RustAnalyzer,
+}
+
+impl EmitKind {
+    pub fn emitter(self) -> &'static dyn Emitter {
+        match self {
+            Self::Flake => &FlakeNix,
+            Self::Lock => &FlakeLock,
+            Self::Envrc => &Envrc,
+            Self::Devcontainer => &Devcontainer,
+            Self::RustAnalyzer => &RustAnalyzer,
+        }
+    }
+
+    /// Whether the emitted file needs `nix flake lock` to have run
+    pub fn needs_eval(self) -> bool {
+        self == Self::Lock
+    }
+}
+
+struct FlakeNix;
+
+impl Emitter for FlakeNix {
+    fn path(&self) -> &'static str {
+        "flake.nix"
+    }
+
+    fn emit(&self, context: &EmitContext) -> color_eyre::Result<Option<String>> {
+        Ok(Some(context.flake_nix.clone()))
+    }
+}
+
+struct FlakeLock;
+
+impl Emitter for FlakeLock {
+    fn path(&self) -> &'static str {
+        "flake.lock"
+    }
+
+    fn emit(&self, context: &EmitContext) -> color_eyre::Result<Option<String>> {
+        Ok(context.flake_lock.clone())
+    }
+}
+
+struct Envrc;
+
+impl Emitter for Envrc {
+    fn path(&self) -> &'static str {
+        ".envrc"
+    }
+
+    fn emit(&self, context: &EmitContext) -> color_eyre::Result<Option<String>> {
+        // `cargo metadata` reports the workspace root canonicalized
+        let canonical_project_dir = context.project_dir.canonicalize().ok();
+        let relative = |path: PathBuf| {
+            let path = path
+                .strip_prefix(context.project_dir)
+                .ok()
+                .or_else(|| path.strip_prefix(canonical_project_dir.as_deref()?).ok())
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| path.clone());
+            crate::nix_dev_env::shell_quote(&path.to_string_lossy())
+        };
+        let manifest = relative(context.cargo_dir.join("Cargo.toml"));
+        let lock = relative(context.cargo_workspace_root.join("Cargo.lock"));
+        let watched = [
+            manifest.clone(),
+            lock,
+            "riff.toml".into(),
+            "riff.lock".into(),
+        ]
+        .into_iter()
+        .chain(
+            crate::flake_generator::language_files(context.project_dir)
+                .into_iter()
+                .map(relative),
+        )
+        .collect::<Vec<_>>()
+        .join(" ");
+        let print_dev_env = match context.manifest_path {
+            Some(_) => format!("riff print-dev-env --manifest-path {manifest}"),
+            None => "riff print-dev-env".to_string(),
+        };
+        Ok(Some(format!(
+            "\
+# Generated by riff: load the riff environment whenever you enter this directory
+watch_file {watched}
+eval \"$({print_dev_env})\"
+"
+        )))
+    }
+}
+
+struct Devcontainer;
+
+impl Emitter for Devcontainer {
+    fn path(&self) -> &'static str {
+        ".devcontainer/devcontainer.json"
+    }
+
+    fn emit(&self, context: &EmitContext) -> color_eyre::Result<Option<String>> {
+        let name = context
+            .project_dir
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| eyre!("Could not name the project after its directory"))?;
+        let devcontainer = json!({
+            "name": name,
+            "image": "mcr.microsoft.com/devcontainers/base:ubuntu",
+            "features": {
+                "ghcr.io/devcontainers/features/nix:1": {
+                    "extraNixConfig": "experimental-features = nix-command flakes",
+                },
+            },
+            // Realize the environment once, so shells in the container start quickly
+            "postCreateCommand": "nix develop --command true",
+        });
+        Ok(Some(serde_json::to_string_pretty(&devcontainer)? + "\n"))
+    }
+}
+
+struct RustAnalyzer;
+
+impl Emitter for RustAnalyzer {
+    fn path(&self) -> &'static str {
+        "rust-analyzer.toml"
+    }
+
+    fn emit(&self, _context: &EmitContext) -> color_eyre::Result<Option<String>> {
+        let command = [
+            "riff",
+            "run",
+            "--",
+            "cargo",
+            "check",
+            "--workspace",
+            "--all-targets",
+            "--message-format=json",
+        ];
+        let command = serde_json::to_string(&command)?;
+        Ok(Some(format!(
+            "\
+# Generated by riff: run cargo inside the riff environment
+[check]
+overrideCommand = {command}
+
+[cargo.buildScripts]
+overrideCommand = {command}
+"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emitters() -> eyre::Result<()> {
+        let project_dir = tempfile::TempDir::new()?;
+        let context = EmitContext {
+            project_dir: project_dir.path(),
+            manifest_path: None,
+            cargo_dir: project_dir.path().to_owned(),
+            cargo_workspace_root: project_dir.path().to_owned(),
+            flake_nix: "{ }".to_string(),
+            flake_lock: None,
+        };
+
+        assert_eq!(
+            EmitKind::Flake.emitter().emit(&context)?.as_deref(),
+            Some("{ }")
+        );
+        assert_eq!(EmitKind::Lock.emitter().emit(&context)?, None);
+
+        std::fs::write(project_dir.path().join("app.cabal"), "")?;
+        let envrc = EmitKind::Envrc
+            .emitter()
+            .emit(&context)?
+            .unwrap_or_default();
+        assert!(envrc.contains(
+            "watch_file Cargo.toml Cargo.lock riff.toml riff.lock cabal.project stack.yaml app.cabal global.json\n"
+        ));
+
+        let devcontainer = EmitKind::Devcontainer
+            .emitter()
+            .emit(&context)?
+            .unwrap_or_default();
+        let devcontainer: serde_json::Value = serde_json::from_str(&devcontainer)?;
+        assert!(devcontainer["features"]["ghcr.io/devcontainers/features/nix:1"].is_object());
+
+        let rust_analyzer = EmitKind::RustAnalyzer
+            .emitter()
+            .emit(&context)?
+            .unwrap_or_default();
+        let rust_analyzer: toml::Value = toml::from_str(&rust_analyzer)?;
+        assert_eq!(
+            rust_analyzer["check"]["overrideCommand"][0].as_str(),
+            Some("riff")
+        );
+        Ok(())
+    }
+}
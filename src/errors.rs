@@ -0,0 +1,221 @@
+//! Errors which tools wrapping riff can tell apart.
+//!
+//! Each kind of error has a stable code (eg `E0001`), name (eg `no-project`), and exit code.
+//! `--error-format json` prints them as a JSON object on stderr instead of a human readable report.
+
+use std::process::ExitCode;
+
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::dependency_registry::DependencyRegistryError;
+use crate::warnings::{UnknownFieldsDenied, WarningsDenied};
+
+/// How errors are printed, see `--error-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ErrorFormat {
+    /// A report for people to read
+    #[default]
+    Human,
+    /// A JSON object with the error's `code`, `name`, `message`, and `causes`
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Any error without a more specific code
+    Other,
+    /// The directory doesn't contain a project riff recognizes
+    NoProject,
+    /// A tool riff runs, such as `nix` or `cargo`, isn't installed
+    MissingTool,
+    /// A `nix` command failed
+    NixFailed,
+    /// `cargo metadata` failed
+    CargoFailed,
+    /// The dependency registry couldn't be loaded
+    RegistryUnavailable,
+    /// Warnings were reported with `--deny-warnings` set
+    WarningsDenied,
+    /// A `--verify` probe failed in the environment
+    BrokenEnvironment,
+    /// Something needed wasn't cached locally, with `--offline=strict-offline`
+    NotCached,
+}
+
+impl ErrorCode {
+    /// Every code, to check that they're unique
+    #[cfg(test)]
+    pub const ALL: &'static [ErrorCode] = &[
+        Self::Other,
+        Self::NoProject,
+        Self::MissingTool,
+        Self::NixFailed,
+        Self::CargoFailed,
+        Self::RegistryUnavailable,
+        Self::WarningsDenied,
+        Self::BrokenEnvironment,
+        Self::NotCached,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Other => "E0000",
+            Self::NoProject => "E0001",
+            Self::MissingTool => "E0002",
+            Self::NixFailed => "E0003",
+            Self::CargoFailed => "E0004",
+            Self::RegistryUnavailable => "E0005",
+            Self::WarningsDenied => "E0006",
+            Self::BrokenEnvironment => "E0007",
+            Self::NotCached => "E0008",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Other => "error",
+            Self::NoProject => "no-project",
+            Self::MissingTool => "missing-tool",
+            Self::NixFailed => "nix-failed",
+            Self::CargoFailed => "cargo-failed",
+            Self::RegistryUnavailable => "registry-unavailable",
+            Self::WarningsDenied => "warnings-denied",
+            Self::BrokenEnvironment => "broken-environment",
+            Self::NotCached => "not-cached",
+        }
+    }
+
+    /// What riff exits with, from 240 up so it can't be mistaken for the exit code of a command
+    /// `riff run` or `riff shell` ran, which riff exits with when that command fails
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Other => 240,
+            Self::NoProject => 241,
+            Self::MissingTool => 242,
+            Self::NixFailed => 243,
+            Self::CargoFailed => 244,
+            Self::RegistryUnavailable => 245,
+            Self::WarningsDenied => 246,
+            Self::BrokenEnvironment => 247,
+            Self::NotCached => 248,
+        }
+    }
+
+    /// The code of the most specific error in `err`'s chain, the one nearest its root cause
+    pub fn of(err: &eyre::Report) -> Self {
+        err.chain()
+            .rev()
+            .find_map(|err| {
+                if let Some(err) = err.downcast_ref::<RiffError>() {
+                    Some(err.code)
+                } else if err.is::<DependencyRegistryError>() {
+                    Some(Self::RegistryUnavailable)
+                } else if err.is::<WarningsDenied>() || err.is::<UnknownFieldsDenied>() {
+                    Some(Self::WarningsDenied)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(Self::Other)
+    }
+}
+
+/// An error with an [`ErrorCode`]
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct RiffError {
+    pub code: ErrorCode,
+    message: String,
+    #[source]
+    source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
+}
+
+impl RiffError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn with_source(
+        mut self,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    ) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+}
+
+/// Print `err` in `format`, returning the exit code for it
+pub fn report(err: &eyre::Report, format: ErrorFormat) -> ExitCode {
+    let code = ErrorCode::of(err);
+    crate::spinner::suspend(|| match format {
+        ErrorFormat::Human => eprintln!("Error: {err:?}"),
+        ErrorFormat::Json => eprintln!("{}", to_json(err, code)),
+    });
+    ExitCode::from(code.exit_code())
+}
+
+fn to_json(err: &eyre::Report, code: ErrorCode) -> serde_json::Value {
+    json!({
+        "code": code.code(),
+        "name": code.name(),
+        "message": err.to_string(),
+        "causes": err.chain().skip(1).map(ToString::to_string).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes_are_found_in_the_chain() {
+        let err = eyre::Report::new(
+            RiffError::new(ErrorCode::NixFailed, "`nix flake lock` failed")
+                .with_source(std::io::Error::from(std::io::ErrorKind::NotFound)),
+        )
+        .wrap_err("Could not prepare the environment");
+        assert_eq!(ErrorCode::of(&err), ErrorCode::NixFailed);
+        assert_eq!(
+            to_json(&err, ErrorCode::of(&err)),
+            json!({
+                "code": "E0003",
+                "name": "nix-failed",
+                "message": "Could not prepare the environment",
+                "causes": ["`nix flake lock` failed", "entity not found"],
+            })
+        );
+
+        // A coded error caused by another is about the cause
+        let err = eyre::Report::new(
+            RiffError::new(
+                ErrorCode::BrokenEnvironment,
+                "Could not load the environment",
+            )
+            .with_source(RiffError::new(
+                ErrorCode::MissingTool,
+                "`nix` isn't installed",
+            )),
+        );
+        assert_eq!(ErrorCode::of(&err), ErrorCode::MissingTool);
+
+        let err = eyre::Report::new(WarningsDenied(2));
+        assert_eq!(ErrorCode::of(&err), ErrorCode::WarningsDenied);
+        assert_eq!(ErrorCode::of(&eyre::eyre!("oops")), ErrorCode::Other);
+    }
+
+    #[test]
+    fn error_codes_are_unique() {
+        for (i, a) in ErrorCode::ALL.iter().enumerate() {
+            for b in &ErrorCode::ALL[i + 1..] {
+                assert_ne!(a.code(), b.code());
+                assert_ne!(a.name(), b.name());
+                assert_ne!(a.exit_code(), b.exit_code());
+            }
+        }
+    }
+}
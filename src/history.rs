@@ -0,0 +1,236 @@
+//! A local history of `riff run`s, for seeing where the time of a dev loop goes without sending
+//! anything anywhere.
+//!
+//! Recording is opt-in, with `riff history enable`. Each run is then a line of JSON in
+//! `$XDG_STATE_HOME/riff/history.jsonl`:
+//!
+//! ```json
+//! {"timestamp":1681300000,"project":"3f2a9c1e07b4d6a8","command":"cargo test","environment_ms":840,"duration_ms":15210,"exit_code":0}
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use eyre::WrapErr;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::flake_generator;
+use crate::RIFF_XDG_PREFIX;
+
+/// The history, in `$XDG_STATE_HOME/riff`
+const HISTORY_FILE: &str = "history.jsonl";
+/// Runs are recorded while this file exists, in `$XDG_CONFIG_HOME/riff`
+const HISTORY_ENABLED_PATH: &str = "history-enabled";
+
+/// One `riff run`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the run started, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// A hash of the project's directory, so runs of a project can be told apart without
+    /// recording where it is
+    pub project: String,
+    /// The command, and any `--and` commands, as given
+    pub command: String,
+    /// How long preparing the environment took, if riff got that far
+    pub environment_ms: Option<u64>,
+    /// How long the whole run took, preparing the environment included
+    pub duration_ms: u64,
+    /// What the command exited with (`128 + signal` if it was killed by a signal), or nothing if
+    /// riff itself failed or the exit code is unknown
+    pub exit_code: Option<i32>,
+}
+
+/// Whether runs are recorded
+pub fn enabled() -> bool {
+    xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)
+        .ok()
+        .and_then(|xdg_dirs| xdg_dirs.find_config_file(HISTORY_ENABLED_PATH))
+        .is_some()
+}
+
+/// Start or stop recording runs
+pub async fn set_enabled(enabled: bool) -> eyre::Result<()> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    let path = xdg_dirs.place_config_file(HISTORY_ENABLED_PATH)?;
+    if enabled {
+        tokio::fs::write(
+            &path,
+            "riff records runs in its history while this file exists, see `riff history`.\n",
+        )
+        .await?;
+    } else {
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The hash a project's runs are recorded under
+pub fn project_hash(project_dir: &Path) -> String {
+    let mut hash = flake_generator::project_cache_key(project_dir);
+    hash.truncate(16);
+    hash
+}
+
+/// The seconds since the Unix epoch of `time`
+pub fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+fn history_path() -> eyre::Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.place_state_file(HISTORY_FILE)?)
+}
+
+/// Add `entry` to the end of the history
+pub async fn record(entry: &Entry) -> eyre::Result<()> {
+    // Nothing ran in a dry run, so there's nothing to remember
+    if crate::execution::dry_run() {
+        return Ok(());
+    }
+    let path = history_path()?;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // Appends of a single line don't interleave with those of other riff processes
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .wrap_err_with(|| format!("Could not open `{}`", path.display()))?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// Every recorded run, oldest first
+pub async fn load() -> eyre::Result<Vec<Entry>> {
+    let path = history_path()?;
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display()))
+        }
+    };
+    Ok(parse(&contents))
+}
+
+/// Forget every recorded run
+pub async fn clear() -> eyre::Result<()> {
+    let path = history_path()?;
+    if !crate::execution::perform(crate::execution::Effect::Remove(path.clone())) {
+        return Ok(());
+    }
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn parse(contents: &str) -> Vec<Entry> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                // A line cut short by a riff which was killed while writing it
+                tracing::debug!(%err, line, "Skipping a history entry which can't be parsed");
+                None
+            }
+        })
+        .collect()
+}
+
+/// How a command fared over its recorded runs
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandStats {
+    pub runs: usize,
+    pub failures: usize,
+    /// The median time preparing the environment took
+    pub median_environment: Duration,
+    /// The median time a whole run took
+    pub median_duration: Duration,
+}
+
+/// The stats of each command in `entries`
+pub fn stats(entries: &[Entry]) -> BTreeMap<&str, CommandStats> {
+    let mut by_command = BTreeMap::<&str, Vec<&Entry>>::new();
+    for entry in entries {
+        by_command.entry(&entry.command).or_default().push(entry);
+    }
+    by_command
+        .into_iter()
+        .map(|(command, entries)| {
+            let stats = CommandStats {
+                runs: entries.len(),
+                failures: entries
+                    .iter()
+                    .filter(|entry| entry.exit_code != Some(0))
+                    .count(),
+                median_environment: median(entries.iter().filter_map(|entry| entry.environment_ms)),
+                median_duration: median(entries.iter().map(|entry| entry.duration_ms)),
+            };
+            (command, stats)
+        })
+        .collect()
+}
+
+fn median(millis: impl Iterator<Item = u64>) -> Duration {
+    let mut millis = millis.collect::<Vec<_>>();
+    millis.sort_unstable();
+    Duration::from_millis(millis.get(millis.len() / 2).copied().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(command: &str, environment_ms: Option<u64>, exit_code: Option<i32>) -> Entry {
+        Entry {
+            timestamp: 1681300000,
+            project: "3f2a9c1e07b4d6a8".to_string(),
+            command: command.to_string(),
+            environment_ms,
+            duration_ms: environment_ms.unwrap_or(0) + 1000,
+            exit_code,
+        }
+    }
+
+    #[test]
+    fn history_is_summarized_by_command() {
+        let entries = parse(
+            &[
+                entry("cargo test", Some(800), Some(0)),
+                entry("cargo test", Some(200), Some(101)),
+                entry("cargo test", Some(400), Some(0)),
+                entry("cargo build", None, None),
+            ]
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .chain([r#"{"timestamp":16813"#.to_string()])
+            .collect::<Vec<_>>()
+            .join("\n"),
+        );
+        assert_eq!(entries.len(), 4);
+
+        let stats = stats(&entries);
+        assert_eq!(
+            stats["cargo test"],
+            CommandStats {
+                runs: 3,
+                failures: 1,
+                median_environment: Duration::from_millis(400),
+                median_duration: Duration::from_millis(1400),
+            }
+        );
+        assert_eq!(stats["cargo build"].failures, 1);
+    }
+}
@@ -0,0 +1,134 @@
+//! The HTTP client used for every request riff makes: refreshing the registry, sending telemetry,
+//! fetching layers, and downloading the Nix installer.
+//!
+//! Requests give up after the configured timeouts instead of hanging on a bad network, and go
+//! through the proxies named by `HTTPS_PROXY`, `HTTP_PROXY`, and `ALL_PROXY`, except for hosts
+//! matched by `NO_PROXY`.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use clap::Args;
+use reqwest::{Client, NoProxy, Proxy};
+
+const DEFAULT_NETWORK_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+static NETWORK_OPTIONS: OnceLock<NetworkOptions> = OnceLock::new();
+
+#[derive(Debug, Clone, Args)]
+pub struct NetworkOptions {
+    /// Give up on a network request which hasn't finished after this many seconds
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_NETWORK_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = DEFAULT_NETWORK_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub network_timeout: u64,
+    /// Give up on connecting to a server after this many seconds
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_CONNECT_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = DEFAULT_CONNECT_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub connect_timeout: u64,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self {
+            network_timeout: DEFAULT_NETWORK_TIMEOUT_SECS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT_SECS,
+        }
+    }
+}
+
+impl NetworkOptions {
+    /// Use these options for every HTTP client created afterwards
+    pub fn install(self) {
+        if NETWORK_OPTIONS.set(self).is_err() {
+            tracing::debug!("Network options were already installed");
+        }
+    }
+
+    pub fn network_timeout(&self) -> Duration {
+        Duration::from_secs(self.network_timeout)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+}
+
+pub fn options() -> &'static NetworkOptions {
+    NETWORK_OPTIONS.get_or_init(Default::default)
+}
+
+/// An HTTP client with the configured timeouts and proxies
+pub fn client() -> reqwest::Result<Client> {
+    let options = options();
+    let mut builder = Client::builder()
+        .timeout(options.network_timeout())
+        .connect_timeout(options.connect_timeout().min(options.network_timeout()))
+        // Replace reqwest's own proxy detection with ours, so `NO_PROXY` is honored
+        .no_proxy();
+    for proxy in proxies(|name| std::env::var(name).ok())? {
+        builder = builder.proxy(proxy);
+    }
+    builder.build()
+}
+
+/// The proxies configured by the environment variables `var` looks up, checking the lowercase
+/// spelling of each variable first, like curl does
+fn proxies(var: impl Fn(&str) -> Option<String>) -> reqwest::Result<Vec<Proxy>> {
+    let var = |name: &str| {
+        var(&name.to_lowercase())
+            .or_else(|| var(name))
+            .filter(|value| !value.is_empty())
+    };
+    let no_proxy = || var("NO_PROXY").and_then(|no_proxy| NoProxy::from_string(&no_proxy));
+
+    let mut proxies = vec![];
+    if let Some(url) = var("HTTPS_PROXY") {
+        // The URL may contain credentials, so it isn't logged
+        tracing::debug!("Using HTTPS proxy");
+        proxies.push(Proxy::https(url)?.no_proxy(no_proxy()));
+    }
+    if let Some(url) = var("HTTP_PROXY") {
+        tracing::debug!("Using HTTP proxy");
+        proxies.push(Proxy::http(url)?.no_proxy(no_proxy()));
+    }
+    if let Some(url) = var("ALL_PROXY") {
+        tracing::debug!("Using proxy for all requests");
+        proxies.push(Proxy::all(url)?.no_proxy(no_proxy()));
+    }
+    Ok(proxies)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn proxies_from_environment() -> eyre::Result<()> {
+        let env = HashMap::from([
+            ("https_proxy", "http://proxy.example.com:3128"),
+            ("HTTP_PROXY", "http://proxy.example.com:3128"),
+            ("ALL_PROXY", ""),
+            ("NO_PROXY", "localhost,.internal"),
+        ]);
+        let configured = proxies(|name| env.get(name).map(ToString::to_string))?;
+        assert_eq!(configured.len(), 2);
+
+        assert!(proxies(|_| None)?.is_empty());
+        Ok(())
+    }
+}
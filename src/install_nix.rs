@@ -0,0 +1,222 @@
+//! Installing Nix with the Determinate Nix Installer, for users who don't have it yet.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use atty::Stream;
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use tokio::process::{Child, Command};
+
+/// Where the release of the Determinate Nix Installer riff runs is served from, pinned so that
+/// [`INSTALLER_SHA256`] stays its checksum (see `releasing.md` for updating both)
+const INSTALLER_URL: &str = "https://install.determinate.systems/nix/tag/v0.18.0";
+const INSTALLER_HOST: &str = "install.determinate.systems";
+/// The SHA-256 of the installer at [`INSTALLER_URL`], which riff checks before running it
+// TODO: the checksum of the v0.18.0 installer, from `curl -sSfL <INSTALLER_URL> | sha256sum`
+const INSTALLER_SHA256: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Where the installer puts `nix`, which won't be on our `PATH` until a new shell starts
+const NIX_PROFILE_BIN: &str = "/nix/var/nix/profiles/default/bin";
+
+/// The `nix` executable on the `PATH`, if there is one
+pub fn find_nix() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join("nix"))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether the user was already asked, so they aren't asked again after saying no
+static ASKED: AtomicBool = AtomicBool::new(false);
+
+/// If `nix` is missing, offer to install it, returning whether it was installed.
+///
+/// Only offers when riff is run interactively, outside CI, and not `offline`, and only once. With
+/// `without_asking` (see `--install-nix`), installs without asking instead.
+pub async fn offer_install(offline: bool, without_asking: bool) -> color_eyre::Result<bool> {
+    if find_nix().is_some() || offline {
+        return Ok(false);
+    }
+    if !crate::execution::perform(crate::execution::Effect::Fetch(INSTALLER_URL.to_string())) {
+        return Ok(false);
+    }
+    if without_asking {
+        install(true).await?;
+        return Ok(true);
+    }
+    if is_ci::cached()
+        || !(atty::is(Stream::Stdin) && atty::is(Stream::Stderr))
+        || ASKED.swap(true, Ordering::SeqCst)
+    {
+        return Ok(false);
+    }
+
+    let accepted = crate::spinner::suspend(|| -> color_eyre::Result<bool> {
+        eprint!(
+            "Riff needs {nix}, which isn't installed. Install it now with the Determinate Nix Installer ({url})? [y/N] ",
+            nix = "Nix".cyan(),
+            url = INSTALLER_URL.blue().underline(),
+        );
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    })?;
+    if !accepted {
+        return Ok(false);
+    }
+
+    install(false).await?;
+    Ok(true)
+}
+
+/// Spawn `command`, which runs `nix`, offering to install Nix and trying again if it is missing.
+///
+/// Commands which generate a flake install Nix up front with `--install-nix`, so this only asks.
+pub async fn spawn_nix(command: &mut Command, offline: bool) -> std::io::Result<Child> {
+    match command.spawn() {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            match offer_install(offline, false).await {
+                Ok(true) => command.spawn(),
+                Ok(false) => Err(err),
+                Err(install_err) => {
+                    eprintln!("{}", format!("{install_err:?}").red());
+                    Err(err)
+                }
+            }
+        }
+        result => result,
+    }
+}
+
+/// Download, check, and run the installer, then make the new `nix` visible to this process
+///
+/// The installer asks for confirmation itself, unless `no_confirm` is set.
+pub async fn install(no_confirm: bool) -> color_eyre::Result<()> {
+    let script = download_installer().await?;
+    let script_file = tempfile::Builder::new()
+        .prefix("nix-installer")
+        .suffix(".sh")
+        .tempfile()?;
+    tokio::fs::write(script_file.path(), &script).await?;
+
+    // The installer asks for its own confirmation (and `sudo`), so it needs the terminal, which
+    // is only handed over synchronously. Keep that off the runtime's worker threads.
+    let script_path = script_file.path().to_path_buf();
+    let status = tokio::task::spawn_blocking(move || {
+        crate::spinner::suspend(|| {
+            let mut command = std::process::Command::new("sh");
+            command.arg(&script_path).arg("install");
+            if no_confirm {
+                command.arg("--no-confirm");
+            }
+            command.status()
+        })
+    })
+    .await?
+    .wrap_err("Could not run the Determinate Nix Installer")?;
+    if !status.success() {
+        return Err(eyre!(
+            "The Determinate Nix Installer failed ({status}), see its output above"
+        ));
+    }
+
+    add_to_path(Path::new(NIX_PROFILE_BIN));
+    match find_nix() {
+        Some(_) => {
+            eprintln!(
+                "{check} Installed {nix}. Start a new shell to use it outside of riff.",
+                check = "✓".green(),
+                nix = "Nix".cyan(),
+            );
+            Ok(())
+        }
+        None => Err(eyre!(
+            "The Determinate Nix Installer finished, but `nix` isn't in `{NIX_PROFILE_BIN}`"
+        )),
+    }
+}
+
+async fn download_installer() -> color_eyre::Result<Vec<u8>> {
+    let response = crate::http::client()?
+        .get(INSTALLER_URL)
+        .send()
+        .await
+        .wrap_err("Could not download the Determinate Nix Installer")?
+        .error_for_status()
+        .wrap_err("Could not download the Determinate Nix Installer")?;
+    let final_url = response.url().clone();
+    let script = response.bytes().await?.to_vec();
+    check_installer(&final_url, &script, INSTALLER_SHA256)?;
+    Ok(script)
+}
+
+/// Make sure we were served the installer over HTTPS, from its own host, and that it is the
+/// release riff trusts
+fn check_installer(
+    url: &reqwest::Url,
+    script: &[u8],
+    expected_sha256: &str,
+) -> color_eyre::Result<()> {
+    if url.scheme() != "https" || url.host_str() != Some(INSTALLER_HOST) {
+        return Err(eyre!(
+            "Refusing to run the Nix installer served from `{url}`, expected it from `{INSTALLER_URL}`"
+        ));
+    }
+    if !script.starts_with(b"#!") {
+        return Err(eyre!(
+            "Refusing to run the Nix installer from `{url}`, it isn't a shell script"
+        ));
+    }
+    let sha256 = crate::riff_lock::sha256_hex(script);
+    if !sha256.eq_ignore_ascii_case(expected_sha256) {
+        return Err(eyre!(
+            "Refusing to run the Nix installer from `{url}`, its SHA-256 is {sha256} but riff expected {expected_sha256}"
+        ));
+    }
+    Ok(())
+}
+
+fn add_to_path(dir: &Path) {
+    let mut paths = vec![dir.to_path_buf()];
+    paths.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+    if let Ok(path) = std::env::join_paths(paths) {
+        std::env::set_var("PATH", path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn installer_must_come_from_its_host_over_https() -> eyre::Result<()> {
+        let script = b"#!/bin/sh\necho install\n";
+        let sha256 = crate::riff_lock::sha256_hex(script);
+        let pinned = sha256.as_str();
+        assert!(check_installer(&INSTALLER_URL.parse()?, script, pinned).is_ok());
+        assert!(check_installer(
+            &"http://install.determinate.systems/nix".parse()?,
+            script,
+            pinned
+        )
+        .is_err());
+        assert!(check_installer(&"https://example.com/nix".parse()?, script, pinned).is_err());
+        assert!(check_installer(&INSTALLER_URL.parse()?, b"<html>", pinned).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn installer_must_match_the_pinned_checksum() -> eyre::Result<()> {
+        let script = b"#!/bin/sh\necho install\n";
+        let url = INSTALLER_URL.parse()?;
+        let sha256 = crate::riff_lock::sha256_hex(script);
+        assert!(check_installer(&url, b"#!/bin/sh\necho tampered\n", &sha256).is_err());
+        assert!(check_installer(&url, script, &sha256.to_uppercase()).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+//! Layers: profiles shared from other repositories, so several projects can use the same ones.
+//!
+//! A layer is a `riff-layer.toml` published in a repository or at a URL:
+//!
+//! ```toml
+//! version = 1
+//!
+//! substituters = ["https://cache.example.com"]
+//! trusted-public-keys = ["cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ="]
+//!
+//! [profile.gpu]
+//! runtime-inputs = ["cudatoolkit"]
+//!
+//! [conflicts]
+//! BINDGEN_EXTRA_CLANG_ARGS = "last"
+//! ```
+//!
+//! `riff layers add github:acme/riff-layers` fetches and validates it, caches it in
+//! `$XDG_CACHE_HOME/riff/layers`, and records its source in `$XDG_CONFIG_HOME/riff/layers.toml`.
+//! A project then uses it with `include = ["riff-layers"]` in its `riff.toml`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use xdg::BaseDirectories;
+
+use crate::project_config::{ConflictStrategy, Profile};
+use crate::RIFF_XDG_PREFIX;
+
+/// The file a layer's repository publishes
+pub const LAYER_FILE: &str = "riff-layer.toml";
+/// The registered layers, in `$XDG_CONFIG_HOME/riff`
+const LAYERS_CONFIG_FILE: &str = "layers.toml";
+/// The fetched layers, in `$XDG_CACHE_HOME/riff`
+const LAYERS_CACHE_DIR: &str = "layers";
+const SUPPORTED_LAYER_VERSIONS: &[usize] = &[1];
+
+/// Where a layer is fetched from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerSource {
+    /// `github:owner/repo[/ref]`, fetching `riff-layer.toml` from the repository's root
+    GitHub {
+        owner: String,
+        repo: String,
+        reference: Option<String>,
+    },
+    /// An `https://` URL of the layer file itself
+    Url(reqwest::Url),
+}
+
+impl FromStr for LayerSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("github:") {
+            let mut parts = path.splitn(3, '/');
+            let (Some(owner), Some(repo)) = (parts.next(), parts.next()) else {
+                return Err(format!("`{s}` should look like `github:owner/repo[/ref]`"));
+            };
+            if owner.is_empty() || repo.is_empty() {
+                return Err(format!("`{s}` should look like `github:owner/repo[/ref]`"));
+            }
+            return Ok(Self::GitHub {
+                owner: owner.to_string(),
+                repo: repo.to_string(),
+                reference: parts.next().map(ToString::to_string),
+            });
+        }
+        let url =
+            reqwest::Url::parse(s).map_err(|err| format!("`{s}` is not a valid URL: {err}"))?;
+        if url.scheme() != "https" {
+            return Err(format!(
+                "`{s}` should be `github:owner/repo[/ref]` or an `https://` URL"
+            ));
+        }
+        Ok(Self::Url(url))
+    }
+}
+
+impl Display for LayerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::GitHub {
+                owner,
+                repo,
+                reference: None,
+            } => write!(f, "github:{owner}/{repo}"),
+            Self::GitHub {
+                owner,
+                repo,
+                reference: Some(reference),
+            } => write!(f, "github:{owner}/{repo}/{reference}"),
+            Self::Url(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+impl LayerSource {
+    /// Where the layer file is downloaded from
+    pub fn url(&self) -> String {
+        match self {
+            Self::GitHub {
+                owner,
+                repo,
+                reference,
+            } => format!(
+                "https://raw.githubusercontent.com/{owner}/{repo}/{}/{LAYER_FILE}",
+                reference.as_deref().unwrap_or("HEAD")
+            ),
+            Self::Url(url) => url.to_string(),
+        }
+    }
+
+    /// The name the layer is registered under unless another is given: the repository's name, or
+    /// the file's name without its extension
+    pub fn default_name(&self) -> String {
+        match self {
+            Self::GitHub { repo, .. } => repo.clone(),
+            Self::Url(url) => {
+                let file_name = url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .unwrap_or_default();
+                file_name
+                    .strip_suffix(".toml")
+                    .unwrap_or(file_name)
+                    .to_string()
+            }
+        }
+    }
+}
+
+/// The contents of a `riff-layer.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub(crate) version: usize,
+    /// Profiles which projects including the layer can select with `--profile`
+    #[serde(default)]
+    pub(crate) profile: BTreeMap<String, Profile>,
+    /// How to resolve environment variables which dependencies set to different values
+    #[serde(default)]
+    pub(crate) conflicts: HashMap<String, ConflictStrategy>,
+    /// Binary caches for Nix to fetch from, such as a company's own
+    #[serde(default)]
+    pub(crate) substituters: Vec<String>,
+    /// The public keys the binary caches sign with
+    #[serde(default, rename = "trusted-public-keys")]
+    pub(crate) trusted_public_keys: Vec<String>,
+}
+
+impl Layer {
+    /// Parse and validate a layer file
+    pub fn parse(contents: &str) -> color_eyre::Result<Self> {
+        let layer: Self = toml::from_str(contents)?;
+        if !SUPPORTED_LAYER_VERSIONS.contains(&layer.version) {
+            return Err(eyre!(
+                "Unsupported layer version: 1 (expected) != {} (got)",
+                layer.version
+            ));
+        }
+        Ok(layer)
+    }
+}
+
+/// The layers registered with `riff layers add`, stored in `layers.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct LayersConfig {
+    #[serde(default)]
+    layer: BTreeMap<String, RegisteredLayer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RegisteredLayer {
+    source: String,
+}
+
+impl LayersConfig {
+    async fn load() -> color_eyre::Result<Self> {
+        let path = config_path()?;
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => toml::from_str(&contents)
+                .wrap_err_with(|| format!("Could not parse `{}`", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display())),
+        }
+    }
+
+    async fn write(&self) -> color_eyre::Result<()> {
+        let path = config_path()?;
+        tokio::fs::write(&path, toml::to_string(self)?)
+            .await
+            .wrap_err_with(|| format!("Could not write `{}`", path.display()))
+    }
+}
+
+fn config_path() -> color_eyre::Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.place_config_file(LAYERS_CONFIG_FILE)?)
+}
+
+pub(crate) fn cache_path(name: &str) -> color_eyre::Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.place_cache_file(Path::new(LAYERS_CACHE_DIR).join(format!("{name}.toml")))?)
+}
+
+fn validate_name(name: &str) -> color_eyre::Result<()> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(is_valid) {
+        return Err(eyre!(
+            "`{name}` is not a valid layer name, use letters, digits, `-`, and `_`"
+        ));
+    }
+    Ok(())
+}
+
+/// Download the layer from `source`, validate it, and cache it as `name`
+async fn fetch(name: &str, source: &LayerSource) -> color_eyre::Result<Layer> {
+    let url = source.url();
+    tracing::debug!(%url, "Fetching layer `{name}`");
+    let contents = crate::http::client()?
+        .get(&url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .wrap_err_with(|| format!("Could not fetch the layer `{name}` from {url}"))?
+        .text()
+        .await?;
+    let layer = Layer::parse(&contents)
+        .wrap_err_with(|| format!("`{url}` is not a valid `{LAYER_FILE}`"))?;
+    let path = cache_path(name)?;
+    tokio::fs::write(&path, contents)
+        .await
+        .wrap_err_with(|| format!("Could not write `{}`", path.display()))?;
+    Ok(layer)
+}
+
+/// Fetch the layer at `source` and register it as `name` (or its default name)
+#[tracing::instrument(skip_all, fields(%source))]
+pub async fn add(name: Option<String>, source: LayerSource) -> color_eyre::Result<String> {
+    let name = name.unwrap_or_else(|| source.default_name());
+    validate_name(&name)?;
+    let mut config = LayersConfig::load().await?;
+    if let Some(existing) = config.layer.get(&name) {
+        if existing.source != source.to_string() {
+            return Err(eyre!(
+                "A layer named `{name}` is already registered from `{}`, choose another with `{name_flag}`",
+                existing.source,
+                name_flag = "--name".cyan(),
+            ));
+        }
+    }
+
+    fetch(&name, &source).await?;
+    config.layer.insert(
+        name.clone(),
+        RegisteredLayer {
+            source: source.to_string(),
+        },
+    );
+    config.write().await?;
+    Ok(name)
+}
+
+/// Fetch the named layers again, or all of them if `names` is empty
+#[tracing::instrument(skip_all)]
+pub async fn update(names: &[String]) -> color_eyre::Result<Vec<String>> {
+    let config = LayersConfig::load().await?;
+    for name in names {
+        if !config.layer.contains_key(name) {
+            return Err(eyre!("There is no layer named `{name}`"));
+        }
+    }
+    let mut updated = vec![];
+    for (name, registered) in &config.layer {
+        if !names.is_empty() && !names.contains(name) {
+            continue;
+        }
+        let source = registered.source.parse::<LayerSource>().map_err(|err| {
+            eyre!(
+                "The layer `{name}` in `{}` has an invalid source: {err}",
+                LAYERS_CONFIG_FILE
+            )
+        })?;
+        fetch(name, &source).await?;
+        updated.push(name.clone());
+    }
+    Ok(updated)
+}
+
+/// Unregister the layer `name` and forget its cached copy
+pub async fn remove(name: &str) -> color_eyre::Result<()> {
+    let mut config = LayersConfig::load().await?;
+    if config.layer.remove(name).is_none() {
+        return Err(eyre!("There is no layer named `{name}`"));
+    }
+    config.write().await?;
+    match tokio::fs::remove_file(cache_path(name)?).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The registered layers' names and sources
+pub async fn list() -> color_eyre::Result<BTreeMap<String, String>> {
+    Ok(LayersConfig::load()
+        .await?
+        .layer
+        .into_iter()
+        .map(|(name, registered)| (name, registered.source))
+        .collect())
+}
+
+/// Read the cached copy of the layer `name`, without using the network
+pub async fn load(name: &str) -> color_eyre::Result<Layer> {
+    let path = cache_path(name)?;
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(eyre!(
+                "The layer `{name}` isn't registered, add it with `{riff_layers_add}`",
+                riff_layers_add = "riff layers add <source> --name <name>".cyan(),
+            ))
+        }
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display()))
+        }
+    };
+    Layer::parse(&contents).wrap_err_with(|| {
+        format!(
+            "The cached layer `{name}` is invalid, refresh it with `{riff_layers_update}`",
+            riff_layers_update = format!("riff layers update {name}").cyan(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sources() -> Result<(), String> {
+        let source: LayerSource = "github:acme/riff-layers".parse()?;
+        assert_eq!(
+            source.url(),
+            "https://raw.githubusercontent.com/acme/riff-layers/HEAD/riff-layer.toml"
+        );
+        assert_eq!(source.default_name(), "riff-layers");
+        assert_eq!(source.to_string(), "github:acme/riff-layers");
+
+        let source: LayerSource = "github:acme/riff-layers/v2".parse()?;
+        assert_eq!(
+            source.url(),
+            "https://raw.githubusercontent.com/acme/riff-layers/v2/riff-layer.toml"
+        );
+        assert_eq!(source.to_string(), "github:acme/riff-layers/v2");
+
+        let source: LayerSource = "https://example.com/layers/gpu.toml".parse()?;
+        assert_eq!(source.default_name(), "gpu");
+
+        assert!("github:acme".parse::<LayerSource>().is_err());
+        assert!("http://example.com/gpu.toml"
+            .parse::<LayerSource>()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_layers() -> eyre::Result<()> {
+        let layer = Layer::parse(
+            r#"
+            version = 1
+
+            [profile.gpu]
+            runtime-inputs = ["cudatoolkit"]
+            "#,
+        )?;
+        assert!(layer.profile["gpu"].runtime_inputs.contains("cudatoolkit"));
+
+        assert!(Layer::parse("version = 2").is_err());
+        assert!(Layer::parse("version = 1\nnixpkgs = \"github:NixOS/nixpkgs\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn layer_names() {
+        assert!(validate_name("riff-layers_2").is_ok());
+        assert!(validate_name("../escape").is_err());
+        assert!(validate_name("").is_err());
+    }
+}
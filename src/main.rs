@@ -1,24 +1,56 @@
+mod cabal;
 mod cargo_metadata;
 mod cmds;
+mod command_runner;
 mod dependency_registry;
+mod detectors;
 mod dev_env;
+mod dotnet;
+mod editor;
+mod emitters;
+mod errors;
+mod execution;
+mod file_lock;
 mod flake_generator;
+mod history;
+mod http;
+mod install_nix;
+mod layers;
+mod nix_config;
 mod nix_dev_env;
+mod nix_progress;
+mod offline;
+mod otel;
+mod picker;
+mod platform;
+mod process_limits;
+mod project_config;
+mod remote;
+mod riff_lock;
 mod spinner;
+mod target_cfg;
 mod telemetry;
+mod timings;
+mod trust;
+mod verify;
+mod warnings;
 
 use std::error::Error;
-use std::io::Write;
 use std::process::ExitCode;
 
 use atty::Stream;
 use clap::Parser;
 use eyre::WrapErr;
-use owo_colors::OwoColorize;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 use cmds::Commands;
+use errors::ErrorFormat;
+use flake_generator::Interrupted;
+use http::NetworkOptions;
+use offline::OfflineMode;
+use process_limits::ProcessLimits;
+use spinner::ProgressMode;
 use telemetry::Telemetry;
 
 const RIFF_XDG_PREFIX: &str = "riff";
@@ -32,13 +64,112 @@ struct Cli {
     /// Turn off user telemetry ping
     #[clap(long, global = true, env = "RIFF_DISABLE_TELEMETRY")]
     disable_telemetry: bool,
-    /// Disable all network usage except `nix develop`
-    // TODO(@hoverbear): Can we disable that, too?
-    #[clap(long, global = true, env = "RIFF_OFFLINE")]
-    offline: bool,
+    /// Use what's cached instead of the network: `cached` (the default with `--offline`) may
+    /// still download packages missing from the Nix store, `strict-offline` downloads nothing and
+    /// fails if something isn't cached
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_OFFLINE",
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "online",
+        default_missing_value = "cached",
+        value_parser = offline::parse_offline_mode
+    )]
+    offline: OfflineMode,
+    /// Fail if riff reports any warnings which aren't suppressed in `riff.toml`
+    #[clap(long, global = true, env = "RIFF_DENY_WARNINGS")]
+    deny_warnings: bool,
+    /// Fail on fields riff doesn't know in `package.metadata.riff`, `workspace.metadata.riff`, the
+    /// dependency registry, and registry overlays, instead of warning about them
+    #[clap(long, global = true, env = "RIFF_STRICT")]
+    strict: bool,
+    /// The profile from `riff.toml` to apply to the environment
+    #[clap(long, global = true, env = "RIFF_PROFILE")]
+    profile: Option<String>,
+    /// The workspace member to prepare the environment for, leaving out what only other members'
+    /// dependencies need
+    #[clap(long, global = true, env = "RIFF_PACKAGE", value_name = "NAME")]
+    package: Option<String>,
+    /// A flake whose `overlays.default` (or `overlays.<name>`, given as `<flakeref>#<name>`) to
+    /// apply to Nixpkgs, after those in `riff.toml` (may be repeated)
+    #[clap(long, global = true, value_name = "FLAKEREF")]
+    overlay: Vec<String>,
+    /// Install Nix with the Determinate Nix Installer without asking, if it's missing
+    #[clap(long, global = true, env = "RIFF_INSTALL_NIX")]
+    install_nix: bool,
+    /// Use the current directory as the project, instead of looking for the project it's in
+    #[clap(long, global = true, env = "RIFF_NO_WALK_UP")]
+    no_walk_up: bool,
+    /// The `Cargo.toml` to read, when it isn't at the root of the project (eg `rust/Cargo.toml`)
+    #[clap(long, global = true, env = "RIFF_MANIFEST_PATH", value_name = "PATH")]
+    manifest_path: Option<std::path::PathBuf>,
+    /// The target triple which decides the target-specific dependencies
+    /// (`[target.'cfg(...)'.dependencies]`) needing inputs (default: this machine's), or which
+    /// `riff registry suggest` suggests settings for
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_TARGET",
+        value_name = "TRIPLE",
+        value_parser = target_cfg::parse_target
+    )]
+    target: Option<target_lexicon::Triple>,
+    /// Give directories without a project riff recognizes a base environment (git, GNU Make, and
+    /// pkg-config) instead of failing
+    #[clap(long, global = true, env = "RIFF_ALLOW_EMPTY")]
+    allow_empty: bool,
     /// Print out debug logging
     #[clap(long, global = true)]
     debug: bool,
+    /// How to print errors: a report for people to read, or a JSON object with a stable `code`
+    /// for tools wrapping riff
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_ERROR_FORMAT",
+        value_enum,
+        default_value_t = ErrorFormat::Human
+    )]
+    error_format: ErrorFormat,
+    /// Fetch the registry and the flake's inputs again, and run `cargo metadata` and evaluate the
+    /// environment again, instead of using cached copies
+    #[clap(long, global = true, env = "RIFF_REFRESH")]
+    refresh: bool,
+    /// Print what riff would do (commands it would run, files it would write, and requests it
+    /// would send) instead of doing it
+    #[clap(long, global = true, env = "RIFF_DRY_RUN")]
+    dry_run: bool,
+    /// Print how long each step of preparing the environment took
+    #[clap(long, global = true, env = "RIFF_TIMINGS")]
+    timings: bool,
+    /// Send riff's internal spans to this OpenTelemetry collector once riff is done, using OTLP
+    /// over HTTP (eg `http://localhost:4318`)
+    #[clap(
+        long,
+        global = true,
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT",
+        value_name = "URL"
+    )]
+    otel_endpoint: Option<String>,
+    /// How to report progress of long running steps
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_PROGRESS",
+        value_enum,
+        default_value_t = ProgressMode::Animated
+    )]
+    progress: ProgressMode,
+    /// Don't report progress, the same as `--progress quiet`
+    #[clap(short, long, global = true, env = "RIFF_QUIET")]
+    quiet: bool,
+    #[clap(flatten)]
+    process_limits: ProcessLimits,
+    #[clap(flatten)]
+    network: NetworkOptions,
 }
 
 #[tokio::main]
@@ -49,6 +180,11 @@ async fn main() -> color_eyre::Result<std::process::ExitCode> {
 
     setup_tracing().await?;
 
+    // Telemetry turned off in `riff setup` stays off, also for `riff`s run inside riff
+    if telemetry::disabled_by_preference() && std::env::var_os("RIFF_DISABLE_TELEMETRY").is_none() {
+        std::env::set_var("RIFF_DISABLE_TELEMETRY", "1");
+    }
+
     let maybe_args = Cli::try_parse();
 
     let args = match maybe_args {
@@ -57,41 +193,88 @@ async fn main() -> color_eyre::Result<std::process::ExitCode> {
             let telemetry_ok_via_env = match std::env::var("RIFF_DISABLE_TELEMETRY")
                 .or_else(|_| std::env::var("RIFF_OFFLINE"))
             {
-                Ok(val) if val == "false" || val == "0" || val.is_empty() => true,
+                Ok(val) if val == "false" || val == "0" || val == "online" || val.is_empty() => {
+                    true
+                }
                 Err(_) => true,
                 _ => false,
             };
-            let telemetry_ok_via_flag = !std::env::args()
-                .take_while(|v| v != "--")
-                .any(|v| v == *"--disable-telemetry" || v == *"--offline");
+            let telemetry_ok_via_flag = !std::env::args().take_while(|v| v != "--").any(|v| {
+                v == *"--disable-telemetry"
+                    || v == *"--offline"
+                    || (v.starts_with("--offline=") && v != *"--offline=online")
+            });
             if telemetry_ok_via_env && telemetry_ok_via_flag {
-                Telemetry::new().await.send().await.ok();
+                Telemetry::new().await.enqueue().await.ok();
+                telemetry::finish().await;
             }
             e.exit() // Dead!
         }
     };
-    match args.command {
-        Commands::PrintDevEnv(print_dev_env) => {
-            Ok(exit_status_to_exit_code(print_dev_env.cmd().await?))
-        }
-        Commands::Shell(shell) => Ok(exit_status_to_exit_code(shell.cmd().await?)),
-        Commands::Run(run) => {
-            let code = run.cmd().await?;
-            if let Some(code) = code {
-                if code == 127 {
-                    writeln!(
-                        std::io::stderr(),
-                        "The command you attempted to run was not found.
-Try running it in a shell; for example:
-\t{riff_run_example}\n",
-                        riff_run_example =
-                            format!("riff run -- sh -c '{}'", run.command.join(" ")).cyan(),
-                    )?;
-                }
-            }
+    if let Some(code) = platform::redirect_unsupported().await? {
+        return Ok(code);
+    }
 
-            Ok(exit_status_to_exit_code(code))
+    // Pipeline logs can't show an animated spinner
+    spinner::set_progress_mode(match args.command {
+        _ if args.quiet => ProgressMode::Quiet,
+        Commands::Ci(_) => ProgressMode::PlainVerbose,
+        _ => args.progress,
+    });
+    args.process_limits.clone().install();
+    args.network.clone().install();
+    let execution_context = execution::ExecutionContext {
+        dry_run: args.dry_run,
+    };
+    if args.timings {
+        timings::enable();
+    }
+    if let Some(endpoint) = &args.otel_endpoint {
+        otel::enable(endpoint);
+    }
+    let error_format = args.error_format;
+    let offline = args.offline.is_offline();
+
+    let result: color_eyre::Result<ExitCode> = execution::scope(execution_context, async move {
+        match args.command {
+            Commands::PrintDevEnv(print_dev_env) => {
+                Ok(exit_status_to_exit_code(print_dev_env.cmd().await?))
+            }
+            Commands::Shell(shell) => Ok(exit_status_to_exit_code(shell.cmd().await?)),
+            Commands::Eject(eject) => Ok(exit_status_to_exit_code(eject.cmd().await?)),
+            Commands::Generate(generate) => Ok(exit_status_to_exit_code(generate.cmd().await?)),
+            Commands::Doctor(doctor) => Ok(exit_status_to_exit_code(doctor.cmd().await?)),
+            Commands::Lock(lock) => Ok(exit_status_to_exit_code(lock.cmd().await?)),
+            Commands::Prepare(prepare) => Ok(exit_status_to_exit_code(prepare.cmd().await?)),
+            Commands::Layers(layers) => Ok(exit_status_to_exit_code(layers.cmd().await?)),
+            Commands::Ci(ci) => Ok(exit_status_to_exit_code(ci.cmd().await?)),
+            Commands::Setup(setup) => Ok(exit_status_to_exit_code(setup.cmd().await?)),
+            Commands::Env(env) => Ok(exit_status_to_exit_code(env.cmd().await?)),
+            Commands::Registry(registry) => Ok(exit_status_to_exit_code(registry.cmd().await?)),
+            Commands::PushCache(push_cache) => {
+                Ok(exit_status_to_exit_code(push_cache.cmd().await?))
+            }
+            Commands::History(history) => Ok(exit_status_to_exit_code(history.cmd().await?)),
+            Commands::Clean(clean) => Ok(exit_status_to_exit_code(clean.cmd().await?)),
+            Commands::Hook(hook) => Ok(exit_status_to_exit_code(hook.cmd().await?)),
+            Commands::Trust(trust) => Ok(exit_status_to_exit_code(trust.cmd().await?)),
+            Commands::Untrust(untrust) => Ok(exit_status_to_exit_code(untrust.cmd().await?)),
+            Commands::Shim(shim) => Ok(exit_status_to_exit_code(shim.cmd().await?)),
+            Commands::Which(which) => Ok(exit_status_to_exit_code(which.cmd().await?)),
+            Commands::SelfTest(self_test) => Ok(exit_status_to_exit_code(self_test.cmd().await?)),
+            Commands::Run(run) => Ok(exit_status_to_exit_code(run.cmd().await?)),
         }
+    })
+    .await;
+    timings::report();
+    telemetry::finish().await;
+    execution::scope(execution_context, otel::finish(offline)).await;
+
+    match result {
+        // Exit like a process killed by SIGINT would, rather than printing an error report
+        Err(err) if err.downcast_ref::<Interrupted>().is_some() => Ok(ExitCode::from(130)),
+        Err(err) => Ok(errors::report(&err, error_format)),
+        Ok(code) => Ok(code),
     }
 }
 
@@ -138,6 +321,7 @@ async fn setup_tracing() -> eyre::Result<()> {
         .with(filter_layer)
         .with(fmt_layer)
         .with(ErrorLayer::default())
+        .with(otel::OtlpLayer)
         .try_init()?;
 
     Ok(())
@@ -0,0 +1,241 @@
+//! The user's Nix configuration, which needs the experimental features riff's `nix` commands use,
+//! and the settings riff adds to it.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use eyre::WrapErr;
+use tokio::process::Command;
+
+/// The experimental features riff needs Nix to have enabled
+pub const REQUIRED_FEATURES: &[&str] = &["nix-command", "flakes"];
+
+/// The `nix` command `subcommand` (eg `["flake", "lock"]`), with the experimental features riff
+/// needs enabled for it whether or not the user's configuration enables them, and the configured
+/// substituters. Every `nix` command riff runs on the environment starts here.
+pub fn command<'a>(subcommand: impl IntoIterator<Item = &'a str>) -> Command {
+    let mut command = Command::new("nix");
+    command
+        .args([
+            "--extra-experimental-features",
+            &REQUIRED_FEATURES.join(" "),
+        ])
+        .args(subcommand);
+    apply_substituters(&mut command);
+    command
+}
+
+/// The experimental features enabled in Nix's configuration, or `None` if Nix couldn't be asked
+pub async fn experimental_features() -> Option<Vec<String>> {
+    // `nix show-config` became `nix config show` in Nix 2.20, and is deprecated since
+    for subcommand in [["config", "show"].as_slice(), ["show-config"].as_slice()] {
+        let Ok(output) = Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command"])
+            .args(subcommand)
+            .output()
+            .await
+        else {
+            return None;
+        };
+        if output.status.success() {
+            return Some(parse_experimental_features(&String::from_utf8_lossy(
+                &output.stdout,
+            )));
+        }
+    }
+    None
+}
+
+/// The required features missing from `enabled`
+pub fn missing_features(enabled: &[String]) -> Vec<&'static str> {
+    REQUIRED_FEATURES
+        .iter()
+        .copied()
+        .filter(|feature| !enabled.iter().any(|enabled| enabled == feature))
+        .collect()
+}
+
+/// Enable `features` in the user's `nix.conf`, returning its path. Features it already enables
+/// are left alone, so it is only written if some aren't.
+pub async fn enable_features(features: &[&str]) -> color_eyre::Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("nix")?;
+    let nix_conf = xdg_dirs.place_config_file("nix.conf")?;
+    let existing = match tokio::fs::read_to_string(&nix_conf).await {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Could not read `{}`", nix_conf.display()))
+        }
+    };
+    if let Some(contents) = with_features(&existing, features) {
+        crate::execution::write(&nix_conf, contents).await?;
+    }
+    Ok(nix_conf)
+}
+
+/// The `nix.conf` contents `existing` with `features` enabled, or `None` if it enables them all
+/// already
+fn with_features(existing: &str, features: &[&str]) -> Option<String> {
+    let enabled = [
+        configured_features(existing, "experimental-features"),
+        configured_features(existing, "extra-experimental-features"),
+    ]
+    .concat();
+    let missing = features
+        .iter()
+        .filter(|feature| !enabled.iter().any(|enabled| enabled == *feature))
+        .copied()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return None;
+    }
+    let mut contents = existing.to_string();
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    // `extra-` adds to the features enabled elsewhere (eg `/etc/nix/nix.conf`) instead of
+    // replacing them
+    contents.push_str(&format!(
+        "\n# Added by riff\nextra-experimental-features = {}\n",
+        missing.join(" ")
+    ));
+    Some(contents)
+}
+
+/// Binary caches to fetch from besides those in Nix's configuration, from `riff.toml` and layers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Substituters {
+    pub substituters: Vec<String>,
+    pub trusted_public_keys: Vec<String>,
+}
+
+impl Substituters {
+    /// What these settings add, to ask whether to trust the project configuring them
+    pub fn describe(&self) -> Vec<String> {
+        let substituters = self
+            .substituters
+            .iter()
+            .map(|substituter| format!("the binary cache `{substituter}`"));
+        let keys = self
+            .trusted_public_keys
+            .iter()
+            .map(|key| format!("the trusted public key `{key}`"));
+        substituters.chain(keys).collect()
+    }
+}
+
+static SUBSTITUTERS: OnceLock<Substituters> = OnceLock::new();
+
+/// Pass `substituters` to every `nix` command run afterwards
+pub fn use_substituters(substituters: Substituters) {
+    if SUBSTITUTERS.set(substituters).is_err() {
+        tracing::debug!("Substituters were already configured");
+    }
+}
+
+/// Add the configured substituters to a `nix` command
+///
+/// They're passed as `extra-` options, so they add to the caches in the user's `nix.conf` (and
+/// `NIX_USER_CONF_FILES`) instead of replacing them. A multi-user Nix only uses substituters which
+/// its `trusted-substituters` lists, or any if the user is in `trusted-users`.
+fn apply_substituters(command: &mut Command) {
+    let Some(substituters) = SUBSTITUTERS.get() else {
+        return;
+    };
+    if !substituters.substituters.is_empty() {
+        command.args([
+            "--option",
+            "extra-substituters",
+            &substituters.substituters.join(" "),
+        ]);
+    }
+    if !substituters.trusted_public_keys.is_empty() {
+        command.args([
+            "--option",
+            "extra-trusted-public-keys",
+            &substituters.trusted_public_keys.join(" "),
+        ]);
+    }
+}
+
+/// The features in the `experimental-features = ...` line of `nix show-config` output
+fn parse_experimental_features(show_config: &str) -> Vec<String> {
+    show_config
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == "experimental-features")
+        .map(|(_, value)| value.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// The features in every `setting = ...` line of a `nix.conf`
+fn configured_features(nix_conf: &str, setting: &str) -> Vec<String> {
+    nix_conf
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| line.split_once('='))
+        .filter(|(name, _)| name.trim() == setting)
+        .flat_map(|(_, value)| value.split_whitespace().map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_enable_the_required_features() {
+        let command = command(["flake", "lock"]);
+        assert_eq!(
+            command.as_std().get_args().collect::<Vec<_>>(),
+            [
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "flake",
+                "lock"
+            ]
+        );
+    }
+
+    #[test]
+    fn experimental_features_from_show_config() {
+        let enabled = parse_experimental_features(
+            "\
+cores = 0
+experimental-features = flakes nix-command
+extra-platforms =
+",
+        );
+        assert_eq!(enabled, vec!["flakes", "nix-command"]);
+        assert!(missing_features(&enabled).is_empty());
+        assert_eq!(
+            missing_features(&parse_experimental_features("cores = 0\n")),
+            vec!["nix-command", "flakes"]
+        );
+    }
+
+    #[test]
+    fn enabling_features_adds_only_missing_ones() {
+        let added = with_features("max-jobs = 4", &["nix-command", "flakes"]);
+        assert_eq!(
+            added.as_deref(),
+            Some("max-jobs = 4\n\n# Added by riff\nextra-experimental-features = nix-command flakes\n")
+        );
+        // Enabling them again changes nothing
+        assert_eq!(
+            with_features(&added.unwrap(), &["nix-command", "flakes"]),
+            None
+        );
+        assert_eq!(
+            with_features(
+                "experimental-features = nix-command # for riff\n",
+                &["nix-command", "flakes"]
+            )
+            .as_deref(),
+            Some(
+                "experimental-features = nix-command # for riff\n\n# Added by riff\n\
+                extra-experimental-features = flakes\n"
+            )
+        );
+    }
+}
@@ -1,26 +1,227 @@
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::process::Stdio;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
 
+use clap::Args;
 use eyre::WrapErr;
 use owo_colors::OwoColorize;
 use serde::Deserialize;
 use tokio::process::Command;
 
-pub async fn get_nix_dev_env(flake_dir: &Path) -> color_eyre::Result<NixDevEnv> {
-    let output = get_raw_nix_dev_env(flake_dir).await?;
+use crate::errors::{ErrorCode, RiffError};
+use crate::execution::Effect;
+use crate::flake_generator::missing_nix;
+use crate::offline::OfflineMode;
+use crate::riff_lock::sha256_hex;
+use crate::spinner::{self, Phase};
+use crate::RIFF_XDG_PREFIX;
 
-    serde_json::from_str(&output).wrap_err(
+/// Where `nix print-dev-env` output is cached, in `$XDG_CACHE_HOME/riff`
+const DEV_ENV_CACHE_DIR: &str = "dev-envs";
+
+/// The environment of the flake in `flake_dir`, from the cache unless `refresh` is set, using the
+/// network as `offline` allows
+pub async fn get_nix_dev_env(
+    flake_dir: &Path,
+    offline: OfflineMode,
+    refresh: bool,
+) -> color_eyre::Result<NixDevEnv> {
+    let cache_key = dev_env_cache_key(flake_dir).await;
+    if let (Some(cache_key), false) = (&cache_key, refresh) {
+        if let Some(dev_env) = NixDevEnv::load_cached(cache_key).await {
+            return Ok(dev_env);
+        }
+    }
+
+    let Some(output) = get_raw_nix_dev_env(flake_dir, offline).await? else {
+        return Ok(NixDevEnv::default());
+    };
+    let dev_env = serde_json::from_str(&output).wrap_err(
         "Unable to parse output produced by `nix print-dev-env` into our desired structure",
+    )?;
+    if let Some(cache_key) = &cache_key {
+        if let Err(err) = cache_dev_env(cache_key, &output).await {
+            tracing::debug!(%err, "Could not cache `nix print-dev-env` output");
+        }
+    }
+    Ok(dev_env)
+}
+
+/// Identifies the output of `nix print-dev-env` for the flake in `flake_dir`, which only depends
+/// on the flake, its lock, and the Nix evaluating it
+async fn dev_env_cache_key(flake_dir: &Path) -> Option<String> {
+    let flake_nix = tokio::fs::read(flake_dir.join("flake.nix")).await.ok()?;
+    let flake_lock = tokio::fs::read(flake_dir.join("flake.lock")).await.ok()?;
+    let nix_version = crate::nix_config::command(["--version"])
+        .output()
+        .await
+        .ok()?;
+    if !nix_version.status.success() {
+        return None;
+    }
+
+    let mut key = Vec::new();
+    for part in [flake_nix, flake_lock, nix_version.stdout] {
+        key.extend(sha256_hex(&part).into_bytes());
+    }
+    Some(sha256_hex(&key))
+}
+
+/// The cached environments, shared by every project whose flake is the same
+pub(crate) fn cached_dev_envs() -> color_eyre::Result<Vec<PathBuf>> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.list_cache_files(DEV_ENV_CACHE_DIR))
+}
+
+fn dev_env_cache_path(cache_key: &str) -> color_eyre::Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(
+        xdg_dirs
+            .place_cache_file(Path::new(DEV_ENV_CACHE_DIR).join(format!("{cache_key}.json")))?,
     )
 }
 
+async fn cache_dev_env(cache_key: &str, output: &str) -> color_eyre::Result<()> {
+    let path = dev_env_cache_path(cache_key)?;
+    if !crate::execution::perform(Effect::Write(path.clone())) {
+        return Ok(());
+    }
+    // Another riff may be reading the same entry, so only ever replace it whole
+    let temp_path = path.with_extension(format!("json.new{}", std::process::id()));
+    tokio::fs::write(&temp_path, output)
+        .await
+        .wrap_err_with(|| format!("Could not write `{}`", temp_path.display()))?;
+    tokio::fs::rename(&temp_path, &path)
+        .await
+        .wrap_err_with(|| format!("Could not replace `{}`", path.display()))
+}
+
+/// Variables `nix print-dev-env` reports which describe the Nix build sandbox rather than the
+/// environment, so they are left out of it (the list `nix develop` ignores, in develop.cc)
+const IGNORED_VARS: &[&str] = &[
+    "BASHOPTS",
+    "HOME",
+    "NIX_BUILD_TOP",
+    "NIX_ENFORCE_PURITY",
+    "NIX_LOG_FD",
+    "NIX_REMOTE",
+    "PPID",
+    "SHELL",
+    "SHELLOPTS",
+    "SSL_CERT_FILE",
+    "TEMP",
+    "TEMPDIR",
+    "TERM",
+    "TMP",
+    "TMPDIR",
+    "TZ",
+    "UID",
+];
+
 /// The output schema of `nix print-dev-env --json`.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct NixDevEnv {
     variables: HashMap<String, Variable>,
 }
 
+impl NixDevEnv {
+    /// The cached environment for `cache_key`, if it is still usable
+    #[tracing::instrument]
+    async fn load_cached(cache_key: &str) -> Option<Self> {
+        let path = dev_env_cache_path(cache_key).ok()?;
+        let contents = tokio::fs::read_to_string(&path).await.ok()?;
+        let dev_env: Self = match serde_json::from_str(&contents) {
+            Ok(dev_env) => dev_env,
+            Err(err) => {
+                tracing::debug!(%err, "Ignoring unreadable cached `nix print-dev-env` output");
+                return None;
+            }
+        };
+        if !dev_env.store_paths_exist() {
+            tracing::debug!("Cached environment refers to store paths which were removed");
+            return None;
+        }
+        tracing::debug!("Using cached `nix print-dev-env` output");
+        Some(dev_env)
+    }
+
+    /// The variables the environment exports, by name, without those describing the build sandbox
+    pub fn exported_variables(&self) -> BTreeMap<&str, &str> {
+        self.variables
+            .iter()
+            .filter(|(name, _)| !IGNORED_VARS.contains(&name.as_str()))
+            .filter_map(|(name, value)| match value {
+                Variable::Exported(value) => Some((name.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The environment's `shellHook`, which `nix develop` runs as the shell starts
+    pub fn shell_hook(&self) -> Option<&str> {
+        match self.variables.get("shellHook") {
+            Some(Variable::Exported(hook) | Variable::Var(hook)) if !hook.is_empty() => Some(hook),
+            _ => None,
+        }
+    }
+
+    /// The path of `program` in the environment's `PATH`, if it's there and executable
+    pub fn find_program(&self, program: &str) -> Option<PathBuf> {
+        let Some(Variable::Exported(path)) = self.variables.get("PATH") else {
+            return None;
+        };
+        std::env::split_paths(path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| crate::detectors::is_executable(candidate))
+    }
+
+    /// The package names (eg `openssl` for `/nix/store/<hash>-openssl-3.0.8-dev`) of the
+    /// environment's build inputs
+    pub fn input_names(&self) -> BTreeSet<String> {
+        ["buildInputs", "nativeBuildInputs", "propagatedBuildInputs"]
+            .into_iter()
+            .filter_map(|name| match self.variables.get(name) {
+                Some(Variable::Exported(paths) | Variable::Var(paths)) => Some(paths),
+                _ => None,
+            })
+            .flat_map(|paths| paths.split_whitespace())
+            .map(package_name)
+            .collect()
+    }
+
+    /// Whether the store paths the environment's variables refer to still exist, which they may
+    /// not after a garbage collection, unlike after `nix print-dev-env` (which builds them)
+    fn store_paths_exist(&self) -> bool {
+        store_paths_exist(self.exported_variables())
+    }
+}
+
+/// Whether every store path mentioned in the values of `variables` (eg
+/// `/nix/store/<hash>-openssl-3.0.8` in `/nix/store/<hash>-openssl-3.0.8/lib:/usr/lib`) exists,
+/// except the shell derivation's own outputs (eg `out`), which are never built
+pub(crate) fn store_paths_exist<'a>(
+    variables: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> bool {
+    let variables = variables.into_iter().collect::<BTreeMap<_, _>>();
+    let outputs = variables
+        .get("outputs")
+        .map(|outputs| outputs.split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_else(|| vec!["out"]);
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || "+-._?=".contains(c);
+    variables
+        .into_iter()
+        .filter(|(name, _)| !outputs.contains(name))
+        .all(|(_, value)| {
+            value.match_indices("/nix/store/").all(|(start, prefix)| {
+                let name = &value[start + prefix.len()..];
+                let name = &name[..name.find(|c| !is_name_char(c)).unwrap_or(name.len())];
+                name.is_empty() || Path::new("/nix/store").join(name).exists()
+            })
+        })
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", content = "value")]
 pub enum Variable {
@@ -28,125 +229,531 @@ pub enum Variable {
     Exported(String),
     #[serde(rename = "var")]
     Var(String),
+    // Arrays and associative arrays can't be exported, so their values are never read
     #[serde(rename = "array")]
-    Array(Vec<String>),
+    Array(#[allow(dead_code)] Vec<String>),
     #[serde(rename = "associative")]
-    Associative(HashMap<String, String>),
+    Associative(#[allow(dead_code)] HashMap<String, String>),
 }
 
-pub async fn get_raw_nix_dev_env(flake_dir: &Path) -> color_eyre::Result<String> {
-    let mut nix_command = Command::new("nix");
+/// The output of `nix print-dev-env --json` for the flake in `flake_dir`, unless this is a dry run
+pub async fn get_raw_nix_dev_env(
+    flake_dir: &Path,
+    offline: OfflineMode,
+) -> color_eyre::Result<Option<String>> {
+    let _timer = crate::timings::start("nix print-dev-env");
+    let mut nix_command = crate::nix_config::command(["print-dev-env", "--json"]);
     nix_command
-        .arg("print-dev-env")
-        .arg("--json")
-        .args(["--extra-experimental-features", "flakes nix-command"])
         .arg("-L")
         .arg(format!("path://{}", flake_dir.to_str().unwrap()))
         .stdin(Stdio::inherit())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit());
-    tracing::trace!(command = ?nix_command.as_std(), "Running");
+        .kill_on_drop(true);
+    crate::process_limits::apply_to_nix(&mut nix_command);
 
-    // TODO(@hoverbear): Try to enable this somehow. Right now since we don't keep the lock
-    // in a consistent place, we can't reliably pick up a lock generated in online mode.
-    //
-    // If we stored the generated flake/lock in a consistent place this could be enabled.
-    //
-    // if self.offline {
-    //     nix_develop_command.arg("--offline");
-    // }
-
-    let nix_command_exit = match nix_command
-        .spawn()
-        .wrap_err("Failed to spawn `nix develop`")? // This could throw a `EWOULDBLOCK`
-        .wait_with_output()
+    // Only with `--offline=strict-offline`, since the packages of a lock generated online may not
+    // be in the store yet
+    if offline.is_strict() {
+        nix_command.arg("--offline");
+    }
+    if !crate::execution::perform(Effect::run(nix_command.as_std())) {
+        return Ok(None);
+    }
+
+    let spinner = spinner::start(
+        Phase::EvaluatingEnvironment,
+        &format!(
+            "Running `{nix_print_dev_env}`",
+            nix_print_dev_env = "nix print-dev-env".cyan()
+        ),
+    )
+    .wrap_err("Failed to construct progress spinner")?;
+    let nix_command_exit = crate::nix_progress::run_nix(&mut nix_command, false, &spinner)
         .await
-    {
-        Ok(nix_command_exit) => nix_command_exit,
-        Err(err) => {
-            let err_msg = format!(
-                "\
-                Could not execute `{nix_print_dev_env}`. Is `{nix}` installed?\n\n\
-                Get instructions for installing Nix: {nix_install_url}\
-                ",
-                nix_print_dev_env = "nix print-dev-env".cyan(),
-                nix = "nix".cyan(),
-                nix_install_url = "https://nixos.org/download.html".blue().underline(),
-            );
-            eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-            std::process::exit(1);
+        .map_err(|err| missing_nix("nix print-dev-env").with_source(err))?;
+    spinner.finish_and_clear();
+    if !nix_command_exit.status.success() {
+        if offline.is_strict() {
+            return Err(crate::offline::not_cached(
+                "nix print-dev-env",
+                "a package of the environment",
+                &nix_command_exit.progress.errors(),
+            )
+            .into());
         }
-    };
+        return Err(RiffError::new(
+            ErrorCode::NixFailed,
+            format!(
+                "`nix print-dev-env` failed:\n{}",
+                nix_command_exit.progress.errors()
+            ),
+        )
+        .into());
+    }
 
     String::from_utf8(nix_command_exit.stdout)
+        .map(Some)
         .wrap_err("Output produced by `nix print-dev-env` was not valid UTF8")
 }
 
+/// The package name in a store path, without its hash, version, or output
+fn package_name(store_path: &str) -> String {
+    let name = store_path.rsplit('/').next().unwrap_or(store_path);
+    let name = match name.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name,
+        _ => name,
+    };
+    name.split('-')
+        .take_while(|part| !part.starts_with(|c: char| c.is_ascii_digit()))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Variables passed through from the parent environment even with `--pure`, like
+/// `nix develop --ignore-environment` does
+const PURE_ALLOWED_VARS: &[&str] = &["DISPLAY", "HOME", "LOGNAME", "TERM", "TZ", "USER"];
+
+/// Which parts of the parent's environment commands run in the environment see
+#[derive(Debug, Clone, Default, Args)]
+pub struct HostEnvironment {
+    /// Start from an empty environment instead of this one, keeping only a few variables such as
+    /// `HOME`, `TERM`, and `USER`, to check the project doesn't depend on anything else
+    #[clap(long)]
+    pub pure: bool,
+    /// Start from an empty environment with only the riff environment's variables (and any
+    /// `--keep`), not even `HOME` or `TERM`
+    #[clap(long, conflicts_with = "pure")]
+    pub no_inherit_env: bool,
+    /// Pass this variable through from this environment, instead of any value the environment
+    /// sets, even with `--pure` (can be repeated)
+    #[clap(long, value_name = "VAR")]
+    pub keep: Vec<String>,
+    /// Remove this variable, whether it comes from this environment or the riff environment
+    /// (can be repeated)
+    #[clap(long, value_name = "VAR")]
+    pub unset: Vec<String>,
+    /// Don't warn about variables set in this environment which the riff environment replaces
+    #[clap(long)]
+    pub no_override_warning: bool,
+}
+
+impl HostEnvironment {
+    /// Whether commands start from an empty environment, rather than this one
+    fn isolated(&self) -> bool {
+        self.pure || self.no_inherit_env
+    }
+}
+
+// TODO(@edolstra): Copied from develop.cc, would be nice to
+// keep these in sync somehow (e.g. `nix print-dev-env --json`
+// could output them).
+const PREPENDED_VARS: &[&str] = &["PATH", "XDG_DATA_DIRS"];
+
+/// The variables of the environment riff runs in, which commands run in the riff environment
+/// inherit from
+type HostVariables = HashMap<OsString, OsString>;
+
+/// The value of `name` in `host_variables`, if it's set to something valid UTF-8
+fn host_variable<'a>(host_variables: &'a HostVariables, name: &str) -> Option<&'a str> {
+    host_variables.get(OsStr::new(name))?.to_str()
+}
+
+/// The variables set in `host_variables` which the riff environment replaces with a different
+/// value, to warn about, in order
+fn overridden_variables<'a>(
+    dev_env: &'a NixDevEnv,
+    host_environment: &HostEnvironment,
+    host_variables: &HostVariables,
+) -> Vec<&'a str> {
+    // Variables of a surrounding riff environment aren't the user's own
+    if host_environment.isolated()
+        || host_environment.no_override_warning
+        || host_variables.contains_key(OsStr::new("IN_RIFF"))
+    {
+        return vec![];
+    }
+    dev_env
+        .exported_variables()
+        .into_iter()
+        .filter(|(name, value)| {
+            !PREPENDED_VARS.contains(name)
+                && *name != "NIX_BUILD_CORES"
+                && !host_environment.keep.iter().any(|keep| keep == name)
+                && !host_environment.unset.iter().any(|unset| unset == name)
+                && matches!(host_variable(host_variables, name), Some(old_value) if old_value != *value)
+        })
+        .map(|(name, _)| name)
+        .collect()
+}
+
 pub async fn run_in_dev_env(
     dev_env: &NixDevEnv,
     command_name: &str,
+    host_environment: &HostEnvironment,
 ) -> color_eyre::Result<Command> {
-    let mut command = Command::new(command_name);
-
-    // TODO(@edolstra): Copied from develop.cc, would be nice to
-    // keep these in sync somehow (e.g. `nix print-dev-env --json`
-    // could output them).
-    let prepended_vars = HashSet::from(["PATH".to_owned(), "XDG_DATA_DIRS".to_owned()]);
-
-    let ignored_vars = HashSet::from(
-        [
-            "BASHOPTS",
-            "HOME",
-            "NIX_BUILD_TOP",
-            "NIX_ENFORCE_PURITY",
-            "NIX_LOG_FD",
-            "NIX_REMOTE",
-            "PPID",
-            "SHELL",
-            "SHELLOPTS",
-            "SSL_CERT_FILE",
-            "TEMP",
-            "TEMPDIR",
-            "TERM",
-            "TMP",
-            "TMPDIR",
-            "TZ",
-            "UID",
-        ]
-        .map(str::to_owned),
-    );
+    Ok(dev_env_command(dev_env, command_name, host_environment).into())
+}
 
-    for (name, value) in &dev_env.variables {
-        if let Variable::Exported(value) = value {
-            if ignored_vars.contains(name) {
-                continue;
+/// The command `command_name` with the environment's variables set, as a standard library command
+/// (which, unlike Tokio's, can replace riff with `exec`, see `riff run --exec`)
+pub fn dev_env_command(
+    dev_env: &NixDevEnv,
+    command_name: &str,
+    host_environment: &HostEnvironment,
+) -> std::process::Command {
+    let host_variables = std::env::vars_os().collect();
+    dev_env_command_from(dev_env, command_name, host_environment, &host_variables)
+}
+
+/// [`dev_env_command`], run from an environment of `host_variables` rather than riff's own
+fn dev_env_command_from(
+    dev_env: &NixDevEnv,
+    command_name: &str,
+    host_environment: &HostEnvironment,
+    host_variables: &HostVariables,
+) -> std::process::Command {
+    let mut command = std::process::Command::new(command_name);
+    if host_environment.isolated() {
+        command.env_clear();
+        let passed_through: &[&str] = if host_environment.pure {
+            PURE_ALLOWED_VARS
+        } else {
+            &[]
+        };
+        let allowed = passed_through
+            .iter()
+            .copied()
+            .chain(host_environment.keep.iter().map(String::as_str));
+        for name in allowed {
+            if let Some(value) = host_variables.get(OsStr::new(name)) {
+                command.env(name, value);
             }
-            let mut value = value.clone();
-            if prepended_vars.contains(name) {
-                if let Ok(old_value) = std::env::var(name) {
-                    value = format!("{value}:{old_value}");
-                }
+        }
+    }
+
+    for (name, value) in dev_env.exported_variables() {
+        if host_environment.keep.iter().any(|keep| keep == name)
+            && host_variables.contains_key(OsStr::new(name))
+        {
+            continue;
+        }
+        // The environment's is the machine it was built on's, see below
+        if name == "NIX_BUILD_CORES" {
+            continue;
+        }
+        let mut value = value.to_string();
+        if PREPENDED_VARS.contains(&name) && !host_environment.isolated() {
+            if let Some(old_value) = host_variable(host_variables, name) {
+                value = format!("{value}:{old_value}");
             }
-            command.env(name, value);
         }
+        command.env(name, value);
+    }
+
+    // For build tools which size their parallelism by it, as Nix builds do. Your own is kept.
+    let own_build_cores = host_variables.contains_key(OsStr::new("NIX_BUILD_CORES"))
+        && (!host_environment.isolated()
+            || host_environment
+                .keep
+                .iter()
+                .any(|keep| keep == "NIX_BUILD_CORES"));
+    if !own_build_cores {
+        command.env(
+            "NIX_BUILD_CORES",
+            crate::process_limits::build_cores().to_string(),
+        );
+    }
+
+    for name in &host_environment.unset {
+        command.env_remove(name);
+    }
+
+    let overridden = overridden_variables(dev_env, host_environment, host_variables);
+    if !overridden.is_empty() {
+        crate::spinner::suspend(|| {
+            eprintln!(
+                "{} The riff environment replaces {} from your environment; pass `{}` to keep yours",
+                "⚠".yellow(),
+                overridden
+                    .iter()
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                "--keep VAR".cyan(),
+            );
+        });
     }
 
     // Increment $IN_RIFF.
     command.env(
         "IN_RIFF",
-        (std::env::var("IN_RIFF")
-            .ok()
+        (host_variable(host_variables, "IN_RIFF")
             .and_then(|s| s.parse::<u32>().ok())
             .unwrap_or(0)
             + 1)
         .to_string(),
     );
 
-    Ok(command)
+    command
+}
+
+/// Resources used by a command run in the environment, reported by `riff run --stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// From spawning the command until it exited
+    pub wall_time: Duration,
+    /// User and system CPU time, including its children which it waited for
+    pub cpu_time: Option<Duration>,
+    /// The largest resident set size of it or any of its children which it waited for
+    pub max_rss_bytes: Option<u64>,
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1}s wall", self.wall_time.as_secs_f64())?;
+        if let Some(cpu_time) = self.cpu_time {
+            write!(f, ", {:.1}s CPU", cpu_time.as_secs_f64())?;
+        }
+        if let Some(max_rss_bytes) = self.max_rss_bytes {
+            write!(
+                f,
+                ", {:.1} MiB max RSS",
+                max_rss_bytes as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawn `command` and wait for it to exit.
+///
+/// See [`spawn_and_wait_with_usage`].
+pub async fn spawn_and_wait(command: &mut Command) -> std::io::Result<ExitStatus> {
+    Ok(spawn_and_wait_with_usage(command).await?.0)
+}
+
+/// Spawn `command`, wait for it to exit, and report the resources it used.
+///
+/// When riff is in the foreground of a terminal, the child is moved into its own process group
+/// and made the foreground process group, like a shell does for its jobs. Signals from the
+/// terminal (eg Ctrl-C, Ctrl-Z, or `SIGWINCH` when the window is resized) then go straight to it,
+/// and an interactive shell can do job control. When the child stops (eg on Ctrl-Z), riff takes the
+/// terminal back and stops too, and hands the terminal back and continues the child when it is
+/// continued itself. The terminal's modes are restored once the child exits. Termination signals
+/// sent to riff itself are forwarded to the child.
+#[cfg(unix)]
+pub async fn spawn_and_wait_with_usage(
+    command: &mut Command,
+) -> std::io::Result<(ExitStatus, ResourceUsage)> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // SAFETY: `tcgetpgrp` and `getpgrp` have no preconditions
+    let foreground = unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() };
+    let terminal_modes = if foreground {
+        // SAFETY: `tcgetattr` fully initializes `termios` when it succeeds
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            (libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0).then_some(termios)
+        }
+    } else {
+        None
+    };
+    if foreground {
+        // SAFETY: Only async-signal-safe functions are called between `fork` and `exec`
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                set_foreground_process_group(libc::getpgrp());
+                Ok(())
+            });
+        }
+    }
+
+    let started = Instant::now();
+    let child = command.spawn()?;
+    let pid = child.id().map(|pid| pid as libc::pid_t);
+    if let (true, Some(pid)) = (foreground, pid) {
+        // The child does this too, but we can't know which of us gets to run first.
+        // SAFETY: `pid` is our own child
+        unsafe { libc::setpgid(pid, pid) };
+        set_foreground_process_group(pid);
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = signal(SignalKind::quit())?;
+
+    // We reap the child ourselves with `wait4`, rather than `Child::wait`, to learn what resources
+    // it used. Once it's dropped, tokio finds the child already reaped and forgets about it.
+    let mut wait = tokio::task::spawn_blocking(move || match pid {
+        Some(pid) => wait4(pid, foreground, terminal_modes),
+        None => Err(std::io::Error::other(
+            "The command exited before it could be waited for",
+        )),
+    });
+    let waited = loop {
+        let signum = tokio::select! {
+            waited = &mut wait => break waited.map_err(std::io::Error::from).and_then(|waited| waited),
+            _ = terminate.recv() => libc::SIGTERM,
+            _ = hangup.recv() => libc::SIGHUP,
+            _ = interrupt.recv() => libc::SIGINT,
+            _ = quit.recv() => libc::SIGQUIT,
+        };
+        // If the child shares our process group, the terminal already sent these to it as well.
+        if !foreground && (signum == libc::SIGINT || signum == libc::SIGQUIT) {
+            continue;
+        }
+        if let Some(pid) = pid {
+            tracing::debug!(signum, pid, "Forwarding signal to child");
+            // SAFETY: `pid` is our own child
+            unsafe { libc::kill(pid, signum) };
+        }
+    };
+
+    if foreground {
+        // SAFETY: `getpgrp` has no preconditions
+        set_foreground_process_group(unsafe { libc::getpgrp() });
+    }
+    if let Some(termios) = terminal_modes {
+        // A child that crashed or was killed may have left the terminal in raw mode.
+        // SAFETY: `termios` was filled in by `tcgetattr`
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &termios) };
+    }
+    drop(child);
+
+    let (status, rusage) = waited?;
+    let timeval = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    // `ru_maxrss` is in bytes on macOS, and in kilobytes elsewhere
+    let max_rss_bytes = if cfg!(target_os = "macos") {
+        rusage.ru_maxrss as u64
+    } else {
+        rusage.ru_maxrss as u64 * 1024
+    };
+    let usage = ResourceUsage {
+        wall_time: started.elapsed(),
+        cpu_time: Some(timeval(rusage.ru_utime) + timeval(rusage.ru_stime)),
+        max_rss_bytes: Some(max_rss_bytes),
+    };
+    Ok((status, usage))
+}
+
+#[cfg(not(unix))]
+pub async fn spawn_and_wait_with_usage(
+    command: &mut Command,
+) -> std::io::Result<(ExitStatus, ResourceUsage)> {
+    let started = Instant::now();
+    let status = command.spawn()?.wait().await?;
+    let usage = ResourceUsage {
+        wall_time: started.elapsed(),
+        ..Default::default()
+    };
+    Ok((status, usage))
+}
+
+/// Block until the child `pid` exits, returning its status and the resources it used.
+///
+/// If the child is the `foreground` process group of the terminal, riff stops along with it, see
+/// [`stop_with_child`], restoring `terminal_modes` meanwhile.
+#[cfg(unix)]
+fn wait4(
+    pid: libc::pid_t,
+    foreground: bool,
+    terminal_modes: Option<libc::termios>,
+) -> std::io::Result<(ExitStatus, libc::rusage)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let options = if foreground { libc::WUNTRACED } else { 0 };
+    let mut status = 0;
+    // SAFETY: `wait4` fully initializes `rusage` when it succeeds
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: `status` and `rusage` are valid for writes, and `pid` is our own child
+        if unsafe { libc::wait4(pid, &mut status, options, &mut rusage) } == pid {
+            if libc::WIFSTOPPED(status) {
+                stop_with_child(pid, terminal_modes.as_ref());
+                continue;
+            }
+            return Ok((ExitStatus::from_raw(status), rusage));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Stop riff, now that its foreground child `pid` stopped, as the shell's job it is in. The
+/// terminal is taken back from the child, with riff's `terminal_modes`, for the shell to have.
+/// Once riff is continued, the child is too, and gets the terminal back with its own modes unless
+/// riff was continued in the background.
+#[cfg(unix)]
+fn stop_with_child(pid: libc::pid_t, terminal_modes: Option<&libc::termios>) {
+    // SAFETY: `tcgetattr` fully initializes `termios` when it succeeds, `getpgrp` has no
+    // preconditions, and `pid` is our own child
+    unsafe {
+        let mut child_modes: libc::termios = std::mem::zeroed();
+        let child_modes =
+            (libc::tcgetattr(libc::STDIN_FILENO, &mut child_modes) == 0).then_some(child_modes);
+        set_foreground_process_group(libc::getpgrp());
+        if let Some(termios) = terminal_modes {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, termios);
+        }
+
+        tracing::debug!(pid, "The child stopped, stopping along with it");
+        libc::raise(libc::SIGTSTP);
+
+        if libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() {
+            if let Some(termios) = child_modes {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &termios);
+            }
+            set_foreground_process_group(pid);
+        }
+        tracing::debug!(pid, "Continuing the child");
+        libc::kill(pid, libc::SIGCONT);
+    }
+}
+
+/// Make `pgrp` the foreground process group of the terminal on stdin
+#[cfg(unix)]
+fn set_foreground_process_group(pgrp: libc::pid_t) {
+    // Changing the foreground process group from a background process group raises `SIGTTOU`,
+    // which would stop us, unless the signal is blocked.
+    //
+    // SAFETY: The signal sets are initialized by `sigemptyset` before use, and all of these
+    // functions are async-signal-safe, so this can be called from `pre_exec`
+    unsafe {
+        let mut block: libc::sigset_t = std::mem::zeroed();
+        let mut previous: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut block);
+        libc::sigaddset(&mut block, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &block, &mut previous);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgrp);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+    }
+}
+
+/// The exit code a shell would report for `status`, `128 + signal` if the process was killed by a
+/// signal
+pub fn exit_code(status: ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return Some(128 + signal);
+        }
+    }
+    status.code()
 }
 
 #[cfg(target_os = "linux")]
-pub async fn get_shell() -> color_eyre::Result<String> {
+pub async fn get_shell(shell_override: Option<String>) -> color_eyre::Result<String> {
+    if let Some(shell) = shell_override {
+        return Ok(shell);
+    }
     // Use $SHELL, the user's shell from /etc/passwd, or bash.
     Ok(tokio::task::spawn_blocking(|| {
         std::env::var("SHELL").ok().or_else(|| {
@@ -160,9 +767,310 @@ pub async fn get_shell() -> color_eyre::Result<String> {
 }
 
 #[cfg(not(target_os = "linux"))]
-pub async fn get_shell() -> color_eyre::Result<String> {
+pub async fn get_shell(shell_override: Option<String>) -> color_eyre::Result<String> {
+    if let Some(shell) = shell_override {
+        return Ok(shell);
+    }
     // Use $SHELL, or bash.
     Ok(tokio::task::spawn_blocking(|| std::env::var("SHELL").ok())
         .await?
         .unwrap_or_else(|| "bash".to_owned()))
 }
+
+/// Quote `word` so that POSIX shells (and fish) treat it as a single word
+pub fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_environments_need_their_store_paths() {
+        let dev_env = |path: &str| NixDevEnv {
+            variables: HashMap::from([("PATH".to_string(), Variable::Exported(path.into()))]),
+        };
+        assert!(dev_env("/usr/bin").store_paths_exist());
+        assert!(
+            !dev_env("/nix/store/00000000000000000000000000000000-missing/bin:/usr/bin")
+                .store_paths_exist()
+        );
+
+        let dev_env = NixDevEnv {
+            variables: HashMap::from([
+                ("PATH".to_string(), Variable::Exported("/usr/bin".into())),
+                (
+                    "OPENSSL_DIR".to_string(),
+                    Variable::Exported(
+                        "/nix/store/00000000000000000000000000000000-openssl-3.0.8-dev".into(),
+                    ),
+                ),
+            ]),
+        };
+        assert!(!dev_env.store_paths_exist());
+        assert!(store_paths_exist([(
+            "CFLAGS",
+            "-L/nix/store/ -I/usr/include"
+        )]));
+        assert!(store_paths_exist([(
+            "out",
+            "/nix/store/00000000000000000000000000000000-riff-shell"
+        )]));
+    }
+
+    #[test]
+    fn input_names_drop_hashes_and_versions() {
+        let dev_env = NixDevEnv {
+            variables: HashMap::from([
+                (
+                    "buildInputs".to_string(),
+                    Variable::Var(
+                        "/nix/store/0c8qy5bjxqxdmfaahnvbwjnbv3vgqpsz-openssl-3.0.8-dev".into(),
+                    ),
+                ),
+                (
+                    "nativeBuildInputs".to_string(),
+                    Variable::Var(
+                        "/nix/store/1aq0yhc8rvjx3dpsxy3lv9fhlfvj3zbx-pkg-config-wrapper-0.29.2 \
+                        /nix/store/ajd9cxzrjd6kr2ijzlj0z2pcrngacmy5-rustc-1.68.0"
+                            .into(),
+                    ),
+                ),
+            ]),
+        };
+        assert_eq!(
+            dev_env.input_names(),
+            ["openssl", "pkg-config-wrapper", "rustc"]
+                .map(String::from)
+                .into()
+        );
+    }
+
+    #[tokio::test]
+    async fn host_environment_is_filtered() -> eyre::Result<()> {
+        let dev_env = NixDevEnv {
+            variables: HashMap::from([
+                (
+                    "PATH".to_string(),
+                    Variable::Exported("/dev-env/bin".into()),
+                ),
+                ("FOO".to_string(), Variable::Exported("dev".into())),
+                ("BAR".to_string(), Variable::Exported("dev".into())),
+                (
+                    "NIX_BUILD_CORES".to_string(),
+                    Variable::Exported("64".into()),
+                ),
+            ]),
+        };
+        let host_environment = HostEnvironment {
+            pure: true,
+            no_inherit_env: false,
+            keep: vec!["PATH".to_string()],
+            unset: vec!["BAR".to_string()],
+            no_override_warning: false,
+        };
+        let command = run_in_dev_env(&dev_env, "true", &host_environment).await?;
+        let envs: HashMap<_, _> = command
+            .as_std()
+            .get_envs()
+            .map(|(name, value)| (name.to_owned(), value.map(ToOwned::to_owned)))
+            .collect();
+
+        assert_eq!(
+            envs.get(std::ffi::OsStr::new("FOO")),
+            Some(&Some("dev".into()))
+        );
+        // Removed after clearing, so either left out or explicitly unset
+        assert!(!matches!(
+            envs.get(std::ffi::OsStr::new("BAR")),
+            Some(Some(_))
+        ));
+        assert_eq!(
+            envs.get(std::ffi::OsStr::new("PATH")),
+            Some(&std::env::var_os("PATH"))
+        );
+
+        let host_environment = HostEnvironment {
+            no_inherit_env: true,
+            ..Default::default()
+        };
+        let command = run_in_dev_env(&dev_env, "true", &host_environment).await?;
+        let envs: HashMap<_, _> = command
+            .as_std()
+            .get_envs()
+            .map(|(name, value)| (name.to_owned(), value.map(ToOwned::to_owned)))
+            .collect();
+        assert_eq!(
+            envs.get(std::ffi::OsStr::new("PATH")),
+            Some(&Some("/dev-env/bin".into()))
+        );
+        assert!(!envs.contains_key(std::ffi::OsStr::new("HOME")));
+        // This machine's cores, not those of the machine the environment was built on
+        assert_eq!(
+            envs.get(std::ffi::OsStr::new("NIX_BUILD_CORES")),
+            Some(&Some(
+                crate::process_limits::build_cores().to_string().into()
+            ))
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pure_drops_the_host_environment() -> eyre::Result<()> {
+        let host_variables = host_variables([
+            ("RIFF_TEST_HOST_ONLY", "host"),
+            ("TERM", "xterm"),
+            ("PATH", "/host/bin"),
+        ]);
+        let dev_env = NixDevEnv {
+            variables: HashMap::from([(
+                "RIFF_TEST_DEV".to_string(),
+                Variable::Exported("dev".into()),
+            )]),
+        };
+        let host_environment = HostEnvironment {
+            pure: true,
+            ..Default::default()
+        };
+        let output = Command::from(dev_env_command_from(
+            &dev_env,
+            "/usr/bin/env",
+            &host_environment,
+            &host_variables,
+        ))
+        .output()
+        .await?;
+        let env = String::from_utf8(output.stdout)?;
+        let names = env
+            .lines()
+            .filter_map(|line| line.split_once('=').map(|(name, _)| name))
+            .collect::<BTreeSet<_>>();
+        assert!(names.contains("RIFF_TEST_DEV"));
+        assert!(names.contains("TERM"));
+        assert!(!names.contains("RIFF_TEST_HOST_ONLY"));
+        assert!(!names.contains("PATH"));
+        Ok(())
+    }
+
+    #[test]
+    fn overridden_variables_are_found() {
+        let host_variables = host_variables([
+            ("RIFF_TEST_REPLACED", "host"),
+            ("RIFF_TEST_SAME", "dev"),
+            ("RIFF_TEST_KEPT", "host"),
+        ]);
+        let dev_env = NixDevEnv {
+            variables: HashMap::from([
+                (
+                    "PATH".to_string(),
+                    Variable::Exported("/dev-env/bin".into()),
+                ),
+                (
+                    "RIFF_TEST_REPLACED".to_string(),
+                    Variable::Exported("dev".into()),
+                ),
+                (
+                    "RIFF_TEST_SAME".to_string(),
+                    Variable::Exported("dev".into()),
+                ),
+                (
+                    "RIFF_TEST_KEPT".to_string(),
+                    Variable::Exported("dev".into()),
+                ),
+            ]),
+        };
+        let host_environment = HostEnvironment {
+            keep: vec!["RIFF_TEST_KEPT".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            overridden_variables(&dev_env, &host_environment, &host_variables),
+            vec!["RIFF_TEST_REPLACED"]
+        );
+
+        let quiet = HostEnvironment {
+            no_override_warning: true,
+            ..Default::default()
+        };
+        assert!(overridden_variables(&dev_env, &quiet, &host_variables).is_empty());
+        let pure = HostEnvironment {
+            pure: true,
+            ..Default::default()
+        };
+        assert!(overridden_variables(&dev_env, &pure, &host_variables).is_empty());
+
+        // Nor are a surrounding riff environment's
+        let mut nested = host_variables.clone();
+        nested.insert("IN_RIFF".into(), "1".into());
+        assert!(overridden_variables(&dev_env, &HostEnvironment::default(), &nested).is_empty());
+    }
+
+    fn host_variables<const N: usize>(variables: [(&str, &str); N]) -> HostVariables {
+        variables
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn spawn_and_wait_reports_exit_code() -> eyre::Result<()> {
+        let status = spawn_and_wait(Command::new("sh").args(["-c", "exit 6"])).await?;
+        assert_eq!(exit_code(status), Some(6));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn spawn_and_wait_reports_signal_deaths() -> eyre::Result<()> {
+        let status = spawn_and_wait(Command::new("sh").args(["-c", "kill -TERM $$"])).await?;
+        assert_eq!(exit_code(status), Some(128 + libc::SIGTERM));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn spawn_and_wait_reports_usage() -> eyre::Result<()> {
+        let (status, usage) = spawn_and_wait_with_usage(Command::new("sh").args([
+            "-c",
+            "sleep 0.1; i=0; while [ $i -lt 10000 ]; do i=$((i+1)); done",
+        ]))
+        .await?;
+        assert!(status.success());
+        assert!(usage.wall_time >= Duration::from_millis(100));
+        assert!(usage.cpu_time.is_some());
+        assert!(usage.max_rss_bytes.unwrap_or(0) > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn resource_usage_display() {
+        let usage = ResourceUsage {
+            wall_time: Duration::from_millis(1250),
+            cpu_time: Some(Duration::from_millis(3400)),
+            max_rss_bytes: Some(120 * 1024 * 1024),
+        };
+        assert_eq!(usage.to_string(), "1.2s wall, 3.4s CPU, 120.0 MiB max RSS");
+        let usage = ResourceUsage {
+            wall_time: Duration::from_secs(2),
+            ..Default::default()
+        };
+        assert_eq!(usage.to_string(), "2.0s wall");
+    }
+
+    #[test]
+    fn shell_quote_words() {
+        assert_eq!(shell_quote("cargo"), "cargo");
+        assert_eq!(shell_quote("--features=a,b"), "--features=a,b");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("hello world"), "'hello world'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,327 @@
+//! Progress of `nix` commands, from the activities they report with `--log-format internal-json`.
+//!
+//! Each line nix writes to stderr in that format is `@nix ` followed by a JSON event: an activity
+//! (such as a download or a build) starting or stopping, a result of an activity (such as how many
+//! bytes it has downloaded), or a message.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::process::{ExitStatus, Stdio};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::spinner::Spinner;
+
+/// How many lines of build logs are kept to explain a failure
+const MAX_BUILD_LOG_LINES: usize = 25;
+
+/// Messages at this level or more severe are kept to explain a failure (nix's `lvlWarn`)
+const MAX_KEPT_MESSAGE_LEVEL: u64 = 1;
+
+// Nix's `ActivityType`s riff reports on
+const ACTIVITY_FILE_TRANSFER: u64 = 101;
+const ACTIVITY_BUILD: u64 = 105;
+const ACTIVITY_SUBSTITUTE: u64 = 108;
+
+// Nix's `ResultType`s riff reports on
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+const RESULT_PROGRESS: u64 = 105;
+
+/// One `@nix` line of `--log-format internal-json` output
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum LogEvent {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Msg {
+        level: u64,
+        msg: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug)]
+struct Activity {
+    kind: u64,
+    text: String,
+    /// What the activity is about, eg the store path being fetched
+    subject: Option<String>,
+    done: u64,
+    expected: u64,
+}
+
+/// What a running `nix` command is doing, built up from its log
+#[derive(Debug, Default)]
+pub struct NixProgress {
+    activities: BTreeMap<u64, Activity>,
+    messages: Vec<String>,
+    build_log: VecDeque<String>,
+}
+
+impl NixProgress {
+    /// Update the progress with a line of the command's stderr
+    pub fn handle(&mut self, line: &str) {
+        let Some(json) = line.strip_prefix("@nix ") else {
+            // Not everything nix prints goes through its logger
+            tracing::trace!(line, "nix");
+            self.messages.push(line.to_string());
+            return;
+        };
+        let event = match serde_json::from_str(json) {
+            Ok(event) => event,
+            Err(err) => {
+                tracing::debug!(%err, line, "Could not parse nix's log");
+                return;
+            }
+        };
+        match event {
+            LogEvent::Start {
+                id,
+                kind,
+                text,
+                fields,
+            } => {
+                let subject = fields.first().and_then(Value::as_str).map(store_path_name);
+                self.activities.insert(
+                    id,
+                    Activity {
+                        kind,
+                        text,
+                        subject,
+                        done: 0,
+                        expected: 0,
+                    },
+                );
+            }
+            LogEvent::Stop { id } => {
+                self.activities.remove(&id);
+            }
+            LogEvent::Result {
+                id,
+                kind: RESULT_PROGRESS,
+                fields,
+            } => {
+                if let Some(activity) = self.activities.get_mut(&id) {
+                    let field = |i: usize| fields.get(i).and_then(Value::as_u64).unwrap_or(0);
+                    activity.done = field(0);
+                    activity.expected = field(1);
+                }
+            }
+            LogEvent::Result {
+                kind: RESULT_BUILD_LOG_LINE,
+                fields,
+                ..
+            } => {
+                if let Some(line) = fields.first().and_then(Value::as_str) {
+                    tracing::trace!(line, "nix build");
+                    if self.build_log.len() == MAX_BUILD_LOG_LINES {
+                        self.build_log.pop_front();
+                    }
+                    self.build_log.push_back(line.to_string());
+                }
+            }
+            LogEvent::Msg { level, msg } => {
+                tracing::trace!(level, msg, "nix");
+                if level <= MAX_KEPT_MESSAGE_LEVEL {
+                    self.messages.push(msg);
+                }
+            }
+            LogEvent::Result { .. } | LogEvent::Other => {}
+        }
+    }
+
+    /// A short description of what's happening now, eg
+    /// `fetching 3 paths (12.5 of 40.0 MiB), building hello-2.12`
+    pub fn summary(&self) -> Option<String> {
+        let mut parts = Vec::new();
+
+        let fetching = self.of_kind(ACTIVITY_SUBSTITUTE).count();
+        let (done, expected) = self
+            .of_kind(ACTIVITY_FILE_TRANSFER)
+            .fold((0, 0), |(done, expected), activity| {
+                (done + activity.done, expected + activity.expected)
+            });
+        let downloaded = (expected > 0).then(|| format!("{} of {} MiB", mib(done), mib(expected)));
+        match (fetching, downloaded) {
+            (0, Some(downloaded)) => parts.push(format!("downloading {downloaded}")),
+            (0, None) => {}
+            (fetching, downloaded) => parts.push(format!(
+                "fetching {fetching} path{s}{downloaded}",
+                s = if fetching == 1 { "" } else { "s" },
+                downloaded = downloaded
+                    .map(|downloaded| format!(" ({downloaded})"))
+                    .unwrap_or_default(),
+            )),
+        }
+
+        let builds = self
+            .of_kind(ACTIVITY_BUILD)
+            .filter_map(|activity| activity.subject.as_deref())
+            .collect::<Vec<_>>();
+        match builds.as_slice() {
+            [] => {}
+            [build] => parts.push(format!("building {build}")),
+            [build, rest @ ..] => parts.push(format!("building {build} and {} more", rest.len())),
+        }
+
+        if parts.is_empty() {
+            // Something else, such as fetching the flake's inputs, described by nix itself
+            return self
+                .activities
+                .values()
+                .rev()
+                .find(|activity| !activity.text.is_empty())
+                .map(|activity| activity.text.clone());
+        }
+        Some(parts.join(", "))
+    }
+
+    /// The errors and warnings nix printed, followed by the end of any build logs, to explain why
+    /// it failed
+    pub fn errors(&self) -> String {
+        let mut errors = self.messages.join("\n");
+        if !self.build_log.is_empty() {
+            errors.push_str("\n\nLast lines of the build log:\n");
+            errors.push_str(
+                &self
+                    .build_log
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+        errors
+    }
+
+    fn of_kind(&self, kind: u64) -> impl Iterator<Item = &Activity> {
+        self.activities
+            .values()
+            .filter(move |activity| activity.kind == kind)
+    }
+}
+
+/// The output of a `nix` command run by [`run_nix`]
+#[derive(Debug)]
+pub struct NixOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub progress: NixProgress,
+}
+
+/// Run a `nix` command, showing what it's doing on `spinner` until it exits
+///
+/// The command's stderr is parsed rather than shown, see [`NixProgress::errors`] for what it
+/// printed. If the command succeeds, the warnings and other messages it printed are shown.
+pub async fn run_nix(
+    command: &mut Command,
+    offline: bool,
+    spinner: &Spinner,
+) -> std::io::Result<NixOutput> {
+    command
+        .args(["--log-format", "internal-json"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut progress = NixProgress::default();
+    let output = crate::command_runner::run(command, offline, &mut |line| {
+        progress.handle(line);
+        spinner.set_detail(progress.summary().as_deref());
+    })
+    .await?;
+    spinner.set_detail(None);
+
+    // A failure's messages are shown with its error, but warnings (such as one about a dirty git
+    // tree or an unknown setting) matter even when nix succeeds
+    if output.status.success() && !progress.messages.is_empty() {
+        crate::spinner::suspend(|| {
+            for message in &progress.messages {
+                eprintln!("{message}");
+            }
+        });
+    }
+
+    Ok(NixOutput {
+        status: output.status,
+        stdout: output.stdout,
+        progress,
+    })
+}
+
+/// The name of a store path or derivation, without its directory, hash, or `.drv` extension
+fn store_path_name(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let name = name.strip_suffix(".drv").unwrap_or(name);
+    match name.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name.to_string(),
+        _ => name.to_string(),
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1}", bytes as f64 / (1024.0 * 1024.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_is_summarized() {
+        let mut progress = NixProgress::default();
+        progress.handle(
+            r#"@nix {"action":"start","id":1,"level":3,"type":112,"text":"fetching NixOS/nixpkgs","fields":[]}"#,
+        );
+        assert_eq!(
+            progress.summary().as_deref(),
+            Some("fetching NixOS/nixpkgs")
+        );
+
+        for line in [
+            r#"@nix {"action":"stop","id":1}"#,
+            r#"@nix {"action":"start","id":2,"level":4,"type":108,"text":"copying path","fields":["/nix/store/0c8qy5bjxqxdmfaahnvbwjnbv3vgqpsz-openssl-3.0.8","https://cache.nixos.org"]}"#,
+            r#"@nix {"action":"start","id":3,"level":4,"type":108,"text":"copying path","fields":["/nix/store/1aq0yhc8rvjx3dpsxy3lv9fhlfvj3zbx-pkg-config-0.29.2","https://cache.nixos.org"]}"#,
+            r#"@nix {"action":"start","id":4,"level":4,"type":101,"text":"downloading","fields":["https://cache.nixos.org/nar/x.nar.xz"]}"#,
+            r#"@nix {"action":"result","id":4,"type":105,"fields":[1048576,4194304,0,0]}"#,
+            r#"@nix {"action":"start","id":5,"level":3,"type":105,"text":"building","fields":["/nix/store/ajd9cxzrjd6kr2ijzlj0z2pcrngacmy5-hello-2.12.1.drv","",1,1]}"#,
+            r#"@nix {"action":"result","id":5,"type":101,"fields":["checking for gcc... gcc"]}"#,
+            r#"@nix {"action":"msg","level":0,"msg":"error: builder for 'hello-2.12.1.drv' failed"}"#,
+            r#"@nix {"action":"msg","level":3,"msg":"unpacking sources"}"#,
+        ] {
+            progress.handle(line);
+        }
+        assert_eq!(
+            progress.summary().as_deref(),
+            Some("fetching 2 paths (1.0 of 4.0 MiB), building hello-2.12.1")
+        );
+        assert_eq!(
+            progress.errors(),
+            "error: builder for 'hello-2.12.1.drv' failed\n\n\
+            Last lines of the build log:\n\
+            checking for gcc... gcc"
+        );
+
+        for id in 2..=5 {
+            progress.handle(&format!(r#"@nix {{"action":"stop","id":{id}}}"#));
+        }
+        assert_eq!(progress.summary(), None);
+    }
+}
@@ -0,0 +1,84 @@
+//! `--offline`: how much riff, and the `nix` and `cargo` commands it runs, may use the network.
+//!
+//! `--offline` alone (`--offline=cached`) keeps riff from refreshing what it has cached, and has
+//! `nix flake lock` and `cargo metadata` use only what they have, though `nix print-dev-env` may
+//! still download packages the store is missing. `--offline=strict-offline` downloads nothing at
+//! all, failing with the `not-cached` error code as soon as something isn't cached.
+//!
+//! Each command takes the mode from `--offline` and passes it on, in
+//! [`GenerateOptions`](crate::flake_generator::GenerateOptions) and to what it runs itself.
+
+use crate::errors::{ErrorCode, RiffError};
+
+/// How much the network may be used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OfflineMode {
+    /// Use the network as needed
+    #[default]
+    Online,
+    /// Use what's cached rather than refreshing it, downloading only packages Nix doesn't have
+    Cached,
+    /// Download nothing, and fail if something needed isn't cached
+    StrictOffline,
+}
+
+impl OfflineMode {
+    /// Whether riff should use what it has cached rather than the network
+    pub fn is_offline(self) -> bool {
+        self != Self::Online
+    }
+
+    /// Whether nothing may be downloaded, even by `nix print-dev-env`
+    pub fn is_strict(self) -> bool {
+        self == Self::StrictOffline
+    }
+}
+
+/// Parse `--offline=MODE` (or `RIFF_OFFLINE`), where `true` and `false` are the `cached` and
+/// `online` they meant before there were modes
+pub fn parse_offline_mode(mode: &str) -> Result<OfflineMode, String> {
+    match mode.trim().to_lowercase().as_str() {
+        "online" | "false" | "no" | "off" | "0" | "" => Ok(OfflineMode::Online),
+        "cached" | "true" | "yes" | "on" | "1" => Ok(OfflineMode::Cached),
+        "strict-offline" | "strict" => Ok(OfflineMode::StrictOffline),
+        _ => Err(format!(
+            "`{mode}` isn't `online`, `cached`, or `strict-offline`"
+        )),
+    }
+}
+
+/// The error for `command` failing with `--offline=strict-offline`, most likely because `what` it
+/// needed isn't cached
+pub fn not_cached(command: &str, what: &str, errors: &str) -> RiffError {
+    RiffError::new(
+        ErrorCode::NotCached,
+        format!(
+            "`{command}` failed with `--offline=strict-offline`, most likely because {what} \
+            isn't cached locally; run riff once online to cache it:\n{errors}"
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_modes() {
+        assert_eq!(parse_offline_mode("cached"), Ok(OfflineMode::Cached));
+        assert_eq!(
+            parse_offline_mode("strict-offline"),
+            Ok(OfflineMode::StrictOffline)
+        );
+        // `RIFF_OFFLINE=true` and `RIFF_OFFLINE=false` keep working
+        assert_eq!(parse_offline_mode("true"), Ok(OfflineMode::Cached));
+        assert_eq!(parse_offline_mode("false"), Ok(OfflineMode::Online));
+        assert!(parse_offline_mode("airplane").is_err());
+
+        assert!(!OfflineMode::Online.is_offline());
+        assert!(OfflineMode::Cached.is_offline());
+        assert!(OfflineMode::StrictOffline.is_offline());
+        assert!(!OfflineMode::Cached.is_strict());
+        assert!(OfflineMode::StrictOffline.is_strict());
+    }
+}
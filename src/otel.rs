@@ -0,0 +1,344 @@
+//! `--otel-endpoint`: riff's internal spans, sent to an OpenTelemetry collector once riff is done.
+//!
+//! The [`OtlpLayer`] is always part of the tracing subscriber, but records nothing unless an
+//! endpoint was given to [`enable`]. Spans are sent with OTLP over HTTP, encoded as JSON, as a
+//! single trace per invocation.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::execution::{self, Effect};
+
+/// How long sending the spans may delay riff's exit
+const EXPORT_BUDGET: Duration = Duration::from_secs(5);
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+static FINISHED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+
+/// Where to send spans to
+#[derive(Debug)]
+struct Exporter {
+    url: String,
+    headers: Vec<(String, String)>,
+    trace_id: [u8; 16],
+}
+
+/// Record spans from now on, to send them to the OTLP/HTTP collector at `endpoint` (eg
+/// `http://localhost:4318`) when riff is done
+pub fn enable(endpoint: &str) {
+    let exporter = Exporter {
+        url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        headers: headers(&std::env::var("OTEL_EXPORTER_OTLP_HEADERS").unwrap_or_default()),
+        trace_id: uuid::Uuid::new_v4().into_bytes(),
+    };
+    if EXPORTER.set(exporter).is_err() {
+        tracing::debug!("OpenTelemetry export was already enabled");
+    }
+}
+
+/// Headers to send with the spans, given like `OTEL_EXPORTER_OTLP_HEADERS` (eg
+/// `api-key=secret,tenant=ci`)
+fn headers(spec: &str) -> Vec<(String, String)> {
+    spec.split(',')
+        .filter_map(|header| header.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// A span, as it is sent
+#[derive(Debug, Clone)]
+struct SpanData {
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    target: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    events: Vec<(SystemTime, String)>,
+}
+
+/// The fields of a span or event, formatted
+#[derive(Debug, Default)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push((field.name(), format!("{value:?}")));
+    }
+}
+
+/// Records riff's spans, and the events inside them, for [`finish`] to send
+#[derive(Debug, Default)]
+pub struct OtlpLayer;
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if EXPORTER.get().is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let parent_span_id = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| data.span_id)
+        });
+        let mut span_id = [0; 8];
+        span_id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..8]);
+        let now = SystemTime::now();
+        span.extensions_mut().insert(SpanData {
+            span_id,
+            parent_span_id,
+            name: attrs.metadata().name(),
+            target: attrs.metadata().target(),
+            start: now,
+            end: now,
+            attributes: fields.0,
+            events: vec![],
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            let mut fields = Fields::default();
+            values.record(&mut fields);
+            data.attributes.extend(fields.0);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let message = fields
+                .0
+                .iter()
+                .map(|(name, value)| match *name {
+                    "message" => value.clone(),
+                    _ => format!("{name}={value}"),
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            data.events.push((SystemTime::now(), message));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(mut data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        data.end = SystemTime::now();
+        if let Ok(mut finished) = FINISHED.lock() {
+            finished.push(data);
+        }
+    }
+}
+
+/// Send the recorded spans, if export was enabled. Failing to send them doesn't fail riff.
+pub async fn finish(offline: bool) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let spans = match FINISHED.lock() {
+        Ok(mut finished) => std::mem::take(&mut *finished),
+        Err(_) => return,
+    };
+    if spans.is_empty() {
+        return;
+    }
+    if offline {
+        tracing::debug!("Not sending spans to {} while offline", exporter.url);
+        return;
+    }
+    if !execution::perform(Effect::Fetch(exporter.url.clone())) {
+        return;
+    }
+
+    let body = request_body(exporter.trace_id, &spans);
+    let result = async {
+        let mut request = crate::http::client()?
+            .post(&exporter.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .timeout(EXPORT_BUDGET.min(crate::http::options().network_timeout()));
+        for (name, value) in &exporter.headers {
+            request = request.header(name, value);
+        }
+        request.send().await?.error_for_status()
+    };
+    match result.await {
+        Ok(_) => tracing::debug!(spans = spans.len(), "Sent spans to {}", exporter.url),
+        Err(err) => tracing::warn!(%err, "Could not send spans to {}", exporter.url),
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` for `spans`, in its JSON encoding
+fn request_body(trace_id: [u8; 16], spans: &[SpanData]) -> Value {
+    let mut by_target = HashMap::<&str, Vec<Value>>::new();
+    for span in spans {
+        by_target.entry(span.target).or_default().push(json!({
+            "traceId": hex(&trace_id),
+            "spanId": hex(&span.span_id),
+            "parentSpanId": span.parent_span_id.as_ref().map(|id| hex(id)).unwrap_or_default(),
+            "name": span.name,
+            // `SPAN_KIND_INTERNAL`
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(span.end),
+            "attributes": span
+                .attributes
+                .iter()
+                .map(|(key, value)| attribute(key, value))
+                .collect::<Vec<_>>(),
+            "events": span
+                .events
+                .iter()
+                .map(|(time, message)| json!({
+                    "timeUnixNano": unix_nanos(*time),
+                    "name": message,
+                }))
+                .collect::<Vec<_>>(),
+        }));
+    }
+    let mut scope_spans = by_target
+        .into_iter()
+        .map(|(target, spans)| {
+            json!({
+                "scope": { "name": target },
+                "spans": spans,
+            })
+        })
+        .collect::<Vec<_>>();
+    scope_spans.sort_by_key(|scope| scope["scope"]["name"].as_str().map(ToString::to_string));
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", env!("CARGO_PKG_NAME")),
+                    attribute("service.version", env!("CARGO_PKG_VERSION")),
+                    attribute("host.arch", std::env::consts::ARCH),
+                    attribute("os.type", std::env::consts::OS),
+                ],
+            },
+            "scopeSpans": scope_spans,
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// OTLP's JSON encoding gives 64 bit integers as strings
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_from_environment() {
+        assert_eq!(
+            headers("api-key=secret, tenant=ci,,=nameless"),
+            vec![
+                ("api-key".to_string(), "secret".to_string()),
+                ("tenant".to_string(), "ci".to_string()),
+            ]
+        );
+        assert!(headers("").is_empty());
+    }
+
+    #[test]
+    fn spans_as_otlp_json() {
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let parent = SpanData {
+            span_id: [1; 8],
+            parent_span_id: None,
+            name: "generate_flake_from_project_dir",
+            target: "riff::flake_generator",
+            start,
+            end: start + Duration::from_millis(1500),
+            attributes: vec![("offline", "false".to_string())],
+            events: vec![],
+        };
+        let child = SpanData {
+            span_id: [2; 8],
+            parent_span_id: Some([1; 8]),
+            name: "detect",
+            target: "riff::dev_env",
+            start,
+            end: start + Duration::from_secs(1),
+            attributes: vec![],
+            events: vec![(start, "Detected Rust".to_string())],
+        };
+        let body = request_body([0xab; 16], &[child, parent]);
+
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0],
+            json!({ "key": "service.name", "value": { "stringValue": "riff" } })
+        );
+        let scopes = resource["scopeSpans"].as_array().unwrap();
+        assert_eq!(scopes[0]["scope"]["name"], "riff::dev_env");
+        assert_eq!(scopes[1]["scope"]["name"], "riff::flake_generator");
+
+        let child = &scopes[0]["spans"][0];
+        assert_eq!(child["traceId"], "ab".repeat(16));
+        assert_eq!(child["spanId"], "0202020202020202");
+        assert_eq!(child["parentSpanId"], "0101010101010101");
+        assert_eq!(child["events"][0]["name"], "Detected Rust");
+        assert_eq!(child["events"][0]["timeUnixNano"], "1000000000");
+
+        let parent = &scopes[1]["spans"][0];
+        assert_eq!(parent["parentSpanId"], "");
+        assert_eq!(parent["endTimeUnixNano"], "2500000000");
+        assert_eq!(
+            parent["attributes"][0],
+            json!({ "key": "offline", "value": { "stringValue": "false" } })
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! `riff generate --interactive`: assembling an environment by hand for a project riff doesn't
+//! recognize, from common packages or a search of Nixpkgs.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use atty::Stream;
+use eyre::{eyre, WrapErr};
+use owo_colors::OwoColorize;
+use serde::Deserialize;
+
+use crate::spinner::Phase;
+
+/// The packages offered before anything is searched for, with what they provide
+const COMMON_PACKAGES: &[(&str, &str)] = &[
+    ("cargo", "Rust's package manager"),
+    ("rustc", "The Rust compiler"),
+    ("go", "The Go toolchain"),
+    ("nodejs", "Node.js and npm"),
+    ("python3", "Python 3"),
+    ("gcc", "The GNU C and C++ compilers"),
+    ("gnumake", "GNU Make"),
+    ("cmake", "CMake"),
+    ("pkg-config", "Finds libraries to build against"),
+    ("openssl", "OpenSSL"),
+    ("protobuf", "Protocol Buffers, with protoc"),
+    ("sqlite", "SQLite"),
+];
+
+/// How many search results to offer
+const MAX_SEARCH_RESULTS: usize = 15;
+
+/// One package of `nix search --json` output
+#[derive(Debug, Clone, Deserialize)]
+struct SearchResult {
+    #[serde(default)]
+    description: String,
+}
+
+/// Ask which packages the environment should have, searching `nixpkgs` for those which aren't
+/// among the common ones
+pub async fn pick_packages(nixpkgs: &str, offline: bool) -> color_eyre::Result<Vec<String>> {
+    if !(atty::is(Stream::Stdin) && atty::is(Stream::Stderr)) {
+        return Err(eyre!(
+            "Picking packages with `--interactive` needs a terminal to ask questions on"
+        ));
+    }
+
+    let mut offered: Vec<(String, String)> = COMMON_PACKAGES
+        .iter()
+        .map(|(attr, description)| (attr.to_string(), description.to_string()))
+        .collect();
+    let mut picked: Vec<String> = Vec::new();
+    loop {
+        let answer = crate::spinner::suspend(|| -> color_eyre::Result<String> {
+            eprintln!();
+            for (number, (attr, description)) in offered.iter().enumerate() {
+                let mark = if picked.contains(attr) { "✓" } else { " " };
+                eprintln!(
+                    "{mark} {number:>2}. {attr} {description}",
+                    number = number + 1,
+                    attr = attr.cyan(),
+                    description = description.dimmed(),
+                );
+            }
+            eprint!(
+                "Add packages by number, search Nixpkgs with a word, or press Enter when done: "
+            );
+            std::io::stderr().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            Ok(answer.trim().to_string())
+        })?;
+
+        if answer.is_empty() {
+            break;
+        }
+        match parse_numbers(&answer, offered.len()) {
+            Some(numbers) => {
+                for number in numbers {
+                    let attr = &offered[number - 1].0;
+                    if !picked.contains(attr) {
+                        picked.push(attr.clone());
+                    }
+                }
+            }
+            None => match search(nixpkgs, &answer, offline).await {
+                Ok(results) if results.is_empty() => {
+                    eprintln!("Nixpkgs has nothing matching `{answer}`");
+                }
+                Ok(results) => offered = results,
+                Err(err) => eprintln!("{} {err:#}", "⚠".yellow()),
+            },
+        }
+    }
+
+    if picked.is_empty() {
+        return Err(eyre!("No packages were picked for the environment"));
+    }
+    Ok(picked)
+}
+
+/// The 1-based numbers in `answer`, if it's only numbers which are all at most `max`
+fn parse_numbers(answer: &str, max: usize) -> Option<Vec<usize>> {
+    answer
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            word.parse()
+                .ok()
+                .filter(|number| (1..=max).contains(number))
+        })
+        .collect()
+}
+
+/// The packages in `nixpkgs` matching `query`, best first
+async fn search(
+    nixpkgs: &str,
+    query: &str,
+    offline: bool,
+) -> color_eyre::Result<Vec<(String, String)>> {
+    let mut command = crate::nix_config::command(["search"]);
+    command
+        .arg("--json")
+        .arg(nixpkgs)
+        .arg(regex_escape(query))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    if offline {
+        command.arg("--offline");
+    }
+    crate::process_limits::apply_to_nix(&mut command);
+
+    let spinner =
+        crate::spinner::start(Phase::QueryingNixpkgs, &format!("Searching for `{query}`"))
+            .wrap_err("Failed to construct progress spinner")?;
+    let output = crate::command_runner::run(&mut command, offline, &mut |_| {})
+        .await
+        .wrap_err("Could not run `nix search`")?;
+    spinner.finish_and_clear();
+    if !output.status.success() {
+        return Err(eyre!(
+            "Could not search Nixpkgs:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let results: BTreeMap<String, SearchResult> = serde_json::from_slice(&output.stdout)
+        .wrap_err("Could not parse the output of `nix search`")?;
+    Ok(rank(results, query))
+}
+
+/// The results as attribute names (without the `legacyPackages.<system>.` prefix) and
+/// descriptions, those whose name matches `query` most closely first
+fn rank(results: BTreeMap<String, SearchResult>, query: &str) -> Vec<(String, String)> {
+    let query = query.to_lowercase();
+    let mut ranked: Vec<(usize, String, String)> = results
+        .into_iter()
+        .map(|(key, result)| {
+            let attr = key.splitn(3, '.').nth(2).unwrap_or(&key).to_string();
+            let name = attr.rsplit('.').next().unwrap_or(&attr).to_lowercase();
+            let score = if name == query {
+                0
+            } else if name.starts_with(&query) {
+                1
+            } else if name.contains(&query) {
+                2
+            } else {
+                // Only the description matched
+                3
+            };
+            (score, attr, result.description)
+        })
+        .collect();
+    ranked.sort_by(|(a_score, a_attr, _), (b_score, b_attr, _)| {
+        (a_score, a_attr.len(), a_attr).cmp(&(b_score, b_attr.len(), b_attr))
+    });
+    ranked
+        .into_iter()
+        .take(MAX_SEARCH_RESULTS)
+        .map(|(_, attr, description)| (attr, description))
+        .collect()
+}
+
+/// `query` with the characters regular expressions treat specially escaped, since `nix search`
+/// takes a regular expression
+fn regex_escape(query: &str) -> String {
+    query
+        .chars()
+        .flat_map(|c| {
+            let special = r"\.+*?()|[]{}^$".contains(c);
+            special.then_some('\\').into_iter().chain([c])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_results_are_ranked() -> eyre::Result<()> {
+        let results: BTreeMap<String, SearchResult> = serde_json::from_str(
+            r#"{
+                "legacyPackages.x86_64-linux.libpqxx": {"description": "C++ library for PostgreSQL", "pname": "libpqxx", "version": "7.7.5"},
+                "legacyPackages.x86_64-linux.postgresql": {"description": "A powerful, open source object-relational database system", "pname": "postgresql", "version": "15.3"},
+                "legacyPackages.x86_64-linux.postgresql_14": {"description": "A powerful, open source object-relational database system", "pname": "postgresql", "version": "14.8"},
+                "legacyPackages.x86_64-linux.python3Packages.psycopg2": {"description": "PostgreSQL database adapter for the Python programming language", "pname": "psycopg2", "version": "2.9.6"}
+            }"#,
+        )?;
+        let attrs: Vec<String> = rank(results, "postgresql")
+            .into_iter()
+            .map(|(attr, _)| attr)
+            .collect();
+        assert_eq!(
+            attrs,
+            vec![
+                "postgresql",
+                "postgresql_14",
+                "libpqxx",
+                "python3Packages.psycopg2"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn answers_are_numbers_or_searches() {
+        assert_eq!(parse_numbers("1, 3 12", 12), Some(vec![1, 3, 12]));
+        assert_eq!(parse_numbers("13", 12), None);
+        assert_eq!(parse_numbers("0", 12), None);
+        assert_eq!(parse_numbers("postgres", 12), None);
+        assert_eq!(regex_escape("c++"), r"c\+\+");
+    }
+}
@@ -0,0 +1,63 @@
+//! Checks for platforms riff can't run on natively.
+
+use std::process::ExitCode;
+
+/// Whether programs using OpenGL or Vulkan from Nix need nixGL to load the host's drivers, which
+/// they find by themselves only on NixOS (and macOS, which nixGL doesn't support)
+pub fn needs_nixgl() -> bool {
+    cfg!(target_os = "linux") && !std::path::Path::new("/etc/NIXOS").exists()
+}
+
+/// Run before doing any real work (network, registry, or `nix` access).
+///
+/// Returns `Some` exit code if the command was handed off to somewhere riff is supported, and an
+/// error if riff can't run here at all.
+#[cfg(unix)]
+pub async fn redirect_unsupported() -> color_eyre::Result<Option<ExitCode>> {
+    Ok(None)
+}
+
+/// Nix doesn't run natively on Windows, but it does in WSL, so offer to rerun the command there.
+#[cfg(windows)]
+pub async fn redirect_unsupported() -> color_eyre::Result<Option<ExitCode>> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    use atty::Stream;
+    use eyre::eyre;
+    use owo_colors::OwoColorize;
+    use tokio::process::Command;
+
+    let wsl_available = Command::new("wsl.exe")
+        .arg("--status")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map(|status| status.success())
+        .unwrap_or(false);
+
+    if wsl_available && atty::is(Stream::Stdin) && atty::is(Stream::Stderr) {
+        eprint!(
+            "Nix doesn't run natively on Windows. Run this command with the `{riff}` installed in WSL instead? [Y/n] ",
+            riff = "riff".cyan(),
+        );
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if matches!(answer.trim().to_lowercase().as_str(), "" | "y" | "yes") {
+            let status = Command::new("wsl.exe")
+                .arg("--")
+                .arg("riff")
+                .args(std::env::args().skip(1))
+                .status()
+                .await?;
+            return Ok(Some(ExitCode::from(status.code().unwrap_or(1) as u8)));
+        }
+    }
+
+    Err(eyre!(
+        "Riff uses Nix, which doesn't run natively on Windows. Install Nix and riff inside WSL \
+        (https://learn.microsoft.com/windows/wsl/install) and run riff there instead."
+    ))
+}
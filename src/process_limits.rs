@@ -0,0 +1,183 @@
+//! Limits on the heavy subprocesses (`nix`, `cargo metadata`) riff spawns while preparing an
+//! environment, so that they don't starve the user's interactive work.
+//!
+//! `--max-jobs` and `--cores` can also be set in `riff.toml`, and the cores are what
+//! `NIX_BUILD_CORES` is in the environment, for build tools which follow it.
+
+use std::num::NonZeroUsize;
+use std::sync::OnceLock;
+
+use clap::Args;
+use tokio::process::Command;
+
+static PROCESS_LIMITS: OnceLock<ProcessLimits> = OnceLock::new();
+/// The `max-jobs` and `cores` of the project's `riff.toml`
+static PROJECT_LIMITS: OnceLock<ProcessLimits> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct ProcessLimits {
+    /// Increase the niceness of the `nix` and `cargo` processes riff runs by this much
+    ///
+    /// This does not affect builds done by a Nix daemon.
+    #[clap(long, global = true, env = "RIFF_NICE")]
+    pub nice: Option<i32>,
+    /// Run `nix` and `cargo` processes in the idle I/O scheduling class (Linux only)
+    #[clap(long, global = true, env = "RIFF_IONICE_IDLE")]
+    pub ionice_idle: bool,
+    /// The maximum number of jobs Nix builds in parallel
+    #[clap(long, global = true, env = "RIFF_MAX_JOBS")]
+    pub max_jobs: Option<usize>,
+    /// The number of cores each Nix build job may use
+    #[clap(long, global = true, env = "RIFF_CORES")]
+    pub cores: Option<usize>,
+}
+
+impl ProcessLimits {
+    /// Use these limits for every subprocess spawned afterwards
+    pub fn install(self) {
+        if PROCESS_LIMITS.set(self).is_err() {
+            tracing::debug!("Process limits were already installed");
+        }
+    }
+}
+
+fn limits() -> &'static ProcessLimits {
+    PROCESS_LIMITS.get_or_init(Default::default)
+}
+
+/// Limit Nix builds as the project's `riff.toml` says, where the command line doesn't
+pub fn use_project_limits(max_jobs: Option<usize>, cores: Option<usize>) {
+    let project_limits = ProcessLimits {
+        max_jobs,
+        cores,
+        ..Default::default()
+    };
+    if PROJECT_LIMITS.set(project_limits).is_err() {
+        tracing::debug!("Project limits were already configured");
+    }
+}
+
+fn max_jobs() -> Option<usize> {
+    limits()
+        .max_jobs
+        .or_else(|| PROJECT_LIMITS.get().and_then(|limits| limits.max_jobs))
+}
+
+fn cores() -> Option<usize> {
+    limits()
+        .cores
+        .or_else(|| PROJECT_LIMITS.get().and_then(|limits| limits.cores))
+}
+
+/// What `NIX_BUILD_CORES` is in the environment: the configured cores, or those available to
+/// riff (which, unlike the machine's, are limited by the container it runs in) for `0` or none
+pub fn build_cores() -> usize {
+    cores()
+        .filter(|cores| *cores > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, NonZeroUsize::get))
+}
+
+/// Apply the configured niceness to `command`
+pub fn apply(command: &mut Command) {
+    limits().lower_priority_of(command);
+}
+
+/// Like `apply`, and also pass the configured `--max-jobs` and `--cores` to a `nix` command
+pub fn apply_to_nix(command: &mut Command) {
+    apply(command);
+    command.args(nix_args(max_jobs(), cores()));
+}
+
+impl ProcessLimits {
+    fn lower_priority_of(&self, command: &mut Command) {
+        if self.nice.is_some() || self.ionice_idle {
+            lower_priority(command, self.nice.unwrap_or(0), self.ionice_idle);
+        }
+    }
+}
+
+/// The arguments passing `max_jobs` and `cores` to a `nix` command
+fn nix_args(max_jobs: Option<usize>, cores: Option<usize>) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(max_jobs) = max_jobs {
+        args.extend(["--max-jobs".to_string(), max_jobs.to_string()]);
+    }
+    if let Some(cores) = cores {
+        args.extend(["--cores".to_string(), cores.to_string()]);
+    }
+    args
+}
+
+#[cfg(unix)]
+fn lower_priority(command: &mut Command, nice: i32, ionice_idle: bool) {
+    // SAFETY: Only async-signal-safe functions are called between `fork` and `exec`
+    unsafe {
+        command.pre_exec(move || {
+            // `nice` can legitimately return -1, and failing to lower our priority isn't fatal.
+            libc::nice(nice);
+            if ionice_idle {
+                set_io_idle();
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn lower_priority(_command: &mut Command, _nice: i32, _ionice_idle: bool) {
+    tracing::debug!("Lowering process priority is not supported on this platform");
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_idle() {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    // SAFETY: `ioprio_set` only reads its integer arguments
+    unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        );
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_io_idle() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nix_gets_max_jobs_and_cores() {
+        assert!(nix_args(None, None).is_empty());
+        assert_eq!(nix_args(Some(2), None), ["--max-jobs", "2"]);
+        assert_eq!(
+            nix_args(Some(2), Some(0)),
+            ["--max-jobs", "2", "--cores", "0"]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn niceness_is_increased() -> eyre::Result<()> {
+        async fn niceness(limits: &ProcessLimits) -> eyre::Result<i32> {
+            let mut command = Command::new("nice");
+            limits.lower_priority_of(&mut command);
+            let output = command.output().await?;
+            Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+        }
+
+        let own = niceness(&ProcessLimits::default()).await?;
+        let lowered = niceness(&ProcessLimits {
+            nice: Some(3),
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!(lowered, (own + 3).min(19));
+        Ok(())
+    }
+}
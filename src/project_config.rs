@@ -0,0 +1,589 @@
+//! Project-wide configuration, read from a `riff.toml` next to the project's manifest.
+//!
+//! ```toml
+//! nixpkgs = "github:NixOS/nixpkgs/nixos-unstable"
+//!
+//! # Flakes whose `overlays.default` (or `overlays.<name>`, with `#<name>`) apply to Nixpkgs
+//! overlays = ["github:oxalica/rust-overlay"]
+//!
+//! # Layers registered with `riff layers add`, whose profiles and conflict strategies apply as if
+//! # they were written here. Settings here win, and later layers win over earlier ones.
+//! include = ["riff-layers"]
+//!
+//! # Binary caches to fetch the environment from, besides those in Nix's own configuration
+//! substituters = ["https://cache.example.com"]
+//! trusted-public-keys = ["cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ="]
+//! # Where `riff push-cache` copies the environment to
+//! push-cache = "s3://acme-nix-cache?region=eu-west-1"
+//!
+//! # Limits on Nix builds, unless `--max-jobs` and `--cores` are given
+//! max-jobs = 2
+//! cores = 4
+//!
+//! # Inputs and environment variables to leave out, whichever dependency added them.
+//! exclude-build-inputs = ["glxinfo"]
+//! unset-environment-variables = ["RUST_LOG"]
+//!
+//! # Long-lived release branches can keep an older package set while `main` moves on.
+//! [branch."release/*"]
+//! nixpkgs = "github:NixOS/nixpkgs/nixos-22.11"
+//!
+//! # Selected with `--profile gpu`. A profile named `default` applies when none is selected.
+//! [profile.gpu]
+//! build-inputs = ["cudatoolkit"]
+//! environment-variables = { CUDA_VISIBLE_DEVICES = "0" }
+//!
+//! # Shorthands for `riff run`, eg `riff run test`. Arguments after the name are appended.
+//! [commands]
+//! test = "cargo nextest run"
+//! fmt = "cargo fmt && prettier -w ."
+//!
+//! # How to resolve variables which dependencies set to different values: `first`, `last`, or
+//! # `append` (joining with `:`).
+//! [conflicts]
+//! BINDGEN_EXTRA_CLANG_ARGS = "last"
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::layers::{self, Layer};
+use crate::nix_config::Substituters;
+use crate::warnings::WarningCode;
+
+pub const PROJECT_CONFIG_FILE: &str = "riff.toml";
+
+/// A flake template next to `riff.toml`, which riff renders instead of its own
+pub const PROJECT_TEMPLATE_FILE: &str = "riff-template.nix";
+
+/// The `nixpkgs` flake reference used when the project doesn't configure one
+pub const DEFAULT_NIXPKGS: &str = "github:NixOS/nixpkgs/nixos-unstable";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// The flake reference to take Nix packages from
+    pub(crate) nixpkgs: Option<String>,
+    /// Flakes whose overlays to apply to Nixpkgs, as `<flakeref>[#<overlay>]`
+    #[serde(default)]
+    pub(crate) overlays: Vec<String>,
+    /// The names of layers (see `riff layers`) to take profiles and conflict strategies from
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+    /// Overrides applied when the project's current git branch matches the key, which may
+    /// contain `*` wildcards
+    #[serde(default)]
+    pub(crate) branch: BTreeMap<String, EnvironmentConfig>,
+    /// Build inputs to leave out of the environment, whichever dependency added them
+    #[serde(default, rename = "exclude-build-inputs")]
+    pub(crate) exclude_build_inputs: HashSet<String>,
+    /// Runtime inputs to leave out of the environment, whichever dependency added them
+    #[serde(default, rename = "exclude-runtime-inputs")]
+    pub(crate) exclude_runtime_inputs: HashSet<String>,
+    /// Environment variables to leave out of the environment, whichever dependency set them
+    #[serde(default, rename = "unset-environment-variables")]
+    pub(crate) unset_environment_variables: HashSet<String>,
+    /// Warnings not to report, by code (eg `W0001`) or name (eg `unmapped-sys-crate`)
+    #[serde(default)]
+    pub(crate) suppress: Vec<String>,
+    /// Named adjustments to the detected environment, selected with `--profile`
+    #[serde(default)]
+    pub(crate) profile: BTreeMap<String, Profile>,
+    /// How to resolve each environment variable which dependencies set to different values
+    #[serde(default)]
+    pub(crate) conflicts: HashMap<String, ConflictStrategy>,
+    /// Shell commands which `riff run <name>` runs in place of a program called `name`
+    #[serde(default)]
+    pub(crate) commands: BTreeMap<String, String>,
+    /// Give the project a base environment if riff doesn't recognize it, see `--allow-empty`
+    #[serde(default, rename = "allow-empty")]
+    pub(crate) allow_empty: bool,
+    /// Binary caches for Nix to fetch from, besides those in its own configuration
+    #[serde(default)]
+    pub(crate) substituters: Vec<String>,
+    /// The public keys the binary caches sign with
+    #[serde(default, rename = "trusted-public-keys")]
+    pub(crate) trusted_public_keys: Vec<String>,
+    /// The store URL `riff push-cache` copies the environment to
+    #[serde(rename = "push-cache")]
+    pub(crate) push_cache: Option<String>,
+    /// The maximum number of jobs Nix builds in parallel, unless `--max-jobs` is given
+    #[serde(rename = "max-jobs")]
+    pub(crate) max_jobs: Option<usize>,
+    /// The number of cores each Nix build job (and `NIX_BUILD_CORES` in the environment) may
+    /// use, unless `--cores` is given
+    pub(crate) cores: Option<usize>,
+}
+
+/// How to resolve an environment variable which several sources set to different values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictStrategy {
+    /// Keep the value set first
+    First,
+    /// Use the value set last
+    Last,
+    /// Join the values with `:`, as for `PATH`
+    Append,
+}
+
+/// Variables which hold `:`-separated lists of paths, and so are appended to by default
+const PATH_LIKE_VARIABLES: &[&str] = &[
+    "CMAKE_PREFIX_PATH",
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "LD_LIBRARY_PATH",
+    "LIBRARY_PATH",
+    "PATH",
+    "PKG_CONFIG_PATH",
+    "XDG_DATA_DIRS",
+];
+
+impl ConflictStrategy {
+    /// The strategy for `name` when the project doesn't configure one
+    pub fn default_for(name: &str) -> Self {
+        if PATH_LIKE_VARIABLES.contains(&name) {
+            Self::Append
+        } else {
+            Self::Last
+        }
+    }
+}
+
+/// Inputs and environment variables to filter out of the environment once every dependency has
+/// been applied, declared in `riff.toml` or a workspace member's `package.metadata.riff`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Exclusions {
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) exclude_build_inputs: HashSet<String>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) exclude_runtime_inputs: HashSet<String>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) unset_environment_variables: HashSet<String>,
+}
+
+impl Exclusions {
+    pub fn extend(&mut self, other: &Exclusions) {
+        self.exclude_build_inputs
+            .extend(other.exclude_build_inputs.iter().cloned());
+        self.exclude_runtime_inputs
+            .extend(other.exclude_runtime_inputs.iter().cloned());
+        self.unset_environment_variables
+            .extend(other.unset_environment_variables.iter().cloned());
+    }
+}
+
+/// Inputs and environment variables to add to or remove from the detected environment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(default)]
+    pub(crate) build_inputs: HashSet<String>,
+    #[serde(default)]
+    pub(crate) remove_build_inputs: HashSet<String>,
+    #[serde(default)]
+    pub(crate) runtime_inputs: HashSet<String>,
+    #[serde(default)]
+    pub(crate) remove_runtime_inputs: HashSet<String>,
+    #[serde(default)]
+    pub(crate) environment_variables: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) remove_environment_variables: HashSet<String>,
+}
+
+impl Profile {
+    /// The environment variables the profile sets, described like
+    /// [`crate::dependency_registry::rust::RustDependencyData::overrides`]
+    pub fn overrides(&self) -> Vec<String> {
+        let mut overrides = self
+            .environment_variables
+            .iter()
+            .map(|(name, value)| format!("`{name}={value}`"))
+            .collect::<Vec<_>>();
+        overrides.sort();
+        overrides
+    }
+
+    /// This without the environment variables of an untrusted project
+    pub fn without_overrides(&self) -> Self {
+        Self {
+            environment_variables: HashMap::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// The settings which can be overridden per branch
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentConfig {
+    /// The flake reference to take Nix packages from
+    pub(crate) nixpkgs: Option<String>,
+}
+
+impl ProjectConfig {
+    /// Read the `riff.toml` in `project_dir`, if there is one
+    #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
+    pub async fn load(project_dir: &Path) -> color_eyre::Result<Self> {
+        let config_path = project_dir.join(PROJECT_CONFIG_FILE);
+        let contents = match tokio::fs::read_to_string(&config_path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Could not read `{}`", config_path.display()))
+            }
+        };
+        let mut config: Self = toml::from_str(&contents)
+            .wrap_err_with(|| format!("Could not parse `{}`", config_path.display()))?;
+        config
+            .validate()
+            .wrap_err_with(|| format!("Invalid configuration in `{}`", config_path.display()))?;
+
+        let mut included = Vec::with_capacity(config.include.len());
+        for name in &config.include {
+            included.push(layers::load(name).await.wrap_err_with(|| {
+                format!("Could not include a layer in `{}`", config_path.display())
+            })?);
+        }
+        for layer in included.into_iter().rev() {
+            config.include_layer(layer);
+        }
+        Ok(config)
+    }
+
+    /// Take the profiles and conflict strategies this configuration doesn't define from `layer`
+    ///
+    /// Layers are included last to first, so later layers win over earlier ones.
+    fn include_layer(&mut self, layer: Layer) {
+        for (name, profile) in layer.profile {
+            self.profile.entry(name).or_insert(profile);
+        }
+        for (variable, strategy) in layer.conflicts {
+            self.conflicts.entry(variable).or_insert(strategy);
+        }
+        for substituter in layer.substituters {
+            if !self.substituters.contains(&substituter) {
+                self.substituters.push(substituter);
+            }
+        }
+        for key in layer.trusted_public_keys {
+            if !self.trusted_public_keys.contains(&key) {
+                self.trusted_public_keys.push(key);
+            }
+        }
+    }
+
+    fn validate(&self) -> color_eyre::Result<()> {
+        for entry in &self.suppress {
+            if !WarningCode::ALL.iter().any(|code| code.matches(entry)) {
+                return Err(eyre!(
+                    "`suppress` lists `{entry}`, which is not a known warning"
+                ));
+            }
+        }
+        if let Some(substituter) = self.substituters.iter().find(|url| !url.contains("://")) {
+            return Err(eyre!(
+                "`substituters` lists `{substituter}`, which is not a URL such as `https://cache.example.com`"
+            ));
+        }
+        if let Some(key) = self
+            .trusted_public_keys
+            .iter()
+            .find(|key| !key.contains(':'))
+        {
+            return Err(eyre!(
+                "`trusted-public-keys` lists `{key}`, which should look like `<name>:<base64 key>`"
+            ));
+        }
+        for name in self.commands.keys() {
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(eyre!(
+                    "`commands` defines `{name}`, but command names can't be empty or contain spaces"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The binary caches configured here and in included layers
+    pub fn substituters(&self) -> Substituters {
+        Substituters {
+            substituters: self.substituters.clone(),
+            trusted_public_keys: self.trusted_public_keys.clone(),
+        }
+    }
+
+    /// The overlays to apply to Nixpkgs: those configured here, then `extra` (from `--overlay`)
+    pub fn overlays(&self, extra: &[String]) -> Vec<String> {
+        self.overlays.iter().chain(extra).cloned().collect()
+    }
+
+    /// The inputs and environment variables to filter out of the environment
+    pub fn exclusions(&self) -> Exclusions {
+        Exclusions {
+            exclude_build_inputs: self.exclude_build_inputs.clone(),
+            exclude_runtime_inputs: self.exclude_runtime_inputs.clone(),
+            unset_environment_variables: self.unset_environment_variables.clone(),
+        }
+    }
+
+    /// The profile named `requested`, or the `default` profile (if any) when none was requested
+    pub fn profile(&self, requested: Option<&str>) -> color_eyre::Result<Option<(&str, &Profile)>> {
+        let Some(requested) = requested else {
+            return Ok(self
+                .profile
+                .get_key_value("default")
+                .map(|(name, profile)| (name.as_str(), profile)));
+        };
+        match self.profile.get_key_value(requested) {
+            Some((name, profile)) => Ok(Some((name, profile))),
+            None if self.profile.is_empty() => Err(eyre!(
+                "There is no profile named `{requested}`, and `{PROJECT_CONFIG_FILE}` doesn't define any profiles"
+            )),
+            None => Err(eyre!(
+                "There is no profile named `{requested}` in `{PROJECT_CONFIG_FILE}`, available profiles are: {}",
+                self.profile.keys().map(|name| format!("`{name}`")).collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+
+    /// The settings for `project_dir`, with the overrides for its current git branch applied
+    pub async fn resolve(&self, project_dir: &Path) -> EnvironmentConfig {
+        let mut environment = EnvironmentConfig {
+            nixpkgs: self.nixpkgs.clone(),
+        };
+        if self.branch.is_empty() {
+            return environment;
+        }
+        let Some(branch) = current_git_branch(project_dir).await else {
+            return environment;
+        };
+        if let Some((pattern, overrides)) = self.branch_overrides(&branch) {
+            tracing::debug!(%branch, %pattern, "Applying branch overrides from `riff.toml`");
+            if overrides.nixpkgs.is_some() {
+                environment.nixpkgs = overrides.nixpkgs.clone();
+            }
+        }
+        environment
+    }
+
+    /// The overrides whose pattern matches `branch`, preferring an exact match and then the
+    /// longest (most specific) pattern
+    fn branch_overrides(&self, branch: &str) -> Option<(&str, &EnvironmentConfig)> {
+        if let Some((pattern, overrides)) = self.branch.get_key_value(branch) {
+            return Some((pattern, overrides));
+        }
+        self.branch
+            .iter()
+            .filter(|(pattern, _)| branch_matches(pattern, branch))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(pattern, overrides)| (pattern.as_str(), overrides))
+    }
+}
+
+/// Whether `branch` matches `pattern`, where `*` matches any run of characters (including `/`)
+pub(crate) fn branch_matches(pattern: &str, branch: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == branch,
+        Some((prefix, rest)) => {
+            let Some(branch) = branch.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=branch.len())
+                .filter(|&split| branch.is_char_boundary(split))
+                .any(|split| branch_matches(rest, &branch[split..]))
+        }
+    }
+}
+
+/// The name of the branch checked out in `project_dir`, or `None` outside a git repository or
+/// with a detached `HEAD`
+async fn current_git_branch(project_dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(project_dir)
+        .args(["symbolic-ref", "--quiet", "--short", "HEAD"])
+        .output()
+        .await
+        .map_err(|err| tracing::debug!(%err, "Could not run `git`"))
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let branch = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!branch.is_empty()).then_some(branch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documented_example_is_valid() -> eyre::Result<()> {
+        let example = include_str!("project_config.rs")
+            .lines()
+            .map_while(|line| line.strip_prefix("//!"))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .skip_while(|line| *line != "```toml")
+            .skip(1)
+            .take_while(|line| *line != "```")
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config: ProjectConfig = toml::from_str(&example)?;
+        config.validate().map_err(|err| eyre::eyre!("{err}"))?;
+        assert!(config.exclude_build_inputs.contains("glxinfo"));
+        Ok(())
+    }
+
+    #[test]
+    fn branch_patterns() {
+        assert!(branch_matches("main", "main"));
+        assert!(!branch_matches("main", "main2"));
+        assert!(branch_matches("release/*", "release/1.0"));
+        assert!(branch_matches("release/*", "release/1.x/hotfix"));
+        assert!(!branch_matches("release/*", "feature/release/1.0"));
+        assert!(branch_matches("*/wip", "me/wip"));
+        assert!(branch_matches("*", "anything"));
+    }
+
+    #[test]
+    fn most_specific_branch_overrides_win() -> eyre::Result<()> {
+        let config: ProjectConfig = toml::from_str(
+            r#"
+            nixpkgs = "github:NixOS/nixpkgs/nixos-unstable"
+
+            [branch."release/*"]
+            nixpkgs = "github:NixOS/nixpkgs/nixos-22.05"
+
+            [branch."release/2.*"]
+            nixpkgs = "github:NixOS/nixpkgs/nixos-22.11"
+
+            [branch."release/2.0"]
+            nixpkgs = "github:NixOS/nixpkgs/nixos-23.05"
+            "#,
+        )?;
+        let nixpkgs_for = |branch| {
+            config
+                .branch_overrides(branch)
+                .and_then(|(_, overrides)| overrides.nixpkgs.as_deref())
+        };
+
+        assert_eq!(nixpkgs_for("main"), None);
+        assert_eq!(
+            nixpkgs_for("release/1.0"),
+            Some("github:NixOS/nixpkgs/nixos-22.05")
+        );
+        assert_eq!(
+            nixpkgs_for("release/2.1"),
+            Some("github:NixOS/nixpkgs/nixos-22.11")
+        );
+        assert_eq!(
+            nixpkgs_for("release/2.0"),
+            Some("github:NixOS/nixpkgs/nixos-23.05")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_suppressed_warnings_are_rejected() -> eyre::Result<()> {
+        let config: ProjectConfig = toml::from_str(r#"suppress = ["W0001", "unknown-target"]"#)?;
+        assert!(config.validate().is_ok());
+        let config: ProjectConfig = toml::from_str(r#"suppress = ["W9999"]"#)?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn select_profile() -> eyre::Result<()> {
+        let config: ProjectConfig = toml::from_str(
+            r#"
+            [profile.default]
+            build-inputs = ["hello"]
+
+            [profile.gpu]
+            runtime-inputs = ["cudatoolkit"]
+            remove-environment-variables = ["CUDA_VISIBLE_DEVICES"]
+            "#,
+        )?;
+
+        assert_eq!(config.profile(None)?.map(|(name, _)| name), Some("default"));
+        let (name, gpu) = config.profile(Some("gpu"))?.expect("gpu profile exists");
+        assert_eq!(name, "gpu");
+        assert!(gpu.runtime_inputs.contains("cudatoolkit"));
+        assert!(config.profile(Some("ci")).is_err());
+        assert_eq!(ProjectConfig::default().profile(None)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn included_layers_fill_in_settings() -> eyre::Result<()> {
+        let mut config: ProjectConfig = toml::from_str(
+            r#"
+            include = ["base", "team"]
+
+            [profile.ci]
+            build-inputs = ["project"]
+            "#,
+        )?;
+        let base = Layer::parse(
+            r#"
+            version = 1
+
+            [profile.ci]
+            build-inputs = ["base"]
+
+            [profile.gpu]
+            build-inputs = ["base"]
+
+            [conflicts]
+            CC = "first"
+            "#,
+        )?;
+        let team = Layer::parse(
+            r#"
+            version = 1
+
+            substituters = ["https://cache.example.com"]
+            trusted-public-keys = ["cache.example.com-1:AAAA"]
+
+            [profile.gpu]
+            build-inputs = ["team"]
+            "#,
+        )?;
+        config.include_layer(team);
+        config.include_layer(base);
+
+        assert!(config.profile["ci"].build_inputs.contains("project"));
+        assert!(config.profile["gpu"].build_inputs.contains("team"));
+        assert_eq!(config.conflicts["CC"], ConflictStrategy::First);
+        assert_eq!(
+            config.substituters(),
+            Substituters {
+                substituters: vec!["https://cache.example.com".to_string()],
+                trusted_public_keys: vec!["cache.example.com-1:AAAA".to_string()],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn substituters_are_validated() -> eyre::Result<()> {
+        let config: ProjectConfig = toml::from_str(r#"substituters = ["cache.example.com"]"#)?;
+        assert!(config.validate().is_err());
+        let config: ProjectConfig = toml::from_str(r#"trusted-public-keys = ["AAAA"]"#)?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<ProjectConfig>("nixpkg = \"typo\"").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Running commands in a project's environment on another machine, over SSH.
+
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+
+use eyre::WrapErr;
+use itertools::Itertools;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use tokio::process::Command;
+
+use crate::nix_dev_env::shell_quote;
+
+/// A machine to run commands on, written as `ssh://[user@]host[:port][/working/directory]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteTarget {
+    /// The `[user@]host` given to `ssh`
+    destination: String,
+    port: Option<u16>,
+    /// Where to run the command, otherwise the remote user's home directory
+    working_dir: Option<String>,
+}
+
+impl FromStr for RemoteTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|err| format!("`{s}` is not a valid URL: {err}"))?;
+        if url.scheme() != "ssh" {
+            return Err(format!("`{s}` is not an `ssh://` URL"));
+        }
+        let host = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| format!("`{s}` does not name a host"))?;
+        // The URL keeps the user and path percent-encoded, as they were written
+        let decode = |component: &str| {
+            percent_decode_str(component)
+                .decode_utf8()
+                .map(|decoded| decoded.into_owned())
+                .map_err(|err| format!("`{s}` is not a valid URL: {err}"))
+        };
+        let destination = match decode(url.username())?.as_str() {
+            "" => host.to_string(),
+            user => format!("{user}@{host}"),
+        };
+        let working_dir = match decode(url.path())?.as_str() {
+            "" | "/" => None,
+            path => Some(path.to_string()),
+        };
+        Ok(Self {
+            destination,
+            port: url.port(),
+            working_dir,
+        })
+    }
+}
+
+impl RemoteTarget {
+    /// Copy the flake in `flake_dir` to the remote machine and run `command` in its development
+    /// shell there, streaming the output back.
+    #[tracing::instrument(skip_all, fields(destination = %self.destination))]
+    pub async fn run(
+        &self,
+        flake_dir: &Path,
+        command: &[String],
+    ) -> color_eyre::Result<ExitStatus> {
+        let mut archive_command = std::process::Command::new("tar");
+        archive_command
+            .arg("-C")
+            .arg(flake_dir)
+            .args(["-cf", "-", "."])
+            .stdout(Stdio::piped());
+        let mut ssh_command = Command::new("ssh");
+        if let Some(port) = self.port {
+            ssh_command.arg("-p").arg(port.to_string());
+        }
+        ssh_command.arg(&self.destination).arg(format!(
+            "sh -c {}",
+            shell_quote(&self.remote_script(command))
+        ));
+        // A dry run prints both ends of the pipe, and starts neither
+        let performed = [
+            crate::execution::Effect::run(&archive_command),
+            crate::execution::Effect::run(ssh_command.as_std()),
+        ]
+        .map(crate::execution::perform);
+        if !performed.iter().all(|performed| *performed) {
+            return Ok(crate::command_runner::exit_status(0));
+        }
+
+        let mut archive = archive_command
+            .spawn()
+            .wrap_err("Could not run `tar` to archive the generated flake")?;
+        let archive_stdout = archive
+            .stdout
+            .take()
+            .ok_or_else(|| eyre::eyre!("`tar` was started without a piped stdout"))?;
+        ssh_command.stdin(Stdio::from(archive_stdout));
+
+        tracing::trace!(command = ?ssh_command.as_std(), "Running");
+        let status = crate::nix_dev_env::spawn_and_wait(&mut ssh_command)
+            .await
+            .wrap_err_with(|| format!("Could not run `ssh {}`", self.destination))?;
+        // `ssh` has read the whole archive by now, so this doesn't block for long.
+        archive
+            .wait()
+            .wrap_err("Could not archive the generated flake")?;
+
+        Ok(status)
+    }
+
+    /// The script run on the remote machine, which unpacks the flake from stdin and runs `command`
+    fn remote_script(&self, command: &[String]) -> String {
+        let cd = match &self.working_dir {
+            Some(working_dir) => format!("cd {}\n", shell_quote(working_dir)),
+            None => "".to_string(),
+        };
+        format!(
+            "\
+            set -e\n\
+            flake_dir=$(mktemp -d)\n\
+            trap 'rm -rf \"$flake_dir\"' EXIT\n\
+            tar -C \"$flake_dir\" -xf -\n\
+            {cd}\
+            nix develop --extra-experimental-features 'flakes nix-command' \"path://$flake_dir\" --command {command}\n\
+            ",
+            command = command.iter().map(String::as_str).map(shell_quote).join(" "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemoteTarget;
+    use crate::execution::ExecutionContext;
+
+    #[test]
+    fn parse_remote_target() {
+        assert_eq!(
+            "ssh://builder".parse(),
+            Ok(RemoteTarget {
+                destination: "builder".to_string(),
+                port: None,
+                working_dir: None,
+            })
+        );
+        assert_eq!(
+            "ssh://me@builder:2222/home/me/project".parse(),
+            Ok(RemoteTarget {
+                destination: "me@builder".to_string(),
+                port: Some(2222),
+                working_dir: Some("/home/me/project".to_string()),
+            })
+        );
+        assert_eq!(
+            "ssh://first%20last@builder/srv/my%20project".parse(),
+            Ok(RemoteTarget {
+                destination: "first last@builder".to_string(),
+                port: None,
+                working_dir: Some("/srv/my project".to_string()),
+            })
+        );
+        assert!("https://builder".parse::<RemoteTarget>().is_err());
+        assert!("builder".parse::<RemoteTarget>().is_err());
+    }
+
+    #[test]
+    fn remote_script_runs_command_in_working_dir() {
+        let target: RemoteTarget = "ssh://builder/srv/project".parse().unwrap();
+        let script = target.remote_script(&["cargo".to_string(), "build --release".to_string()]);
+        assert!(script.contains("cd /srv/project\n"));
+        assert!(script.contains("--command cargo 'build --release'"));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_dont_archive_or_connect() -> eyre::Result<()> {
+        let target: RemoteTarget = "ssh://builder.invalid/srv/project".parse().unwrap();
+        let flake_dir = tempfile::TempDir::new()?;
+        let dry_run = ExecutionContext { dry_run: true };
+        let status = crate::execution::scope(
+            dry_run,
+            target.run(
+                flake_dir.path(),
+                &["cargo".to_string(), "build".to_string()],
+            ),
+        )
+        .await?;
+        // `ssh` would have failed to reach the host
+        assert!(status.success());
+        Ok(())
+    }
+}
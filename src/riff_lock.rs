@@ -0,0 +1,311 @@
+//! `riff.lock`, a snapshot of a project's fully resolved environment.
+//!
+//! When a project has a `riff.lock`, commands use the environment recorded in it instead of
+//! detecting one, so the environment doesn't change as riff's registry evolves. `riff lock`
+//! creates it and `riff lock --update` refreshes it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use target_lexicon::Triple;
+
+use crate::dependency_registry::rust::RustDependencyData;
+use crate::dev_env::DevEnvironment;
+use crate::project_config::DEFAULT_NIXPKGS;
+
+pub const RIFF_LOCK_FILE: &str = "riff.lock";
+const RIFF_LOCK_VERSION: usize = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RiffLock {
+    pub version: usize,
+    /// The version of riff which wrote the lock
+    pub riff_version: String,
+    /// The Nix system the environment was resolved for, eg `x86_64-linux`, since registry entries
+    /// can provide different inputs on each
+    #[serde(default)]
+    pub system: Option<String>,
+    /// The target the environment was resolved for with `--target`, if it isn't the machine's
+    /// own, since target-specific dependencies and registry settings differ between targets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// The `nixpkgs` flake reference, see `riff.toml`
+    pub nixpkgs: String,
+    /// The revision `nixpkgs` was locked to
+    pub nixpkgs_rev: Option<String>,
+    /// The hash of the `Cargo.lock` the environment was detected from, to spot stale locks
+    pub cargo_lock_sha256: Option<String>,
+    /// The resolved environment
+    pub environment: LockedEnvironment,
+    /// What each riff registry entry used contributed to the environment
+    pub registry: BTreeMap<String, LockedEnvironment>,
+    /// The contents of the generated `flake.lock`
+    pub flake_lock: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LockedEnvironment {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub build_inputs: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub runtime_inputs: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment_variables: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shell_hooks: Vec<String>,
+}
+
+impl LockedEnvironment {
+    pub fn from_dev_env(dev_env: &DevEnvironment) -> Self {
+        Self {
+            build_inputs: dev_env.build_inputs.iter().cloned().collect(),
+            runtime_inputs: dev_env.runtime_inputs.iter().cloned().collect(),
+            environment_variables: dev_env
+                .environment_variables
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            shell_hooks: dev_env.shell_hooks.clone(),
+        }
+    }
+
+    /// What a registry entry provides for `target`
+    pub fn from_dependency(dependency: &RustDependencyData, target: &Triple) -> Self {
+        Self {
+            build_inputs: dependency.build_inputs(target).into_iter().collect(),
+            runtime_inputs: dependency.runtime_inputs(target).into_iter().collect(),
+            environment_variables: dependency
+                .environment_variables(target)
+                .into_iter()
+                .collect(),
+            shell_hooks: dependency.shell_hooks(target),
+        }
+    }
+
+    /// The environment variables and shell hooks recorded, described like
+    /// [`RustDependencyData::overrides`], which could have come from anywhere and so only apply if
+    /// the project is trusted
+    pub fn overrides(&self) -> Vec<String> {
+        self.environment_variables
+            .iter()
+            .map(|(name, value)| format!("`{name}={value}`"))
+            .chain(
+                self.shell_hooks
+                    .iter()
+                    .map(|shell_hook| format!("a shell hook running `{}`", shell_hook.trim())),
+            )
+            .collect()
+    }
+
+    /// This without the environment variables and shell hooks of an untrusted project
+    pub fn without_overrides(&self) -> Self {
+        Self {
+            environment_variables: BTreeMap::new(),
+            shell_hooks: vec![],
+            ..self.clone()
+        }
+    }
+
+    pub fn apply_to(&self, dev_env: &mut DevEnvironment) {
+        dev_env.build_inputs = self.build_inputs.iter().cloned().collect();
+        dev_env.runtime_inputs = self.runtime_inputs.iter().cloned().collect();
+        dev_env.environment_variables = self
+            .environment_variables
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        for shell_hook in &self.shell_hooks {
+            dev_env.add_shell_hook(shell_hook);
+        }
+    }
+}
+
+impl RiffLock {
+    /// Snapshot `dev_env`, as detected in the project whose Cargo manifest is in `cargo_dir` (see
+    /// [`crate::flake_generator::cargo_dir`]) and locked in `flake_dir`
+    pub async fn new(
+        cargo_dir: &Path,
+        flake_dir: &Path,
+        dev_env: &DevEnvironment<'_>,
+    ) -> color_eyre::Result<Self> {
+        let flake_lock_path = flake_dir.join("flake.lock");
+        let flake_lock: serde_json::Value = serde_json::from_str(
+            &tokio::fs::read_to_string(&flake_lock_path)
+                .await
+                .wrap_err("Could not read the generated `flake.lock`")?,
+        )
+        .wrap_err("Could not parse the generated `flake.lock`")?;
+        let nixpkgs_rev = flake_lock
+            .pointer("/nodes/nixpkgs/locked/rev")
+            .and_then(|rev| rev.as_str())
+            .map(ToString::to_string);
+
+        Ok(Self {
+            version: RIFF_LOCK_VERSION,
+            riff_version: env!("CARGO_PKG_VERSION").to_string(),
+            system: Some(current_system()),
+            target: (dev_env.target != Triple::host()).then(|| dev_env.target.to_string()),
+            nixpkgs: dev_env
+                .nixpkgs
+                .clone()
+                .unwrap_or_else(|| DEFAULT_NIXPKGS.to_string()),
+            nixpkgs_rev,
+            cargo_lock_sha256: cargo_lock_sha256(cargo_dir).await?,
+            environment: LockedEnvironment::from_dev_env(dev_env),
+            registry: dev_env.registry_entries.clone(),
+            flake_lock,
+        })
+    }
+
+    /// Read the `riff.lock` in `project_dir`, if there is one
+    pub async fn load(project_dir: &Path) -> color_eyre::Result<Option<Self>> {
+        let lock_path = project_dir.join(RIFF_LOCK_FILE);
+        let contents = match tokio::fs::read_to_string(&lock_path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Could not read `{}`", lock_path.display()))
+            }
+        };
+        let lock: Self = serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("Could not parse `{}`", lock_path.display()))?;
+        if lock.version != RIFF_LOCK_VERSION {
+            return Err(eyre!(
+                "`{}` has version {}, but this riff only understands version {RIFF_LOCK_VERSION}. Run `riff lock --update` to recreate it.",
+                lock_path.display(),
+                lock.version,
+            ));
+        }
+        match &lock.system {
+            Some(system) if *system != current_system() => Err(eyre!(
+                "`{}` was made on `{system}`, not this `{}` machine. Run `riff lock --update` to recreate it here.",
+                lock_path.display(),
+                current_system(),
+            )),
+            _ => Ok(Some(lock)),
+        }
+    }
+
+    pub async fn write(&self, project_dir: &Path) -> color_eyre::Result<()> {
+        let lock_path = project_dir.join(RIFF_LOCK_FILE);
+        let mut contents = serde_json::to_string_pretty(self)?;
+        contents.push('\n');
+        crate::execution::write(&lock_path, contents).await
+    }
+
+    /// Whether the environment was resolved for `target`
+    pub fn is_for(&self, target: &Triple) -> bool {
+        let locked = self
+            .target
+            .clone()
+            .unwrap_or_else(|| Triple::host().to_string());
+        locked == target.to_string()
+    }
+
+    /// Whether the `Cargo.lock` of the project whose Cargo manifest is in `cargo_dir` changed
+    /// since the lock was made
+    pub async fn is_stale(&self, cargo_dir: &Path) -> color_eyre::Result<bool> {
+        Ok(cargo_lock_sha256(cargo_dir).await? != self.cargo_lock_sha256)
+    }
+}
+
+fn current_system() -> String {
+    crate::target_cfg::nix_system(&target_lexicon::HOST)
+}
+
+/// The SHA-256 of `contents`, in hex
+pub(crate) fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The SHA-256 of the `Cargo.lock` of the workspace whose member's manifest is in `cargo_dir`
+/// (see [`crate::cargo_metadata::workspace_root`]), if there is one
+pub(crate) async fn cargo_lock_sha256(cargo_dir: &Path) -> color_eyre::Result<Option<String>> {
+    workspace_lock_sha256(&crate::cargo_metadata::workspace_root(cargo_dir).await).await
+}
+
+/// The SHA-256 of the `Cargo.lock` in `workspace_root`, if there is one
+pub(crate) async fn workspace_lock_sha256(
+    workspace_root: &Path,
+) -> color_eyre::Result<Option<String>> {
+    let cargo_lock = workspace_root.join("Cargo.lock");
+    match tokio::fs::read(&cargo_lock).await {
+        Ok(contents) => Ok(Some(sha256_hex(&contents))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).wrap_err_with(|| format!("Could not read `{}`", cargo_lock.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependency_registry::DependencyRegistry;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn lock_round_trip() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry = DependencyRegistry::new(true, false).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.build_inputs.insert("openssl".to_string());
+        dev_env
+            .environment_variables
+            .insert("HELLO".to_string(), "WORLD".to_string());
+        dev_env.add_shell_hook("mkdir -p .cache");
+
+        let project_dir = TempDir::new()?;
+        tokio::fs::write(project_dir.path().join("Cargo.lock"), "version = 3\n").await?;
+        let flake_dir = TempDir::new()?;
+        tokio::fs::write(
+            flake_dir.path().join("flake.lock"),
+            r#"{"nodes":{"nixpkgs":{"locked":{"rev":"abc123"}}},"version":7}"#,
+        )
+        .await?;
+
+        let lock = RiffLock::new(project_dir.path(), flake_dir.path(), &dev_env).await?;
+        assert_eq!(lock.nixpkgs_rev.as_deref(), Some("abc123"));
+        lock.write(project_dir.path()).await?;
+        let loaded = RiffLock::load(project_dir.path()).await?;
+        assert_eq!(loaded.as_ref(), Some(&lock));
+        assert!(!lock.is_stale(project_dir.path()).await?);
+        assert!(lock.is_for(&Triple::host()));
+        let windows =
+            crate::target_cfg::parse_target("x86_64-pc-windows-msvc").map_err(eyre::Error::msg)?;
+        assert!(!lock.is_for(&windows));
+        dev_env.target = windows.clone();
+        let windows_lock = RiffLock::new(project_dir.path(), flake_dir.path(), &dev_env).await?;
+        assert!(windows_lock.is_for(&windows));
+        assert!(!windows_lock.is_for(&Triple::host()));
+
+        let mut replayed = DevEnvironment::new(&registry);
+        lock.environment.apply_to(&mut replayed);
+        assert_eq!(replayed.build_inputs, dev_env.build_inputs);
+        assert_eq!(
+            replayed.environment_variables,
+            dev_env.environment_variables
+        );
+        assert_eq!(replayed.shell_hooks, dev_env.shell_hooks);
+
+        tokio::fs::write(project_dir.path().join("Cargo.lock"), "version = 4\n").await?;
+        assert!(lock.is_stale(project_dir.path()).await?);
+
+        RiffLock {
+            system: Some("mips64-plan9".to_string()),
+            ..lock
+        }
+        .write(project_dir.path())
+        .await?;
+        assert!(RiffLock::load(project_dir.path()).await.is_err());
+        Ok(())
+    }
+}
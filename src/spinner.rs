@@ -1,20 +1,127 @@
-use std::time::Duration;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
+use tokio::task::JoinHandle;
 
 const LEADER: char = ' ';
 const DRUM: char = '🥁';
 const QUARTER_NOTE: char = '♩';
 const EIGHTH_NOTE: char = '♪';
 
-pub struct SimpleSpinner;
+/// How often `ProgressMode::PlainVerbose` reports that a step is still running
+const PLAIN_STATUS_INTERVAL: Duration = Duration::from_secs(5);
 
-impl SimpleSpinner {
-    pub fn new_with_message(msg: Option<&str>) -> color_eyre::Result<ProgressBar> {
-        let spinner = ProgressBar::new_spinner();
-        spinner.enable_steady_tick(Duration::from_millis(260));
-        spinner.set_style(
-            ProgressStyle::with_template("{msg}{spinner}")?.tick_strings(&[
+/// How progress of long running steps is reported to the user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// An animated spinner, redrawn in place
+    #[default]
+    Animated,
+    /// Periodic, timestamped status lines, suitable for screen readers and log files
+    PlainVerbose,
+    /// Nothing at all
+    Quiet,
+}
+
+/// The stages of preparing an environment, named in the progress so users can see which is slow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Finding out what the project depends on, eg with `cargo metadata`
+    Detecting,
+    /// Loading the registry of what dependencies need
+    ResolvingRegistry,
+    /// Locking the flake's inputs with `nix flake lock`
+    LockingFlake,
+    /// Evaluating and building the environment
+    EvaluatingEnvironment,
+    /// Looking packages up in Nixpkgs
+    QueryingNixpkgs,
+    /// Copying the environment to a binary cache
+    PushingCache,
+    /// Checking the environment works with `--verify`
+    Verifying,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Detecting => "Detecting",
+            Phase::ResolvingRegistry => "Resolving registry",
+            Phase::LockingFlake => "Locking flake",
+            Phase::EvaluatingEnvironment => "Evaluating environment",
+            Phase::QueryingNixpkgs => "Querying Nixpkgs",
+            Phase::PushingCache => "Pushing to cache",
+            Phase::Verifying => "Verifying",
+        }
+    }
+}
+
+/// Reports the progress of long running steps
+pub trait Progress: Send + Sync {
+    /// Start reporting a step of `phase`, until the returned `Spinner` is finished or dropped
+    fn start(&self, phase: Phase, message: &str) -> color_eyre::Result<Spinner>;
+}
+
+/// Spinners drawn in place, one line per step running at the time
+pub struct AnimatedProgress {
+    // Steps like `cargo metadata` and `nix flake lock` can run at the same time, so every spinner
+    // is drawn through one `MultiProgress` to keep them from clobbering each other.
+    multi_progress: MultiProgress,
+}
+
+/// Timestamped status lines as steps start, run on, and finish
+pub struct PlainProgress;
+
+/// No progress at all, for `--quiet` and tests
+pub struct QuietProgress;
+
+static PROGRESS: OnceLock<Box<dyn Progress>> = OnceLock::new();
+
+// Shared with `AnimatedProgress` so `suspend` can hide its spinners
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Set how every step started afterwards reports its progress
+pub fn set_progress_mode(mode: ProgressMode) {
+    let progress: Box<dyn Progress> = match mode {
+        ProgressMode::Animated => Box::new(AnimatedProgress {
+            multi_progress: multi_progress().clone(),
+        }),
+        ProgressMode::PlainVerbose => Box::new(PlainProgress),
+        ProgressMode::Quiet => Box::new(QuietProgress),
+    };
+    if PROGRESS.set(progress).is_err() {
+        tracing::debug!("Progress mode was already set");
+    }
+}
+
+/// Start reporting a step of `phase`, see [`Progress::start`]
+pub fn start(phase: Phase, message: &str) -> color_eyre::Result<Spinner> {
+    match PROGRESS.get() {
+        Some(progress) => progress.start(phase, message),
+        // Before the mode is set, as in tests
+        None => QuietProgress.start(phase, message),
+    }
+}
+
+fn multi_progress() -> &'static MultiProgress {
+    MULTI_PROGRESS.get_or_init(MultiProgress::new)
+}
+
+/// Hide any active spinners while `f` runs, so it can write to the terminal without interleaving
+pub fn suspend<F: FnOnce() -> R, R>(f: F) -> R {
+    multi_progress().suspend(f)
+}
+
+impl Progress for AnimatedProgress {
+    fn start(&self, phase: Phase, message: &str) -> color_eyre::Result<Spinner> {
+        // Clear on drop as well, so a cancelled step doesn't leave a stale frame behind.
+        let bar = self
+            .multi_progress
+            .add(ProgressBar::new_spinner().with_finish(ProgressFinish::AndClear));
+        bar.enable_steady_tick(Duration::from_millis(260));
+        bar.set_style(
+            ProgressStyle::with_template("{prefix:.bold} {msg}{spinner}")?.tick_strings(&[
                 // "Play" the quarter note for a whole 115bpm beat
                 &([LEADER, DRUM, QUARTER_NOTE].into_iter().collect::<String>()),
                 &([LEADER, DRUM, QUARTER_NOTE].into_iter().collect::<String>()),
@@ -31,11 +138,179 @@ impl SimpleSpinner {
                     .collect::<String>()),
             ]),
         );
+        bar.set_prefix(format!("{}:", phase.name()));
+        bar.set_message(message.to_owned());
+
+        Ok(Spinner::Animated {
+            bar,
+            message: message.to_owned(),
+        })
+    }
+}
+
+impl Progress for PlainProgress {
+    fn start(&self, phase: Phase, message: &str) -> color_eyre::Result<Spinner> {
+        let message = strip_ansi(message);
+        let started = Instant::now();
+        plain_status(&format!("{}: {message}", phase.name()));
+
+        let detail = Arc::new(Mutex::new(None::<String>));
+        let ticker_message = lowercase_first(&message);
+        let ticker_detail = detail.clone();
+        let ticker = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PLAIN_STATUS_INTERVAL);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let detail = match &*ticker_detail.lock().unwrap() {
+                    Some(detail) => format!(" ({detail})"),
+                    None => String::new(),
+                };
+                plain_status(&format!(
+                    "Still {ticker_message}{detail}... {}s elapsed",
+                    started.elapsed().as_secs()
+                ));
+            }
+        });
 
-        if let Some(msg) = msg {
-            spinner.set_message(msg.to_owned());
+        Ok(Spinner::Plain {
+            message,
+            detail,
+            started,
+            ticker,
+        })
+    }
+}
+
+impl Progress for QuietProgress {
+    fn start(&self, _phase: Phase, _message: &str) -> color_eyre::Result<Spinner> {
+        Ok(Spinner::Quiet)
+    }
+}
+
+/// A progress indicator for a single long running step, created by a [`Progress`]
+pub enum Spinner {
+    Animated {
+        bar: ProgressBar,
+        message: String,
+    },
+    Plain {
+        message: String,
+        /// What the step is doing now, shown in the periodic status lines
+        detail: Arc<Mutex<Option<String>>>,
+        started: Instant,
+        ticker: JoinHandle<()>,
+    },
+    Quiet,
+}
+
+impl Spinner {
+    /// Show what the step is doing now, such as how much it has downloaded, after its message
+    pub fn set_detail(&self, new_detail: Option<&str>) {
+        match self {
+            Spinner::Animated { bar, message } => match new_detail {
+                Some(new_detail) => bar.set_message(format!("{message}: {new_detail}")),
+                None => bar.set_message(message.clone()),
+            },
+            Spinner::Plain { detail, .. } => {
+                *detail.lock().unwrap() = new_detail.map(strip_ansi);
+            }
+            Spinner::Quiet => {}
         }
+    }
+
+    pub fn finish_and_clear(&self) {
+        match self {
+            Spinner::Animated { bar, .. } => bar.finish_and_clear(),
+            Spinner::Plain {
+                message,
+                started,
+                ticker,
+                ..
+            } => {
+                ticker.abort();
+                plain_status(&format!(
+                    "Finished {} after {}s",
+                    lowercase_first(message),
+                    started.elapsed().as_secs()
+                ));
+            }
+            Spinner::Quiet => {}
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        if let Spinner::Plain { ticker, .. } = self {
+            ticker.abort();
+        }
+    }
+}
+
+fn plain_status(msg: &str) {
+    let seconds_today = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() % (24 * 60 * 60))
+        .unwrap_or(0);
+    eprintln!(
+        "[{:02}:{:02}:{:02}Z] {msg}",
+        seconds_today / (60 * 60),
+        seconds_today % (60 * 60) / 60,
+        seconds_today % 60,
+    );
+}
+
+fn lowercase_first(msg: &str) -> String {
+    let mut chars = msg.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Remove the terminal color codes `owo_colors` adds, since plain output often ends up in log files
+fn strip_ansi(msg: &str) -> String {
+    let mut stripped = String::with_capacity(msg.len());
+    let mut chars = msg.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the rest of the escape sequence, eg `[36m`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use owo_colors::OwoColorize;
+
+    use super::*;
+
+    #[test]
+    fn plain_messages_are_uncolored() {
+        let msg = format!("Running `{}`", "nix flake lock".cyan());
+        assert_eq!(strip_ansi(&msg), "Running `nix flake lock`");
+        assert_eq!(
+            lowercase_first(&strip_ansi(&msg)),
+            "running `nix flake lock`"
+        );
+    }
 
-        Ok(spinner)
+    #[test]
+    fn steps_report_nothing_before_the_mode_is_set() -> eyre::Result<()> {
+        let spinner = start(Phase::LockingFlake, "Running `nix flake lock`")?;
+        assert!(matches!(spinner, Spinner::Quiet));
+        spinner.set_detail(Some("downloading 1.0 of 4.0 MiB"));
+        spinner.finish_and_clear();
+        Ok(())
     }
 }
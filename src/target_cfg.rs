@@ -0,0 +1,289 @@
+//! Which of a crate's target-specific dependencies (`[target.'cfg(...)'.dependencies]`) apply.
+//!
+//! `cargo metadata` reports the target each dependency is declared for as written: a `cfg(...)`
+//! expression or a target triple. These are evaluated for the host, or the `--target` given
+//! (see [`crate::flake_generator::GenerateOptions::target`]), except for build dependencies,
+//! which are always built for the host. It also names targets the way Nix names systems.
+
+use std::str::FromStr;
+
+use target_lexicon::{Architecture, Environment, OperatingSystem, Triple};
+
+/// Parse a `--target` triple
+pub fn parse_target(triple: &str) -> Result<Triple, String> {
+    Triple::from_str(triple).map_err(|err| format!("Unknown target `{triple}`: {err}"))
+}
+
+/// Whether a dependency declared for `spec` (eg `cfg(windows)` or `x86_64-pc-windows-msvc`)
+/// applies to `target`. `cfg` expressions riff can't read are taken to apply, to leave nothing
+/// out, as are those which depend on what riff doesn't know about the target (eg
+/// `target_feature`, or `debug_assertions`).
+pub fn applies(spec: &str, target: &Triple) -> bool {
+    let spec = spec.trim();
+    let Some(expression) = spec.strip_prefix("cfg(") else {
+        return match Triple::from_str(spec) {
+            Ok(triple) => triple == *target,
+            Err(_) => spec == target.to_string(),
+        };
+    };
+    let mut parser = Parser {
+        rest: expression.strip_suffix(')').unwrap_or_default(),
+        target,
+    };
+    match parser.predicate() {
+        Some(Some(applies)) if parser.rest.trim().is_empty() => applies,
+        Some(None) if parser.rest.trim().is_empty() => {
+            tracing::debug!(%spec, "The dependency's target depends on what riff doesn't know, assuming it applies");
+            true
+        }
+        _ => {
+            tracing::debug!(%spec, "Could not read the dependency's target, assuming it applies");
+            true
+        }
+    }
+}
+
+/// Evaluates a `cfg(...)` predicate as it is read.
+///
+/// A predicate is `None` if it can't be read, and `Some(None)` if riff doesn't know whether it
+/// holds for the target, in which case neither does `not` of it, nor `all` or `any` of it unless
+/// the other predicates decide them.
+struct Parser<'a> {
+    rest: &'a str,
+    target: &'a Triple,
+}
+
+impl<'a> Parser<'a> {
+    /// `name`, `name = "value"`, `all(...)`, `any(...)`, or `not(...)`
+    fn predicate(&mut self) -> Option<Option<bool>> {
+        let name = self.identifier()?;
+        if self.eat('(') {
+            let mut values = vec![];
+            while !self.eat(')') {
+                values.push(self.predicate()?);
+                if !self.eat(',') && !self.peek(')') {
+                    return None;
+                }
+            }
+            return match name {
+                "all" if values.contains(&Some(false)) => Some(Some(false)),
+                "all" => Some(
+                    values
+                        .iter()
+                        .all(|value| *value == Some(true))
+                        .then_some(true),
+                ),
+                "any" if values.contains(&Some(true)) => Some(Some(true)),
+                "any" => Some(
+                    values
+                        .iter()
+                        .all(|value| *value == Some(false))
+                        .then_some(false),
+                ),
+                "not" if values.len() == 1 => Some(values[0].map(|value| !value)),
+                _ => None,
+            };
+        }
+        if self.eat('=') {
+            let value = self.string()?;
+            return Some(key_values(name, self.target).map(|values| values.contains(&value)));
+        }
+        // Besides the target families, names like `debug_assertions` or `test` depend on how the
+        // crate is built
+        if !FAMILIES.contains(&name) {
+            return Some(None);
+        }
+        Some(
+            key_values("target_family", self.target)
+                .map(|families| families.iter().any(|family| family == name)),
+        )
+    }
+
+    fn identifier(&mut self) -> Option<&'a str> {
+        self.rest = self.rest.trim_start();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest.len());
+        let (identifier, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        (!identifier.is_empty()).then_some(identifier)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.rest = self.rest.trim_start().strip_prefix('"')?;
+        let end = self.rest.find('"')?;
+        let value = self.rest[..end].to_string();
+        self.rest = &self.rest[end + 1..];
+        Some(value)
+    }
+
+    fn peek(&mut self, c: char) -> bool {
+        self.rest = self.rest.trim_start();
+        self.rest.starts_with(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek(c) {
+            self.rest = &self.rest[c.len_utf8()..];
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The Nix name of the system `target` runs on, eg `x86_64-linux` or `aarch64-darwin`
+pub fn nix_system(target: &Triple) -> String {
+    let arch = match target.architecture {
+        Architecture::Arm(_) if target.architecture.to_string().starts_with("armv7") => {
+            "armv7l".to_string()
+        }
+        Architecture::Arm(_) if target.architecture.to_string().starts_with("armv6") => {
+            "armv6l".to_string()
+        }
+        Architecture::X86_32(_) => "i686".to_string(),
+        Architecture::Riscv64(_) => "riscv64".to_string(),
+        arch => arch.to_string(),
+    };
+    let os = match target.operating_system {
+        OperatingSystem::Darwin | OperatingSystem::MacOSX { .. } => "darwin".to_string(),
+        os => os.to_string(),
+    };
+    format!("{arch}-{os}")
+}
+
+/// The target families, which `cfg` also takes on their own (eg `cfg(unix)`)
+const FAMILIES: &[&str] = &["unix", "windows", "wasm"];
+
+/// The values of the `cfg` key `key` for `target`, as `rustc --print cfg` would give them, if
+/// riff knows them
+fn key_values(key: &str, target: &Triple) -> Option<Vec<String>> {
+    let value = match key {
+        "target_os" => match target.operating_system {
+            OperatingSystem::Darwin | OperatingSystem::MacOSX { .. } => "macos".to_string(),
+            os => os.to_string(),
+        },
+        "target_family" => {
+            return Some(match target.operating_system {
+                OperatingSystem::Windows => vec!["windows".to_string()],
+                _ if matches!(
+                    target.architecture,
+                    Architecture::Wasm32 | Architecture::Wasm64
+                ) =>
+                {
+                    vec!["wasm".to_string()]
+                }
+                OperatingSystem::Unknown | OperatingSystem::None_ => vec![],
+                _ => vec!["unix".to_string()],
+            })
+        }
+        "target_arch" => {
+            let arch = target.architecture.to_string();
+            match arch.as_str() {
+                "i386" | "i586" | "i686" => "x86".to_string(),
+                _ if arch.starts_with("arm") || arch.starts_with("thumb") => "arm".to_string(),
+                _ if arch.starts_with("riscv64") => "riscv64".to_string(),
+                _ if arch.starts_with("riscv32") => "riscv32".to_string(),
+                _ => arch,
+            }
+        }
+        "target_env" => match target.environment {
+            Environment::Msvc => "msvc".to_string(),
+            env if env.to_string().starts_with("gnu") => "gnu".to_string(),
+            env if env.to_string().starts_with("musl") => "musl".to_string(),
+            _ => String::new(),
+        },
+        "target_vendor" => target.vendor.to_string(),
+        "target_pointer_width" => match target.pointer_width() {
+            Ok(width) => width.bits().to_string(),
+            Err(_) => return None,
+        },
+        "target_endian" => match target.endianness() {
+            Ok(target_lexicon::Endianness::Little) => "little".to_string(),
+            Ok(target_lexicon::Endianness::Big) => "big".to_string(),
+            Err(_) => return None,
+        },
+        // eg `target_feature`, `target_has_atomic`, `panic`, or `feature`
+        _ => return None,
+    };
+    Some(vec![value])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nix_systems() -> eyre::Result<()> {
+        for (triple, system) in [
+            ("x86_64-unknown-linux-gnu", "x86_64-linux"),
+            ("aarch64-apple-darwin", "aarch64-darwin"),
+            ("armv7-unknown-linux-gnueabihf", "armv7l-linux"),
+            ("i686-unknown-linux-gnu", "i686-linux"),
+            ("riscv64gc-unknown-linux-gnu", "riscv64-linux"),
+        ] {
+            let target = parse_target(triple).map_err(eyre::Error::msg)?;
+            assert_eq!(nix_system(&target), system, "{triple}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_expressions() -> eyre::Result<()> {
+        let linux = parse_target("x86_64-unknown-linux-gnu").map_err(eyre::Error::msg)?;
+        let windows = parse_target("x86_64-pc-windows-msvc").map_err(eyre::Error::msg)?;
+        let mac = parse_target("aarch64-apple-darwin").map_err(eyre::Error::msg)?;
+
+        assert!(applies("cfg(unix)", &linux));
+        assert!(!applies("cfg(unix)", &windows));
+        assert!(applies("cfg(windows)", &windows));
+        assert!(applies(r#"cfg(target_os = "macos")"#, &mac));
+        assert!(!applies(r#"cfg(target_os = "macos")"#, &linux));
+        assert!(applies(
+            r#"cfg(all(target_arch = "x86_64", target_env = "gnu"))"#,
+            &linux
+        ));
+        assert!(applies(
+            r#"cfg(any(target_os = "ios", target_os = "macos"))"#,
+            &mac
+        ));
+        assert!(applies(r#"cfg(not(target_os = "windows"))"#, &mac));
+        assert!(applies(r#"cfg(target_pointer_width = "64")"#, &mac));
+        assert!(!applies(
+            "cfg(all(unix, not(target_vendor = \"apple\")))",
+            &mac
+        ));
+
+        assert!(applies("x86_64-pc-windows-msvc", &windows));
+        assert!(!applies("x86_64-pc-windows-msvc", &linux));
+        // Whatever riff can't read is kept
+        assert!(applies("cfg(all(unix", &linux));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_cfg_expressions_apply() -> eyre::Result<()> {
+        let linux = parse_target("x86_64-unknown-linux-gnu").map_err(eyre::Error::msg)?;
+
+        assert!(applies(r#"cfg(target_feature = "sse2")"#, &linux));
+        assert!(applies(r#"cfg(not(target_feature = "sse2"))"#, &linux));
+        assert!(applies(r#"cfg(target_has_atomic = "64")"#, &linux));
+        assert!(applies(r#"cfg(panic = "abort")"#, &linux));
+        assert!(applies(r#"cfg(feature = "tls")"#, &linux));
+        assert!(applies("cfg(debug_assertions)", &linux));
+        assert!(applies("cfg(not(test))", &linux));
+        assert!(applies(
+            r#"cfg(any(windows, target_feature = "crt-static"))"#,
+            &linux
+        ));
+        // What riff knows still decides when it can
+        assert!(!applies(
+            r#"cfg(all(windows, target_feature = "crt-static"))"#,
+            &linux
+        ));
+        assert!(applies(r#"cfg(any(unix, debug_assertions))"#, &linux));
+        assert!(!applies("cfg(not(any(unix, test)))", &linux));
+        Ok(())
+    }
+}
@@ -1,20 +1,26 @@
-use std::{collections::HashSet, path::Path, time::Duration};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use clap::Parser;
 use eyre::eyre;
-use reqwest::Response;
 use secrecy::Secret;
 use serde::Serialize;
 use tokio::{
     fs::OpenOptions,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    process::Command,
+    task::JoinHandle,
 };
 use uuid::Uuid;
 
-use crate::{cmds::Commands, dev_env::DetectedLanguage, Cli, RIFF_XDG_PREFIX};
+use crate::{cmds::Commands, dev_env::DetectedLanguage, file_lock::FileLock, Cli, RIFF_XDG_PREFIX};
 
 static TELEMETRY_DISTINCT_ID_PATH: &str = "distinct_id";
+/// Held while reading or creating the distinct ID, so concurrent first runs agree on one
+static TELEMETRY_DISTINCT_ID_LOCK_PATH: &str = "distinct_id.lock";
 static TELEMETRY_IDENTIFIER_DESCRIPTION: &str =  "This is a randomly generated version 4 UUID.
 Determinate Systems uses this ID to know how many people use the tool and to focus our limited research and development.
 This ID is completely random and contains no personally identifiable information about you.
@@ -22,6 +28,27 @@ You can delete this file at any time to create a new ID.
 You can also disable ID generation; see the documentation on telemetry to see how to do so.";
 static TELEMETRY_REMOTE_URL: &str = "https://registry.riff.determinate.systems/telemetry";
 pub static TELEMETRY_HEADER_NAME: &str = "X-RIFF-Client-Info";
+/// Present in `$XDG_CONFIG_HOME/riff` when the user turned telemetry off with `riff setup`
+static TELEMETRY_DISABLED_PATH: &str = "telemetry-disabled";
+/// Events waiting to be sent, one JSON object per line, in `$XDG_DATA_HOME/riff`
+static TELEMETRY_QUEUE_PATH: &str = "telemetry-queue.jsonl";
+/// Held while changing the queue, so concurrent riff invocations don't lose each other's events
+static TELEMETRY_QUEUE_LOCK_PATH: &str = "telemetry-queue.lock";
+/// Queued events beyond this many are dropped, oldest first, so an offline machine doesn't
+/// accumulate them forever
+const TELEMETRY_QUEUE_CAPACITY: usize = 100;
+/// The most events sent by one flush
+const TELEMETRY_BATCH_SIZE: usize = 20;
+/// How many times to try sending an event before leaving the rest of the queue for later
+const TELEMETRY_SEND_ATTEMPTS: u32 = 3;
+/// How long a flush may take in total, including backoff between attempts
+const TELEMETRY_FLUSH_BUDGET: Duration = Duration::from_secs(1);
+/// Claimed queue files older than this belong to a flush which never finished (eg because riff
+/// was killed), and are claimed again
+const TELEMETRY_CLAIM_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// The background flush started by [`Telemetry::enqueue`], awaited by [`finish`]
+static FLUSH_HANDLE: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
 
 #[derive(Default, Debug, Clone, Copy, Serialize)]
 struct DistinctId(Uuid);
@@ -84,6 +111,25 @@ impl Telemetry {
             Some(Commands::Shell(_)) => Some("shell".to_string()),
             Some(Commands::Run(_)) => Some("run".to_string()),
             Some(Commands::PrintDevEnv(_)) => Some("print-dev-env".to_string()),
+            Some(Commands::Eject(_)) => Some("eject".to_string()),
+            Some(Commands::Generate(_)) => Some("generate".to_string()),
+            Some(Commands::Doctor(_)) => Some("doctor".to_string()),
+            Some(Commands::Lock(_)) => Some("lock".to_string()),
+            Some(Commands::Prepare(_)) => Some("prepare".to_string()),
+            Some(Commands::Layers(_)) => Some("layers".to_string()),
+            Some(Commands::Ci(_)) => Some("ci".to_string()),
+            Some(Commands::Setup(_)) => Some("setup".to_string()),
+            Some(Commands::Env(_)) => Some("env".to_string()),
+            Some(Commands::Registry(_)) => Some("registry".to_string()),
+            Some(Commands::PushCache(_)) => Some("push-cache".to_string()),
+            Some(Commands::History(_)) => Some("history".to_string()),
+            Some(Commands::Clean(_)) => Some("clean".to_string()),
+            Some(Commands::Hook(_)) => Some("hook".to_string()),
+            Some(Commands::Trust(_)) => Some("trust".to_string()),
+            Some(Commands::Untrust(_)) => Some("untrust".to_string()),
+            Some(Commands::Shim(_)) => Some("shim".to_string()),
+            Some(Commands::Which(_)) => Some("which".to_string()),
+            Some(Commands::SelfTest(_)) => Some("self-test".to_string()),
             None => None,
         };
 
@@ -116,18 +162,35 @@ impl Telemetry {
         self
     }
 
+    /// Queue this event to be sent, and start sending queued events in the background
+    ///
+    /// Nothing here waits on the network: events which can't be sent now stay queued for a later
+    /// invocation.
     #[tracing::instrument(skip_all)]
-    pub(crate) async fn send(&self) -> eyre::Result<Response> {
-        tracing::trace!(data = ?self, "Sending telemetry data to {TELEMETRY_REMOTE_URL}");
+    pub(crate) async fn enqueue(&self) -> eyre::Result<()> {
+        if !crate::execution::perform(crate::execution::Effect::Fetch(
+            TELEMETRY_REMOTE_URL.to_string(),
+        )) {
+            return Ok(());
+        }
         let header_data = self.as_header_data()?;
-        let http_client = reqwest::Client::new();
-        let req = http_client
-            .post(TELEMETRY_REMOTE_URL)
-            .header(TELEMETRY_HEADER_NAME, &header_data)
-            .timeout(Duration::from_millis(250));
-        let res = req.send().await?;
-        tracing::debug!(telemetry = ?self, "Sent telemetry data to {TELEMETRY_REMOTE_URL}");
-        Ok(res)
+        let queue_dir = queue_dir()?;
+        append_to_queue(&queue_dir, &header_data, TELEMETRY_QUEUE_CAPACITY).await?;
+        tracing::debug!(telemetry = ?self, "Queued telemetry data for {TELEMETRY_REMOTE_URL}");
+
+        let mut flush_handle = FLUSH_HANDLE.lock().expect("Telemetry flush lock poisoned");
+        if flush_handle
+            .as_ref()
+            .map(|handle| handle.is_finished())
+            .unwrap_or(true)
+        {
+            *flush_handle = Some(tokio::spawn(async move {
+                if let Err(err) = flush(&queue_dir, Instant::now() + TELEMETRY_FLUSH_BUDGET).await {
+                    tracing::debug!(%err, "Could not flush telemetry queue");
+                }
+            }));
+        }
+        Ok(())
     }
 
     pub(crate) fn as_header_data(&self) -> Result<String, serde_json::Error> {
@@ -135,9 +198,176 @@ impl Telemetry {
     }
 }
 
+/// Wait for a background flush started during this invocation, which takes at most
+/// `TELEMETRY_FLUSH_BUDGET`, so queued events aren't lost when riff exits
+pub(crate) async fn finish() {
+    let flush_handle = FLUSH_HANDLE
+        .lock()
+        .expect("Telemetry flush lock poisoned")
+        .take();
+    if let Some(flush_handle) = flush_handle {
+        flush_handle.await.ok();
+    }
+}
+
+/// Whether the user turned telemetry off with `riff setup`
+pub(crate) fn disabled_by_preference() -> bool {
+    xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)
+        .ok()
+        .and_then(|xdg_dirs| xdg_dirs.find_config_file(TELEMETRY_DISABLED_PATH))
+        .is_some()
+}
+
+/// Remember whether the user wants telemetry sent, for every later invocation
+pub(crate) async fn set_preference(enabled: bool) -> eyre::Result<()> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    let path = xdg_dirs.place_config_file(TELEMETRY_DISABLED_PATH)?;
+    if enabled {
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    } else {
+        tokio::fs::write(
+            &path,
+            "Telemetry is disabled while this file exists, see `riff setup`.\n",
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+fn queue_dir() -> eyre::Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.create_data_directory("")?)
+}
+
+/// Append `event` to the queue in `queue_dir`, dropping the oldest events beyond `capacity`
+async fn append_to_queue(queue_dir: &Path, event: &str, capacity: usize) -> eyre::Result<()> {
+    let _lock = FileLock::exclusive(&queue_dir.join(TELEMETRY_QUEUE_LOCK_PATH)).await?;
+    let queue_path = queue_dir.join(TELEMETRY_QUEUE_PATH);
+    let mut queue_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&queue_path)
+        .await?;
+    queue_file
+        .write_all(format!("{event}\n").as_bytes())
+        .await?;
+    drop(queue_file);
+
+    let queued = tokio::fs::read_to_string(&queue_path).await?;
+    let events = queued.lines().collect::<Vec<_>>();
+    if events.len() > capacity {
+        tracing::debug!(
+            dropped = events.len() - capacity,
+            "Dropping oldest queued telemetry events"
+        );
+        let kept = events[events.len() - capacity..].join("\n") + "\n";
+        tokio::fs::write(&queue_path, kept).await?;
+    }
+    Ok(())
+}
+
+/// Take ownership of the queued events, so concurrent riff invocations don't send them twice
+///
+/// The queue is renamed to a claim file named for when it was claimed, along with any claim
+/// files abandoned by flushes which never finished.
+async fn claim_queue(queue_dir: &Path) -> eyre::Result<Vec<String>> {
+    let _lock = FileLock::exclusive(&queue_dir.join(TELEMETRY_QUEUE_LOCK_PATH)).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let mut events = vec![];
+
+    let mut entries = tokio::fs::read_dir(queue_dir).await?;
+    let mut claims = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let claimed_at = file_name
+            .strip_prefix(TELEMETRY_QUEUE_PATH)
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|suffix| suffix.split('.').next())
+            .and_then(|secs| secs.parse::<u64>().ok());
+        if let Some(claimed_at) = claimed_at {
+            if now.saturating_sub(Duration::from_secs(claimed_at)) > TELEMETRY_CLAIM_EXPIRY {
+                claims.push(entry.path());
+            }
+        }
+    }
+
+    let claim_path = queue_dir.join(format!(
+        "{TELEMETRY_QUEUE_PATH}.{}.{}",
+        now.as_secs(),
+        std::process::id()
+    ));
+    match tokio::fs::rename(queue_dir.join(TELEMETRY_QUEUE_PATH), &claim_path).await {
+        Ok(()) => claims.push(claim_path),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    for claim in claims {
+        // Another invocation may have reclaimed an abandoned claim first
+        match tokio::fs::read_to_string(&claim).await {
+            Ok(claimed) => {
+                tokio::fs::remove_file(&claim).await?;
+                events.extend(claimed.lines().map(ToString::to_string));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(events)
+}
+
+/// Send up to `TELEMETRY_BATCH_SIZE` queued events before `deadline`, retrying with backoff, and
+/// return the rest to the queue
+#[tracing::instrument(skip_all)]
+async fn flush(queue_dir: &Path, deadline: Instant) -> eyre::Result<()> {
+    let events = claim_queue(queue_dir).await?;
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let http_client = crate::http::client()?;
+    let mut sent = 0;
+    'events: for event in events.iter().take(TELEMETRY_BATCH_SIZE) {
+        let mut backoff = Duration::from_millis(100);
+        for attempt in 1..=TELEMETRY_SEND_ATTEMPTS {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break 'events;
+            }
+            let req = http_client
+                .post(TELEMETRY_REMOTE_URL)
+                .header(TELEMETRY_HEADER_NAME, event)
+                .timeout(remaining.min(crate::http::options().network_timeout()));
+            match req.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => {
+                    tracing::debug!(telemetry = %event, "Sent telemetry data to {TELEMETRY_REMOTE_URL}");
+                    sent += 1;
+                    continue 'events;
+                }
+                Err(err) => {
+                    tracing::debug!(%err, attempt, "Could not send telemetry data to {TELEMETRY_REMOTE_URL}");
+                    tokio::time::sleep(backoff.min(remaining)).await;
+                    backoff *= 2;
+                }
+            }
+        }
+        break;
+    }
+
+    for event in &events[sent..] {
+        append_to_queue(queue_dir, event, TELEMETRY_QUEUE_CAPACITY).await?;
+    }
+    Ok(())
+}
+
 async fn distinct_id() -> eyre::Result<Uuid> {
     let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
     let distinct_id_path = xdg_dirs.place_config_file(Path::new(TELEMETRY_DISTINCT_ID_PATH))?;
+    let _lock =
+        FileLock::exclusive(&xdg_dirs.place_config_file(TELEMETRY_DISTINCT_ID_LOCK_PATH)?).await?;
 
     let mut distinct_id_file = OpenOptions::new()
         .read(true)
@@ -172,9 +402,7 @@ async fn distinct_id() -> eyre::Result<Uuid> {
 }
 
 async fn nix_version() -> eyre::Result<Option<String>> {
-    let mut command = Command::new("nix");
-    command.arg("--version");
-    let output = command.output().await;
+    let output = crate::nix_config::command(["--version"]).output().await;
     match output {
         Ok(output) => {
             if output.status.success() {
@@ -192,3 +420,50 @@ async fn nix_version() -> eyre::Result<Option<String>> {
         Err(err) => Err(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queue_is_bounded_and_claimed_once() -> eyre::Result<()> {
+        let queue_dir = TempDir::new()?;
+        for event in ["1", "2", "3", "4"] {
+            append_to_queue(queue_dir.path(), event, 3).await?;
+        }
+
+        assert_eq!(claim_queue(queue_dir.path()).await?, vec!["2", "3", "4"]);
+        assert!(claim_queue(queue_dir.path()).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn abandoned_claims_are_reclaimed() -> eyre::Result<()> {
+        let queue_dir = TempDir::new()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let abandoned = now - TELEMETRY_CLAIM_EXPIRY.as_secs() - 1;
+        tokio::fs::write(
+            queue_dir
+                .path()
+                .join(format!("{TELEMETRY_QUEUE_PATH}.{abandoned}.1")),
+            "old\n",
+        )
+        .await?;
+        // A flush in progress elsewhere
+        tokio::fs::write(
+            queue_dir
+                .path()
+                .join(format!("{TELEMETRY_QUEUE_PATH}.{now}.2")),
+            "in-flight\n",
+        )
+        .await?;
+        append_to_queue(queue_dir.path(), "new", TELEMETRY_QUEUE_CAPACITY).await?;
+
+        let mut events = claim_queue(queue_dir.path()).await?;
+        events.sort();
+        assert_eq!(events, vec!["new", "old"]);
+        Ok(())
+    }
+}
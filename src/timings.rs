@@ -0,0 +1,105 @@
+//! `--timings`: how long each step of preparing an environment took, summarized once riff is done.
+//!
+//! Steps time themselves by holding a [`Timer`] from [`start`]. Timers do nothing unless timings
+//! were enabled, so they can stay in place unconditionally.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// When timings were enabled, which is also when the total is measured from
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static TIMINGS: Mutex<Vec<(String, Duration)>> = Mutex::new(Vec::new());
+
+/// Record timings from now on
+pub fn enable() {
+    if STARTED.set(Instant::now()).is_err() {
+        tracing::debug!("Timings were already enabled");
+    }
+}
+
+/// Times a step until it is dropped
+#[derive(Debug)]
+pub struct Timer {
+    label: String,
+    started: Instant,
+}
+
+/// Start timing the step called `label`
+#[must_use = "the step is timed until the timer is dropped"]
+pub fn start(label: impl Into<String>) -> Option<Timer> {
+    STARTED.get()?;
+    Some(Timer {
+        label: label.into(),
+        started: Instant::now(),
+    })
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        tracing::debug!(label = %self.label, ?elapsed, "Timed step");
+        if let Ok(mut timings) = TIMINGS.lock() {
+            timings.push((std::mem::take(&mut self.label), elapsed));
+        }
+    }
+}
+
+/// Print the recorded timings, if timings were enabled
+pub fn report() {
+    let Some(started) = STARTED.get() else {
+        return;
+    };
+    let timings = match TIMINGS.lock() {
+        Ok(timings) => timings.clone(),
+        Err(_) => return,
+    };
+    crate::spinner::suspend(|| eprint!("{}", render(&timings, started.elapsed())));
+}
+
+/// Steps can run at the same time (eg `nix flake lock` and detection), so the total is the wall
+/// time since timings were enabled rather than the sum of the steps.
+fn render(timings: &[(String, Duration)], total: Duration) -> String {
+    let width = timings
+        .iter()
+        .map(|(label, _)| label.len())
+        .chain(["total".len()])
+        .max()
+        .unwrap_or_default();
+    let mut table = String::from("Timings:\n");
+    for (label, elapsed) in timings
+        .iter()
+        .map(|(label, elapsed)| (label.as_str(), elapsed))
+        .chain([("total", &total)])
+    {
+        table.push_str(&format!(
+            "  {label:<width$}  {:>7.2}s\n",
+            elapsed.as_secs_f64()
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_aligns_steps() {
+        let table = render(
+            &[
+                ("cargo metadata".to_string(), Duration::from_millis(1500)),
+                ("nix flake lock".to_string(), Duration::from_millis(250)),
+            ],
+            Duration::from_secs(2),
+        );
+        assert_eq!(
+            table,
+            "\
+Timings:
+  cargo metadata     1.50s
+  nix flake lock     0.25s
+  total              2.00s
+"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! Whether to trust a project's own configuration with what riff can't check for you.
+//!
+//! A project's `workspace.metadata.riff` and its members' `package.metadata.riff` can set
+//! environment variables and shell hooks, which run as you in `riff shell`, as can the profiles of
+//! its `riff.toml` (and the layers that includes), the environment recorded in its `riff.lock`,
+//! and its `riff-template.nix`. Its `riff.toml` can also add binary caches, which Nix fetches the
+//! environment from. The first time riff sees a project whose configuration does, it asks whether
+//! to trust it, and remembers the answer in `$XDG_STATE_HOME/riff/trust.json`, along with a hash
+//! of what it was trusted with, so that it asks again when that changes (eg after a `git pull`).
+//! `riff trust` and `riff untrust` decide ahead of time, or change your mind. The same decision
+//! lets the shell hook (see `riff hook`) activate the project's environment, which it can't ask
+//! about at a prompt.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use atty::Stream;
+use eyre::WrapErr;
+use itertools::Itertools;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+
+use crate::warnings::{Warning, WarningCode};
+use crate::RIFF_XDG_PREFIX;
+
+/// The decisions, by project directory, in `$XDG_STATE_HOME/riff`
+const TRUST_FILE: &str = "trust.json";
+
+fn trust_path() -> color_eyre::Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
+    Ok(xdg_dirs.place_state_file(TRUST_FILE)?)
+}
+
+/// What was decided about a project
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", from = "StoredDecision")]
+struct Decision {
+    trusted: bool,
+    /// The hashes (see [`overrides_hash`]) of the overrides the project was trusted with
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    approved: BTreeSet<String>,
+    /// Trusted with `riff trust`, which approves the overrides the next riff command sees
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    approve_next: bool,
+}
+
+/// A [`Decision`] as `trust.json` has it, which older riffs wrote as just whether the project is
+/// trusted, without what it was trusted with
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDecision {
+    Trusted(bool),
+    #[serde(rename_all = "kebab-case")]
+    Decision {
+        trusted: bool,
+        #[serde(default)]
+        approved: BTreeSet<String>,
+        #[serde(default)]
+        approve_next: bool,
+    },
+}
+
+impl From<StoredDecision> for Decision {
+    fn from(stored: StoredDecision) -> Self {
+        match stored {
+            StoredDecision::Trusted(trusted) => Decision {
+                trusted,
+                ..Default::default()
+            },
+            StoredDecision::Decision {
+                trusted,
+                approved,
+                approve_next,
+            } => Decision {
+                trusted,
+                approved,
+                approve_next,
+            },
+        }
+    }
+}
+
+/// The projects whose `riff trust` this riff is approving the overrides of, so that every check
+/// in the command after `riff trust` approves them, not just the first
+static APPROVING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Every decision made, whether each project is trusted
+async fn decisions() -> color_eyre::Result<BTreeMap<PathBuf, Decision>> {
+    let path = trust_path()?;
+    match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("Could not parse `{}`", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err).wrap_err_with(|| format!("Could not read `{}`", path.display())),
+    }
+}
+
+async fn write_decisions(decisions: &BTreeMap<PathBuf, Decision>) -> color_eyre::Result<()> {
+    crate::execution::write(&trust_path()?, serde_json::to_string_pretty(decisions)?).await
+}
+
+/// Whether the project in `project_dir` is trusted, if that was decided
+pub async fn decision(project_dir: &Path) -> color_eyre::Result<Option<bool>> {
+    Ok(decisions()
+        .await?
+        .get(&key(project_dir))
+        .map(|decision| decision.trusted))
+}
+
+/// Remember whether the project in `project_dir` is trusted, with whatever its configuration sets
+/// the next time riff looks
+pub async fn decide(project_dir: &Path, trusted: bool) -> color_eyre::Result<()> {
+    let mut decisions = decisions().await?;
+    let decision = decisions.entry(key(project_dir)).or_default();
+    decision.trusted = trusted;
+    decision.approve_next = trusted;
+    if !trusted {
+        decision.approved.clear();
+    }
+    write_decisions(&decisions).await
+}
+
+/// Decisions are kept for the directory however it was reached
+fn key(project_dir: &Path) -> PathBuf {
+    project_dir
+        .canonicalize()
+        .unwrap_or_else(|_| project_dir.to_path_buf())
+}
+
+/// Whether to apply `overrides`, the environment variables, shell hooks, and binary caches the
+/// configuration of the project in `project_dir` sets (as described to the user).
+///
+/// Asks, the first time and whenever a trusted project's `overrides` change, if riff can.
+/// Otherwise, such as in CI, they aren't applied unless the project was trusted with `riff trust`
+/// since they changed.
+pub async fn check(project_dir: &Path, overrides: &[String]) -> color_eyre::Result<bool> {
+    if overrides.is_empty() {
+        return Ok(true);
+    }
+    let key = key(project_dir);
+    let hash = overrides_hash(overrides);
+    let mut decisions = decisions().await?;
+    let changed = match decisions.get_mut(&key) {
+        Some(decision) if !decision.trusted => return Ok(false),
+        Some(decision) if decision.approved.contains(&hash) => return Ok(true),
+        Some(decision) if decision.approve_next || APPROVING.lock().unwrap().contains(&key) => {
+            APPROVING.lock().unwrap().insert(key);
+            decision.approve_next = false;
+            decision.approved.insert(hash);
+            write_decisions(&decisions).await?;
+            return Ok(true);
+        }
+        Some(_) => true,
+        None => false,
+    };
+    if is_ci::cached() || !(atty::is(Stream::Stdin) && atty::is(Stream::Stderr)) {
+        tracing::debug!(project_dir = %project_dir.display(), changed, "Not trusting the project without asking");
+        return Ok(false);
+    }
+
+    let trusted = crate::spinner::suspend(|| -> color_eyre::Result<bool> {
+        eprintln!(
+            "The configuration of `{}` {}:",
+            project_dir.display().to_string().cyan(),
+            if changed {
+                "changed since you trusted it, and now sets"
+            } else {
+                "sets"
+            },
+        );
+        for item in overrides {
+            eprintln!("  {item}");
+        }
+        eprint!("Trust this project and apply them? [y/N] ");
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    })?;
+    let decision = decisions.entry(key).or_default();
+    decision.trusted = trusted;
+    if trusted {
+        decision.approved.insert(hash);
+    } else {
+        decision.approved.clear();
+    }
+    write_decisions(&decisions).await?;
+    if !trusted {
+        eprintln!(
+            "Leaving them out; run `{}` if you change your mind",
+            "riff trust".cyan()
+        );
+    }
+    Ok(trusted)
+}
+
+/// Identifies `overrides` (in any order), so that a change to them is noticed
+fn overrides_hash(overrides: &[String]) -> String {
+    let sorted = overrides.iter().collect::<BTreeSet<_>>();
+    crate::riff_lock::sha256_hex(sorted.into_iter().join("\n").as_bytes())
+}
+
+/// The warning that what `source` sets, `overrides`, was left out of an untrusted project
+pub fn untrusted_warning(source: &str, overrides: &[String]) -> Warning {
+    Warning::new(
+        WarningCode::UntrustedProject,
+        format!(
+            "{source} sets {}, which were left out since the project isn't trusted. Run `riff \
+            trust` to apply them.",
+            overrides.join(", ")
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn changed_overrides_are_asked_about_again() -> eyre::Result<()> {
+        let state_dir = tempfile::TempDir::new()?;
+        std::env::set_var("XDG_STATE_HOME", state_dir.path());
+        let project_dir = tempfile::TempDir::new()?;
+        let before = ["`GREETING=hello`".to_string()];
+        let after = ["a shell hook running `curl https://example.com | sh`".to_string()];
+        let mut decisions = BTreeMap::new();
+        decisions.insert(
+            key(project_dir.path()),
+            Decision {
+                trusted: true,
+                approved: [overrides_hash(&before)].into(),
+                approve_next: false,
+            },
+        );
+        write_decisions(&decisions).await?;
+
+        // Without a terminal to ask on, what changed isn't applied until `riff trust`
+        assert!(check(project_dir.path(), &before).await?);
+        assert!(!check(project_dir.path(), &after).await?);
+        decide(project_dir.path(), true).await?;
+        assert!(check(project_dir.path(), &after).await?);
+        assert!(check(project_dir.path(), &before).await?);
+
+        decide(project_dir.path(), false).await?;
+        assert!(!check(project_dir.path(), &before).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn decisions_without_hashes_are_read() -> eyre::Result<()> {
+        let decisions: BTreeMap<PathBuf, Decision> =
+            serde_json::from_str(r#"{ "/old": true, "/new": { "trusted": false } }"#)?;
+        assert!(decisions[Path::new("/old")].trusted);
+        assert!(decisions[Path::new("/old")].approved.is_empty());
+        assert!(!decisions[Path::new("/new")].trusted);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! `--verify`: quick checks that what the environment provides works, run before the user's own
+//! commands so a broken environment fails with a clear message instead of in the middle of a build.
+
+use std::collections::BTreeSet;
+use std::process::Stdio;
+use std::time::Duration;
+
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::errors::{ErrorCode, RiffError};
+use crate::nix_dev_env::{self, HostEnvironment, NixDevEnv};
+use crate::spinner::{self, Phase};
+
+/// How long a probe may take before the environment counts as broken
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A command which succeeds if the environment provides something correctly
+struct Probe {
+    /// The inputs (by package name, eg `openssl`) the environment must all have for the probe to
+    /// run, or none for what every environment has
+    inputs: &'static [&'static str],
+    argv: &'static [&'static str],
+}
+
+const PROBES: &[Probe] = &[
+    // Every environment has the C compiler of its `stdenv`
+    Probe {
+        inputs: &[],
+        argv: &["cc", "--version"],
+    },
+    Probe {
+        inputs: &["cargo"],
+        argv: &["cargo", "--version"],
+    },
+    Probe {
+        inputs: &["rustc"],
+        argv: &["rustc", "--version"],
+    },
+    Probe {
+        inputs: &["nodejs"],
+        argv: &["node", "--version"],
+    },
+    Probe {
+        inputs: &["python3"],
+        argv: &["python3", "--version"],
+    },
+    Probe {
+        inputs: &["go"],
+        argv: &["go", "version"],
+    },
+    Probe {
+        inputs: &["protobuf"],
+        argv: &["protoc", "--version"],
+    },
+    Probe {
+        inputs: &["cmake"],
+        argv: &["cmake", "--version"],
+    },
+    Probe {
+        inputs: &["openssl", "pkg-config-wrapper"],
+        argv: &["pkg-config", "--exists", "openssl"],
+    },
+];
+
+/// Run the probes for the inputs of `dev_env`, failing on the first which doesn't succeed
+pub async fn verify(
+    dev_env: &NixDevEnv,
+    host_environment: &HostEnvironment,
+) -> color_eyre::Result<()> {
+    let probes = probes_for(&dev_env.input_names());
+    let spinner = spinner::start(
+        Phase::Verifying,
+        &format!("Checking the environment with {} probes", probes.len()),
+    )
+    .wrap_err("Failed to construct progress spinner")?;
+    // Any overridden variables are warned about once, for the command the probes precede
+    let host_environment = &HostEnvironment {
+        no_override_warning: true,
+        ..host_environment.clone()
+    };
+
+    for probe in probes {
+        let command_line = probe.argv.join(" ");
+        spinner.set_detail(Some(&format!("`{command_line}`")));
+        let mut command =
+            nix_dev_env::run_in_dev_env(dev_env, probe.argv[0], host_environment).await?;
+        command
+            .args(&probe.argv[1..])
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        tracing::trace!(command = ?command.as_std(), "Probing");
+        let broken = |reason: String| {
+            RiffError::new(
+                ErrorCode::BrokenEnvironment,
+                format!("The environment is broken: `{command_line}` {reason}"),
+            )
+        };
+        let output = match tokio::time::timeout(PROBE_TIMEOUT, command.output()).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => {
+                return Err(broken("could not run".to_string()).with_source(err).into())
+            }
+            Err(_) => {
+                return Err(
+                    broken(format!("didn't finish within {}s", PROBE_TIMEOUT.as_secs())).into(),
+                )
+            }
+        };
+        if !output.status.success() {
+            return Err(broken(format!(
+                "failed:\n{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .into());
+        }
+    }
+
+    spinner.finish_and_clear();
+    eprintln!("{} The environment works", "✓".green());
+    Ok(())
+}
+
+/// The probes whose inputs are all in `inputs`
+fn probes_for(inputs: &BTreeSet<String>) -> Vec<&'static Probe> {
+    PROBES
+        .iter()
+        .filter(|probe| probe.inputs.iter().all(|input| inputs.contains(*input)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_follow_the_inputs() {
+        let inputs = ["cargo", "openssl", "pkg-config-wrapper"]
+            .map(String::from)
+            .into();
+        let probes = probes_for(&inputs)
+            .into_iter()
+            .map(|probe| probe.argv.join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            probes,
+            [
+                "cc --version",
+                "cargo --version",
+                "pkg-config --exists openssl"
+            ]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Advisory warnings about a project's environment.
+//!
+//! Each warning has a stable code (eg `W0001`) and name (eg `unmapped-sys-crate`), either of which
+//! can be listed in `suppress` in `riff.toml` to silence it. `--deny-warnings` turns any remaining
+//! warnings into an error, for use in CI. `--strict` does the same for `unknown-field` warnings
+//! only, even if they're suppressed.
+
+use std::fmt;
+
+use owo_colors::OwoColorize;
+
+/// Returned when warnings were reported and `--deny-warnings` was passed
+#[derive(Debug, thiserror::Error)]
+#[error("{0} warning(s) were reported and `--deny-warnings` is set")]
+pub struct WarningsDenied(pub usize);
+
+/// Returned when fields riff doesn't know were found and `--strict` was passed
+#[derive(Debug, thiserror::Error)]
+#[error("{0} unknown field(s) were reported and `--strict` is set")]
+pub struct UnknownFieldsDenied(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum WarningCode {
+    /// A `-sys` crate which riff doesn't know the external dependencies of
+    UnmappedSysCrate,
+    /// A `targets` key in `package.metadata.riff` which isn't a target triple
+    UnknownTarget,
+    /// A `riff.lock` made for a different `Cargo.lock`
+    StaleLock,
+    /// An environment variable set to different values by different dependencies
+    EnvironmentVariableConflict,
+    /// A crate whose build script looks for a native library which riff doesn't know about
+    UnmappedNativeLibrary,
+    /// A field riff doesn't know, eg a misspelling in `package.metadata.riff` or a registry overlay
+    UnknownField,
+    /// Environment variables or shell hooks from the project's own configuration, left out since
+    /// the project isn't trusted
+    UntrustedProject,
+    /// A `riff-detector-*` program which failed or printed something riff couldn't read
+    DetectorFailed,
+    /// A .NET SDK pinned by `global.json` which Nixpkgs no longer has
+    UnavailableSdk,
+}
+
+impl WarningCode {
+    pub const ALL: &'static [WarningCode] = &[
+        Self::UnmappedSysCrate,
+        Self::UnknownTarget,
+        Self::StaleLock,
+        Self::EnvironmentVariableConflict,
+        Self::UnmappedNativeLibrary,
+        Self::UnknownField,
+        Self::UntrustedProject,
+        Self::DetectorFailed,
+        Self::UnavailableSdk,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::UnmappedSysCrate => "W0001",
+            Self::UnknownTarget => "W0002",
+            Self::StaleLock => "W0003",
+            Self::EnvironmentVariableConflict => "W0004",
+            Self::UnmappedNativeLibrary => "W0005",
+            Self::UnknownField => "W0006",
+            Self::UntrustedProject => "W0007",
+            Self::DetectorFailed => "W0008",
+            Self::UnavailableSdk => "W0009",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UnmappedSysCrate => "unmapped-sys-crate",
+            Self::UnknownTarget => "unknown-target",
+            Self::StaleLock => "stale-lock",
+            Self::EnvironmentVariableConflict => "environment-variable-conflict",
+            Self::UnmappedNativeLibrary => "unmapped-native-library",
+            Self::UnknownField => "unknown-field",
+            Self::UntrustedProject => "untrusted-project",
+            Self::DetectorFailed => "detector-failed",
+            Self::UnavailableSdk => "unavailable-sdk",
+        }
+    }
+
+    /// Whether `code_or_name` (as written in `suppress`) refers to this warning
+    pub fn matches(self, code_or_name: &str) -> bool {
+        code_or_name.eq_ignore_ascii_case(self.code()) || code_or_name == self.name()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub code: WarningCode,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(code: WarningCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.code.code(),
+            self.code.name(),
+            self.message
+        )
+    }
+}
+
+/// Print the `warnings` which aren't listed in `suppress`, failing with [`WarningsDenied`] if
+/// there were any and `deny` is set, or with [`UnknownFieldsDenied`] if there were unknown fields
+/// and `strict` is set
+pub fn report(
+    warnings: &[Warning],
+    suppress: &[String],
+    deny: bool,
+    strict: bool,
+) -> color_eyre::Result<()> {
+    let mut reported = unsuppressed(warnings, suppress);
+    let unknown_fields = warnings
+        .iter()
+        .filter(|warning| warning.code == WarningCode::UnknownField)
+        .collect::<Vec<_>>();
+    if strict {
+        // Strict mode can't be suppressed
+        reported.retain(|warning| warning.code != WarningCode::UnknownField);
+        reported.extend(unknown_fields.iter().copied());
+    }
+    if reported.is_empty() {
+        return Ok(());
+    }
+
+    crate::spinner::suspend(|| {
+        for warning in &reported {
+            eprintln!("{} {}", "⚠".yellow(), warning);
+        }
+    });
+
+    if strict && !unknown_fields.is_empty() {
+        return Err(UnknownFieldsDenied(unknown_fields.len()).into());
+    }
+    if deny {
+        return Err(WarningsDenied(reported.len()).into());
+    }
+    Ok(())
+}
+
+fn unsuppressed<'a>(warnings: &'a [Warning], suppress: &[String]) -> Vec<&'a Warning> {
+    warnings
+        .iter()
+        .filter(|warning| !suppress.iter().any(|entry| warning.code.matches(entry)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suppress_by_code_or_name() {
+        let warnings = vec![
+            Warning::new(WarningCode::UnmappedSysCrate, "`foo-sys`"),
+            Warning::new(WarningCode::UnknownTarget, "`not-a-triple`"),
+        ];
+
+        assert_eq!(unsuppressed(&warnings, &[]).len(), 2);
+        assert_eq!(
+            unsuppressed(&warnings, &["W0001".to_string()]),
+            vec![&warnings[1]]
+        );
+        assert_eq!(
+            unsuppressed(&warnings, &["unknown-target".to_string()]),
+            vec![&warnings[0]]
+        );
+        assert!(report(&warnings, &["w0001".into(), "W0002".into()], true, false).is_ok());
+        assert!(report(&warnings, &["W0001".into()], true, false).is_err());
+    }
+
+    #[test]
+    fn strict_mode_fails_on_unknown_fields() {
+        let warnings = vec![Warning::new(
+            WarningCode::UnknownField,
+            "In `package.metadata.riff` of `foo`: Unknown field `build_inputs`",
+        )];
+        let suppress = ["unknown-field".to_string()];
+
+        assert!(report(&warnings, &suppress, true, false).is_ok());
+        assert!(report(&warnings, &[], false, false).is_ok());
+        let err = report(&warnings, &suppress, false, true).unwrap_err();
+        assert!(err.is::<UnknownFieldsDenied>());
+    }
+}